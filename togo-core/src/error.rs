@@ -1,11 +1,13 @@
 use thiserror::Error;
 
-use crate::storage::StorageError;
+use crate::{shard::ShardError, storage::StorageError};
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Storage error: {}", .0)]
     StorageError(StorageError),
+    #[error("Shard error: {}", .0)]
+    ShardError(ShardError),
 }
 
 impl From<StorageError> for Error {
@@ -13,3 +15,9 @@ impl From<StorageError> for Error {
         Self::StorageError(value)
     }
 }
+
+impl From<ShardError> for Error {
+    fn from(value: ShardError) -> Self {
+        Self::ShardError(value)
+    }
+}
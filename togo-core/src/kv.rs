@@ -0,0 +1,1394 @@
+use std::{
+    ops::Bound,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use togo_vr::{
+    codec::{get_u64, Codec, CodecResult},
+    replica::client::{ClientIdentity, ClientReply, ClientTable},
+    state::{Checkpoint, CheckpointChunk, OperationContext, StateError, StateMachine, StateResult},
+};
+
+use crate::{
+    operation::{Operation, OperationResult},
+    shard::range::{KeyRange, RangeStats},
+    storage::{
+        checkpoint::{CheckpointFile, CheckpointStore},
+        Delete, Get, Scan, Snapshot, StorageError, StorageResult, Upsert,
+    },
+    transaction::{TransactionId, TransactionRecord},
+};
+
+const DATA_KEYSPACE: u8 = 0;
+const HISTORY_KEYSPACE: u8 = 1;
+const EXPIRY_KEYSPACE: u8 = 2;
+const META_KEYSPACE: u8 = 3;
+const INTENT_KEYSPACE: u8 = 4;
+const TRANSACTION_KEYSPACE: u8 = 5;
+const COORDINATOR_KEYSPACE: u8 = 6;
+
+const LAST_TIMESTAMP_META: &[u8] = b"last_timestamp";
+const CLIENT_TABLE_META: &[u8] = b"client_table";
+const RANGE_META: &[u8] = b"range";
+
+const PREPARED_STATUS: u8 = 0;
+const ABORTED_STATUS: u8 = 1;
+
+const TOMBSTONE_FLAG: u8 = 0b0000_0001;
+const EXPIRES_FLAG: u8 = 0b0000_0010;
+
+pub struct KvStateMachine<S> {
+    storage: S,
+    retain_history: bool,
+    // timestamp of the most recently applied operation - reads treat it as
+    // "now" so that every replica hides exactly the same expired keys
+    last_timestamp: AtomicU64,
+    checkpoints: Option<CheckpointStore>,
+    // the keys owned by the shard, only ever changed through committed splits
+    // and merges
+    range: RwLock<KeyRange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionedValue {
+    pub version: u64,
+    pub value: Bytes,
+}
+
+impl AsRef<[u8]> for VersionedValue {
+    fn as_ref(&self) -> &[u8] {
+        &self.value
+    }
+}
+
+impl Codec for VersionedValue {
+    fn encode(&self, buffer: &mut BytesMut) {
+        buffer.put_u64(self.version);
+        self.value.encode(buffer);
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        Ok(Self {
+            version: get_u64(data)?,
+            value: Bytes::decode(data)?,
+        })
+    }
+}
+
+impl<S> KvStateMachine<S> {
+    pub fn new(storage: S) -> Self {
+        Self {
+            storage,
+            retain_history: false,
+            last_timestamp: AtomicU64::new(0),
+            checkpoints: None,
+            range: RwLock::new(KeyRange::default()),
+        }
+    }
+
+    pub fn with_history(storage: S) -> Self {
+        Self {
+            storage,
+            retain_history: true,
+            last_timestamp: AtomicU64::new(0),
+            checkpoints: None,
+            range: RwLock::new(KeyRange::default()),
+        }
+    }
+
+    pub fn with_checkpoints(mut self, checkpoints: CheckpointStore) -> Self {
+        self.checkpoints = Some(checkpoints);
+        self
+    }
+
+    /// Restricts the state machine to a part of the keyspace, e.g. for a
+    /// shard created by a split.
+    pub fn with_range(mut self, range: KeyRange) -> Self {
+        self.range = RwLock::new(range);
+        self
+    }
+
+    /// Carries the notion of "now" over from another copy of the state,
+    /// e.g. for a shard being migrated.
+    pub fn with_last_timestamp(self, last_timestamp: u64) -> Self {
+        self.last_timestamp
+            .fetch_max(last_timestamp, Ordering::AcqRel);
+        self
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub fn last_timestamp(&self) -> u64 {
+        self.last_timestamp.load(Ordering::Acquire)
+    }
+
+    pub fn checkpoints(&self) -> Option<&CheckpointStore> {
+        self.checkpoints.as_ref()
+    }
+
+    pub fn range(&self) -> StorageResult<KeyRange> {
+        self.read_range().map(|range| range.clone())
+    }
+
+    fn read_range(&self) -> StorageResult<RwLockReadGuard<'_, KeyRange>> {
+        self.range
+            .read()
+            .map_err(|_| StorageError::Unknown("Key range lock is poisoned!".into()))
+    }
+
+    fn write_range(&self) -> StorageResult<RwLockWriteGuard<'_, KeyRange>> {
+        self.range
+            .write()
+            .map_err(|_| StorageError::Unknown("Key range lock is poisoned!".into()))
+    }
+}
+
+impl<S> KvStateMachine<S>
+where
+    S: Get + Upsert + Delete + Scan,
+{
+    pub fn apply(
+        &self,
+        context: &OperationContext,
+        operation: &Operation,
+    ) -> StorageResult<OperationResult> {
+        let OperationContext {
+            op_number,
+            timestamp,
+            ..
+        } = *context;
+
+        self.last_timestamp.fetch_max(timestamp, Ordering::AcqRel);
+
+        if let Some(key) = operation.routing_key() {
+            if !self.read_range()?.contains(&key) {
+                return Ok(OperationResult::OutOfRange);
+            }
+        }
+
+        if let Some(key) = operation.key() {
+            if let Some(transaction) = self.intent(key)? {
+                return Ok(OperationResult::Locked(transaction));
+            }
+        }
+
+        match operation {
+            Operation::Upsert(key, value, ttl) => {
+                let expires_at = ttl.map(|ttl| expiry_timestamp(timestamp, ttl));
+
+                self.write(op_number, key, Some(value), expires_at)
+            }
+            Operation::CompareAndUpsert(key, value, expected_version) => {
+                let current_version = self.get(key)?.map(|current| current.version);
+
+                if current_version != *expected_version {
+                    return Ok(OperationResult::VersionMismatch(current_version));
+                }
+
+                self.write(op_number, key, Some(value), None)
+            }
+            Operation::Delete(key) => self.write(op_number, key, None, None),
+            Operation::SweepExpired(limit) => self.sweep_expired(op_number, timestamp, *limit),
+            Operation::Split(at) => self.split(at),
+            Operation::Merge(adjacent) => self.merge(adjacent),
+            Operation::Prepare(transaction, writes) => self.prepare(*transaction, writes),
+            Operation::Commit(transaction) => {
+                self.commit_transaction(op_number, timestamp, *transaction)
+            }
+            Operation::Abort(transaction) => self.abort_transaction(*transaction),
+            Operation::Record(transaction, record, expected_version) => {
+                self.record_transaction(op_number, *transaction, record, *expected_version)
+            }
+            Operation::Forget(transaction) => {
+                self.storage.delete(coordinator_key(*transaction))?;
+
+                Ok(OperationResult::Deleted)
+            }
+            Operation::Install(range, records) => self.install(range, records),
+            Operation::NoOp => Ok(OperationResult::NoOp),
+        }
+    }
+
+    /// Reads the value as it was right after `commit_number` was applied -
+    /// like `get`, it leaves out values that have expired by now.
+    pub fn get_at<K>(&self, key: K, commit_number: u64) -> StorageResult<Option<VersionedValue>>
+    where
+        K: AsRef<[u8]>,
+    {
+        if !self.retain_history {
+            return Err(StorageError::HistoryNotRetained);
+        }
+
+        let now = self.last_timestamp.load(Ordering::Acquire);
+        let key = key.as_ref();
+        let range = (
+            Bound::Included(history_key(key, 0)),
+            Bound::Included(history_key(key, commit_number)),
+        );
+
+        match self.storage.scan_range(range).last().transpose()? {
+            Some((_, record)) => {
+                let record = Record::decode(record.as_ref())?;
+
+                if record.is_expired(now) {
+                    return Ok(None);
+                }
+
+                Ok(record.into_versioned())
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Moves every key within the range over to the target, along with its
+    /// history and expiry index entries and the transaction records routed
+    /// to it - once a split or merge has been committed, the keys follow the
+    /// range to its new owner.
+    pub fn move_range<T: Upsert>(
+        &self,
+        range: &KeyRange,
+        target: &KvStateMachine<T>,
+    ) -> StorageResult<u64> {
+        let records = self.records(range)?;
+        let keys = records
+            .iter()
+            .filter(|(key, _)| key.first() == Some(&DATA_KEYSPACE))
+            .count();
+
+        for (key, value) in records {
+            target.storage.upsert(&key, value)?;
+            self.storage.delete(key)?;
+        }
+
+        Ok(keys as u64)
+    }
+
+    fn split(&self, at: &Bytes) -> StorageResult<OperationResult> {
+        let mut range = self.write_range()?;
+
+        if !range.contains(at) {
+            return Ok(OperationResult::OutOfRange);
+        }
+
+        // transactions only ever resolve within the shard they were prepared
+        // in, so their keys can't be handed over before that
+        let released = KeyRange {
+            start: at.clone(),
+            end: range.end.clone(),
+        };
+
+        if let Some(entry) = self.storage.scan_range(intent_range(&released)).next() {
+            let (_, transaction) = entry?;
+
+            return Ok(OperationResult::Locked(parse_transaction(
+                transaction.as_ref(),
+            )?));
+        }
+
+        Ok(OperationResult::Split(KeyRange {
+            start: at.clone(),
+            end: range.end.replace(at.clone()),
+        }))
+    }
+
+    fn merge(&self, adjacent: &KeyRange) -> StorageResult<OperationResult> {
+        let mut range = self.write_range()?;
+
+        if range.end.as_ref() != Some(&adjacent.start) {
+            return Ok(OperationResult::OutOfRange);
+        }
+
+        range.end = adjacent.end.clone();
+
+        Ok(OperationResult::Merged(range.clone()))
+    }
+
+    fn prepare(
+        &self,
+        transaction: TransactionId,
+        writes: &[Operation],
+    ) -> StorageResult<OperationResult> {
+        match self.storage.get(transaction_key(transaction))? {
+            Some(record) if record.as_ref().first() == Some(&ABORTED_STATUS) => {
+                return Ok(OperationResult::Aborted)
+            }
+            Some(_) => return Ok(OperationResult::Prepared),
+            None => {}
+        }
+
+        let range = self.range()?;
+
+        for write in writes {
+            let Some(key) = write.key() else {
+                return Ok(OperationResult::Aborted);
+            };
+
+            if !range.contains(key) {
+                return Ok(OperationResult::OutOfRange);
+            }
+
+            if let Some(holder) = self.intent(key)? {
+                return Ok(OperationResult::Locked(holder));
+            }
+
+            if let Operation::CompareAndUpsert(key, _, expected_version) = write {
+                let current_version = self.get(key)?.map(|current| current.version);
+
+                if current_version != *expected_version {
+                    return Ok(OperationResult::VersionMismatch(current_version));
+                }
+            }
+        }
+
+        let mut record = BytesMut::new();
+
+        record.put_u8(PREPARED_STATUS);
+        record.put_u32(writes.len() as u32);
+
+        for write in writes {
+            write.encode(&mut record);
+        }
+
+        self.storage.upsert(transaction_key(transaction), record)?;
+
+        for key in writes.iter().filter_map(Operation::key) {
+            self.storage
+                .upsert(intent_key(key), transaction.0.to_be_bytes())?;
+        }
+
+        Ok(OperationResult::Prepared)
+    }
+
+    fn commit_transaction(
+        &self,
+        op_number: u64,
+        timestamp: u64,
+        transaction: TransactionId,
+    ) -> StorageResult<OperationResult> {
+        let writes = match self.storage.get(transaction_key(transaction))? {
+            Some(record) if record.as_ref().first() == Some(&PREPARED_STATUS) => {
+                Vec::<Operation>::from_bytes(&record.as_ref()[1..])?
+            }
+            _ => return Ok(OperationResult::NoOp),
+        };
+
+        // versions were checked while preparing, and the keys have been
+        // locked ever since
+        for write in &writes {
+            match write {
+                Operation::Upsert(key, value, ttl) => {
+                    let expires_at = ttl.map(|ttl| expiry_timestamp(timestamp, ttl));
+
+                    self.write(op_number, key, Some(value), expires_at)?;
+                }
+                Operation::CompareAndUpsert(key, value, _) => {
+                    self.write(op_number, key, Some(value), None)?;
+                }
+                Operation::Delete(key) => {
+                    self.write(op_number, key, None, None)?;
+                }
+                _ => {}
+            }
+        }
+
+        self.release_intents(&writes)?;
+        self.storage.delete(transaction_key(transaction))?;
+
+        Ok(OperationResult::Committed(op_number))
+    }
+
+    // the record is kept around as a marker, so that a prepare delayed past
+    // the abort doesn't lock the keys again
+    fn abort_transaction(&self, transaction: TransactionId) -> StorageResult<OperationResult> {
+        if let Some(record) = self.storage.get(transaction_key(transaction))? {
+            if record.as_ref().first() == Some(&PREPARED_STATUS) {
+                self.release_intents(&Vec::<Operation>::from_bytes(&record.as_ref()[1..])?)?;
+            }
+        }
+
+        self.storage
+            .upsert(transaction_key(transaction), [ABORTED_STATUS])?;
+
+        Ok(OperationResult::Aborted)
+    }
+
+    fn install(
+        &self,
+        installed: &KeyRange,
+        records: &[(Bytes, Bytes)],
+    ) -> StorageResult<OperationResult> {
+        let mut range = self.write_range()?;
+
+        if !range.covers(installed) {
+            return Ok(OperationResult::OutOfRange);
+        }
+
+        for (key, _) in records {
+            if !owner(key)?.is_some_and(|owner| installed.contains(&owner)) {
+                return Ok(OperationResult::OutOfRange);
+            }
+        }
+
+        for (key, value) in records {
+            self.storage.upsert(key, value)?;
+        }
+
+        *range = installed.clone();
+
+        Ok(OperationResult::Installed(records.len() as u64))
+    }
+
+    fn record_transaction(
+        &self,
+        op_number: u64,
+        transaction: TransactionId,
+        record: &Bytes,
+        expected_version: Option<u64>,
+    ) -> StorageResult<OperationResult> {
+        let current_version = self
+            .transaction_record(transaction)?
+            .map(|current| current.version);
+
+        if current_version != expected_version {
+            return Ok(OperationResult::VersionMismatch(current_version));
+        }
+
+        let record = Record {
+            version: op_number,
+            expires_at: None,
+            value: Some(record.clone()),
+        };
+
+        self.storage
+            .upsert(coordinator_key(transaction), record.encode())?;
+
+        Ok(OperationResult::Written(op_number))
+    }
+
+    fn release_intents(&self, writes: &[Operation]) -> StorageResult<()> {
+        for key in writes.iter().filter_map(Operation::key) {
+            self.storage.delete(intent_key(key))?;
+        }
+
+        Ok(())
+    }
+
+    fn intent(&self, key: &[u8]) -> StorageResult<Option<TransactionId>> {
+        match self.storage.get(intent_key(key))? {
+            Some(transaction) => parse_transaction(transaction.as_ref()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn write(
+        &self,
+        op_number: u64,
+        key: &[u8],
+        value: Option<&Bytes>,
+        expires_at: Option<u64>,
+    ) -> StorageResult<OperationResult> {
+        let record = Record {
+            version: op_number,
+            expires_at,
+            value: value.cloned(),
+        };
+
+        match value {
+            Some(_) => self.storage.upsert(data_key(key), record.encode())?,
+            None => self.storage.delete(data_key(key))?,
+        }
+
+        // stale index entries left behind by overwrites are skipped by the sweep
+        if let Some(expires_at) = expires_at {
+            self.storage.upsert(expiry_key(expires_at, key), [])?;
+        }
+
+        if self.retain_history {
+            self.storage
+                .upsert(history_key(key, op_number), record.encode())?;
+        }
+
+        Ok(match value {
+            Some(_) => OperationResult::Written(op_number),
+            None => OperationResult::Deleted,
+        })
+    }
+
+    fn sweep_expired(
+        &self,
+        op_number: u64,
+        timestamp: u64,
+        limit: u32,
+    ) -> StorageResult<OperationResult> {
+        let range = (
+            Bound::Included(expiry_key(0, &[])),
+            Bound::Excluded(expiry_key(timestamp.saturating_add(1), &[])),
+        );
+
+        // collected upfront, as the sweep modifies the range it's iterating over
+        let expired = self
+            .storage
+            .scan_range(range)
+            .take(limit as usize)
+            .collect::<StorageResult<Vec<_>>>()?;
+
+        let mut reclaimed = 0;
+
+        for (index_key, _) in expired {
+            let (expires_at, key) = parse_expiry_key(index_key.as_ref())?;
+
+            if let Some(record) = self.storage.get(data_key(key))? {
+                if Record::decode(record.as_ref())?.expires_at == Some(expires_at) {
+                    self.write(op_number, key, None, None)?;
+                    reclaimed += 1;
+                }
+            }
+
+            self.storage.delete(index_key)?;
+        }
+
+        Ok(OperationResult::Reclaimed(reclaimed))
+    }
+}
+
+impl<S: Scan> KvStateMachine<S> {
+    /// Live values within the range, in key order - keys outside of the
+    /// shard's own range are left out.
+    pub fn scan(
+        &self,
+        range: &KeyRange,
+        limit: usize,
+    ) -> StorageResult<Vec<(Bytes, VersionedValue)>> {
+        let now = self.last_timestamp.load(Ordering::Acquire);
+        let range = range.intersection(&self.range()?);
+
+        if range.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut entries = Vec::new();
+
+        for entry in self.storage.scan_range(data_range(&range)) {
+            if entries.len() >= limit {
+                break;
+            }
+
+            let (key, record) = entry?;
+            let record = Record::decode(record.as_ref())?;
+
+            if record.is_expired(now) {
+                continue;
+            }
+
+            if let Some(value) = record.into_versioned() {
+                entries.push((Bytes::copy_from_slice(&key.as_ref()[1..]), value));
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// The coordinator's record of the transaction, if the shard owns it.
+    pub fn transaction_record(
+        &self,
+        transaction: TransactionId,
+    ) -> StorageResult<Option<VersionedValue>> {
+        if !self
+            .read_range()?
+            .contains(&TransactionRecord::key(transaction))
+        {
+            return Ok(None);
+        }
+
+        match self.storage.get(coordinator_key(transaction))? {
+            Some(record) => Ok(Record::decode(record.as_ref())?.into_versioned()),
+            None => Ok(None),
+        }
+    }
+
+    /// Everything stored for the keys within the range - their records,
+    /// history and expiry index entries, and the transaction records routed
+    /// to them - as it's handed over to the range's next owner.
+    pub fn records(&self, range: &KeyRange) -> StorageResult<Vec<(Bytes, Bytes)>> {
+        let copy = |(key, value): (S::ReturnValue, S::ReturnValue)| {
+            (
+                Bytes::copy_from_slice(key.as_ref()),
+                Bytes::copy_from_slice(value.as_ref()),
+            )
+        };
+        let mut records = self
+            .storage
+            .scan_range(data_range(range))
+            .map(|entry| entry.map(copy))
+            .collect::<StorageResult<Vec<_>>>()?;
+
+        // none of the other keyspaces is ordered by the key alone, so they're
+        // filtered
+        for keyspace in [HISTORY_KEYSPACE, EXPIRY_KEYSPACE, COORDINATOR_KEYSPACE] {
+            for entry in self.storage.scan_prefix([keyspace]) {
+                let (key, value) = copy(entry?);
+
+                if owner(&key)?.is_some_and(|owner| range.contains(&owner)) {
+                    records.push((key, value));
+                }
+            }
+        }
+
+        Ok(records)
+    }
+
+    /// Whether any key expires at or before the timestamp, i.e. whether a
+    /// sweep stamped with it would have anything to reclaim.
+    pub fn expires_by(&self, timestamp: u64) -> StorageResult<bool> {
+        let range = (
+            Bound::Included(expiry_key(0, &[])),
+            Bound::Excluded(expiry_key(timestamp.saturating_add(1), &[])),
+        );
+
+        self.storage
+            .scan_range(range)
+            .next()
+            .transpose()
+            .map(|entry| entry.is_some())
+    }
+
+    /// Counts the live keys within the shard's range and finds the key in
+    /// the middle of them by size, where the range would be split.
+    pub fn range_stats(&self) -> StorageResult<RangeStats> {
+        let range = data_range(&self.range()?);
+        let mut stats = RangeStats::default();
+
+        for entry in self.storage.scan_range(range.clone()) {
+            let (key, record) = entry?;
+
+            stats.keys += 1;
+            stats.bytes += (key.as_ref().len() + record.as_ref().len()) as u64;
+        }
+
+        let mut bytes = 0;
+
+        for entry in self.storage.scan_range(range) {
+            let (key, record) = entry?;
+
+            bytes += (key.as_ref().len() + record.as_ref().len()) as u64;
+
+            if bytes * 2 >= stats.bytes {
+                stats.middle_key = Some(Bytes::copy_from_slice(&key.as_ref()[1..]));
+                break;
+            }
+        }
+
+        Ok(stats)
+    }
+}
+
+impl<S> StateMachine<Operation, OperationResult> for KvStateMachine<S>
+where
+    S: Get + Upsert + Delete + Scan,
+{
+    fn apply_operations(
+        &self,
+        operations: &[(OperationContext, &Operation)],
+    ) -> StateResult<Vec<OperationResult>> {
+        operations
+            .iter()
+            .map(|(context, operation)| {
+                self.apply(context, operation)
+                    .map_err(|error| StateError::ApplyFailed(error.to_string()))
+            })
+            .collect()
+    }
+}
+
+impl<S> KvStateMachine<S>
+where
+    S: Snapshot + Upsert,
+{
+    /// Replaces the whole state with a checkpoint, e.g. on startup, handing
+    /// back the client table stored along with it.
+    pub fn restore(
+        &mut self,
+        checkpoint: &CheckpointFile,
+    ) -> StorageResult<ClientTable<OperationResult>> {
+        let checkpoints = self.checkpoints.as_ref().ok_or_else(no_checkpoint_store)?;
+
+        checkpoints.restore(&mut self.storage, checkpoint)?;
+
+        let last_timestamp = match self.storage.get(meta_key(LAST_TIMESTAMP_META))? {
+            Some(value) => value
+                .as_ref()
+                .try_into()
+                .map(u64::from_be_bytes)
+                .map_err(|_| {
+                    StorageError::CorruptionDetected("Malformed last timestamp!".into())
+                })?,
+            None => 0,
+        };
+
+        self.last_timestamp.store(last_timestamp, Ordering::Release);
+
+        *self.write_range()? = match self.storage.get(meta_key(RANGE_META))? {
+            Some(range) => KeyRange::decode(&mut range.as_ref())?,
+            None => KeyRange::default(),
+        };
+
+        match self.storage.get(meta_key(CLIENT_TABLE_META))? {
+            Some(clients) => decode_client_table(clients.as_ref()),
+            None => Ok(ClientTable::new()),
+        }
+    }
+
+    fn checkpoint_store(&self) -> StorageResult<&CheckpointStore> {
+        self.checkpoints.as_ref().ok_or_else(no_checkpoint_store)
+    }
+}
+
+impl<S> Checkpoint<OperationResult> for KvStateMachine<S>
+where
+    S: Snapshot + Upsert,
+{
+    fn checkpoint(
+        &self,
+        op_number: u64,
+        view_number: u64,
+        clients: &ClientTable<OperationResult>,
+    ) -> StateResult<()> {
+        // reads depend on the last timestamp too, so it has to travel along
+        let last_timestamp = self.last_timestamp.load(Ordering::Acquire);
+
+        self.checkpoint_store()
+            .and_then(|checkpoints| {
+                let mut range = BytesMut::new();
+
+                self.range()?.encode(&mut range);
+                self.storage
+                    .upsert(meta_key(LAST_TIMESTAMP_META), last_timestamp.to_be_bytes())?;
+                self.storage.upsert(meta_key(RANGE_META), range)?;
+                self.storage
+                    .upsert(meta_key(CLIENT_TABLE_META), encode_client_table(clients))?;
+                checkpoints.save(&self.storage, op_number, view_number)
+            })
+            .map(|_| ())
+            .map_err(|error| StateError::CheckpointFailed(error.to_string()))
+    }
+
+    fn read_checkpoint(
+        &self,
+        op_number: u64,
+        offset: u64,
+        length: usize,
+    ) -> StateResult<CheckpointChunk> {
+        self.checkpoint_store()
+            .and_then(|checkpoints| checkpoints.read(op_number, offset, length))
+            .map(|(data, last)| CheckpointChunk {
+                data: data.into(),
+                last,
+            })
+            .map_err(|error| StateError::CheckpointTransferFailed(error.to_string()))
+    }
+
+    fn receive_checkpoint(&self, op_number: u64, offset: u64, data: &[u8]) -> StateResult<()> {
+        self.checkpoint_store()
+            .and_then(|checkpoints| checkpoints.receive(op_number, offset, data))
+            .map_err(|error| StateError::CheckpointTransferFailed(error.to_string()))
+    }
+
+    fn install_checkpoint(&mut self, op_number: u64) -> StateResult<ClientTable<OperationResult>> {
+        self.checkpoint_store()
+            .and_then(|checkpoints| checkpoints.finish_receive(op_number))
+            .and_then(|checkpoint| self.restore(&checkpoint))
+            .map_err(|error| StateError::CheckpointTransferFailed(error.to_string()))
+    }
+
+    fn last_timestamp(&self) -> u64 {
+        self.last_timestamp.load(Ordering::Acquire)
+    }
+}
+
+impl<S> Get for KvStateMachine<S>
+where
+    S: Get,
+{
+    type ReturnValue = VersionedValue;
+
+    fn get<K>(&self, key: K) -> StorageResult<Option<Self::ReturnValue>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let now = self.last_timestamp.load(Ordering::Acquire);
+
+        // keys handed over by a split linger until they've been moved
+        if !self.read_range()?.contains(key.as_ref()) {
+            return Ok(None);
+        }
+
+        match self.storage.get(data_key(key.as_ref()))? {
+            Some(record) => {
+                let record = Record::decode(record.as_ref())?;
+
+                if record.is_expired(now) {
+                    return Ok(None);
+                }
+
+                Ok(record.into_versioned())
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+struct Record {
+    version: u64,
+    expires_at: Option<u64>,
+    value: Option<Bytes>,
+}
+
+impl Record {
+    fn encode(&self) -> Bytes {
+        let value = self.value.as_deref().unwrap_or_default();
+        let mut buffer = BytesMut::with_capacity(17 + value.len());
+        let mut flags = 0;
+
+        if self.value.is_none() {
+            flags |= TOMBSTONE_FLAG;
+        }
+
+        if self.expires_at.is_some() {
+            flags |= EXPIRES_FLAG;
+        }
+
+        buffer.put_u64(self.version);
+        buffer.put_u8(flags);
+
+        if let Some(expires_at) = self.expires_at {
+            buffer.put_u64(expires_at);
+        }
+
+        buffer.put_slice(value);
+        buffer.freeze()
+    }
+
+    fn decode(mut data: &[u8]) -> StorageResult<Self> {
+        if data.len() < 9 {
+            return Err(StorageError::CorruptionDetected(
+                "Stored record is too short!".into(),
+            ));
+        }
+
+        let version = data.get_u64();
+        let flags = data.get_u8();
+
+        let expires_at = match flags & EXPIRES_FLAG {
+            0 => None,
+            _ if data.len() < 8 => {
+                return Err(StorageError::CorruptionDetected(
+                    "Stored record is missing its expiry!".into(),
+                ))
+            }
+            _ => Some(data.get_u64()),
+        };
+
+        Ok(Self {
+            version,
+            expires_at,
+            value: (flags & TOMBSTONE_FLAG == 0).then(|| Bytes::copy_from_slice(data)),
+        })
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
+
+    fn into_versioned(self) -> Option<VersionedValue> {
+        let version = self.version;
+
+        self.value.map(|value| VersionedValue { version, value })
+    }
+}
+
+fn data_key(key: &[u8]) -> Bytes {
+    let mut buffer = BytesMut::with_capacity(1 + key.len());
+
+    buffer.put_u8(DATA_KEYSPACE);
+    buffer.put_slice(key);
+    buffer.freeze()
+}
+
+// history keys are length-prefixed so that a key's versions never interleave
+// with the versions of a longer key sharing its prefix
+fn history_key(key: &[u8], version: u64) -> Bytes {
+    let mut buffer = BytesMut::with_capacity(13 + key.len());
+
+    buffer.put_u8(HISTORY_KEYSPACE);
+    buffer.put_u32(key.len() as u32);
+    buffer.put_slice(key);
+    buffer.put_u64(version);
+    buffer.freeze()
+}
+
+fn data_range(range: &KeyRange) -> (Bound<Bytes>, Bound<Bytes>) {
+    let end = match &range.end {
+        Some(end) => data_key(end),
+        None => Bytes::copy_from_slice(&[DATA_KEYSPACE + 1]),
+    };
+
+    (
+        Bound::Included(data_key(&range.start)),
+        Bound::Excluded(end),
+    )
+}
+
+fn intent_key(key: &[u8]) -> Bytes {
+    let mut buffer = BytesMut::with_capacity(1 + key.len());
+
+    buffer.put_u8(INTENT_KEYSPACE);
+    buffer.put_slice(key);
+    buffer.freeze()
+}
+
+fn intent_range(range: &KeyRange) -> (Bound<Bytes>, Bound<Bytes>) {
+    let end = match &range.end {
+        Some(end) => intent_key(end),
+        None => Bytes::copy_from_slice(&[INTENT_KEYSPACE + 1]),
+    };
+
+    (
+        Bound::Included(intent_key(&range.start)),
+        Bound::Excluded(end),
+    )
+}
+
+fn transaction_key(transaction: TransactionId) -> Bytes {
+    let mut buffer = BytesMut::with_capacity(9);
+
+    buffer.put_u8(TRANSACTION_KEYSPACE);
+    buffer.put_u64(transaction.0);
+    buffer.freeze()
+}
+
+fn coordinator_key(transaction: TransactionId) -> Bytes {
+    let mut buffer = BytesMut::with_capacity(9);
+
+    buffer.put_u8(COORDINATOR_KEYSPACE);
+    buffer.put_u64(transaction.0);
+    buffer.freeze()
+}
+
+// the key a stored entry follows to a new owner, if it's one that does
+fn owner(stored: &[u8]) -> StorageResult<Option<Bytes>> {
+    Ok(match stored.first() {
+        Some(&DATA_KEYSPACE) => Some(Bytes::copy_from_slice(&stored[1..])),
+        Some(&HISTORY_KEYSPACE) => Some(Bytes::copy_from_slice(parse_history_key(stored)?)),
+        Some(&EXPIRY_KEYSPACE) => Some(Bytes::copy_from_slice(parse_expiry_key(stored)?.1)),
+        Some(&COORDINATOR_KEYSPACE) => {
+            Some(TransactionRecord::key(parse_transaction(&stored[1..])?))
+        }
+        _ => None,
+    })
+}
+
+fn parse_transaction(value: &[u8]) -> StorageResult<TransactionId> {
+    value
+        .try_into()
+        .map(|value| TransactionId(u64::from_be_bytes(value)))
+        .map_err(|_| StorageError::CorruptionDetected("Malformed transaction identifier!".into()))
+}
+
+fn parse_history_key(mut index_key: &[u8]) -> StorageResult<&[u8]> {
+    let malformed = || StorageError::CorruptionDetected("Malformed history entry!".into());
+
+    if index_key.len() < 13 || index_key.get_u8() != HISTORY_KEYSPACE {
+        return Err(malformed());
+    }
+
+    let length = index_key.get_u32() as usize;
+
+    index_key.get(..length).ok_or_else(malformed)
+}
+
+fn expiry_key(expires_at: u64, key: &[u8]) -> Bytes {
+    let mut buffer = BytesMut::with_capacity(9 + key.len());
+
+    buffer.put_u8(EXPIRY_KEYSPACE);
+    buffer.put_u64(expires_at);
+    buffer.put_slice(key);
+    buffer.freeze()
+}
+
+fn encode_client_table(clients: &ClientTable<OperationResult>) -> Bytes {
+    let mut buffer = BytesMut::with_capacity(4 + clients.len() * 42);
+
+    buffer.put_u32(clients.len() as u32);
+
+    for (client, reply) in clients {
+        buffer.put_u64(client.0);
+        buffer.put_u64(reply.session);
+        buffer.put_u64(reply.request_number);
+        buffer.put_u64(reply.last_timestamp);
+
+        match &reply.response {
+            Some(response) => response.encode(&mut buffer),
+            None => buffer.put_u8(0),
+        }
+    }
+
+    buffer.freeze()
+}
+
+fn decode_client_table(mut data: &[u8]) -> StorageResult<ClientTable<OperationResult>> {
+    let malformed = || StorageError::CorruptionDetected("Malformed client table!".into());
+    let mut clients = ClientTable::new();
+
+    if data.remaining() < 4 {
+        return Err(malformed());
+    }
+
+    for _ in 0..data.get_u32() {
+        if data.remaining() < 33 {
+            return Err(malformed());
+        }
+
+        let client = ClientIdentity(data.get_u64());
+        let session = data.get_u64();
+        let request_number = data.get_u64();
+        let last_timestamp = data.get_u64();
+
+        let response = match data.first() {
+            Some(0) => {
+                data.advance(1);
+                None
+            }
+            _ => Some(OperationResult::decode(&mut data)?),
+        };
+
+        clients.insert(
+            client,
+            ClientReply {
+                session,
+                request_number,
+                response,
+                last_timestamp,
+            },
+        );
+    }
+
+    Ok(clients)
+}
+
+fn no_checkpoint_store() -> StorageError {
+    StorageError::Unknown("No checkpoint store was configured!".into())
+}
+
+fn meta_key(name: &[u8]) -> Bytes {
+    let mut buffer = BytesMut::with_capacity(1 + name.len());
+
+    buffer.put_u8(META_KEYSPACE);
+    buffer.put_slice(name);
+    buffer.freeze()
+}
+
+fn parse_expiry_key(mut index_key: &[u8]) -> StorageResult<(u64, &[u8])> {
+    if index_key.len() < 9 || index_key.get_u8() != EXPIRY_KEYSPACE {
+        return Err(StorageError::CorruptionDetected(
+            "Malformed expiry index entry!".into(),
+        ));
+    }
+
+    Ok((index_key.get_u64(), index_key))
+}
+
+fn expiry_timestamp(timestamp: u64, ttl: Duration) -> u64 {
+    timestamp.saturating_add(ttl.as_millis().try_into().unwrap_or(u64::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use togo_vr::{
+        replica::client::{ClientIdentity, ClientReply, ClientTable},
+        state::{Checkpoint, OperationContext},
+    };
+
+    use crate::{
+        operation::{Operation, OperationResult},
+        storage::{checkpoint::CheckpointStore, memory::MemoryStorage, Get},
+    };
+
+    use super::KvStateMachine;
+
+    fn context(op_number: u64, timestamp: u64) -> OperationContext {
+        OperationContext {
+            op_number,
+            timestamp,
+            seed: 0,
+        }
+    }
+
+    #[test]
+    pub fn values_carry_the_op_number_that_wrote_them() {
+        let kv = KvStateMachine::new(MemoryStorage::new());
+
+        kv.apply(
+            &context(3, 0),
+            &Operation::Upsert("key".into(), "one".into(), None),
+        )
+        .unwrap();
+        kv.apply(
+            &context(7, 0),
+            &Operation::Upsert("key".into(), "two".into(), None),
+        )
+        .unwrap();
+
+        let value = kv.get("key").unwrap().unwrap();
+
+        assert_eq!(value.version, 7);
+        assert_eq!(value.value, Bytes::from("two"));
+    }
+
+    #[test]
+    pub fn compare_and_upsert_requires_matching_version() {
+        let kv = KvStateMachine::new(MemoryStorage::new());
+
+        let result = kv
+            .apply(
+                &context(1, 0),
+                &Operation::CompareAndUpsert("key".into(), "one".into(), None),
+            )
+            .unwrap();
+        assert_eq!(result, OperationResult::Written(1));
+
+        let result = kv
+            .apply(
+                &context(2, 0),
+                &Operation::CompareAndUpsert("key".into(), "two".into(), Some(5)),
+            )
+            .unwrap();
+        assert_eq!(result, OperationResult::VersionMismatch(Some(1)));
+
+        let result = kv
+            .apply(
+                &context(3, 0),
+                &Operation::CompareAndUpsert("key".into(), "two".into(), Some(1)),
+            )
+            .unwrap();
+        assert_eq!(result, OperationResult::Written(3));
+        assert_eq!(kv.get("key").unwrap().unwrap().value, Bytes::from("two"));
+    }
+
+    #[test]
+    pub fn history_serves_reads_as_of_commit_number() {
+        let kv = KvStateMachine::with_history(MemoryStorage::new());
+
+        kv.apply(
+            &context(2, 0),
+            &Operation::Upsert("key".into(), "one".into(), None),
+        )
+        .unwrap();
+        kv.apply(
+            &context(4, 0),
+            &Operation::Upsert("key".into(), "two".into(), None),
+        )
+        .unwrap();
+        kv.apply(&context(6, 0), &Operation::Delete("key".into()))
+            .unwrap();
+        kv.apply(
+            &context(8, 0),
+            &Operation::Upsert("key2".into(), "other".into(), None),
+        )
+        .unwrap();
+
+        assert_eq!(kv.get_at("key", 1).unwrap(), None);
+        assert_eq!(
+            kv.get_at("key", 3).unwrap().unwrap().value,
+            Bytes::from("one")
+        );
+        assert_eq!(kv.get_at("key", 5).unwrap().unwrap().version, 4);
+        assert_eq!(kv.get_at("key", 9).unwrap(), None);
+        assert_eq!(kv.get("key").unwrap(), None);
+    }
+
+    #[test]
+    pub fn expired_keys_are_hidden_and_swept_through_operations() {
+        let kv = KvStateMachine::new(MemoryStorage::new());
+        let ttl = Some(Duration::from_millis(100));
+
+        kv.apply(
+            &context(1, 1000),
+            &Operation::Upsert("short".into(), "a".into(), ttl),
+        )
+        .unwrap();
+        kv.apply(
+            &context(2, 1000),
+            &Operation::Upsert("long".into(), "b".into(), None),
+        )
+        .unwrap();
+        kv.apply(
+            &context(3, 1050),
+            &Operation::Upsert("renewed".into(), "c".into(), ttl),
+        )
+        .unwrap();
+        kv.apply(
+            &context(4, 1060),
+            &Operation::Upsert("renewed".into(), "d".into(), ttl),
+        )
+        .unwrap();
+
+        assert!(kv.get("short").unwrap().is_some());
+
+        kv.apply(&context(5, 1100), &Operation::NoOp).unwrap();
+
+        assert!(kv.get("short").unwrap().is_none());
+        assert!(kv.get("renewed").unwrap().is_some());
+        assert!(kv.expires_by(1155).unwrap());
+
+        let result = kv
+            .apply(&context(6, 1155), &Operation::SweepExpired(10))
+            .unwrap();
+
+        assert_eq!(result, OperationResult::Reclaimed(1));
+        assert!(kv
+            .storage()
+            .get(super::data_key(b"short"))
+            .unwrap()
+            .is_none());
+        assert_eq!(kv.get("renewed").unwrap().unwrap().version, 4);
+        assert_eq!(kv.get("long").unwrap().unwrap().version, 2);
+        assert!(!kv.expires_by(1155).unwrap());
+        assert!(kv.expires_by(1160).unwrap());
+    }
+
+    #[test]
+    pub fn history_reads_leave_out_expired_values() {
+        let kv = KvStateMachine::with_history(MemoryStorage::new());
+
+        kv.apply(
+            &context(1, 1000),
+            &Operation::Upsert("key".into(), "a".into(), Some(Duration::from_millis(100))),
+        )
+        .unwrap();
+
+        assert_eq!(kv.get_at("key", 1).unwrap().unwrap().version, 1);
+
+        kv.apply(&context(2, 1100), &Operation::NoOp).unwrap();
+
+        assert_eq!(kv.get_at("key", 1).unwrap(), None);
+        assert_eq!(kv.get("key").unwrap(), None);
+    }
+
+    #[test]
+    pub fn checkpoints_are_saved_with_their_op_number_and_pruned() {
+        let directory =
+            std::env::temp_dir().join(format!("togo-checkpoints-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+
+        let checkpoints = CheckpointStore::open(&directory, 1)
+            .unwrap()
+            .with_retained(2);
+        let kv = KvStateMachine::new(MemoryStorage::new()).with_checkpoints(checkpoints);
+
+        for op_number in 1..=3 {
+            kv.apply(
+                &context(op_number, 0),
+                &Operation::Upsert("key".into(), "value".into(), None),
+            )
+            .unwrap();
+            kv.checkpoint(op_number, 1, &ClientTable::new()).unwrap();
+        }
+
+        let checkpoints = kv.checkpoints().unwrap();
+        let stored = checkpoints.list().unwrap();
+        let latest = checkpoints.latest().unwrap().unwrap();
+        let metadata = checkpoints.verify(&latest).unwrap();
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(stored.len(), 2);
+        assert_eq!(latest.op_number, 3);
+        assert_eq!(metadata.header.op_number, 3);
+        assert_eq!(metadata.header.view, 1);
+        assert_eq!(metadata.manifest.key_count, 4);
+    }
+
+    #[test]
+    pub fn checkpoints_can_be_transferred_in_parts_and_installed() {
+        let directory =
+            std::env::temp_dir().join(format!("togo-checkpoint-transfer-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+
+        let source = KvStateMachine::new(MemoryStorage::new())
+            .with_checkpoints(CheckpointStore::open(directory.join("source"), 1).unwrap());
+        let mut target = KvStateMachine::new(MemoryStorage::new())
+            .with_checkpoints(CheckpointStore::open(directory.join("target"), 1).unwrap());
+
+        source
+            .apply(
+                &context(1, 100),
+                &Operation::Upsert(
+                    "key".into(),
+                    "value".into(),
+                    Some(Duration::from_millis(50)),
+                ),
+            )
+            .unwrap();
+        let clients = ClientTable::from([
+            (
+                ClientIdentity(7),
+                ClientReply {
+                    session: 1,
+                    request_number: 3,
+                    response: Some(OperationResult::Written(1)),
+                    last_timestamp: 100,
+                },
+            ),
+            (
+                ClientIdentity(9),
+                ClientReply {
+                    session: 2,
+                    request_number: 0,
+                    response: None,
+                    last_timestamp: 90,
+                },
+            ),
+        ]);
+
+        source.checkpoint(1, 0, &clients).unwrap();
+
+        let mut offset = 0;
+
+        loop {
+            let chunk = source.read_checkpoint(1, offset, 16).unwrap();
+
+            target.receive_checkpoint(1, offset, &chunk.data).unwrap();
+            offset += chunk.data.len() as u64;
+
+            if chunk.last {
+                break;
+            }
+        }
+
+        let installed_clients = target.install_checkpoint(1).unwrap();
+        let installed = target.checkpoints().unwrap().latest().unwrap();
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(installed.unwrap().op_number, 1);
+        assert_eq!(installed_clients, clients);
+        assert_eq!(
+            target.get("key").unwrap().unwrap().value,
+            Bytes::from("value")
+        );
+
+        target.apply(&context(2, 200), &Operation::NoOp).unwrap();
+
+        assert_eq!(target.get("key").unwrap(), None);
+    }
+}
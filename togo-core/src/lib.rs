@@ -1,5 +1,9 @@
 pub mod error;
+pub mod kv;
 pub mod log;
+pub mod metadata;
 pub mod operation;
+pub mod router;
 pub mod shard;
-pub mod storage;
\ No newline at end of file
+pub mod storage;
+pub mod transaction;
\ No newline at end of file
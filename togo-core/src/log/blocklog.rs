@@ -1,11 +1,575 @@
-use std::path::PathBuf;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
 
-pub struct BlockLog<T> {
+use bytes::{BufMut, BytesMut};
+use togo_vr::{
+    codec::Codec,
+    log::{Log, LogError, LogResult},
+};
+
+use crate::storage::{StorageError, StorageResult};
+
+const BLOCK_EXTENSION: &str = "block";
+const DEFAULT_BLOCK_SIZE: u64 = 64 * 1024 * 1024;
+// every record starts with the length of its entry, the length's checksum
+// and the entry's checksum
+const RECORD_HEADER_SIZE: usize = 12;
 
+/// A durable log kept in a directory of blocks, each holding a run of entries
+/// and named after the index of the first one. Every entry is written as a
+/// record of its length, the length's CRC32, the entry's CRC32 and its
+/// encoding - the length is checked on its own, so that a corrupted one
+/// can't pass for a record torn by a crash.
+///
+/// Entries are kept in memory as well, the files are only read on opening.
+/// Trimming the front removes whole blocks, so a reopened log may start a bit
+/// before the point it had been trimmed to.
+pub struct BlockLog<T> {
+    directory: PathBuf,
+    block_size: u64,
+    sync: bool,
+    offset: u64,
+    // never empty, entries are appended to the last block
+    blocks: Vec<Block<T>>,
+    writer: File,
 }
 
 pub(crate) struct Block<T> {
     pub path: PathBuf,
     pub start_id: u64,
-    pub data: Vec<T>
-}
\ No newline at end of file
+    pub data: Vec<T>,
+    // where each entry's record ends within the file
+    pub ends: Vec<u64>,
+}
+
+/// The records of a single block file, as found on disk.
+pub struct BlockFile<T> {
+    pub path: PathBuf,
+    pub start_id: u64,
+    pub entries: Vec<T>,
+    /// Length of the valid prefix of the file, anything past it is a torn or
+    /// corrupted record.
+    pub valid_length: u64,
+    pub length: u64,
+    /// Whether a record failed a checksum with more data after it - a crash
+    /// while appending only ever tears the very last record.
+    pub corrupted: bool,
+    ends: Vec<u64>,
+}
+
+impl<T: Codec> BlockLog<T> {
+    /// Opens the log in the directory, creating it if needed - a torn record
+    /// at the very end of the log is cut off, any other corruption is an
+    /// error.
+    pub fn open<P: AsRef<Path>>(directory: P) -> StorageResult<Self> {
+        let directory = directory.as_ref().to_path_buf();
+
+        fs::create_dir_all(&directory).map_err(StorageError::Io)?;
+
+        let paths = Self::list(&directory)?;
+        let mut blocks: Vec<Block<T>> = Vec::with_capacity(paths.len());
+
+        for (position, path) in paths.iter().enumerate() {
+            let file = Self::read_block(path)?;
+
+            if let Some(previous) = blocks.last() {
+                if previous.start_id + previous.data.len() as u64 != file.start_id {
+                    return Err(StorageError::CorruptionDetected(format!(
+                        "Block {} doesn't continue the one before it!",
+                        path.display()
+                    )));
+                }
+            }
+
+            if file.valid_length != file.length {
+                if file.corrupted || position + 1 != paths.len() {
+                    return Err(StorageError::CorruptionDetected(format!(
+                        "Block {} has a corrupted record at byte {}!",
+                        path.display(),
+                        file.valid_length
+                    )));
+                }
+
+                OpenOptions::new()
+                    .write(true)
+                    .open(path)
+                    .and_then(|block| block.set_len(file.valid_length))
+                    .map_err(StorageError::Io)?;
+            }
+
+            blocks.push(file.into());
+        }
+
+        if blocks.is_empty() {
+            blocks.push(Block::create(&directory, 0)?);
+        }
+
+        let offset = blocks[0].start_id;
+        let writer = Self::append_to(&blocks[blocks.len() - 1])?;
+
+        Ok(Self {
+            directory,
+            block_size: DEFAULT_BLOCK_SIZE,
+            sync: true,
+            offset,
+            blocks,
+            writer,
+        })
+    }
+
+    /// A new block is started once the last one has grown past this size.
+    pub fn with_block_size(mut self, block_size: u64) -> Self {
+        self.block_size = block_size.max(1);
+        self
+    }
+
+    /// Whether every push waits for the entry to reach the disk.
+    pub fn with_sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Block files in the directory in the order of their first entries.
+    pub fn list(directory: &Path) -> StorageResult<Vec<PathBuf>> {
+        let mut blocks = Vec::new();
+
+        for entry in fs::read_dir(directory).map_err(StorageError::Io)? {
+            let path = entry.map_err(StorageError::Io)?.path();
+
+            if path.extension().and_then(|extension| extension.to_str()) != Some(BLOCK_EXTENSION) {
+                continue;
+            }
+
+            if let Some(start_id) = Self::start_id(&path) {
+                blocks.push((start_id, path));
+            }
+        }
+
+        blocks.sort_unstable_by_key(|(start_id, _)| *start_id);
+
+        Ok(blocks.into_iter().map(|(_, path)| path).collect())
+    }
+
+    /// Reads every record of a block file, stopping at the first one that
+    /// is cut short or fails a checksum.
+    pub fn read_block(path: &Path) -> StorageResult<BlockFile<T>> {
+        let start_id = Self::start_id(path).ok_or_else(|| {
+            StorageError::CorruptionDetected(format!("{} isn't a block file!", path.display()))
+        })?;
+        let contents = fs::read(path).map_err(StorageError::Io)?;
+        let mut entries = Vec::new();
+        let mut ends = Vec::new();
+        let mut position = 0;
+        let mut corrupted = false;
+
+        while let Some(header) = contents.get(position..position + RECORD_HEADER_SIZE) {
+            let length_checksum = u32::from_be_bytes(header[4..8].try_into().unwrap());
+            let start = position + RECORD_HEADER_SIZE;
+
+            // only a record reaching the end of the file can have been torn
+            if crc32fast::hash(&header[0..4]) != length_checksum {
+                corrupted = start < contents.len();
+                break;
+            }
+
+            let length = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+            let checksum = u32::from_be_bytes(header[8..12].try_into().unwrap());
+
+            let Some(payload) = contents.get(start..start + length) else {
+                break;
+            };
+
+            if crc32fast::hash(payload) != checksum {
+                corrupted = start + length < contents.len();
+                break;
+            }
+
+            // a record with a valid checksum that doesn't decode wasn't torn,
+            // it was written by something else entirely
+            entries.push(T::from_bytes(payload)?);
+            position = start + length;
+            ends.push(position as u64);
+        }
+
+        Ok(BlockFile {
+            path: path.to_path_buf(),
+            start_id,
+            entries,
+            valid_length: position as u64,
+            length: contents.len() as u64,
+            corrupted,
+            ends,
+        })
+    }
+
+    fn start_id(path: &Path) -> Option<u64> {
+        path.file_stem()?.to_str()?.parse().ok()
+    }
+
+    fn append_to(block: &Block<T>) -> StorageResult<File> {
+        OpenOptions::new()
+            .append(true)
+            .open(&block.path)
+            .map_err(StorageError::Io)
+    }
+
+    fn last_block(&self) -> &Block<T> {
+        &self.blocks[self.blocks.len() - 1]
+    }
+
+    fn start_block(&mut self, start_id: u64) -> StorageResult<()> {
+        let block = Block::create(&self.directory, start_id)?;
+
+        self.writer = Self::append_to(&block)?;
+        self.blocks.push(block);
+
+        Ok(())
+    }
+
+    fn remove_block(block: &Block<T>) -> StorageResult<()> {
+        match fs::remove_file(&block.path) {
+            Err(error) if error.kind() != ErrorKind::NotFound => Err(StorageError::Io(error)),
+            _ => Ok(()),
+        }
+    }
+
+    fn truncate_last_block(&mut self, length: usize) -> StorageResult<()> {
+        let last = self.blocks.len() - 1;
+        let block = &mut self.blocks[last];
+
+        block.data.truncate(length);
+        block.ends.truncate(length);
+
+        let end = block.ends.last().copied().unwrap_or_default();
+
+        OpenOptions::new()
+            .write(true)
+            .open(&block.path)
+            .and_then(|file| file.set_len(end))
+            .map_err(StorageError::Io)?;
+
+        self.writer = Self::append_to(&self.blocks[last])?;
+
+        Ok(())
+    }
+}
+
+impl<T> Block<T> {
+    fn create(directory: &Path, start_id: u64) -> StorageResult<Self> {
+        let path = directory.join(format!("{start_id:020}.{BLOCK_EXTENSION}"));
+
+        File::create(&path).map_err(StorageError::Io)?;
+
+        Ok(Self {
+            path,
+            start_id,
+            data: Vec::new(),
+            ends: Vec::new(),
+        })
+    }
+}
+
+impl<T> From<BlockFile<T>> for Block<T> {
+    fn from(file: BlockFile<T>) -> Self {
+        Self {
+            path: file.path,
+            start_id: file.start_id,
+            data: file.entries,
+            ends: file.ends,
+        }
+    }
+}
+
+impl<T: Codec> Log<T> for BlockLog<T> {
+    fn current_size(&self) -> u64 {
+        self.current_size_with_offset() - self.offset
+    }
+
+    fn current_offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn current_size_with_offset(&self) -> u64 {
+        let last = self.last_block();
+
+        last.start_id + last.data.len() as u64
+    }
+
+    fn get(&self, index: u64) -> LogResult<&T> {
+        if index < self.offset || index >= self.current_size_with_offset() {
+            return Err(LogError::InvalidIndex);
+        }
+
+        let position = self.blocks.partition_point(|block| block.start_id <= index) - 1;
+        let block = &self.blocks[position];
+
+        Ok(&block.data[(index - block.start_id) as usize])
+    }
+
+    fn push(&mut self, value: T) -> LogResult<()> {
+        let last = self.last_block();
+
+        if last.ends.last().is_some_and(|end| *end >= self.block_size) {
+            self.start_block(self.current_size_with_offset())
+                .map_err(persistence)?;
+        }
+
+        let payload = value.to_bytes();
+        let mut record = BytesMut::with_capacity(RECORD_HEADER_SIZE + payload.len());
+
+        let length = (payload.len() as u32).to_be_bytes();
+
+        record.put_slice(&length);
+        record.put_u32(crc32fast::hash(&length));
+        record.put_u32(crc32fast::hash(&payload));
+        record.put_slice(&payload);
+
+        let written = self
+            .writer
+            .write_all(&record)
+            .and_then(|()| match self.sync {
+                true => self.writer.sync_data(),
+                false => Ok(()),
+            });
+
+        // whatever made it to the file is cut off again, the next record
+        // would otherwise follow a torn one and the log wouldn't open
+        if let Err(error) = written {
+            let length = self.last_block().data.len();
+
+            self.truncate_last_block(length).map_err(persistence)?;
+
+            return Err(persistence(StorageError::Io(error)));
+        }
+
+        let last = self.blocks.len() - 1;
+        let block = &mut self.blocks[last];
+        let end = block.ends.last().copied().unwrap_or_default();
+
+        block.data.push(value);
+        block.ends.push(end + record.len() as u64);
+
+        Ok(())
+    }
+
+    fn trim_front(&mut self, first: u64) -> LogResult<()> {
+        if first < self.offset || first > self.current_size_with_offset() {
+            return Err(LogError::InvalidIndex);
+        }
+
+        // the last block always stays, new entries go there
+        let trimmed = self.blocks[..self.blocks.len() - 1]
+            .iter()
+            .take_while(|block| block.start_id + block.data.len() as u64 <= first)
+            .count();
+
+        for block in self.blocks.drain(..trimmed) {
+            Self::remove_block(&block).map_err(persistence)?;
+        }
+
+        self.offset = first;
+
+        Ok(())
+    }
+
+    fn trim_end(&mut self, last: u64) -> LogResult<()> {
+        if last < self.offset || last >= self.current_size_with_offset() {
+            return Err(LogError::InvalidIndex);
+        }
+
+        while self.blocks.len() > 1 && self.last_block().start_id >= last {
+            let block = self.blocks.pop().unwrap();
+
+            Self::remove_block(&block).map_err(persistence)?;
+        }
+
+        let length = (last - self.last_block().start_id) as usize;
+
+        self.truncate_last_block(length).map_err(persistence)
+    }
+
+    fn reset(&mut self, offset: u64) -> LogResult<()> {
+        for block in self.blocks.drain(..) {
+            Self::remove_block(&block).map_err(persistence)?;
+        }
+
+        self.start_block(offset).map_err(persistence)?;
+        self.offset = offset;
+
+        Ok(())
+    }
+}
+
+fn persistence(error: StorageError) -> LogError {
+    LogError::Persistence(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, io::Write};
+
+    use togo_vr::log::Log;
+
+    use crate::storage::StorageError;
+
+    use super::BlockLog;
+
+    #[test]
+    pub fn entries_survive_reopening_and_torn_tails_are_cut_off() {
+        let directory = std::env::temp_dir().join(format!("togo-blocklog-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+
+        {
+            let mut log = BlockLog::<u64>::open(&directory)
+                .unwrap()
+                .with_block_size(32)
+                .with_sync(false);
+
+            for value in 0..10 {
+                log.push(value).unwrap();
+            }
+
+            log.trim_front(5).unwrap();
+            log.trim_end(8).unwrap();
+            assert_eq!(log.current_offset(), 5);
+            assert_eq!(log.current_size_with_offset(), 8);
+        }
+
+        let last = BlockLog::<u64>::list(&directory).unwrap().pop().unwrap();
+
+        // half a record, as left behind by a crash in the middle of a push
+        OpenOptions::new()
+            .append(true)
+            .open(&last)
+            .and_then(|mut file| file.write_all(&[0, 0, 0, 8, 1, 2]))
+            .unwrap();
+
+        let mut log = BlockLog::<u64>::open(&directory).unwrap();
+
+        // only whole blocks are trimmed, a block holds two entries here
+        assert_eq!(log.current_offset(), 4);
+        assert_eq!(log.current_size_with_offset(), 8);
+        assert_eq!(*log.get(7).unwrap(), 7);
+
+        log.push(8).unwrap();
+        log.reset(20).unwrap();
+        log.push(20).unwrap();
+
+        let log = BlockLog::<u64>::open(&directory).unwrap();
+
+        assert_eq!(log.current_offset(), 20);
+        assert_eq!(*log.get(20).unwrap(), 20);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    pub fn corrupted_records_before_the_end_of_the_last_block_are_errors() {
+        let directory =
+            std::env::temp_dir().join(format!("togo-blocklog-corrupted-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+
+        {
+            let mut log = BlockLog::<u64>::open(&directory).unwrap().with_sync(false);
+
+            for value in 0..4 {
+                log.push(value).unwrap();
+            }
+        }
+
+        let last = BlockLog::<u64>::list(&directory).unwrap().pop().unwrap();
+        let mut contents = std::fs::read(&last).unwrap();
+
+        // the payload of the second record, each one has a 12 byte header
+        contents[32] ^= 0xff;
+        std::fs::write(&last, &contents).unwrap();
+
+        let block = BlockLog::<u64>::read_block(&last).unwrap();
+
+        assert_eq!(block.entries, vec![0]);
+        assert_eq!(block.valid_length, 20);
+        assert!(block.corrupted);
+        assert!(matches!(
+            BlockLog::<u64>::open(&directory),
+            Err(StorageError::CorruptionDetected(_))
+        ));
+        assert_eq!(std::fs::read(&last).unwrap(), contents);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    pub fn corrupted_lengths_before_the_end_of_the_last_block_are_errors() {
+        let directory =
+            std::env::temp_dir().join(format!("togo-blocklog-length-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+
+        {
+            let mut log = BlockLog::<u64>::open(&directory).unwrap().with_sync(false);
+
+            for value in 0..4 {
+                log.push(value).unwrap();
+            }
+        }
+
+        let last = BlockLog::<u64>::list(&directory).unwrap().pop().unwrap();
+        let mut contents = std::fs::read(&last).unwrap();
+
+        // the length of the second record now points past the end of the file
+        contents[20] ^= 0xff;
+        std::fs::write(&last, &contents).unwrap();
+
+        let block = BlockLog::<u64>::read_block(&last).unwrap();
+
+        assert_eq!(block.entries, vec![0]);
+        assert!(block.corrupted);
+        assert!(matches!(
+            BlockLog::<u64>::open(&directory),
+            Err(StorageError::CorruptionDetected(_))
+        ));
+        assert_eq!(std::fs::read(&last).unwrap(), contents);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    pub fn failed_pushes_leave_nothing_behind() {
+        let directory =
+            std::env::temp_dir().join(format!("togo-blocklog-failed-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+
+        let mut log = BlockLog::<u64>::open(&directory).unwrap().with_sync(false);
+
+        log.push(0).unwrap();
+
+        let last = BlockLog::<u64>::list(&directory).unwrap().pop().unwrap();
+
+        // part of a record made it to the file before the write failed
+        OpenOptions::new()
+            .append(true)
+            .open(&last)
+            .and_then(|mut file| file.write_all(&[0, 0, 0, 8, 1, 2]))
+            .unwrap();
+        log.writer = std::fs::File::open(&last).unwrap();
+
+        assert!(log.push(1).is_err());
+        assert_eq!(std::fs::metadata(&last).unwrap().len(), 20);
+
+        log.push(1).unwrap();
+        log.push(2).unwrap();
+
+        let log = BlockLog::<u64>::open(&directory).unwrap();
+
+        assert_eq!(log.current_size_with_offset(), 3);
+        assert_eq!(*log.get(1).unwrap(), 1);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,607 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+    sync::Mutex,
+};
+
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use thiserror::Error;
+
+use crate::{
+    kv::KvStateMachine,
+    operation::{Operation, OperationResult},
+    router::{GroupClient, Router, RouterError, RouterResult, Routing, RoutingSource},
+    shard::{
+        node::Node,
+        placement::{GroupIdentifier, Placement},
+        range::RangeMap,
+        ShardError, ShardIdentifier,
+    },
+    storage::{Delete, Flush, Scan, StorageError, StorageResult, Upsert},
+};
+
+pub type MetadataResult<T> = Result<T, MetadataError>;
+
+/// The configuration is stored under this key of the metadata group's only
+/// shard.
+pub const CONFIGURATION_KEY: &[u8] = b"\xffmetadata/configuration";
+
+pub const METADATA_SHARD: ShardIdentifier = ShardIdentifier(0);
+
+const DEFAULT_MAX_ATTEMPTS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeIdentifier(pub u32);
+
+impl Display for NodeIdentifier {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The authoritative topology of the cluster - which nodes there are, how the
+/// keys are split into shards, which group replicates every shard and which
+/// nodes every group's replicas run on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterConfiguration {
+    /// The op number the configuration was committed with, so every change
+    /// moves it forward - zero for one that hasn't been stored yet.
+    pub epoch: u64,
+    pub nodes: BTreeMap<NodeIdentifier, String>,
+    pub shards: RangeMap,
+    pub placement: Placement,
+    pub replicas: BTreeMap<GroupIdentifier, Vec<NodeIdentifier>>,
+}
+
+impl ClusterConfiguration {
+    /// A configuration with a single shard owning every key.
+    pub fn new(shard: ShardIdentifier) -> Self {
+        Self {
+            epoch: 0,
+            nodes: BTreeMap::new(),
+            shards: RangeMap::new(shard),
+            placement: Placement::new(),
+            replicas: BTreeMap::new(),
+        }
+    }
+
+    pub fn routing(&self) -> Routing<RangeMap> {
+        Routing {
+            map: self.shards.clone(),
+            placement: self.placement.clone(),
+        }
+    }
+
+    /// Addresses of the group's replicas, in replica order - replicas on
+    /// nodes without a known address are left out.
+    pub fn addresses_of(&self, group: GroupIdentifier) -> Vec<&str> {
+        self.replicas
+            .get(&group)
+            .into_iter()
+            .flatten()
+            .filter_map(|node| self.nodes.get(node).map(String::as_str))
+            .collect()
+    }
+
+    pub fn encode(&self) -> Bytes {
+        let mut buffer = BytesMut::new();
+
+        buffer.put_u32(self.nodes.len() as u32);
+
+        for (node, address) in &self.nodes {
+            buffer.put_u32(node.0);
+            buffer.put_u32(address.len() as u32);
+            buffer.put_slice(address.as_bytes());
+        }
+
+        self.shards.encode(&mut buffer);
+        self.placement.encode(&mut buffer);
+        buffer.put_u32(self.replicas.len() as u32);
+
+        for (group, nodes) in &self.replicas {
+            buffer.put_u32(group.0);
+            buffer.put_u32(nodes.len() as u32);
+
+            for node in nodes {
+                buffer.put_u32(node.0);
+            }
+        }
+
+        buffer.freeze()
+    }
+
+    pub fn decode(epoch: u64, mut data: &[u8]) -> StorageResult<Self> {
+        let data = &mut data;
+        let nodes = (0..get_u32(data)?)
+            .map(|_| {
+                let node = NodeIdentifier(get_u32(data)?);
+                let length = get_u32(data)? as usize;
+
+                if data.remaining() < length {
+                    return Err(malformed_configuration());
+                }
+
+                let address = String::from_utf8(data[..length].to_vec())
+                    .map_err(|_| malformed_configuration())?;
+
+                data.advance(length);
+
+                Ok((node, address))
+            })
+            .collect::<StorageResult<_>>()?;
+        let shards = RangeMap::decode(data)?;
+        let placement = Placement::decode(data)?;
+        let replicas = (0..get_u32(data)?)
+            .map(|_| {
+                let group = GroupIdentifier(get_u32(data)?);
+                let nodes = (0..get_u32(data)?)
+                    .map(|_| get_u32(data).map(NodeIdentifier))
+                    .collect::<StorageResult<_>>()?;
+
+                Ok((group, nodes))
+            })
+            .collect::<StorageResult<_>>()?;
+
+        Ok(Self {
+            epoch,
+            nodes,
+            shards,
+            placement,
+            replicas,
+        })
+    }
+}
+
+/// Client of the metadata group, a dedicated VR group storing the cluster's
+/// configuration in its only shard - every change is a compare-and-upsert
+/// against the epoch it was based on, so concurrent changes never get lost.
+///
+/// Routers and nodes follow the configuration through a [`Watch`] - togo
+/// servers don't run a metadata group yet and keep to their static
+/// configuration.
+pub struct MetadataService<C> {
+    router: Router<RangeMap, C, Routing<RangeMap>>,
+    max_attempts: usize,
+}
+
+impl<C: GroupClient + Send + Sync> MetadataService<C> {
+    pub fn new(client: C, group: GroupIdentifier) -> Self {
+        let mut placement = Placement::new();
+
+        placement.assign(METADATA_SHARD, group);
+
+        let routing = Routing {
+            map: RangeMap::new(METADATA_SHARD),
+            placement,
+        };
+
+        Self {
+            router: Router::new(client, routing.clone(), routing),
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+        }
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: usize) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Stores the initial configuration, unless there already is one.
+    pub async fn bootstrap(
+        &self,
+        configuration: ClusterConfiguration,
+    ) -> MetadataResult<ClusterConfiguration> {
+        self.store(configuration, None)
+            .await?
+            .ok_or(MetadataError::AlreadyBootstrapped)
+    }
+
+    pub async fn configuration(&self) -> MetadataResult<ClusterConfiguration> {
+        let value = self
+            .router
+            .get(CONFIGURATION_KEY)
+            .await?
+            .ok_or(MetadataError::NotBootstrapped)?;
+
+        Ok(ClusterConfiguration::decode(value.version, &value.value)?)
+    }
+
+    /// Applies the change to the current configuration - a change that raced
+    /// with another one is applied again, to the configuration that won.
+    pub async fn update<F>(&self, mut change: F) -> MetadataResult<ClusterConfiguration>
+    where
+        F: FnMut(&mut ClusterConfiguration) -> MetadataResult<()> + Send,
+    {
+        for _ in 0..self.max_attempts {
+            let mut configuration = self.configuration().await?;
+            let epoch = configuration.epoch;
+
+            change(&mut configuration)?;
+
+            if let Some(configuration) = self.store(configuration, Some(epoch)).await? {
+                return Ok(configuration);
+            }
+        }
+
+        Err(MetadataError::TooManyConflicts)
+    }
+
+    /// Watches the configuration for changes, starting from the given epoch.
+    pub fn watch(&self, epoch: u64) -> Watch<'_, C> {
+        Watch {
+            service: self,
+            epoch,
+        }
+    }
+
+    // `None` if the stored configuration isn't the expected one
+    async fn store(
+        &self,
+        mut configuration: ClusterConfiguration,
+        expected_epoch: Option<u64>,
+    ) -> MetadataResult<Option<ClusterConfiguration>> {
+        let operation = Operation::CompareAndUpsert(
+            Bytes::from_static(CONFIGURATION_KEY),
+            configuration.encode(),
+            expected_epoch,
+        );
+
+        match self.router.write(operation).await? {
+            OperationResult::Written(epoch) => {
+                configuration.epoch = epoch;
+
+                Ok(Some(configuration))
+            }
+            OperationResult::VersionMismatch(_) => Ok(None),
+            result => Err(MetadataError::Unexpected(result)),
+        }
+    }
+}
+
+/// Routers refresh their routing straight from the metadata group.
+#[async_trait]
+impl<C: GroupClient + Send + Sync> RoutingSource<RangeMap> for MetadataService<C> {
+    async fn routing(&self) -> RouterResult<Routing<RangeMap>> {
+        match self.configuration().await {
+            Ok(configuration) => Ok(configuration.routing()),
+            Err(MetadataError::Router(error)) => Err(error),
+            Err(error) => Err(RouterError::Routing(error.to_string())),
+        }
+    }
+}
+
+/// Polls the metadata group for configurations newer than the last one seen.
+pub struct Watch<'a, C> {
+    service: &'a MetadataService<C>,
+    epoch: u64,
+}
+
+impl<C: GroupClient + Send + Sync> Watch<'_, C> {
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// The current configuration if it has changed since the last poll.
+    pub async fn poll(&mut self) -> MetadataResult<Option<ClusterConfiguration>> {
+        let configuration = match self.service.configuration().await {
+            Ok(configuration) => configuration,
+            Err(MetadataError::NotBootstrapped) => return Ok(None),
+            Err(error) => return Err(error),
+        };
+
+        if configuration.epoch <= self.epoch {
+            return Ok(None);
+        }
+
+        self.epoch = configuration.epoch;
+
+        Ok(Some(configuration))
+    }
+
+    /// Polls for a new configuration and brings the router's routing and the
+    /// node's hosted shards up to date with it - range changes the shards
+    /// haven't committed yet are picked up by a later call. Returns the new
+    /// configuration, if there was one.
+    pub async fn follow<S, G, R, F>(
+        &mut self,
+        router: &Router<RangeMap, G, R>,
+        node: &Mutex<Node<S, RangeMap>>,
+        create: F,
+    ) -> MetadataResult<Option<ClusterConfiguration>>
+    where
+        S: Flush + Upsert + Delete + Scan,
+        G: GroupClient + Send + Sync,
+        R: RoutingSource<RangeMap> + Send + Sync,
+        F: FnMut(ShardIdentifier) -> KvStateMachine<S>,
+    {
+        let Some(configuration) = self.poll().await? else {
+            return Ok(None);
+        };
+
+        router.update(configuration.routing())?;
+        node.lock()
+            .map_err(|_| RouterError::Poisoned)?
+            .follow(&configuration.shards, create)?;
+
+        Ok(Some(configuration))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum MetadataError {
+    #[error("Cluster has already been bootstrapped!")]
+    AlreadyBootstrapped,
+    #[error("Cluster hasn't been bootstrapped yet!")]
+    NotBootstrapped,
+    #[error("Configuration kept changing concurrently!")]
+    TooManyConflicts,
+    #[error("Metadata group answered with an unexpected result! {:?}", .0)]
+    Unexpected(OperationResult),
+    #[error("Router error: {}", .0)]
+    Router(RouterError),
+    #[error("Shard error: {}", .0)]
+    Shard(ShardError),
+    #[error("Storage error: {}", .0)]
+    Storage(StorageError),
+}
+
+impl From<RouterError> for MetadataError {
+    fn from(value: RouterError) -> Self {
+        Self::Router(value)
+    }
+}
+
+impl From<ShardError> for MetadataError {
+    fn from(value: ShardError) -> Self {
+        Self::Shard(value)
+    }
+}
+
+impl From<StorageError> for MetadataError {
+    fn from(value: StorageError) -> Self {
+        Self::Storage(value)
+    }
+}
+
+fn get_u32(data: &mut &[u8]) -> StorageResult<u32> {
+    match data.remaining() {
+        4.. => Ok(data.get_u32()),
+        _ => Err(malformed_configuration()),
+    }
+}
+
+fn malformed_configuration() -> StorageError {
+    StorageError::CorruptionDetected("Malformed cluster configuration!".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    };
+
+    use async_trait::async_trait;
+    use futures::executor::block_on;
+    use togo_vr::state::OperationContext;
+
+    use crate::{
+        kv::KvStateMachine,
+        operation::Operation,
+        router::{GroupClient, Reply, Request, Router, RouterResult, RoutingSource},
+        shard::{
+            node::Node,
+            placement::GroupIdentifier,
+            range::{KeyRange, RangeMap},
+            Shard, ShardIdentifier,
+        },
+        storage::memory::MemoryStorage,
+    };
+
+    use super::{ClusterConfiguration, MetadataError, MetadataService, NodeIdentifier};
+
+    // the metadata group, with all of its replicas rolled into one
+    struct Group {
+        state_machine: KvStateMachine<MemoryStorage>,
+        op_number: AtomicU64,
+    }
+
+    #[async_trait]
+    impl GroupClient for Group {
+        fn replica_count(&self, _: GroupIdentifier) -> usize {
+            1
+        }
+
+        async fn send(
+            &self,
+            _: GroupIdentifier,
+            _: usize,
+            _: ShardIdentifier,
+            request: Request,
+        ) -> RouterResult<Reply> {
+            match request {
+                Request::Write(operation) => {
+                    let context = OperationContext {
+                        op_number: self.op_number.fetch_add(1, Ordering::AcqRel) + 1,
+                        timestamp: 0,
+                        seed: 0,
+                    };
+
+                    Ok(Reply::written(
+                        self.state_machine.apply(&context, &operation)?,
+                    ))
+                }
+                request => Ok(Reply::read(&self.state_machine, &request)?),
+            }
+        }
+    }
+
+    #[test]
+    pub fn configuration_changes_are_versioned_and_watched() {
+        let service = MetadataService::new(
+            Group {
+                state_machine: KvStateMachine::new(MemoryStorage::new()),
+                op_number: AtomicU64::new(0),
+            },
+            GroupIdentifier(0),
+        );
+        let mut configuration = ClusterConfiguration::new(ShardIdentifier(1));
+
+        configuration
+            .nodes
+            .insert(NodeIdentifier(1), "10.0.0.1:7000".into());
+        configuration
+            .nodes
+            .insert(NodeIdentifier(2), "10.0.0.2:7000".into());
+        configuration.replicas.insert(
+            GroupIdentifier(1),
+            vec![NodeIdentifier(2), NodeIdentifier(1)],
+        );
+        configuration
+            .placement
+            .assign(ShardIdentifier(1), GroupIdentifier(1));
+
+        block_on(async {
+            let mut watch = service.watch(0);
+
+            assert!(watch.poll().await.unwrap().is_none());
+
+            let bootstrapped = service.bootstrap(configuration.clone()).await.unwrap();
+
+            assert!(matches!(
+                service.bootstrap(configuration).await,
+                Err(MetadataError::AlreadyBootstrapped)
+            ));
+            assert_eq!(watch.poll().await.unwrap().unwrap(), bootstrapped);
+            assert!(watch.poll().await.unwrap().is_none());
+            assert_eq!(
+                bootstrapped.addresses_of(GroupIdentifier(1)),
+                ["10.0.0.2:7000", "10.0.0.1:7000"]
+            );
+
+            let split = service
+                .update(|configuration| {
+                    configuration.shards.split(
+                        ShardIdentifier(1),
+                        "m".into(),
+                        ShardIdentifier(2),
+                    )?;
+                    configuration
+                        .placement
+                        .assign(ShardIdentifier(2), GroupIdentifier(1));
+
+                    Ok(())
+                })
+                .await
+                .unwrap();
+
+            assert!(split.epoch > bootstrapped.epoch);
+            assert_eq!(watch.poll().await.unwrap().unwrap(), split);
+            assert_eq!(service.routing().await.unwrap(), split.routing());
+        });
+    }
+
+    #[test]
+    pub fn routers_and_nodes_follow_committed_range_changes() {
+        let group = || Group {
+            state_machine: KvStateMachine::new(MemoryStorage::new()),
+            op_number: AtomicU64::new(0),
+        };
+        let service = MetadataService::new(group(), GroupIdentifier(0));
+        let mut configuration = ClusterConfiguration::new(ShardIdentifier(1));
+        let mut node = Node::new(RangeMap::new(ShardIdentifier(1)));
+        let create = |_| KvStateMachine::new(MemoryStorage::new());
+
+        configuration
+            .placement
+            .assign(ShardIdentifier(1), GroupIdentifier(1));
+        node.host(Shard::new(ShardIdentifier(1), MemoryStorage::new()));
+
+        let node = Mutex::new(node);
+        let router = Router::new(group(), configuration.routing(), configuration.routing());
+        let context = |op_number| OperationContext {
+            op_number,
+            timestamp: 0,
+            seed: 0,
+        };
+
+        block_on(async {
+            let mut watch = service.watch(0);
+
+            service.bootstrap(configuration).await.unwrap();
+            service
+                .update(|configuration| {
+                    configuration.shards.split(
+                        ShardIdentifier(1),
+                        "m".into(),
+                        ShardIdentifier(2),
+                    )?;
+                    configuration
+                        .placement
+                        .assign(ShardIdentifier(2), GroupIdentifier(1));
+
+                    Ok(())
+                })
+                .await
+                .unwrap();
+
+            // the parent's log hasn't committed the split yet
+            assert!(watch
+                .follow(&router, &node, create)
+                .await
+                .unwrap()
+                .is_some());
+            assert_eq!(router.shard_for(b"x").unwrap(), ShardIdentifier(2));
+            assert!(node.lock().unwrap().shard(ShardIdentifier(2)).is_none());
+
+            node.lock()
+                .unwrap()
+                .shard(ShardIdentifier(1))
+                .unwrap()
+                .state_machine()
+                .apply(&context(1), &Operation::Split("m".into()))
+                .unwrap();
+            service.update(|_| Ok(())).await.unwrap();
+            watch.follow(&router, &node, create).await.unwrap().unwrap();
+
+            assert_eq!(
+                node.lock().unwrap().map().shard_for(b"x"),
+                ShardIdentifier(2)
+            );
+            assert!(node.lock().unwrap().shard(ShardIdentifier(2)).is_some());
+
+            service
+                .update(|configuration| {
+                    configuration
+                        .shards
+                        .merge(ShardIdentifier(1), ShardIdentifier(2))?;
+                    configuration.placement.remove(ShardIdentifier(2));
+
+                    Ok(())
+                })
+                .await
+                .unwrap();
+
+            {
+                let node = node.lock().unwrap();
+
+                node.shard(ShardIdentifier(2))
+                    .unwrap()
+                    .state_machine()
+                    .apply(&context(1), &Operation::Split("m".into()))
+                    .unwrap();
+                node.shard(ShardIdentifier(1))
+                    .unwrap()
+                    .state_machine()
+                    .apply(&context(2), &Operation::Merge(KeyRange::new("m", None)))
+                    .unwrap();
+            }
+
+            watch.follow(&router, &node, create).await.unwrap().unwrap();
+
+            assert_eq!(router.shard_for(b"x").unwrap(), ShardIdentifier(1));
+            assert!(node.lock().unwrap().shard(ShardIdentifier(2)).is_none());
+        });
+    }
+}
@@ -1,7 +1,329 @@
-use bytes::Bytes;
+use std::time::Duration;
 
+use bytes::{BufMut, Bytes, BytesMut};
+use togo_vr::{
+    codec::{get_u32, get_u64, get_u8, Codec, CodecError, CodecResult},
+    log::Footprint,
+};
+
+use crate::{
+    shard::range::KeyRange,
+    transaction::{TransactionId, TransactionRecord},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Operation {
-    Upsert(Bytes, Bytes),
+    Upsert(Bytes, Bytes, Option<Duration>),
+    /// Upserts the value only if the key's current version matches the
+    /// expected one - `None` expects the key not to exist.
+    CompareAndUpsert(Bytes, Bytes, Option<u64>),
     Delete(Bytes),
-    NoOp
-}
\ No newline at end of file
+    /// Reclaims up to the given number of keys that have expired as of the
+    /// operation's timestamp.
+    SweepExpired(u32),
+    /// Hands the shard's keys from the given one onwards over to a new shard
+    /// - splitting at the shard's own start hands over all of them.
+    Split(Bytes),
+    /// Extends the shard's range over the adjacent one, which has to have
+    /// been handed over by its previous owner.
+    Merge(KeyRange),
+    /// Validates the transaction's writes to this shard and locks their
+    /// keys until it's either committed or aborted - only upserts,
+    /// compare-and-upserts and deletes can be part of a transaction.
+    Prepare(TransactionId, Vec<Operation>),
+    Commit(TransactionId),
+    Abort(TransactionId),
+    /// Compare-and-upserts the coordinator's record of the transaction, kept
+    /// apart from the keys clients write to - `None` expects there to be no
+    /// record yet.
+    Record(TransactionId, Bytes, Option<u64>),
+    /// Drops the coordinator's record of a finished transaction.
+    Forget(TransactionId),
+    /// Takes over a range migrated from another group along with what's
+    /// stored for it there, as exported by `KvStateMachine::records` - meant
+    /// for a shard that doesn't own anything yet.
+    Install(KeyRange, Vec<(Bytes, Bytes)>),
+    NoOp,
+}
+
+impl Operation {
+    /// The key the operation writes to, if it's about a single one.
+    pub fn key(&self) -> Option<&Bytes> {
+        match self {
+            Operation::Upsert(key, ..)
+            | Operation::CompareAndUpsert(key, ..)
+            | Operation::Delete(key) => Some(key),
+            _ => None,
+        }
+    }
+
+    /// The key deciding which shard the operation goes to - coordinator
+    /// records go wherever their transaction's record key is owned.
+    pub fn routing_key(&self) -> Option<Bytes> {
+        match self {
+            Operation::Record(transaction, ..) | Operation::Forget(transaction) => {
+                Some(TransactionRecord::key(*transaction))
+            }
+            operation => operation.key().cloned(),
+        }
+    }
+
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Operation::Upsert(..) | Operation::CompareAndUpsert(..) | Operation::Delete(_)
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OperationResult {
+    Written(u64),
+    Deleted,
+    VersionMismatch(Option<u64>),
+    Reclaimed(u32),
+    /// The key or range change doesn't fall within the shard's range.
+    OutOfRange,
+    /// The range handed over by a split.
+    Split(KeyRange),
+    /// The shard's range after a merge.
+    Merged(KeyRange),
+    /// The transaction's writes have been validated and their keys locked.
+    Prepared,
+    /// The transaction's writes have been applied with the given version.
+    Committed(u64),
+    Aborted,
+    /// The key is locked by a prepared transaction.
+    Locked(TransactionId),
+    /// Number of records installed.
+    Installed(u64),
+    NoOp,
+}
+
+impl Footprint for Operation {
+    fn footprint(&self) -> usize {
+        match self {
+            Operation::Upsert(key, value, _) | Operation::CompareAndUpsert(key, value, _) => {
+                key.len() + value.len()
+            }
+            Operation::Delete(key) | Operation::Split(key) => key.len(),
+            Operation::Merge(range) => {
+                range.start.len() + range.end.as_ref().map_or(0, |end| end.len())
+            }
+            Operation::Prepare(_, writes) => writes.iter().map(Footprint::footprint).sum(),
+            Operation::Record(_, record, _) => record.len(),
+            Operation::Install(range, records) => {
+                range.start.len()
+                    + range.end.as_ref().map_or(0, |end| end.len())
+                    + records
+                        .iter()
+                        .map(|(key, value)| key.len() + value.len())
+                        .sum::<usize>()
+            }
+            Operation::SweepExpired(_)
+            | Operation::Commit(_)
+            | Operation::Abort(_)
+            | Operation::Forget(_)
+            | Operation::NoOp => 0,
+        }
+    }
+}
+
+// optional numbers are encoded with `u64::MAX` standing for `None`
+impl Codec for Operation {
+    fn encode(&self, buffer: &mut BytesMut) {
+        match self {
+            Operation::Upsert(key, value, ttl) => {
+                buffer.put_u8(0);
+                key.encode(buffer);
+                value.encode(buffer);
+                buffer.put_u64(ttl.map_or(u64::MAX, |ttl| {
+                    ttl.as_millis().try_into().unwrap_or(u64::MAX - 1)
+                }));
+            }
+            Operation::CompareAndUpsert(key, value, expected_version) => {
+                buffer.put_u8(1);
+                key.encode(buffer);
+                value.encode(buffer);
+                buffer.put_u64(expected_version.unwrap_or(u64::MAX));
+            }
+            Operation::Delete(key) => {
+                buffer.put_u8(2);
+                key.encode(buffer);
+            }
+            Operation::SweepExpired(limit) => {
+                buffer.put_u8(3);
+                buffer.put_u32(*limit);
+            }
+            Operation::Split(at) => {
+                buffer.put_u8(4);
+                at.encode(buffer);
+            }
+            Operation::Merge(range) => {
+                buffer.put_u8(5);
+                range.encode(buffer);
+            }
+            Operation::Prepare(transaction, writes) => {
+                buffer.put_u8(6);
+                transaction.encode(buffer);
+                writes.encode(buffer);
+            }
+            Operation::Commit(transaction) => {
+                buffer.put_u8(7);
+                transaction.encode(buffer);
+            }
+            Operation::Abort(transaction) => {
+                buffer.put_u8(8);
+                transaction.encode(buffer);
+            }
+            Operation::NoOp => buffer.put_u8(9),
+            Operation::Record(transaction, record, expected_version) => {
+                buffer.put_u8(10);
+                transaction.encode(buffer);
+                record.encode(buffer);
+                buffer.put_u64(expected_version.unwrap_or(u64::MAX));
+            }
+            Operation::Forget(transaction) => {
+                buffer.put_u8(11);
+                transaction.encode(buffer);
+            }
+            Operation::Install(range, records) => {
+                buffer.put_u8(12);
+                range.encode(buffer);
+                buffer.put_u32(records.len() as u32);
+
+                for (key, value) in records {
+                    key.encode(buffer);
+                    value.encode(buffer);
+                }
+            }
+        }
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        match get_u8(data)? {
+            0 => {
+                let key = Bytes::decode(data)?;
+                let value = Bytes::decode(data)?;
+                let ttl = get_u64(data)?;
+
+                Ok(Operation::Upsert(
+                    key,
+                    value,
+                    (ttl != u64::MAX).then(|| Duration::from_millis(ttl)),
+                ))
+            }
+            1 => {
+                let key = Bytes::decode(data)?;
+                let value = Bytes::decode(data)?;
+                let expected_version = get_u64(data)?;
+
+                Ok(Operation::CompareAndUpsert(
+                    key,
+                    value,
+                    (expected_version != u64::MAX).then_some(expected_version),
+                ))
+            }
+            2 => Bytes::decode(data).map(Operation::Delete),
+            3 => get_u32(data).map(Operation::SweepExpired),
+            4 => Bytes::decode(data).map(Operation::Split),
+            5 => decode_range(data).map(Operation::Merge),
+            6 => Ok(Operation::Prepare(
+                TransactionId::decode(data)?,
+                Vec::decode(data)?,
+            )),
+            7 => TransactionId::decode(data).map(Operation::Commit),
+            8 => TransactionId::decode(data).map(Operation::Abort),
+            9 => Ok(Operation::NoOp),
+            10 => {
+                let transaction = TransactionId::decode(data)?;
+                let record = Bytes::decode(data)?;
+                let expected_version = get_u64(data)?;
+
+                Ok(Operation::Record(
+                    transaction,
+                    record,
+                    (expected_version != u64::MAX).then_some(expected_version),
+                ))
+            }
+            11 => TransactionId::decode(data).map(Operation::Forget),
+            12 => Ok(Operation::Install(
+                decode_range(data)?,
+                (0..get_u32(data)?)
+                    .map(|_| Ok((Bytes::decode(data)?, Bytes::decode(data)?)))
+                    .collect::<CodecResult<_>>()?,
+            )),
+            tag => Err(CodecError::UnknownTag("operation", tag)),
+        }
+    }
+}
+
+// tags start at 1, so that client tables can store a missing result as 0
+impl Codec for OperationResult {
+    fn encode(&self, buffer: &mut BytesMut) {
+        match self {
+            OperationResult::Written(version) => {
+                buffer.put_u8(1);
+                buffer.put_u64(*version);
+            }
+            OperationResult::Deleted => buffer.put_u8(2),
+            OperationResult::VersionMismatch(None) => buffer.put_u8(3),
+            OperationResult::VersionMismatch(Some(version)) => {
+                buffer.put_u8(4);
+                buffer.put_u64(*version);
+            }
+            OperationResult::Reclaimed(count) => {
+                buffer.put_u8(5);
+                buffer.put_u32(*count);
+            }
+            OperationResult::NoOp => buffer.put_u8(6),
+            OperationResult::OutOfRange => buffer.put_u8(7),
+            OperationResult::Split(range) => {
+                buffer.put_u8(8);
+                range.encode(buffer);
+            }
+            OperationResult::Merged(range) => {
+                buffer.put_u8(9);
+                range.encode(buffer);
+            }
+            OperationResult::Prepared => buffer.put_u8(10),
+            OperationResult::Committed(version) => {
+                buffer.put_u8(11);
+                buffer.put_u64(*version);
+            }
+            OperationResult::Aborted => buffer.put_u8(12),
+            OperationResult::Locked(transaction) => {
+                buffer.put_u8(13);
+                transaction.encode(buffer);
+            }
+            OperationResult::Installed(records) => {
+                buffer.put_u8(14);
+                buffer.put_u64(*records);
+            }
+        }
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        match get_u8(data)? {
+            1 => get_u64(data).map(OperationResult::Written),
+            2 => Ok(OperationResult::Deleted),
+            3 => Ok(OperationResult::VersionMismatch(None)),
+            4 => Ok(OperationResult::VersionMismatch(Some(get_u64(data)?))),
+            5 => get_u32(data).map(OperationResult::Reclaimed),
+            6 => Ok(OperationResult::NoOp),
+            7 => Ok(OperationResult::OutOfRange),
+            8 => decode_range(data).map(OperationResult::Split),
+            9 => decode_range(data).map(OperationResult::Merged),
+            10 => Ok(OperationResult::Prepared),
+            11 => get_u64(data).map(OperationResult::Committed),
+            12 => Ok(OperationResult::Aborted),
+            13 => TransactionId::decode(data).map(OperationResult::Locked),
+            14 => get_u64(data).map(OperationResult::Installed),
+            tag => Err(CodecError::UnknownTag("operation result", tag)),
+        }
+    }
+}
+
+pub(crate) fn decode_range(data: &mut &[u8]) -> CodecResult<KeyRange> {
+    KeyRange::decode(data).map_err(|error| CodecError::Malformed(error.to_string()))
+}
@@ -0,0 +1,674 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, RwLock, RwLockReadGuard},
+};
+
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::future::try_join_all;
+use thiserror::Error;
+use togo_vr::codec::{get_u32, get_u64, get_u8, Codec, CodecError, CodecResult};
+
+use crate::{
+    kv::{KvStateMachine, VersionedValue},
+    operation::{decode_range, Operation, OperationResult},
+    shard::{
+        placement::{GroupIdentifier, Placement},
+        range::KeyRange,
+        Partitioner, ShardError, ShardIdentifier,
+    },
+    storage::{Get, Scan, StorageError, StorageResult},
+    transaction::{TransactionId, TransactionRecord},
+};
+
+pub type RouterResult<T> = Result<T, RouterError>;
+
+const DEFAULT_MAX_ATTEMPTS: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Get(Bytes),
+    Scan {
+        range: KeyRange,
+        limit: usize,
+    },
+    Write(Operation),
+    /// Reads the coordinator's record of a transaction.
+    Record(TransactionId),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Value(Option<VersionedValue>),
+    Entries(Vec<(Bytes, VersionedValue)>),
+    Written(OperationResult),
+}
+
+/// What a replica answers a routed request with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Done(Response),
+    /// The replica isn't the group's primary - the primary is included if
+    /// the replica knows it.
+    NotPrimary {
+        primary: Option<usize>,
+    },
+    /// The shard doesn't own the key (anymore), the routing is outdated.
+    WrongShard,
+}
+
+impl Reply {
+    /// Answers a committed write - writes the shard rejected as out of its
+    /// range were meant for another shard.
+    pub fn written(result: OperationResult) -> Self {
+        match result {
+            OperationResult::OutOfRange => Reply::WrongShard,
+            result => Reply::Done(Response::Written(result)),
+        }
+    }
+
+    /// Answers a read from the shard's state machine.
+    pub fn read<S: Scan>(
+        state_machine: &KvStateMachine<S>,
+        request: &Request,
+    ) -> StorageResult<Self> {
+        let range = state_machine.range()?;
+
+        match request {
+            Request::Get(key) if !range.contains(key) => Ok(Reply::WrongShard),
+            Request::Get(key) => Ok(Reply::Done(Response::Value(state_machine.get(key)?))),
+            // the scanned range is the part the router believes the shard
+            // owns, the shard has to own all of it for the scan to be whole
+            Request::Scan { range: scanned, .. } if !range.covers(scanned) => Ok(Reply::WrongShard),
+            Request::Scan {
+                range: scanned,
+                limit,
+            } => Ok(Reply::Done(Response::Entries(
+                state_machine.scan(scanned, *limit)?,
+            ))),
+            Request::Record(transaction)
+                if !range.contains(&TransactionRecord::key(*transaction)) =>
+            {
+                Ok(Reply::WrongShard)
+            }
+            Request::Record(transaction) => Ok(Reply::Done(Response::Value(
+                state_machine.transaction_record(*transaction)?,
+            ))),
+            Request::Write(_) => Err(StorageError::Unknown(
+                "Writes have to be committed through the log!".into(),
+            )),
+        }
+    }
+}
+
+impl Codec for Request {
+    fn encode(&self, buffer: &mut BytesMut) {
+        match self {
+            Request::Get(key) => {
+                buffer.put_u8(0);
+                key.encode(buffer);
+            }
+            Request::Scan { range, limit } => {
+                buffer.put_u8(1);
+                range.encode(buffer);
+                buffer.put_u64(*limit as u64);
+            }
+            Request::Write(operation) => {
+                buffer.put_u8(2);
+                operation.encode(buffer);
+            }
+            Request::Record(transaction) => {
+                buffer.put_u8(3);
+                transaction.encode(buffer);
+            }
+        }
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        match get_u8(data)? {
+            0 => Bytes::decode(data).map(Request::Get),
+            1 => Ok(Request::Scan {
+                range: decode_range(data)?,
+                limit: get_u64(data)? as usize,
+            }),
+            2 => Operation::decode(data).map(Request::Write),
+            3 => TransactionId::decode(data).map(Request::Record),
+            tag => Err(CodecError::UnknownTag("request", tag)),
+        }
+    }
+}
+
+impl Codec for Response {
+    fn encode(&self, buffer: &mut BytesMut) {
+        match self {
+            Response::Value(value) => {
+                buffer.put_u8(0);
+                value.encode(buffer);
+            }
+            Response::Entries(entries) => {
+                buffer.put_u8(1);
+                buffer.put_u32(entries.len() as u32);
+
+                for (key, value) in entries {
+                    key.encode(buffer);
+                    value.encode(buffer);
+                }
+            }
+            Response::Written(result) => {
+                buffer.put_u8(2);
+                result.encode(buffer);
+            }
+        }
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        match get_u8(data)? {
+            0 => Option::decode(data).map(Response::Value),
+            1 => Ok(Response::Entries(
+                (0..get_u32(data)?)
+                    .map(|_| Ok((Bytes::decode(data)?, VersionedValue::decode(data)?)))
+                    .collect::<CodecResult<_>>()?,
+            )),
+            2 => OperationResult::decode(data).map(Response::Written),
+            tag => Err(CodecError::UnknownTag("response", tag)),
+        }
+    }
+}
+
+// the primary's index is encoded with `u64::MAX` standing for `None`
+impl Codec for Reply {
+    fn encode(&self, buffer: &mut BytesMut) {
+        match self {
+            Reply::Done(response) => {
+                buffer.put_u8(0);
+                response.encode(buffer);
+            }
+            Reply::NotPrimary { primary } => {
+                buffer.put_u8(1);
+                buffer.put_u64(primary.map_or(u64::MAX, |primary| primary as u64));
+            }
+            Reply::WrongShard => buffer.put_u8(2),
+        }
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        match get_u8(data)? {
+            0 => Response::decode(data).map(Reply::Done),
+            1 => {
+                let primary = get_u64(data)?;
+
+                Ok(Reply::NotPrimary {
+                    primary: (primary != u64::MAX).then_some(primary as usize),
+                })
+            }
+            2 => Ok(Reply::WrongShard),
+            tag => Err(CodecError::UnknownTag("reply", tag)),
+        }
+    }
+}
+
+/// Reaches the replicas of a group, e.g. through the VR client - replicas are
+/// addressed by their index within the group, and client sessions and
+/// request numbers are up to the implementation.
+#[async_trait]
+pub trait GroupClient {
+    fn replica_count(&self, group: GroupIdentifier) -> usize;
+
+    async fn send(
+        &self,
+        group: GroupIdentifier,
+        replica: usize,
+        shard: ShardIdentifier,
+        request: Request,
+    ) -> RouterResult<Reply>;
+}
+
+/// Where the router gets the current routing from once it turns out to be
+/// outdated.
+#[async_trait]
+pub trait RoutingSource<P> {
+    async fn routing(&self) -> RouterResult<Routing<P>>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Routing<P> {
+    pub map: P,
+    pub placement: Placement,
+}
+
+/// A routing that never changes, e.g. that of a group hosting a single shard.
+#[async_trait]
+impl<P: Clone + Send + Sync> RoutingSource<P> for Routing<P> {
+    async fn routing(&self) -> RouterResult<Routing<P>> {
+        Ok(self.clone())
+    }
+}
+
+/// Single endpoint in front of a sharded deployment - requests are forwarded
+/// to the primary of the group owning the key, following redirects along the
+/// way, and scans are fanned out to every shard they touch.
+pub struct Router<P, C, R> {
+    client: C,
+    source: R,
+    routing: RwLock<Routing<P>>,
+    // the replica last known to be the primary of every group
+    primaries: Mutex<BTreeMap<GroupIdentifier, usize>>,
+    max_attempts: usize,
+}
+
+impl<P, C, R> Router<P, C, R>
+where
+    P: Partitioner + Send + Sync,
+    C: GroupClient + Send + Sync,
+    R: RoutingSource<P> + Send + Sync,
+{
+    pub fn new(client: C, source: R, routing: Routing<P>) -> Self {
+        Self {
+            client,
+            source,
+            routing: RwLock::new(routing),
+            primaries: Mutex::new(BTreeMap::new()),
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+        }
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: usize) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Where the router gets the current routing from.
+    pub fn source(&self) -> &R {
+        &self.source
+    }
+
+    pub async fn get<K: Into<Bytes>>(&self, key: K) -> RouterResult<Option<VersionedValue>> {
+        let key = key.into();
+
+        match self.execute(&key, Request::Get(key.clone())).await? {
+            Response::Value(value) => Ok(value),
+            _ => Err(RouterError::UnexpectedResponse),
+        }
+    }
+
+    pub async fn write(&self, operation: Operation) -> RouterResult<OperationResult> {
+        let key = operation.routing_key().ok_or(RouterError::Unroutable)?;
+
+        match self.execute(&key, Request::Write(operation)).await? {
+            Response::Written(result) => Ok(result),
+            _ => Err(RouterError::UnexpectedResponse),
+        }
+    }
+
+    /// The coordinator's record of the transaction.
+    pub async fn transaction_record(
+        &self,
+        transaction: TransactionId,
+    ) -> RouterResult<Option<VersionedValue>> {
+        let key = TransactionRecord::key(transaction);
+
+        match self.execute(&key, Request::Record(transaction)).await? {
+            Response::Value(value) => Ok(value),
+            _ => Err(RouterError::UnexpectedResponse),
+        }
+    }
+
+    /// Submits an operation that isn't addressed by a key, e.g. a transaction's
+    /// prepare, to the shard directly - `OutOfRange` means the shard no
+    /// longer owns what the operation touches.
+    pub async fn submit(
+        &self,
+        shard: ShardIdentifier,
+        operation: Operation,
+    ) -> RouterResult<OperationResult> {
+        match self.send(shard, Request::Write(operation)).await? {
+            Some(Response::Written(result)) => Ok(result),
+            Some(_) => Err(RouterError::UnexpectedResponse),
+            None => {
+                self.refresh().await?;
+
+                Ok(OperationResult::OutOfRange)
+            }
+        }
+    }
+
+    /// Submits an operation to the shard's replicas in the given group, even
+    /// if the routing doesn't place the shard there (yet) - e.g. to install a
+    /// shard that's being migrated to the group.
+    pub async fn submit_to(
+        &self,
+        group: GroupIdentifier,
+        shard: ShardIdentifier,
+        operation: Operation,
+    ) -> RouterResult<OperationResult> {
+        match self
+            .send_to(group, shard, Request::Write(operation))
+            .await?
+        {
+            Some(Response::Written(result)) => Ok(result),
+            Some(_) => Err(RouterError::UnexpectedResponse),
+            None => Ok(OperationResult::OutOfRange),
+        }
+    }
+
+    pub fn shard_for(&self, key: &[u8]) -> RouterResult<ShardIdentifier> {
+        Ok(self
+            .read_routing()?
+            .map
+            .shard_for(key)
+            .ok_or(ShardError::NoShards)?)
+    }
+
+    /// Up to `limit` live values within the range in key order, gathered
+    /// from every shard holding some of them.
+    pub async fn scan(
+        &self,
+        range: KeyRange,
+        limit: usize,
+    ) -> RouterResult<Vec<(Bytes, VersionedValue)>> {
+        for _ in 0..self.max_attempts {
+            let shards = self.read_routing()?.map.shards_overlapping(&range);
+            let replies = try_join_all(shards.into_iter().map(|(shard, owned)| {
+                let request = Request::Scan {
+                    range: range.intersection(&owned),
+                    limit,
+                };
+
+                self.send(shard, request)
+            }))
+            .await?;
+
+            // a shard that no longer owns its part might have split or moved,
+            // so the whole scan starts over with the current routing
+            if replies.iter().any(Option::is_none) {
+                self.refresh().await?;
+                continue;
+            }
+
+            let mut entries = Vec::new();
+
+            for reply in replies.into_iter().flatten() {
+                match reply {
+                    Response::Entries(shard_entries) => entries.extend(shard_entries),
+                    _ => return Err(RouterError::UnexpectedResponse),
+                }
+            }
+
+            entries.sort_by(|(left, _), (right, _)| left.cmp(right));
+            entries.truncate(limit);
+
+            return Ok(entries);
+        }
+
+        Err(RouterError::TooManyRedirects)
+    }
+
+    /// Replaces the routing with the one from the source.
+    pub async fn refresh(&self) -> RouterResult<()> {
+        let routing = self.source.routing().await?;
+
+        self.update(routing)
+    }
+
+    /// Replaces the routing, e.g. with a newer one a watch has come across.
+    pub fn update(&self, routing: Routing<P>) -> RouterResult<()> {
+        *self.routing.write().map_err(|_| RouterError::Poisoned)? = routing;
+
+        Ok(())
+    }
+
+    async fn execute(&self, key: &[u8], request: Request) -> RouterResult<Response> {
+        for _ in 0..self.max_attempts {
+            let shard = self.shard_for(key)?;
+
+            match self.send(shard, request.clone()).await? {
+                Some(response) => return Ok(response),
+                None => self.refresh().await?,
+            }
+        }
+
+        Err(RouterError::TooManyRedirects)
+    }
+
+    // `None` when the shard has turned out not to own the key
+    async fn send(
+        &self,
+        shard: ShardIdentifier,
+        request: Request,
+    ) -> RouterResult<Option<Response>> {
+        let group = self.read_routing()?.placement.group_for(shard)?;
+
+        self.send_to(group, shard, request).await
+    }
+
+    async fn send_to(
+        &self,
+        group: GroupIdentifier,
+        shard: ShardIdentifier,
+        request: Request,
+    ) -> RouterResult<Option<Response>> {
+        let replicas = self.client.replica_count(group).max(1);
+
+        for _ in 0..self.max_attempts {
+            let replica = self.primary(group)?;
+
+            match self
+                .client
+                .send(group, replica, shard, request.clone())
+                .await?
+            {
+                Reply::Done(response) => return Ok(Some(response)),
+                Reply::WrongShard => return Ok(None),
+                Reply::NotPrimary { primary } => {
+                    let primary = primary.unwrap_or(replica + 1) % replicas;
+
+                    self.primaries
+                        .lock()
+                        .map_err(|_| RouterError::Poisoned)?
+                        .insert(group, primary);
+                }
+            }
+        }
+
+        Err(RouterError::TooManyRedirects)
+    }
+
+    fn primary(&self, group: GroupIdentifier) -> RouterResult<usize> {
+        let primaries = self.primaries.lock().map_err(|_| RouterError::Poisoned)?;
+
+        Ok(primaries.get(&group).copied().unwrap_or_default())
+    }
+
+    fn read_routing(&self) -> RouterResult<RwLockReadGuard<'_, Routing<P>>> {
+        self.routing.read().map_err(|_| RouterError::Poisoned)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RouterError {
+    #[error("Operation doesn't target a single key!")]
+    Unroutable,
+    #[error("Request was redirected too many times!")]
+    TooManyRedirects,
+    #[error("Replica answered with an unexpected response!")]
+    UnexpectedResponse,
+    #[error("Routing lock is poisoned!")]
+    Poisoned,
+    #[error("Failed to reach the group! {}", .0)]
+    Unreachable(String),
+    #[error("Failed to fetch the routing! {}", .0)]
+    Routing(String),
+    #[error("Shard error: {}", .0)]
+    Shard(ShardError),
+    #[error("Storage error: {}", .0)]
+    Storage(StorageError),
+}
+
+impl From<ShardError> for RouterError {
+    fn from(value: ShardError) -> Self {
+        Self::Shard(value)
+    }
+}
+
+impl From<StorageError> for RouterError {
+    fn from(value: StorageError) -> Self {
+        Self::Storage(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        sync::atomic::{AtomicU64, Ordering},
+    };
+
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use futures::executor::block_on;
+    use togo_vr::state::OperationContext;
+
+    use crate::{
+        kv::KvStateMachine,
+        operation::{Operation, OperationResult},
+        shard::{
+            placement::{GroupIdentifier, Placement},
+            range::{KeyRange, RangeMap},
+            ShardIdentifier,
+        },
+        storage::memory::MemoryStorage,
+    };
+
+    use super::{GroupClient, Reply, Request, Router, RouterResult, Routing, RoutingSource};
+
+    // a single group of two replicas, of which the second one is the primary
+    struct Group {
+        shards: BTreeMap<ShardIdentifier, KvStateMachine<MemoryStorage>>,
+        op_number: AtomicU64,
+    }
+
+    #[async_trait]
+    impl GroupClient for Group {
+        fn replica_count(&self, _: GroupIdentifier) -> usize {
+            2
+        }
+
+        async fn send(
+            &self,
+            _: GroupIdentifier,
+            replica: usize,
+            shard: ShardIdentifier,
+            request: Request,
+        ) -> RouterResult<Reply> {
+            if replica != 1 {
+                return Ok(Reply::NotPrimary { primary: None });
+            }
+
+            let state_machine = &self.shards[&shard];
+
+            match request {
+                Request::Write(operation) => {
+                    let context = OperationContext {
+                        op_number: self.op_number.fetch_add(1, Ordering::AcqRel) + 1,
+                        timestamp: 0,
+                        seed: 0,
+                    };
+
+                    Ok(Reply::written(state_machine.apply(&context, &operation)?))
+                }
+                request => Ok(Reply::read(state_machine, &request)?),
+            }
+        }
+    }
+
+    struct Source(Routing<RangeMap>);
+
+    #[async_trait]
+    impl RoutingSource<RangeMap> for Source {
+        async fn routing(&self) -> RouterResult<Routing<RangeMap>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    fn routing(map: RangeMap) -> Routing<RangeMap> {
+        let mut placement = Placement::new();
+
+        for (shard, _) in map.ranges() {
+            placement.assign(shard, GroupIdentifier(1));
+        }
+
+        Routing { map, placement }
+    }
+
+    #[test]
+    pub fn requests_follow_redirects_and_scans_are_merged_in_order() {
+        let parent = KvStateMachine::new(MemoryStorage::new());
+        let keys = ["apple", "kiwi", "melon", "plum"];
+
+        for (op_number, key) in keys.into_iter().enumerate() {
+            let context = OperationContext {
+                op_number: op_number as u64 + 1,
+                timestamp: 0,
+                seed: 0,
+            };
+
+            parent
+                .apply(
+                    &context,
+                    &Operation::Upsert(key.into(), "fruit".into(), None),
+                )
+                .unwrap();
+        }
+
+        // the shard has split since the router last looked
+        let released = KeyRange::new("m", None);
+        let context = OperationContext {
+            op_number: 5,
+            timestamp: 0,
+            seed: 0,
+        };
+
+        parent
+            .apply(&context, &Operation::Split("m".into()))
+            .unwrap();
+
+        let child = KvStateMachine::new(MemoryStorage::new()).with_range(released.clone());
+
+        parent.move_range(&released, &child).unwrap();
+
+        let stale = RangeMap::new(ShardIdentifier(1));
+        let mut current = stale.clone();
+
+        current
+            .split(ShardIdentifier(1), "m".into(), ShardIdentifier(2))
+            .unwrap();
+
+        let group = Group {
+            shards: BTreeMap::from([(ShardIdentifier(1), parent), (ShardIdentifier(2), child)]),
+            op_number: AtomicU64::new(5),
+        };
+        let router = Router::new(
+            group,
+            Source(routing(current.clone())),
+            routing(stale.clone()),
+        );
+
+        assert_eq!(block_on(router.get("plum")).unwrap().unwrap().version, 4);
+        assert_eq!(
+            block_on(router.write(Operation::Upsert("pear".into(), "fruit".into(), None))).unwrap(),
+            OperationResult::Written(6)
+        );
+
+        let router = Router::new(router.client, Source(routing(current)), routing(stale));
+        let scanned = block_on(router.scan(KeyRange::new("b", None), 10)).unwrap();
+
+        assert_eq!(
+            scanned.into_iter().map(|(key, _)| key).collect::<Vec<_>>(),
+            ["kiwi", "melon", "pear", "plum"].map(Bytes::from)
+        );
+        assert_eq!(
+            block_on(router.scan(KeyRange::default(), 2)).unwrap().len(),
+            2
+        );
+    }
+}
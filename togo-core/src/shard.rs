@@ -1,7 +0,0 @@
-use crate::storage::Flush;
-
-pub struct ShardIdentifier(u32);
-
-pub struct Shard<S: Flush> {
-    storage: S
-}
\ No newline at end of file
@@ -0,0 +1,186 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::{range::KeyRange, Partitioner, ShardIdentifier};
+
+pub const DEFAULT_VIRTUAL_NODES: u32 = 128;
+
+/// Maps keys to shards by consistent hashing - every shard is placed on the
+/// ring `virtual_nodes` times, so adding or removing one only moves about
+/// `1 / shard count` of the keys and spreads them evenly across the rest.
+///
+/// Placement depends on nothing but the shard identifiers, so every node
+/// builds exactly the same ring from the same set of shards.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardMap {
+    virtual_nodes: u32,
+    shards: BTreeSet<ShardIdentifier>,
+    ring: BTreeMap<u64, ShardIdentifier>,
+}
+
+impl ShardMap {
+    pub fn new(virtual_nodes: u32) -> Self {
+        Self {
+            virtual_nodes: virtual_nodes.max(1),
+            shards: BTreeSet::new(),
+            ring: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_shards<I>(shards: I, virtual_nodes: u32) -> Self
+    where
+        I: IntoIterator<Item = ShardIdentifier>,
+    {
+        let mut map = Self::new(virtual_nodes);
+
+        map.shards.extend(shards);
+        map.rebuild();
+        map
+    }
+
+    /// Returns false if the shard has been placed already.
+    pub fn add_shard(&mut self, shard: ShardIdentifier) -> bool {
+        let added = self.shards.insert(shard);
+
+        if added {
+            self.rebuild();
+        }
+
+        added
+    }
+
+    pub fn remove_shard(&mut self, shard: ShardIdentifier) -> bool {
+        let removed = self.shards.remove(&shard);
+
+        if removed {
+            self.rebuild();
+        }
+
+        removed
+    }
+
+    pub fn shard_for<K: AsRef<[u8]>>(&self, key: K) -> Option<ShardIdentifier> {
+        let point = hash(key.as_ref());
+
+        self.ring
+            .range(point..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, shard)| *shard)
+    }
+
+    pub fn shards(&self) -> impl Iterator<Item = ShardIdentifier> + '_ {
+        self.shards.iter().copied()
+    }
+
+    pub fn contains(&self, shard: ShardIdentifier) -> bool {
+        self.shards.contains(&shard)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.is_empty()
+    }
+
+    // rebuilt from scratch, so that colliding virtual nodes are resolved the
+    // same way no matter in which order the shards were added
+    fn rebuild(&mut self) {
+        self.ring.clear();
+
+        for shard in &self.shards {
+            for replica in 0..self.virtual_nodes {
+                let mut point = [0; 8];
+
+                point[..4].copy_from_slice(&shard.0.to_be_bytes());
+                point[4..].copy_from_slice(&replica.to_be_bytes());
+
+                self.ring.entry(hash(&point)).or_insert(*shard);
+            }
+        }
+    }
+}
+
+impl Partitioner for ShardMap {
+    fn shard_for(&self, key: &[u8]) -> Option<ShardIdentifier> {
+        ShardMap::shard_for(self, key)
+    }
+
+    // hashing scatters neighbouring keys, any shard may hold some of them
+    fn shards_overlapping(&self, _: &KeyRange) -> Vec<(ShardIdentifier, KeyRange)> {
+        self.shards()
+            .map(|shard| (shard, KeyRange::default()))
+            .collect()
+    }
+}
+
+impl Default for ShardMap {
+    fn default() -> Self {
+        Self::new(DEFAULT_VIRTUAL_NODES)
+    }
+}
+
+// FNV-1a followed by a 64-bit finalizer - std's hashers are randomly seeded
+// or not guaranteed to be stable across releases, but placement has to match
+// on every node
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{ShardIdentifier, ShardMap, DEFAULT_VIRTUAL_NODES};
+
+    #[test]
+    pub fn keys_are_spread_evenly_and_mostly_stay_when_a_shard_is_added() {
+        let mut map = ShardMap::with_shards((0..4).map(ShardIdentifier), DEFAULT_VIRTUAL_NODES);
+        let keys = (0..10_000)
+            .map(|key| format!("key-{key}"))
+            .collect::<Vec<_>>();
+
+        let before = keys
+            .iter()
+            .map(|key| map.shard_for(key).unwrap())
+            .collect::<Vec<_>>();
+
+        let mut counts = BTreeMap::new();
+
+        for shard in &before {
+            *counts.entry(*shard).or_insert(0) += 1;
+        }
+
+        assert_eq!(counts.len(), 4);
+        assert!(counts.values().all(|count| (1_500..3_500).contains(count)));
+
+        assert!(map.add_shard(ShardIdentifier(4)));
+        assert!(!map.add_shard(ShardIdentifier(4)));
+
+        let moved = keys
+            .iter()
+            .zip(&before)
+            .filter(|(key, shard)| map.shard_for(key).unwrap() != **shard)
+            .inspect(|(key, _)| assert_eq!(map.shard_for(key), Some(ShardIdentifier(4))))
+            .count();
+
+        assert!((1_000..3_000).contains(&moved));
+
+        let rebuilt = ShardMap::with_shards([4, 2, 0, 3, 1].map(ShardIdentifier), 128);
+
+        assert_eq!(rebuilt, map);
+        assert!(ShardMap::default().shard_for("key").is_none());
+    }
+}
@@ -0,0 +1,397 @@
+use bytes::Bytes;
+use togo_vr::state::OperationContext;
+
+use crate::{
+    kv::KvStateMachine,
+    metadata::{ClusterConfiguration, MetadataResult, MetadataService},
+    operation::{Operation, OperationResult},
+    router::{GroupClient, Router},
+    storage::{snapshot::DEFAULT_CHUNK_SIZE, Delete, Flush, Snapshot, Upsert},
+};
+
+use super::{
+    placement::{GroupIdentifier, Placement},
+    range::RangeMap,
+    Shard, ShardError, ShardIdentifier, ShardResult,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationPhase {
+    /// Applying the ops the source group keeps committing.
+    CatchingUp,
+    /// The source group has stopped accepting writes to the shard, the ops
+    /// committed before the fence are all that's left to apply.
+    Fenced { fence_op_number: u64 },
+}
+
+/// Moves a shard to another group while the source keeps serving it.
+///
+/// The shard's storage is copied chunk by chunk, after which the ops the
+/// source commits in the meantime are tailed until the copy is close behind.
+/// Writes are then fenced by committing `Operation::Split` at the start of
+/// the shard's range in the source group - the source answers them with
+/// `OutOfRange` from then on, which sends clients to the placement for the
+/// new owner. Once the ops before the fence have been applied, the copy is
+/// installed through the target group's log and the new placement is
+/// committed in the cluster configuration.
+pub struct Migration<T: Flush> {
+    shard: ShardIdentifier,
+    source: GroupIdentifier,
+    target: GroupIdentifier,
+    state_machine: KvStateMachine<T>,
+    applied_op_number: u64,
+    phase: MigrationPhase,
+}
+
+impl<T> Migration<T>
+where
+    T: Flush + Snapshot + Upsert + Delete,
+{
+    /// Copies the shard as of `op_number`, the last op it has applied, into
+    /// the target's storage.
+    pub fn start<S>(
+        shard: &Shard<S>,
+        op_number: u64,
+        placement: &Placement,
+        target: GroupIdentifier,
+        mut storage: T,
+    ) -> ShardResult<Self>
+    where
+        S: Flush + Snapshot,
+    {
+        let source = placement.group_for(shard.identifier())?;
+
+        storage.clear()?;
+
+        for chunk in shard.storage().snapshot_chunks(DEFAULT_CHUNK_SIZE) {
+            storage.apply_chunk(chunk?)?;
+        }
+
+        let state_machine = KvStateMachine::new(storage)
+            .with_range(shard.state_machine().range()?)
+            .with_last_timestamp(shard.state_machine().last_timestamp());
+
+        Ok(Self {
+            shard: shard.identifier(),
+            source,
+            target,
+            state_machine,
+            applied_op_number: op_number,
+            phase: MigrationPhase::CatchingUp,
+        })
+    }
+
+    pub fn shard(&self) -> ShardIdentifier {
+        self.shard
+    }
+
+    pub fn source(&self) -> GroupIdentifier {
+        self.source
+    }
+
+    pub fn target(&self) -> GroupIdentifier {
+        self.target
+    }
+
+    pub fn phase(&self) -> MigrationPhase {
+        self.phase
+    }
+
+    pub fn applied_op_number(&self) -> u64 {
+        self.applied_op_number
+    }
+
+    /// How far the copy is behind the source's commit number.
+    pub fn lag(&self, commit_number: u64) -> u64 {
+        commit_number.saturating_sub(self.applied_op_number)
+    }
+
+    /// Applies ops committed by the source in order - ops applied already
+    /// are skipped, and so is everything from the fence onwards.
+    pub fn catch_up<'a, I>(&mut self, operations: I) -> ShardResult<u64>
+    where
+        I: IntoIterator<Item = (OperationContext, &'a Operation)>,
+    {
+        for (context, operation) in operations {
+            if context.op_number <= self.applied_op_number {
+                continue;
+            }
+
+            if let MigrationPhase::Fenced { fence_op_number } = self.phase {
+                if context.op_number >= fence_op_number {
+                    break;
+                }
+            }
+
+            if context.op_number != self.applied_op_number + 1 {
+                return Err(ShardError::MissingOp(
+                    self.applied_op_number + 1,
+                    self.shard,
+                ));
+            }
+
+            self.state_machine.apply(&context, operation)?;
+            self.applied_op_number = context.op_number;
+        }
+
+        Ok(self.applied_op_number)
+    }
+
+    /// Records the op that fenced writes in the source group.
+    pub fn fence(&mut self, fence_op_number: u64) -> ShardResult<()> {
+        if fence_op_number <= self.applied_op_number {
+            return Err(ShardError::MigrationNotReady(self.shard));
+        }
+
+        self.phase = MigrationPhase::Fenced { fence_op_number };
+
+        Ok(())
+    }
+
+    /// Commits the copy to the target group in batches, for every one of
+    /// its replicas to install, then places the shard in the target group -
+    /// the target's nodes have to be hosting an empty shard for it.
+    pub async fn finish<C, M>(
+        self,
+        router: &Router<RangeMap, C, MetadataService<M>>,
+    ) -> MetadataResult<ClusterConfiguration>
+    where
+        C: GroupClient + Send + Sync,
+        M: GroupClient + Send + Sync,
+    {
+        if !matches!(
+            self.phase,
+            MigrationPhase::Fenced { fence_op_number }
+                if self.applied_op_number + 1 == fence_op_number
+        ) {
+            return Err(ShardError::MigrationNotReady(self.shard).into());
+        }
+
+        let range = self.state_machine.range()?;
+
+        for batch in batches(self.state_machine.records(&range)?) {
+            let operation = Operation::Install(range.clone(), batch);
+
+            match router.submit_to(self.target, self.shard, operation).await? {
+                OperationResult::Installed(_) => {}
+                result => return Err(ShardError::Refused(self.shard, result).into()),
+            }
+        }
+
+        let configuration = router
+            .source()
+            .update(|configuration| {
+                configuration.placement.assign(self.shard, self.target);
+
+                Ok(())
+            })
+            .await?;
+
+        router.refresh().await?;
+
+        Ok(configuration)
+    }
+}
+
+// records split into batches of about a snapshot chunk each - there's always
+// at least one, so that even an empty shard gets its range installed
+fn batches(records: Vec<(Bytes, Bytes)>) -> Vec<Vec<(Bytes, Bytes)>> {
+    let mut batches = vec![Vec::new()];
+    let mut size = 0;
+
+    for (key, value) in records {
+        if size >= DEFAULT_CHUNK_SIZE {
+            batches.push(Vec::new());
+            size = 0;
+        }
+
+        size += key.len() + value.len();
+        batches.last_mut().expect("never empty").push((key, value));
+    }
+
+    batches
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        sync::atomic::{AtomicU64, Ordering},
+    };
+
+    use async_trait::async_trait;
+    use futures::executor::block_on;
+    use togo_vr::state::OperationContext;
+
+    use crate::{
+        kv::KvStateMachine,
+        metadata::{ClusterConfiguration, MetadataError, MetadataService},
+        operation::{Operation, OperationResult},
+        router::{GroupClient, Reply, Request, Router, RouterResult},
+        shard::{placement::GroupIdentifier, Shard, ShardError, ShardIdentifier},
+        storage::{memory::MemoryStorage, Get},
+    };
+
+    use super::{Migration, MigrationPhase};
+
+    // the metadata group and the target group, each with a single replica
+    // hosting a single shard
+    struct Groups {
+        state_machines: BTreeMap<GroupIdentifier, KvStateMachine<MemoryStorage>>,
+        op_number: AtomicU64,
+    }
+
+    #[async_trait]
+    impl GroupClient for &Groups {
+        fn replica_count(&self, _: GroupIdentifier) -> usize {
+            1
+        }
+
+        async fn send(
+            &self,
+            group: GroupIdentifier,
+            _: usize,
+            _: ShardIdentifier,
+            request: Request,
+        ) -> RouterResult<Reply> {
+            let state_machine = &self.state_machines[&group];
+
+            match request {
+                Request::Write(operation) => {
+                    let context = OperationContext {
+                        op_number: self.op_number.fetch_add(1, Ordering::AcqRel) + 1,
+                        timestamp: 0,
+                        seed: 0,
+                    };
+
+                    Ok(Reply::written(state_machine.apply(&context, &operation)?))
+                }
+                request => Ok(Reply::read(state_machine, &request)?),
+            }
+        }
+    }
+
+    fn committed(op_number: u64, operation: Operation) -> (OperationContext, Operation) {
+        let context = OperationContext {
+            op_number,
+            timestamp: op_number * 10,
+            seed: 0,
+        };
+
+        (context, operation)
+    }
+
+    #[test]
+    pub fn shards_are_copied_tailed_fenced_and_installed_through_the_target_log() {
+        let groups = Groups {
+            state_machines: BTreeMap::from([
+                (
+                    GroupIdentifier(0),
+                    KvStateMachine::new(MemoryStorage::new()),
+                ),
+                (
+                    GroupIdentifier(2),
+                    KvStateMachine::new(MemoryStorage::new()),
+                ),
+            ]),
+            op_number: AtomicU64::new(0),
+        };
+        let metadata = MetadataService::new(&groups, GroupIdentifier(0));
+        let mut configuration = ClusterConfiguration::new(ShardIdentifier(1));
+
+        configuration
+            .placement
+            .assign(ShardIdentifier(1), GroupIdentifier(1));
+
+        let configuration = block_on(metadata.bootstrap(configuration)).unwrap();
+        let router = Router::new(&groups, metadata, configuration.routing());
+        let placement = configuration.placement;
+        let shard = Shard::new(ShardIdentifier(1), MemoryStorage::new());
+        let mut log = vec![
+            committed(1, Operation::Upsert("a".into(), "1".into(), None)),
+            committed(2, Operation::Upsert("b".into(), "2".into(), None)),
+        ];
+
+        for (context, operation) in &log {
+            shard.state_machine().apply(context, operation).unwrap();
+        }
+
+        let mut migration = Migration::start(
+            &shard,
+            2,
+            &placement,
+            GroupIdentifier(2),
+            MemoryStorage::new(),
+        )
+        .unwrap();
+
+        // the source keeps taking writes while the copy catches up
+        log.push(committed(3, Operation::Delete("a".into())));
+        log.push(committed(
+            4,
+            Operation::Upsert("c".into(), "3".into(), None),
+        ));
+        log.push(committed(5, Operation::Split("".into())));
+
+        for (context, operation) in &log[2..] {
+            shard.state_machine().apply(context, operation).unwrap();
+        }
+
+        let entries = || log.iter().map(|(context, operation)| (*context, operation));
+
+        assert_eq!(migration.catch_up(entries().take(3)).unwrap(), 3);
+        assert_eq!(migration.lag(5), 2);
+        assert!(matches!(
+            block_on(
+                Migration::start(
+                    &shard,
+                    5,
+                    &placement,
+                    GroupIdentifier(2),
+                    MemoryStorage::new()
+                )
+                .unwrap()
+                .finish(&router)
+            ),
+            Err(MetadataError::Shard(ShardError::MigrationNotReady(
+                ShardIdentifier(1)
+            )))
+        ));
+
+        migration.fence(5).unwrap();
+
+        assert_eq!(migration.catch_up(entries()).unwrap(), 4);
+        assert_eq!(
+            migration.phase(),
+            MigrationPhase::Fenced { fence_op_number: 5 }
+        );
+
+        let configuration = block_on(migration.finish(&router)).unwrap();
+
+        assert!(configuration.placement.version() > placement.version());
+        assert_eq!(
+            configuration
+                .placement
+                .group_for(ShardIdentifier(1))
+                .unwrap(),
+            GroupIdentifier(2)
+        );
+
+        // the target group committed the copy, and serves it from then on
+        let target = &groups.state_machines[&GroupIdentifier(2)];
+
+        assert!(target.get("a").unwrap().is_none());
+        assert_eq!(target.get("c").unwrap().unwrap().version, 4);
+        assert_eq!(block_on(router.get("b")).unwrap().unwrap().version, 2);
+        assert_eq!(
+            shard
+                .state_machine()
+                .apply(
+                    &committed(6, Operation::NoOp).0,
+                    &Operation::Upsert("d".into(), "4".into(), None)
+                )
+                .unwrap(),
+            OperationResult::OutOfRange
+        );
+    }
+}
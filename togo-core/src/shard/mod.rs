@@ -0,0 +1,159 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use async_trait::async_trait;
+use thiserror::Error;
+use togo_vr::state::{OperationContext, StateMachine, StateResult};
+
+use crate::{
+    kv::KvStateMachine,
+    operation::{Operation, OperationResult},
+    storage::{Delete, Flush, Get, Scan, StorageError, StorageResult, Upsert},
+};
+
+use self::range::{KeyRange, RangeStats};
+
+pub mod map;
+pub mod migration;
+pub mod node;
+pub mod placement;
+pub mod planner;
+pub mod range;
+pub mod rebalance;
+
+pub type ShardResult<T> = Result<T, ShardError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ShardIdentifier(pub u32);
+
+impl Display for ShardIdentifier {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Decides which shard owns a key.
+pub trait Partitioner {
+    fn shard_for(&self, key: &[u8]) -> Option<ShardIdentifier>;
+    /// The shards holding keys within the range along with the part of the
+    /// keyspace each of them owns.
+    fn shards_overlapping(&self, range: &KeyRange) -> Vec<(ShardIdentifier, KeyRange)>;
+}
+
+/// A partition of the keyspace, replicated by its own VR group - it owns its
+/// storage through the state machine applying the group's operations.
+pub struct Shard<S: Flush> {
+    identifier: ShardIdentifier,
+    state_machine: KvStateMachine<S>,
+    // writes applied since the stats were last taken
+    writes: AtomicU64,
+}
+
+impl<S: Flush> Shard<S> {
+    pub fn new(identifier: ShardIdentifier, storage: S) -> Self {
+        Self::with_state_machine(identifier, KvStateMachine::new(storage))
+    }
+
+    pub fn with_state_machine(
+        identifier: ShardIdentifier,
+        state_machine: KvStateMachine<S>,
+    ) -> Self {
+        Self {
+            identifier,
+            state_machine,
+            writes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn identifier(&self) -> ShardIdentifier {
+        self.identifier
+    }
+
+    pub fn state_machine(&self) -> &KvStateMachine<S> {
+        &self.state_machine
+    }
+
+    pub fn state_machine_mut(&mut self) -> &mut KvStateMachine<S> {
+        &mut self.state_machine
+    }
+
+    pub fn storage(&self) -> &S {
+        self.state_machine.storage()
+    }
+}
+
+impl<S> Shard<S>
+where
+    S: Flush + Get + Scan,
+{
+    /// Sizes up the shard and resets its write counter, so that consecutive
+    /// calls measure the throughput in between.
+    pub fn stats(&self) -> StorageResult<RangeStats> {
+        Ok(RangeStats {
+            writes: self.writes.swap(0, Ordering::AcqRel),
+            ..self.state_machine.range_stats()?
+        })
+    }
+}
+
+impl<S> StateMachine<Operation, OperationResult> for Shard<S>
+where
+    S: Flush + Get + Upsert + Delete + Scan,
+{
+    fn apply_operations(
+        &self,
+        operations: &[(OperationContext, &Operation)],
+    ) -> StateResult<Vec<OperationResult>> {
+        let writes = operations
+            .iter()
+            .filter(|(_, operation)| operation.is_write())
+            .count();
+
+        self.writes.fetch_add(writes as u64, Ordering::AcqRel);
+        self.state_machine.apply_operations(operations)
+    }
+}
+
+#[async_trait]
+impl<S> Flush for Shard<S>
+where
+    S: Flush + Send + Sync,
+{
+    async fn flush(&self) -> StorageResult<()> {
+        self.storage().flush().await
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ShardError {
+    #[error("No shards have been placed yet!")]
+    NoShards,
+    #[error("Shard {} isn't hosted by this node!", .0)]
+    NotHosted(ShardIdentifier),
+    #[error("Shard {} isn't placed in the map!", .0)]
+    UnknownShard(ShardIdentifier),
+    #[error("Shard {} has been placed already!", .0)]
+    AlreadyPlaced(ShardIdentifier),
+    #[error("Boundary doesn't fall within shard {}!", .0)]
+    InvalidBoundary(ShardIdentifier),
+    #[error("Shards {} and {} aren't adjacent!", .0, .1)]
+    NotAdjacent(ShardIdentifier, ShardIdentifier),
+    #[error("Shard {} hasn't committed the range change yet!", .0)]
+    NotCommitted(ShardIdentifier),
+    #[error("Op {} of shard {} is missing!", .0, .1)]
+    MissingOp(u64, ShardIdentifier),
+    #[error("Shard {} refused the change! {:?}", .0, .1)]
+    Refused(ShardIdentifier, OperationResult),
+    #[error("Migration of shard {} isn't ready for this step!", .0)]
+    MigrationNotReady(ShardIdentifier),
+    #[error("Storage error: {}", .0)]
+    Storage(StorageError),
+}
+
+impl From<StorageError> for ShardError {
+    fn from(value: StorageError) -> Self {
+        Self::Storage(value)
+    }
+}
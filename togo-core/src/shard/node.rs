@@ -0,0 +1,409 @@
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+
+use crate::{
+    kv::KvStateMachine,
+    storage::{Delete, Flush, Scan, StorageResult, Upsert},
+};
+
+use super::{
+    map::ShardMap,
+    range::{KeyRange, RangeAction, RangeMap, RangePolicy},
+    Partitioner, Shard, ShardError, ShardIdentifier, ShardResult,
+};
+
+/// The shards hosted by a single process, each of them usually a member of a
+/// different VR group - keys are routed to them through the shared map.
+pub struct Node<S: Flush, P = ShardMap> {
+    map: P,
+    shards: BTreeMap<ShardIdentifier, Shard<S>>,
+}
+
+impl<S: Flush, P: Partitioner> Node<S, P> {
+    pub fn new(map: P) -> Self {
+        Self {
+            map,
+            shards: BTreeMap::new(),
+        }
+    }
+
+    pub fn map(&self) -> &P {
+        &self.map
+    }
+
+    pub fn map_mut(&mut self) -> &mut P {
+        &mut self.map
+    }
+
+    /// Starts hosting the shard, handing back the one it replaces.
+    pub fn host(&mut self, shard: Shard<S>) -> Option<Shard<S>> {
+        self.shards.insert(shard.identifier(), shard)
+    }
+
+    pub fn release(&mut self, identifier: ShardIdentifier) -> Option<Shard<S>> {
+        self.shards.remove(&identifier)
+    }
+
+    pub fn shard(&self, identifier: ShardIdentifier) -> Option<&Shard<S>> {
+        self.shards.get(&identifier)
+    }
+
+    pub fn shard_mut(&mut self, identifier: ShardIdentifier) -> Option<&mut Shard<S>> {
+        self.shards.get_mut(&identifier)
+    }
+
+    pub fn shards(&self) -> impl Iterator<Item = &Shard<S>> {
+        self.shards.values()
+    }
+
+    /// The hosted shard owning the key - a key owned by a shard living on
+    /// another node yields [`ShardError::NotHosted`].
+    pub fn route<K: AsRef<[u8]>>(&self, key: K) -> ShardResult<&Shard<S>> {
+        let identifier = self
+            .map
+            .shard_for(key.as_ref())
+            .ok_or(ShardError::NoShards)?;
+
+        self.shard(identifier)
+            .ok_or(ShardError::NotHosted(identifier))
+    }
+
+    pub fn route_mut<K: AsRef<[u8]>>(&mut self, key: K) -> ShardResult<&mut Shard<S>> {
+        let identifier = self
+            .map
+            .shard_for(key.as_ref())
+            .ok_or(ShardError::NoShards)?;
+
+        self.shard_mut(identifier)
+            .ok_or(ShardError::NotHosted(identifier))
+    }
+}
+
+impl<S> Node<S, RangeMap>
+where
+    S: Flush + Upsert + Delete + Scan,
+{
+    /// Sizes up every hosted shard and plans the splits and merges due -
+    /// only neighbours that are both hosted here get merged.
+    pub fn plan(&self, policy: &RangePolicy) -> ShardResult<Vec<RangeAction>> {
+        let stats = self
+            .shards
+            .iter()
+            .map(|(identifier, shard)| Ok((*identifier, shard.stats()?)))
+            .collect::<ShardResult<BTreeMap<_, _>>>()?;
+
+        Ok(self.map.plan(&stats, policy))
+    }
+
+    /// Catches up with the splits and merges of hosted shards the
+    /// configuration records, as soon as the shards' logs have committed
+    /// them - the children of splits start out with the state machine
+    /// `create` makes. Returns how many were carried out.
+    pub fn follow<F>(&mut self, shards: &RangeMap, mut create: F) -> ShardResult<usize>
+    where
+        F: FnMut(ShardIdentifier) -> KvStateMachine<S>,
+    {
+        let mut changes = 0;
+        let merged = self
+            .map
+            .ranges()
+            .filter(|(shard, _)| {
+                self.shards.contains_key(shard) && shards.range_of(*shard).is_none()
+            })
+            .collect::<Vec<_>>();
+
+        for (right, range) in merged {
+            let Some((left, _)) = self
+                .map
+                .ranges()
+                .find(|(_, left)| left.end.as_ref() == Some(&range.start))
+            else {
+                continue;
+            };
+
+            match self.finish_merge(left, right) {
+                Ok(_) => changes += 1,
+                Err(ShardError::NotCommitted(_) | ShardError::NotHosted(_)) => {}
+                Err(error) => return Err(error),
+            }
+        }
+
+        let hosted = self.shards.keys().copied().collect::<Vec<_>>();
+
+        for parent in hosted {
+            let committed = self.shards[&parent].state_machine().range()?;
+            let Some(at) = committed.end else {
+                continue;
+            };
+            let child = shards.shard_for(&at);
+
+            // a shard handing over its whole range is merged or migrated
+            // rather than split
+            if child == parent
+                || shards.range_of(child).map(|range| range.start) != Some(at)
+                || self.map.range_of(child).is_some()
+            {
+                continue;
+            }
+
+            self.finish_split(parent, child, create(child))?;
+            changes += 1;
+        }
+
+        Ok(changes)
+    }
+
+    /// Creates the shard taking over the range handed over by a split
+    /// committed in `parent`, moving the keys over to it - every replica of
+    /// the parent does the same, so the child starts out with one state.
+    pub fn finish_split(
+        &mut self,
+        parent: ShardIdentifier,
+        child: ShardIdentifier,
+        state_machine: KvStateMachine<S>,
+    ) -> ShardResult<u64> {
+        let mapped = self
+            .map
+            .range_of(parent)
+            .ok_or(ShardError::UnknownShard(parent))?;
+        let committed = self
+            .shard(parent)
+            .ok_or(ShardError::NotHosted(parent))?
+            .state_machine()
+            .range()?;
+
+        let released = match committed.end {
+            Some(at) if committed.end != mapped.end => KeyRange {
+                start: at,
+                end: mapped.end,
+            },
+            _ => return Err(ShardError::NotCommitted(parent)),
+        };
+
+        self.map.split(parent, released.start.clone(), child)?;
+
+        let state_machine = state_machine.with_range(released.clone());
+        let moved = self.shards[&parent]
+            .state_machine()
+            .move_range(&released, &state_machine)?;
+
+        self.host(Shard::with_state_machine(child, state_machine));
+
+        Ok(moved)
+    }
+
+    /// Moves the keys of `right` over to `left` once `right` has handed over
+    /// its whole range and `left` has committed the merge, then stops
+    /// hosting `right`.
+    pub fn finish_merge(
+        &mut self,
+        left: ShardIdentifier,
+        right: ShardIdentifier,
+    ) -> ShardResult<u64> {
+        let right_range = self
+            .map
+            .range_of(right)
+            .ok_or(ShardError::UnknownShard(right))?;
+
+        for (shard, committed) in [
+            (left, right_range.end.clone()),
+            (right, Some(right_range.start.clone())),
+        ] {
+            let range = self
+                .shard(shard)
+                .ok_or(ShardError::NotHosted(shard))?
+                .state_machine()
+                .range()?;
+
+            if range.end != committed {
+                return Err(ShardError::NotCommitted(shard));
+            }
+        }
+
+        self.map.merge(left, right)?;
+
+        let moved = self.shards[&right]
+            .state_machine()
+            .move_range(&right_range, self.shards[&left].state_machine())?;
+
+        self.release(right);
+
+        Ok(moved)
+    }
+}
+
+#[async_trait]
+impl<S, P> Flush for Node<S, P>
+where
+    S: Flush + Send + Sync,
+    P: Send + Sync,
+{
+    async fn flush(&self) -> StorageResult<()> {
+        for shard in self.shards.values() {
+            shard.flush().await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use togo_vr::state::OperationContext;
+
+    use crate::{
+        kv::KvStateMachine,
+        operation::{Operation, OperationResult},
+        shard::{
+            map::ShardMap,
+            range::{KeyRange, RangeMap},
+            Shard, ShardError, ShardIdentifier,
+        },
+        storage::{memory::MemoryStorage, Get},
+    };
+
+    use super::Node;
+
+    fn context(op_number: u64) -> OperationContext {
+        OperationContext {
+            op_number,
+            timestamp: 0,
+            seed: 0,
+        }
+    }
+
+    #[test]
+    pub fn keys_are_routed_to_the_hosted_shard_owning_them() {
+        let map = ShardMap::with_shards([ShardIdentifier(1), ShardIdentifier(2)], 64);
+        let mut node = Node::new(map);
+
+        node.host(Shard::new(ShardIdentifier(1), MemoryStorage::new()));
+
+        let keys = (0..64).map(|key| format!("key-{key}")).collect::<Vec<_>>();
+        let (hosted, remote): (Vec<_>, Vec<_>) = keys
+            .iter()
+            .partition(|key| node.map().shard_for(key) == Some(ShardIdentifier(1)));
+
+        assert!(!hosted.is_empty() && !remote.is_empty());
+
+        for key in &hosted {
+            let operation = Operation::Upsert(Bytes::from(key.to_string()), "value".into(), None);
+
+            node.route(key)
+                .unwrap()
+                .state_machine()
+                .apply(&context(1), &operation)
+                .unwrap();
+        }
+
+        let shard = node.shard(ShardIdentifier(1)).unwrap();
+
+        assert!(hosted
+            .iter()
+            .all(|key| shard.state_machine().get(key).unwrap().is_some()));
+        assert!(matches!(
+            node.route(remote[0]),
+            Err(ShardError::NotHosted(ShardIdentifier(2)))
+        ));
+    }
+
+    #[test]
+    pub fn committed_splits_and_merges_move_the_keys() {
+        let mut node = Node::new(RangeMap::new(ShardIdentifier(1)));
+
+        node.host(Shard::new(ShardIdentifier(1), MemoryStorage::new()));
+
+        let parent = node.shard(ShardIdentifier(1)).unwrap().state_machine();
+
+        for (op_number, key) in ["apple", "melon", "peach"].into_iter().enumerate() {
+            parent
+                .apply(
+                    &context(op_number as u64 + 1),
+                    &Operation::Upsert(key.into(), "fruit".into(), None),
+                )
+                .unwrap();
+        }
+
+        assert!(matches!(
+            node.finish_split(
+                ShardIdentifier(1),
+                ShardIdentifier(2),
+                KvStateMachine::new(MemoryStorage::new())
+            ),
+            Err(ShardError::NotCommitted(ShardIdentifier(1)))
+        ));
+
+        let parent = node.shard(ShardIdentifier(1)).unwrap().state_machine();
+
+        assert_eq!(
+            parent
+                .apply(&context(4), &Operation::Split("m".into()))
+                .unwrap(),
+            OperationResult::Split(KeyRange::new("m", None))
+        );
+        assert_eq!(
+            parent
+                .apply(
+                    &context(5),
+                    &Operation::Upsert("zucchini".into(), "vegetable".into(), None)
+                )
+                .unwrap(),
+            OperationResult::OutOfRange
+        );
+
+        let moved = node
+            .finish_split(
+                ShardIdentifier(1),
+                ShardIdentifier(2),
+                KvStateMachine::new(MemoryStorage::new()),
+            )
+            .unwrap();
+
+        assert_eq!(moved, 2);
+        assert_eq!(node.map().shard_for("peach"), ShardIdentifier(2));
+        assert!(node
+            .route("peach")
+            .unwrap()
+            .state_machine()
+            .get("peach")
+            .unwrap()
+            .is_some());
+        assert!(node
+            .shard(ShardIdentifier(1))
+            .unwrap()
+            .storage()
+            .get(b"\0peach")
+            .unwrap()
+            .is_none());
+
+        let left = node.shard(ShardIdentifier(1)).unwrap().state_machine();
+        let right = node.shard(ShardIdentifier(2)).unwrap().state_machine();
+
+        assert_eq!(
+            right
+                .apply(&context(6), &Operation::Split("m".into()))
+                .unwrap(),
+            OperationResult::Split(KeyRange::new("m", None))
+        );
+        assert_eq!(
+            left.apply(&context(6), &Operation::Merge(KeyRange::new("m", None)))
+                .unwrap(),
+            OperationResult::Merged(KeyRange::default())
+        );
+        assert_eq!(
+            node.finish_merge(ShardIdentifier(1), ShardIdentifier(2))
+                .unwrap(),
+            2
+        );
+        assert!(node.shard(ShardIdentifier(2)).is_none());
+        assert!(["apple", "melon", "peach"].iter().all(|key| node
+            .route(key)
+            .unwrap()
+            .state_machine()
+            .get(key)
+            .unwrap()
+            .is_some()));
+    }
+}
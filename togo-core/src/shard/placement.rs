@@ -0,0 +1,100 @@
+use std::collections::BTreeMap;
+
+use bytes::{Buf, BufMut, BytesMut};
+pub use togo_vr::replica::GroupIdentifier;
+
+use crate::storage::{StorageError, StorageResult};
+
+use super::{ShardError, ShardIdentifier, ShardResult};
+
+/// Which group owns every shard - the version changes with every move, so
+/// that clients can tell their copy is outdated.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Placement {
+    groups: BTreeMap<ShardIdentifier, GroupIdentifier>,
+    version: u64,
+}
+
+impl Placement {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Places the shard in the group, returning its previous group.
+    pub fn assign(
+        &mut self,
+        shard: ShardIdentifier,
+        group: GroupIdentifier,
+    ) -> Option<GroupIdentifier> {
+        self.version += 1;
+        self.groups.insert(shard, group)
+    }
+
+    pub fn remove(&mut self, shard: ShardIdentifier) -> Option<GroupIdentifier> {
+        let group = self.groups.remove(&shard)?;
+
+        self.version += 1;
+
+        Some(group)
+    }
+
+    pub fn group_for(&self, shard: ShardIdentifier) -> ShardResult<GroupIdentifier> {
+        self.groups
+            .get(&shard)
+            .copied()
+            .ok_or(ShardError::UnknownShard(shard))
+    }
+
+    pub fn shards_of(&self, group: GroupIdentifier) -> impl Iterator<Item = ShardIdentifier> + '_ {
+        self.groups
+            .iter()
+            .filter(move |(_, owner)| **owner == group)
+            .map(|(shard, _)| *shard)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ShardIdentifier, GroupIdentifier)> + '_ {
+        self.groups.iter().map(|(shard, group)| (*shard, *group))
+    }
+
+    pub(crate) fn encode(&self, buffer: &mut BytesMut) {
+        buffer.put_u64(self.version);
+        buffer.put_u32(self.groups.len() as u32);
+
+        for (shard, group) in &self.groups {
+            buffer.put_u32(shard.0);
+            buffer.put_u32(group.0);
+        }
+    }
+
+    pub(crate) fn decode(data: &mut &[u8]) -> StorageResult<Self> {
+        if data.remaining() < 12 {
+            return Err(malformed_placement());
+        }
+
+        let version = data.get_u64();
+        let count = data.get_u32() as usize;
+
+        if data.remaining() < 8 * count {
+            return Err(malformed_placement());
+        }
+
+        let groups = (0..count)
+            .map(|_| {
+                (
+                    ShardIdentifier(data.get_u32()),
+                    GroupIdentifier(data.get_u32()),
+                )
+            })
+            .collect();
+
+        Ok(Self { groups, version })
+    }
+}
+
+fn malformed_placement() -> StorageError {
+    StorageError::CorruptionDetected("Malformed placement!".into())
+}
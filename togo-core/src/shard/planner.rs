@@ -0,0 +1,397 @@
+use std::sync::{Mutex, MutexGuard};
+
+use bytes::Bytes;
+
+use crate::{
+    kv::KvStateMachine,
+    metadata::{MetadataResult, MetadataService},
+    operation::{Operation, OperationResult},
+    router::{GroupClient, Router, RouterError},
+    storage::{Delete, Flush, Scan, Upsert},
+};
+
+use super::{
+    node::Node,
+    range::{RangeAction, RangeMap, RangePolicy},
+    ShardError, ShardIdentifier,
+};
+
+const DEFAULT_INTERVAL: u64 = 600;
+
+/// Splits and merges the shards hosted by a node as their stats call for -
+/// every range change is committed through the logs of the shards involved
+/// and then in the cluster configuration, before the node moves the keys.
+pub struct RangePlanner<F> {
+    policy: RangePolicy,
+    // ticks between plans, so that the write counts cover a while
+    interval: u64,
+    ticks: u64,
+    // creates the state machine of a shard split off a hosted one
+    create: F,
+}
+
+impl<F> RangePlanner<F> {
+    pub fn new(policy: RangePolicy, create: F) -> Self {
+        Self {
+            policy,
+            interval: DEFAULT_INTERVAL,
+            ticks: 0,
+            create,
+        }
+    }
+
+    pub fn with_interval(mut self, interval: u64) -> Self {
+        self.interval = interval.max(1);
+        self
+    }
+
+    /// Plans and carries out range changes once every `interval` ticks.
+    pub async fn tick<S, C, M>(
+        &mut self,
+        node: &Mutex<Node<S, RangeMap>>,
+        router: &Router<RangeMap, C, MetadataService<M>>,
+    ) -> MetadataResult<Vec<RangeAction>>
+    where
+        F: FnMut(ShardIdentifier) -> KvStateMachine<S>,
+        S: Flush + Upsert + Delete + Scan,
+        C: GroupClient + Send + Sync,
+        M: GroupClient + Send + Sync,
+    {
+        self.ticks += 1;
+
+        if self.ticks < self.interval {
+            return Ok(Vec::new());
+        }
+
+        self.ticks = 0;
+        self.run(node, router).await
+    }
+
+    /// Carries out the splits and merges due, returning the ones done - a
+    /// shard may refuse one for now, e.g. while a transaction holds keys it
+    /// would hand over.
+    pub async fn run<S, C, M>(
+        &mut self,
+        node: &Mutex<Node<S, RangeMap>>,
+        router: &Router<RangeMap, C, MetadataService<M>>,
+    ) -> MetadataResult<Vec<RangeAction>>
+    where
+        F: FnMut(ShardIdentifier) -> KvStateMachine<S>,
+        S: Flush + Upsert + Delete + Scan,
+        C: GroupClient + Send + Sync,
+        M: GroupClient + Send + Sync,
+    {
+        let actions = lock(node)?.plan(&self.policy)?;
+        let mut done = Vec::with_capacity(actions.len());
+
+        for action in actions {
+            let carried_out = match &action {
+                RangeAction::Split { shard, at } => self.split(node, router, *shard, at).await?,
+                RangeAction::Merge { left, right } => merge(node, router, *left, *right).await?,
+            };
+
+            if carried_out {
+                done.push(action);
+            }
+        }
+
+        Ok(done)
+    }
+
+    async fn split<S, C, M>(
+        &mut self,
+        node: &Mutex<Node<S, RangeMap>>,
+        router: &Router<RangeMap, C, MetadataService<M>>,
+        parent: ShardIdentifier,
+        at: &Bytes,
+    ) -> MetadataResult<bool>
+    where
+        F: FnMut(ShardIdentifier) -> KvStateMachine<S>,
+        S: Flush + Upsert + Delete + Scan,
+        C: GroupClient + Send + Sync,
+        M: GroupClient + Send + Sync,
+    {
+        if !matches!(
+            router.submit(parent, Operation::Split(at.clone())).await?,
+            OperationResult::Split(_)
+        ) {
+            return Ok(false);
+        }
+
+        // the child stays in the parent's group, under the next free
+        // identifier - a split that made it into the configuration before
+        // is left as it is
+        let configuration = router
+            .source()
+            .update(|configuration| {
+                if configuration.shards.shard_for(at) != parent {
+                    return Ok(());
+                }
+
+                let child = next_shard(&configuration.shards);
+                let group = configuration.placement.group_for(parent)?;
+
+                configuration.shards.split(parent, at.clone(), child)?;
+                configuration.placement.assign(child, group);
+
+                Ok(())
+            })
+            .await?;
+        let child = configuration.shards.shard_for(at);
+
+        lock(node)?.finish_split(parent, child, (self.create)(child))?;
+        router.refresh().await?;
+
+        Ok(true)
+    }
+}
+
+// the right shard hands over its whole range before the left one takes it
+async fn merge<S, C, M>(
+    node: &Mutex<Node<S, RangeMap>>,
+    router: &Router<RangeMap, C, MetadataService<M>>,
+    left: ShardIdentifier,
+    right: ShardIdentifier,
+) -> MetadataResult<bool>
+where
+    S: Flush + Upsert + Delete + Scan,
+    C: GroupClient + Send + Sync,
+    M: GroupClient + Send + Sync,
+{
+    let range = lock(node)?
+        .map()
+        .range_of(right)
+        .ok_or(ShardError::UnknownShard(right))?;
+
+    if !matches!(
+        router
+            .submit(right, Operation::Split(range.start.clone()))
+            .await?,
+        OperationResult::Split(_)
+    ) {
+        return Ok(false);
+    }
+
+    match router.submit(left, Operation::Merge(range)).await? {
+        OperationResult::Merged(_) => {}
+        result => return Err(ShardError::Refused(left, result).into()),
+    }
+
+    router
+        .source()
+        .update(|configuration| {
+            if configuration.shards.range_of(right).is_some() {
+                configuration.shards.merge(left, right)?;
+                configuration.placement.remove(right);
+            }
+
+            Ok(())
+        })
+        .await?;
+
+    lock(node)?.finish_merge(left, right)?;
+    router.refresh().await?;
+
+    Ok(true)
+}
+
+fn next_shard(shards: &RangeMap) -> ShardIdentifier {
+    ShardIdentifier(
+        shards
+            .ranges()
+            .map(|(shard, _)| shard.0 + 1)
+            .max()
+            .unwrap_or_default(),
+    )
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<'_, T>, RouterError> {
+    mutex.lock().map_err(|_| RouterError::Poisoned)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    };
+
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use futures::executor::block_on;
+    use togo_vr::state::{OperationContext, StateMachine};
+
+    use crate::{
+        kv::KvStateMachine,
+        metadata::{ClusterConfiguration, MetadataService},
+        operation::Operation,
+        router::{GroupClient, Reply, Request, Router, RouterResult},
+        shard::{
+            node::Node,
+            placement::GroupIdentifier,
+            range::{KeyRange, RangeAction, RangeMap, RangePolicy},
+            Shard, ShardIdentifier,
+        },
+        storage::memory::MemoryStorage,
+    };
+
+    use super::RangePlanner;
+
+    // the metadata group and a data group, whose only node hosts every shard
+    #[derive(Clone)]
+    struct Groups {
+        metadata: Arc<KvStateMachine<MemoryStorage>>,
+        node: Arc<Mutex<Node<MemoryStorage, RangeMap>>>,
+        op_number: Arc<AtomicU64>,
+    }
+
+    #[async_trait]
+    impl GroupClient for Groups {
+        fn replica_count(&self, _: GroupIdentifier) -> usize {
+            1
+        }
+
+        async fn send(
+            &self,
+            group: GroupIdentifier,
+            _: usize,
+            shard: ShardIdentifier,
+            request: Request,
+        ) -> RouterResult<Reply> {
+            let context = OperationContext {
+                op_number: self.op_number.fetch_add(1, Ordering::AcqRel) + 1,
+                timestamp: 0,
+                seed: 0,
+            };
+
+            if group == GroupIdentifier(0) {
+                return match request {
+                    Request::Write(operation) => {
+                        Ok(Reply::written(self.metadata.apply(&context, &operation)?))
+                    }
+                    request => Ok(Reply::read(&self.metadata, &request)?),
+                };
+            }
+
+            let node = self.node.lock().unwrap();
+            let Some(shard) = node.shard(shard) else {
+                return Ok(Reply::WrongShard);
+            };
+
+            match request {
+                Request::Write(operation) => {
+                    let mut results = shard.apply_operations(&[(context, &operation)]).unwrap();
+
+                    Ok(Reply::written(results.pop().unwrap()))
+                }
+                request => Ok(Reply::read(shard.state_machine(), &request)?),
+            }
+        }
+    }
+
+    #[test]
+    pub fn busy_shards_are_split_and_idle_neighbours_merged_through_their_logs() {
+        let mut node = Node::new(RangeMap::new(ShardIdentifier(1)));
+
+        node.host(Shard::new(ShardIdentifier(1), MemoryStorage::new()));
+
+        let groups = Groups {
+            metadata: Arc::new(KvStateMachine::new(MemoryStorage::new())),
+            node: Arc::new(Mutex::new(node)),
+            op_number: Arc::new(AtomicU64::new(0)),
+        };
+        let metadata = MetadataService::new(groups.clone(), GroupIdentifier(0));
+        let mut configuration = ClusterConfiguration::new(ShardIdentifier(1));
+
+        configuration
+            .placement
+            .assign(ShardIdentifier(1), GroupIdentifier(1));
+
+        let configuration = block_on(metadata.bootstrap(configuration)).unwrap();
+        let router = Router::new(groups.clone(), metadata, configuration.routing());
+        let mut planner = RangePlanner::new(
+            RangePolicy {
+                split_bytes: None,
+                split_writes: Some(4),
+                merge_bytes: u64::MAX,
+                merge_writes: 0,
+            },
+            |_| KvStateMachine::new(MemoryStorage::new()),
+        )
+        .with_interval(2);
+        let keys = ["apple", "kiwi", "melon", "peach", "plum", "quince"];
+
+        block_on(async {
+            for key in keys {
+                router
+                    .write(Operation::Upsert(key.into(), "fruit".into(), None))
+                    .await
+                    .unwrap();
+            }
+
+            assert!(planner
+                .tick(&groups.node, &router)
+                .await
+                .unwrap()
+                .is_empty());
+
+            let actions = planner.tick(&groups.node, &router).await.unwrap();
+            let [RangeAction::Split { at, .. }] = actions.as_slice() else {
+                panic!("expected a single split, got {actions:?}");
+            };
+            let configuration = router.source().configuration().await.unwrap();
+
+            assert_eq!(
+                configuration.shards.range_of(ShardIdentifier(2)),
+                Some(KeyRange::new(at.clone(), None))
+            );
+            assert_eq!(
+                configuration
+                    .placement
+                    .group_for(ShardIdentifier(2))
+                    .unwrap(),
+                GroupIdentifier(1)
+            );
+            assert_eq!(
+                groups.node.lock().unwrap().map().shard_for(at),
+                ShardIdentifier(2)
+            );
+
+            for key in keys {
+                assert!(router.get(key).await.unwrap().is_some());
+            }
+
+            // nothing has been written since, so the halves merge again
+            let actions = planner.run(&groups.node, &router).await.unwrap();
+
+            assert_eq!(
+                actions,
+                [RangeAction::Merge {
+                    left: ShardIdentifier(1),
+                    right: ShardIdentifier(2),
+                }]
+            );
+
+            let configuration = router.source().configuration().await.unwrap();
+
+            assert_eq!(configuration.shards.ranges().count(), 1);
+            assert!(configuration
+                .placement
+                .group_for(ShardIdentifier(2))
+                .is_err());
+            assert!(groups
+                .node
+                .lock()
+                .unwrap()
+                .shard(ShardIdentifier(2))
+                .is_none());
+
+            for key in keys {
+                assert_eq!(
+                    router.get(key).await.unwrap().unwrap().value,
+                    Bytes::from("fruit")
+                );
+            }
+        });
+    }
+}
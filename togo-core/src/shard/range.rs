@@ -0,0 +1,442 @@
+use std::{
+    collections::BTreeMap,
+    ops::{Bound, RangeBounds},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::storage::{StorageError, StorageResult};
+
+use super::{Partitioner, ShardError, ShardIdentifier, ShardResult};
+
+/// A contiguous range of keys - `end` is exclusive, with `None` standing for
+/// every key past `start`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyRange {
+    pub start: Bytes,
+    pub end: Option<Bytes>,
+}
+
+impl KeyRange {
+    pub fn new<K: Into<Bytes>>(start: K, end: Option<K>) -> Self {
+        Self {
+            start: start.into(),
+            end: end.map(Into::into),
+        }
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        key >= self.start.as_ref() && self.end.as_ref().is_none_or(|end| key < end.as_ref())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        matches!(&self.end, Some(end) if *end <= self.start)
+    }
+
+    pub fn intersection(&self, other: &KeyRange) -> KeyRange {
+        KeyRange {
+            start: self.start.clone().max(other.start.clone()),
+            end: match (&self.end, &other.end) {
+                (Some(end), Some(other_end)) => Some(end.clone().min(other_end.clone())),
+                (end, other_end) => end.clone().or_else(|| other_end.clone()),
+            },
+        }
+    }
+
+    /// Whether every key of the other range falls within this one.
+    pub fn covers(&self, other: &KeyRange) -> bool {
+        other.is_empty()
+            || (self.start <= other.start
+                && match (&self.end, &other.end) {
+                    (None, _) => true,
+                    (Some(_), None) => false,
+                    (Some(end), Some(other_end)) => other_end <= end,
+                })
+    }
+
+    pub(crate) fn encode(&self, buffer: &mut BytesMut) {
+        buffer.put_u32(self.start.len() as u32);
+        buffer.put_slice(&self.start);
+
+        match &self.end {
+            Some(end) => {
+                buffer.put_u8(1);
+                buffer.put_u32(end.len() as u32);
+                buffer.put_slice(end);
+            }
+            None => buffer.put_u8(0),
+        }
+    }
+
+    pub(crate) fn decode(data: &mut &[u8]) -> StorageResult<Self> {
+        let start = decode_bytes(data)?;
+        let end = match data.has_remaining().then(|| data.get_u8()) {
+            Some(0) => None,
+            Some(1) => Some(decode_bytes(data)?),
+            _ => return Err(malformed_range()),
+        };
+
+        Ok(Self { start, end })
+    }
+}
+
+/// Maps keys to shards owning contiguous ranges, which together always cover
+/// the whole keyspace - neighbouring keys end up on the same shard, so range
+/// scans touch as few shards as possible.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeMap {
+    // shards keyed by the start of their range, which ends where the next
+    // one starts
+    shards: BTreeMap<Bytes, ShardIdentifier>,
+}
+
+impl RangeMap {
+    pub fn new(shard: ShardIdentifier) -> Self {
+        Self {
+            shards: BTreeMap::from([(Bytes::new(), shard)]),
+        }
+    }
+
+    pub fn shard_for<K: AsRef<[u8]>>(&self, key: K) -> ShardIdentifier {
+        let key = Bytes::copy_from_slice(key.as_ref());
+
+        self.shards
+            .range(..=key)
+            .next_back()
+            .map(|(_, shard)| *shard)
+            .expect("the first range always starts at the empty key")
+    }
+
+    pub fn range_of(&self, shard: ShardIdentifier) -> Option<KeyRange> {
+        self.ranges()
+            .find(|(identifier, _)| *identifier == shard)
+            .map(|(_, range)| range)
+    }
+
+    /// Shards in key order, along with their ranges.
+    pub fn ranges(&self) -> impl Iterator<Item = (ShardIdentifier, KeyRange)> + '_ {
+        let mut starts = self.shards.iter().peekable();
+
+        std::iter::from_fn(move || {
+            let (start, shard) = starts.next()?;
+            let end = starts.peek().map(|(end, _)| (*end).clone());
+
+            Some((*shard, KeyRange::new(start.clone(), end)))
+        })
+    }
+
+    pub(crate) fn encode(&self, buffer: &mut BytesMut) {
+        buffer.put_u32(self.shards.len() as u32);
+
+        for (start, shard) in &self.shards {
+            buffer.put_u32(start.len() as u32);
+            buffer.put_slice(start);
+            buffer.put_u32(shard.0);
+        }
+    }
+
+    pub(crate) fn decode(data: &mut &[u8]) -> StorageResult<Self> {
+        if data.remaining() < 4 {
+            return Err(malformed_range());
+        }
+
+        let shards = (0..data.get_u32())
+            .map(|_| {
+                let start = decode_bytes(data)?;
+
+                match data.remaining() {
+                    4.. => Ok((start, ShardIdentifier(data.get_u32()))),
+                    _ => Err(malformed_range()),
+                }
+            })
+            .collect::<StorageResult<BTreeMap<_, _>>>()?;
+
+        // the ranges have to cover the whole keyspace
+        match shards.keys().next() {
+            Some(first) if first.is_empty() => Ok(Self { shards }),
+            _ => Err(malformed_range()),
+        }
+    }
+
+    /// The shards a scan over the range has to visit, in key order.
+    pub fn shards_for_range<R: RangeBounds<Bytes>>(
+        &self,
+        range: R,
+    ) -> Vec<(ShardIdentifier, KeyRange)> {
+        self.ranges()
+            .filter(|(_, shard_range)| {
+                let starts_before_end = match range.end_bound() {
+                    Bound::Included(end) => shard_range.start <= *end,
+                    Bound::Excluded(end) => shard_range.start < *end,
+                    Bound::Unbounded => true,
+                };
+                let ends_after_start = match (&shard_range.end, range.start_bound()) {
+                    (None, _) | (_, Bound::Unbounded) => true,
+                    (Some(shard_end), Bound::Included(start) | Bound::Excluded(start)) => {
+                        start < shard_end
+                    }
+                };
+
+                starts_before_end && ends_after_start
+            })
+            .collect()
+    }
+
+    /// Hands the keys of `shard` from `at` onwards over to `child`.
+    pub fn split(
+        &mut self,
+        shard: ShardIdentifier,
+        at: Bytes,
+        child: ShardIdentifier,
+    ) -> ShardResult<()> {
+        let range = self
+            .range_of(shard)
+            .ok_or(ShardError::UnknownShard(shard))?;
+
+        if self.range_of(child).is_some() {
+            return Err(ShardError::AlreadyPlaced(child));
+        }
+
+        if at <= range.start || !range.contains(&at) {
+            return Err(ShardError::InvalidBoundary(shard));
+        }
+
+        self.shards.insert(at, child);
+
+        Ok(())
+    }
+
+    /// Lets `left` take over the keys of the adjacent `right`, returning the
+    /// combined range.
+    pub fn merge(
+        &mut self,
+        left: ShardIdentifier,
+        right: ShardIdentifier,
+    ) -> ShardResult<KeyRange> {
+        let left_range = self.range_of(left).ok_or(ShardError::UnknownShard(left))?;
+        let right_range = self
+            .range_of(right)
+            .ok_or(ShardError::UnknownShard(right))?;
+
+        if left_range.end.as_ref() != Some(&right_range.start) {
+            return Err(ShardError::NotAdjacent(left, right));
+        }
+
+        self.shards.remove(&right_range.start);
+
+        Ok(KeyRange {
+            start: left_range.start,
+            end: right_range.end,
+        })
+    }
+
+    /// Splits and merges due according to the policy - every shard takes
+    /// part in at most one of them.
+    pub fn plan(
+        &self,
+        stats: &BTreeMap<ShardIdentifier, RangeStats>,
+        policy: &RangePolicy,
+    ) -> Vec<RangeAction> {
+        let mut actions = Vec::new();
+        let mut previous: Option<(ShardIdentifier, &RangeStats)> = None;
+
+        for (shard, range) in self.ranges() {
+            let Some(shard_stats) = stats.get(&shard) else {
+                previous = None;
+                continue;
+            };
+
+            if policy.should_split(shard_stats) {
+                if let Some(at) = shard_stats
+                    .middle_key
+                    .clone()
+                    .filter(|at| *at > range.start && range.contains(at))
+                {
+                    actions.push(RangeAction::Split { shard, at });
+                    previous = None;
+                    continue;
+                }
+            }
+
+            if let Some((left, left_stats)) = previous {
+                if policy.should_merge(left_stats, shard_stats) {
+                    actions.push(RangeAction::Merge { left, right: shard });
+                    previous = None;
+                    continue;
+                }
+            }
+
+            previous = Some((shard, shard_stats));
+        }
+
+        actions
+    }
+}
+
+impl Partitioner for RangeMap {
+    fn shard_for(&self, key: &[u8]) -> Option<ShardIdentifier> {
+        Some(RangeMap::shard_for(self, key))
+    }
+
+    fn shards_overlapping(&self, range: &KeyRange) -> Vec<(ShardIdentifier, KeyRange)> {
+        let end = match &range.end {
+            Some(end) => Bound::Excluded(end.clone()),
+            None => Bound::Unbounded,
+        };
+
+        self.shards_for_range((Bound::Included(range.start.clone()), end))
+    }
+}
+
+/// Shards are split once they hold more than `split_bytes` or have applied
+/// more than `split_writes` writes since the stats were last taken, while
+/// neighbours staying below both merge limits together are merged.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RangePolicy {
+    pub split_bytes: Option<u64>,
+    pub split_writes: Option<u64>,
+    pub merge_bytes: u64,
+    pub merge_writes: u64,
+}
+
+impl RangePolicy {
+    fn should_split(&self, stats: &RangeStats) -> bool {
+        self.split_bytes.is_some_and(|limit| stats.bytes > limit)
+            || self.split_writes.is_some_and(|limit| stats.writes > limit)
+    }
+
+    fn should_merge(&self, left: &RangeStats, right: &RangeStats) -> bool {
+        left.bytes + right.bytes <= self.merge_bytes
+            && left.writes + right.writes <= self.merge_writes
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RangeStats {
+    pub keys: u64,
+    pub bytes: u64,
+    pub writes: u64,
+    /// The key splitting the shard's data into two halves of roughly the
+    /// same size.
+    pub middle_key: Option<Bytes>,
+}
+
+/// Has to be committed through the log of the shards involved before the
+/// node can act on it, which `RangePlanner` takes care of - a split commits `Operation::Split` in the shard
+/// itself, a merge commits `Operation::Split` at its own start in the right
+/// shard and `Operation::Merge` in the left one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeAction {
+    Split {
+        shard: ShardIdentifier,
+        at: Bytes,
+    },
+    Merge {
+        left: ShardIdentifier,
+        right: ShardIdentifier,
+    },
+}
+
+fn decode_bytes(data: &mut &[u8]) -> StorageResult<Bytes> {
+    if data.remaining() < 4 {
+        return Err(malformed_range());
+    }
+
+    let length = data.get_u32() as usize;
+
+    if data.remaining() < length {
+        return Err(malformed_range());
+    }
+
+    let bytes = Bytes::copy_from_slice(&data[..length]);
+
+    data.advance(length);
+
+    Ok(bytes)
+}
+
+fn malformed_range() -> StorageError {
+    StorageError::CorruptionDetected("Malformed key range!".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use bytes::{Bytes, BytesMut};
+
+    use crate::shard::{ShardError, ShardIdentifier};
+
+    use super::{KeyRange, RangeAction, RangeMap, RangePolicy, RangeStats};
+
+    #[test]
+    pub fn ranges_are_split_merged_and_planned() {
+        let mut map = RangeMap::new(ShardIdentifier(1));
+
+        map.split(ShardIdentifier(1), "m".into(), ShardIdentifier(2))
+            .unwrap();
+        map.split(ShardIdentifier(2), "t".into(), ShardIdentifier(3))
+            .unwrap();
+
+        assert!(matches!(
+            map.split(ShardIdentifier(1), "p".into(), ShardIdentifier(4)),
+            Err(ShardError::InvalidBoundary(ShardIdentifier(1)))
+        ));
+        assert_eq!(map.shard_for("a"), ShardIdentifier(1));
+        assert_eq!(map.shard_for("m"), ShardIdentifier(2));
+        assert_eq!(map.shard_for("zzz"), ShardIdentifier(3));
+        assert_eq!(
+            map.shards_for_range(Bytes::from("n")..Bytes::from("p"))
+                .into_iter()
+                .map(|(shard, _)| shard)
+                .collect::<Vec<_>>(),
+            vec![ShardIdentifier(2)]
+        );
+        assert_eq!(map.shards_for_range(Bytes::from("b")..).len(), 3);
+
+        let stats = BTreeMap::from([
+            (
+                ShardIdentifier(1),
+                RangeStats {
+                    keys: 10,
+                    bytes: 1000,
+                    writes: 0,
+                    middle_key: Some("f".into()),
+                },
+            ),
+            (ShardIdentifier(2), RangeStats::default()),
+            (ShardIdentifier(3), RangeStats::default()),
+        ]);
+        let policy = RangePolicy {
+            split_bytes: Some(500),
+            split_writes: None,
+            merge_bytes: 100,
+            merge_writes: 10,
+        };
+
+        assert_eq!(
+            map.plan(&stats, &policy),
+            vec![
+                RangeAction::Split {
+                    shard: ShardIdentifier(1),
+                    at: "f".into(),
+                },
+                RangeAction::Merge {
+                    left: ShardIdentifier(2),
+                    right: ShardIdentifier(3),
+                },
+            ]
+        );
+
+        let merged = map.merge(ShardIdentifier(2), ShardIdentifier(3)).unwrap();
+
+        assert_eq!(merged, KeyRange::new("m", None));
+        assert_eq!(map.range_of(ShardIdentifier(2)), Some(merged.clone()));
+
+        let mut buffer = BytesMut::new();
+
+        merged.encode(&mut buffer);
+
+        assert_eq!(KeyRange::decode(&mut buffer.as_ref()).unwrap(), merged);
+    }
+}
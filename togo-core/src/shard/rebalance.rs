@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+
+use super::{
+    placement::{GroupIdentifier, Placement},
+    ShardIdentifier,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Move {
+    pub shard: ShardIdentifier,
+    pub from: GroupIdentifier,
+    pub to: GroupIdentifier,
+}
+
+/// Plans migrations evening out the load across groups - a group with no
+/// shards yet, e.g. one running on newly added machines, takes part as well
+/// as long as it's listed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rebalancer {
+    /// Load difference between the hottest and the coldest group that's
+    /// tolerated without moving anything.
+    pub tolerance: u64,
+    /// Migrations are expensive, so only this many are planned at once.
+    pub max_moves: usize,
+}
+
+impl Default for Rebalancer {
+    fn default() -> Self {
+        Self {
+            tolerance: 0,
+            max_moves: 4,
+        }
+    }
+}
+
+impl Rebalancer {
+    /// Repeatedly moves the shard that narrows the gap between the hottest
+    /// and the coldest group the most. Shards missing from `loads` count as
+    /// idle.
+    pub fn plan<G>(
+        &self,
+        placement: &Placement,
+        groups: G,
+        loads: &BTreeMap<ShardIdentifier, u64>,
+    ) -> Vec<Move>
+    where
+        G: IntoIterator<Item = GroupIdentifier>,
+    {
+        let mut placed = groups
+            .into_iter()
+            .map(|group| (group, BTreeMap::new()))
+            .collect::<BTreeMap<_, _>>();
+
+        for (shard, group) in placement.iter() {
+            let load = loads.get(&shard).copied().unwrap_or_default();
+
+            placed.entry(group).or_default().insert(shard, load);
+        }
+
+        let mut moves = Vec::new();
+
+        while moves.len() < self.max_moves {
+            let total = |shards: &BTreeMap<ShardIdentifier, u64>| shards.values().sum::<u64>();
+
+            let Some((hottest, hottest_load)) = placed
+                .iter()
+                .map(|(group, shards)| (*group, total(shards)))
+                .max_by_key(|(_, load)| *load)
+            else {
+                break;
+            };
+            let Some((coldest, coldest_load)) = placed
+                .iter()
+                .map(|(group, shards)| (*group, total(shards)))
+                .min_by_key(|(_, load)| *load)
+            else {
+                break;
+            };
+
+            let gap = hottest_load - coldest_load;
+
+            if gap <= self.tolerance {
+                break;
+            }
+
+            // moving a shard carrying less than the gap always narrows it,
+            // and it narrows it the most when its load is closest to half
+            let candidate = placed[&hottest]
+                .iter()
+                .filter(|(_, load)| **load > 0 && **load < gap)
+                .min_by_key(|(_, load)| load.abs_diff(gap / 2))
+                .map(|(shard, load)| (*shard, *load));
+
+            let Some((shard, load)) = candidate else {
+                break;
+            };
+
+            if let Some(shards) = placed.get_mut(&hottest) {
+                shards.remove(&shard);
+            }
+
+            placed.entry(coldest).or_default().insert(shard, load);
+
+            moves.push(Move {
+                shard,
+                from: hottest,
+                to: coldest,
+            });
+        }
+
+        moves
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::shard::{
+        placement::{GroupIdentifier, Placement},
+        ShardIdentifier,
+    };
+
+    use super::{Move, Rebalancer};
+
+    #[test]
+    pub fn hot_groups_hand_shards_over_to_new_ones() {
+        let mut placement = Placement::new();
+
+        for shard in 0..4 {
+            placement.assign(ShardIdentifier(shard), GroupIdentifier(1));
+        }
+
+        placement.assign(ShardIdentifier(4), GroupIdentifier(2));
+
+        let loads = BTreeMap::from([
+            (ShardIdentifier(0), 40),
+            (ShardIdentifier(1), 30),
+            (ShardIdentifier(2), 20),
+            (ShardIdentifier(3), 10),
+            (ShardIdentifier(4), 50),
+        ]);
+        let rebalancer = Rebalancer {
+            tolerance: 10,
+            max_moves: 4,
+        };
+
+        let moves = rebalancer.plan(&placement, [1, 2, 3].map(GroupIdentifier), &loads);
+
+        assert_eq!(
+            moves[0],
+            Move {
+                shard: ShardIdentifier(0),
+                from: GroupIdentifier(1),
+                to: GroupIdentifier(3),
+            }
+        );
+
+        for planned in &moves {
+            placement.assign(planned.shard, planned.to);
+        }
+
+        let group_loads = [1, 2, 3].map(|group| {
+            placement
+                .shards_of(GroupIdentifier(group))
+                .map(|shard| loads[&shard])
+                .sum::<u64>()
+        });
+
+        assert!(group_loads.iter().max().unwrap() - group_loads.iter().min().unwrap() <= 10);
+        assert!(rebalancer
+            .plan(&placement, [1, 2, 3].map(GroupIdentifier), &loads)
+            .is_empty());
+    }
+}
@@ -0,0 +1,234 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use super::{
+    snapshot::{self, SnapshotCompression, SnapshotHeader, SnapshotMetadata},
+    Snapshot, StorageError, StorageResult,
+};
+
+const CHECKPOINT_EXTENSION: &str = "snapshot";
+const TEMPORARY_EXTENSION: &str = "tmp";
+const PARTIAL_EXTENSION: &str = "partial";
+
+/// A directory of snapshots named after the op number they were taken at -
+/// only the newest `retained` ones are kept around.
+pub struct CheckpointStore {
+    directory: PathBuf,
+    cluster_id: u64,
+    retained: usize,
+    compression: SnapshotCompression,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckpointFile {
+    pub op_number: u64,
+    pub path: PathBuf,
+}
+
+impl CheckpointStore {
+    pub fn open<P: AsRef<Path>>(directory: P, cluster_id: u64) -> StorageResult<Self> {
+        let directory = directory.as_ref().to_path_buf();
+
+        fs::create_dir_all(&directory).map_err(StorageError::Io)?;
+
+        Ok(Self {
+            directory,
+            cluster_id,
+            retained: 2,
+            compression: SnapshotCompression::None,
+        })
+    }
+
+    pub fn with_retained(mut self, retained: usize) -> Self {
+        self.retained = retained.max(1);
+        self
+    }
+
+    pub fn with_compression(mut self, compression: SnapshotCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    pub fn save<S: Snapshot>(
+        &self,
+        storage: &S,
+        op_number: u64,
+        view: u64,
+    ) -> StorageResult<SnapshotMetadata> {
+        let path = self.checkpoint_path(op_number);
+        let temporary_path = path.with_extension(TEMPORARY_EXTENSION);
+        let mut writer = BufWriter::new(File::create(&temporary_path).map_err(StorageError::Io)?);
+
+        let header = SnapshotHeader {
+            compression: self.compression,
+            ..storage.snapshot_header(op_number, view, self.cluster_id)
+        };
+        let metadata = storage.save_snapshot(header, &mut writer)?;
+
+        writer
+            .into_inner()
+            .map_err(|error| StorageError::Io(error.into_error()))?
+            .sync_all()
+            .and_then(|_| fs::rename(&temporary_path, &path))
+            .map_err(StorageError::Io)?;
+
+        self.prune()?;
+
+        Ok(metadata)
+    }
+
+    pub fn latest(&self) -> StorageResult<Option<CheckpointFile>> {
+        Ok(self.list()?.pop())
+    }
+
+    /// Replaces the storage's contents with the checkpoint.
+    pub fn restore<S: Snapshot>(
+        &self,
+        storage: &mut S,
+        checkpoint: &CheckpointFile,
+    ) -> StorageResult<SnapshotMetadata> {
+        let file = File::open(&checkpoint.path).map_err(StorageError::Io)?;
+        let metadata = storage.apply_snapshot(BufReader::new(file))?;
+
+        if metadata.header.op_number != checkpoint.op_number {
+            return Err(StorageError::CorruptionDetected(format!(
+                "Checkpoint {} holds the state as of op {}!",
+                checkpoint.path.display(),
+                metadata.header.op_number
+            )));
+        }
+
+        Ok(metadata)
+    }
+
+    /// Reads up to `length` bytes of the checkpoint taken at `op_number`,
+    /// along with whether they reach the end of it.
+    pub fn read(
+        &self,
+        op_number: u64,
+        offset: u64,
+        length: usize,
+    ) -> StorageResult<(Vec<u8>, bool)> {
+        let mut file = match File::open(self.checkpoint_path(op_number)) {
+            Ok(file) => file,
+            Err(error) if error.kind() == ErrorKind::NotFound => {
+                return Err(StorageError::NotFound)
+            }
+            Err(error) => return Err(StorageError::Io(error)),
+        };
+
+        let size = file.metadata().map_err(StorageError::Io)?.len();
+        let mut data = Vec::with_capacity(length.min(size.saturating_sub(offset) as usize));
+
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.take(length as u64).read_to_end(&mut data))
+            .map_err(StorageError::Io)?;
+
+        let last = offset + data.len() as u64 >= size;
+
+        Ok((data, last))
+    }
+
+    /// Stages a part of a checkpoint received from another replica - parts
+    /// have to arrive in order, starting at offset 0.
+    pub fn receive(&self, op_number: u64, offset: u64, data: &[u8]) -> StorageResult<()> {
+        let path = self
+            .checkpoint_path(op_number)
+            .with_extension(PARTIAL_EXTENSION);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(StorageError::Io)?;
+
+        if offset == 0 {
+            file.set_len(0).map_err(StorageError::Io)?;
+        } else if file.metadata().map_err(StorageError::Io)?.len() != offset {
+            return Err(StorageError::CorruptionDetected(format!(
+                "Checkpoint {} is missing the data before offset {offset}!",
+                path.display()
+            )));
+        }
+
+        file.write_all(data).map_err(StorageError::Io)
+    }
+
+    /// Verifies a fully received checkpoint and moves it next to the local
+    /// ones.
+    pub fn finish_receive(&self, op_number: u64) -> StorageResult<CheckpointFile> {
+        let path = self.checkpoint_path(op_number);
+        let partial_path = path.with_extension(PARTIAL_EXTENSION);
+        let file = File::open(&partial_path).map_err(StorageError::Io)?;
+
+        if let Err(error) = snapshot::verify(BufReader::new(&file)) {
+            let _ = fs::remove_file(&partial_path);
+            return Err(error);
+        }
+
+        file.sync_all()
+            .and_then(|_| fs::rename(&partial_path, &path))
+            .map_err(StorageError::Io)?;
+
+        self.prune()?;
+
+        Ok(CheckpointFile { op_number, path })
+    }
+
+    /// Checks the checkpoint against its manifest without restoring it.
+    pub fn verify(&self, checkpoint: &CheckpointFile) -> StorageResult<SnapshotMetadata> {
+        let file = File::open(&checkpoint.path).map_err(StorageError::Io)?;
+
+        snapshot::verify(BufReader::new(file))
+    }
+
+    /// Checkpoints sorted from the oldest to the newest.
+    pub fn list(&self) -> StorageResult<Vec<CheckpointFile>> {
+        let mut checkpoints = Vec::new();
+
+        for entry in fs::read_dir(&self.directory).map_err(StorageError::Io)? {
+            let path = entry.map_err(StorageError::Io)?.path();
+
+            if path.extension().and_then(|extension| extension.to_str())
+                != Some(CHECKPOINT_EXTENSION)
+            {
+                continue;
+            }
+
+            let op_number = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok());
+
+            if let Some(op_number) = op_number {
+                checkpoints.push(CheckpointFile { op_number, path });
+            }
+        }
+
+        checkpoints.sort_by_key(|checkpoint| checkpoint.op_number);
+
+        Ok(checkpoints)
+    }
+
+    fn prune(&self) -> StorageResult<()> {
+        let checkpoints = self.list()?;
+        let obsolete = checkpoints.len().saturating_sub(self.retained);
+
+        for checkpoint in &checkpoints[..obsolete] {
+            fs::remove_file(&checkpoint.path).map_err(StorageError::Io)?;
+        }
+
+        Ok(())
+    }
+
+    fn checkpoint_path(&self, op_number: u64) -> PathBuf {
+        self.directory
+            .join(format!("{op_number:020}.{CHECKPOINT_EXTENSION}"))
+    }
+}
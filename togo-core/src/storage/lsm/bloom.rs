@@ -0,0 +1,111 @@
+use bytes::{Buf, BufMut, BytesMut};
+
+use crate::storage::{StorageError, StorageResult};
+
+pub(crate) struct BloomFilter {
+    hash_count: u32,
+    bits: Vec<u8>,
+}
+
+impl BloomFilter {
+    pub fn from_hashes(hashes: &[u64], bits_per_key: usize) -> Self {
+        // k = ln(2) * bits per key minimizes the false positive rate
+        let hash_count = ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30);
+        let bit_count = (hashes.len() * bits_per_key).max(64);
+        let mut bits = vec![0; bit_count.div_ceil(8)];
+
+        for hash in hashes {
+            for position in Self::positions(*hash, hash_count, bits.len() * 8) {
+                bits[position / 8] |= 1 << (position % 8);
+            }
+        }
+
+        Self { hash_count, bits }
+    }
+
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        Self::positions(hash_key(key), self.hash_count, self.bits.len() * 8)
+            .all(|position| self.bits[position / 8] & (1 << (position % 8)) != 0)
+    }
+
+    pub fn encode(&self, buffer: &mut BytesMut) {
+        buffer.put_u32(self.hash_count);
+        buffer.put_u32(self.bits.len() as u32);
+        buffer.put_slice(&self.bits);
+    }
+
+    pub fn decode(mut data: &[u8]) -> StorageResult<Self> {
+        if data.len() < 8 {
+            return Err(StorageError::CorruptionDetected(
+                "SSTable bloom filter is truncated!".into(),
+            ));
+        }
+
+        let hash_count = data.get_u32();
+        let length = data.get_u32() as usize;
+
+        if data.len() < length || length == 0 {
+            return Err(StorageError::CorruptionDetected(
+                "SSTable bloom filter is truncated!".into(),
+            ));
+        }
+
+        Ok(Self {
+            hash_count,
+            bits: data[..length].to_vec(),
+        })
+    }
+
+    // double hashing - the two halves of a single 64-bit hash are combined to
+    // derive the remaining probe positions
+    fn positions(hash: u64, hash_count: u32, bit_count: usize) -> impl Iterator<Item = usize> {
+        let delta = hash.rotate_right(32);
+
+        (0..hash_count as u64).map(move |probe| {
+            (hash.wrapping_add(probe.wrapping_mul(delta)) % bit_count as u64) as usize
+        })
+    }
+}
+
+/// FNV-1a followed by a murmur-style finalizer - stable across processes,
+/// which matters since filters are persisted alongside the tables.
+pub(crate) fn hash_key(key: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+
+    for byte in key {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use super::{hash_key, BloomFilter};
+
+    #[test]
+    pub fn contains_inserted_keys_and_rejects_most_others() {
+        let hashes = (0..1000u32)
+            .map(|key| hash_key(&key.to_be_bytes()))
+            .collect::<Vec<_>>();
+
+        let mut buffer = BytesMut::new();
+        BloomFilter::from_hashes(&hashes, 10).encode(&mut buffer);
+        let filter = BloomFilter::decode(&buffer).unwrap();
+
+        assert!((0..1000u32).all(|key| filter.may_contain(&key.to_be_bytes())));
+
+        let false_positives = (1000..11000u32)
+            .filter(|key| filter.may_contain(&key.to_be_bytes()))
+            .count();
+
+        assert!(false_positives < 300, "{false_positives} false positives");
+    }
+}
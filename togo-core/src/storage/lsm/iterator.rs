@@ -0,0 +1,56 @@
+use std::iter::Peekable;
+
+use bytes::Bytes;
+
+use crate::storage::StorageResult;
+
+use super::Entry;
+
+pub(crate) type EntryIterator<'a> = Box<dyn Iterator<Item = StorageResult<Entry>> + 'a>;
+
+/// Merges sorted sources into a single sorted stream, keeping only the entry
+/// from the newest source when several of them contain the same key.
+pub(crate) struct MergeIterator<'a> {
+    // ordered from the newest source to the oldest one
+    sources: Vec<Peekable<EntryIterator<'a>>>,
+}
+
+impl<'a> MergeIterator<'a> {
+    pub fn new(sources: Vec<EntryIterator<'a>>) -> Self {
+        Self {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+        }
+    }
+}
+
+impl<'a> Iterator for MergeIterator<'a> {
+    type Item = StorageResult<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut smallest: Option<(usize, Bytes)> = None;
+
+        for (index, source) in self.sources.iter_mut().enumerate() {
+            match source.peek() {
+                // ties are won by the earlier, newer source
+                Some(Ok((key, _)))
+                    if smallest.as_ref().is_none_or(|(_, smallest)| key < smallest) =>
+                {
+                    smallest = Some((index, key.clone()));
+                }
+                Some(Err(_)) => return source.next(),
+                _ => {}
+            }
+        }
+
+        let (index, key) = smallest?;
+        let entry = self.sources[index].next();
+
+        for source in self.sources.iter_mut().skip(index + 1) {
+            if matches!(source.peek(), Some(Ok((other, _))) if *other == key) {
+                source.next();
+            }
+        }
+
+        entry
+    }
+}
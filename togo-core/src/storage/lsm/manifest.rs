@@ -0,0 +1,111 @@
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::Path,
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+
+use crate::storage::{StorageError, StorageResult};
+
+const MANIFEST_FILE: &str = "MANIFEST";
+const MANIFEST_TEMPORARY_FILE: &str = "MANIFEST.tmp";
+const MANIFEST_MAGIC: u64 = 0x746f_676f_4d41_4e32; // "togoMAN2"
+
+/// Describes which tables make up each level - rewritten atomically whenever
+/// the set of live tables changes.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Manifest {
+    pub next_table_id: u64,
+    pub levels: Vec<Vec<u64>>,
+}
+
+impl Manifest {
+    pub fn load(directory: &Path) -> StorageResult<Option<Self>> {
+        let data = match fs::read(directory.join(MANIFEST_FILE)) {
+            Ok(data) => data,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(StorageError::Io(error)),
+        };
+
+        Self::decode(&data)
+            .map(Some)
+            .ok_or_else(|| StorageError::CorruptionDetected("LSM manifest is corrupted!".into()))
+    }
+
+    pub fn save(&self, directory: &Path) -> StorageResult<()> {
+        let temporary_path = directory.join(MANIFEST_TEMPORARY_FILE);
+        let mut file = File::create(&temporary_path).map_err(StorageError::Io)?;
+
+        file.write_all(&self.encode())
+            .and_then(|_| file.sync_all())
+            .and_then(|_| fs::rename(&temporary_path, directory.join(MANIFEST_FILE)))
+            .and_then(|_| sync_directory(directory))
+            .map_err(StorageError::Io)
+    }
+
+    fn encode(&self) -> BytesMut {
+        let mut buffer = BytesMut::new();
+
+        buffer.put_u64(MANIFEST_MAGIC);
+        buffer.put_u64(self.next_table_id);
+        buffer.put_u32(self.levels.len() as u32);
+
+        for level in &self.levels {
+            buffer.put_u32(level.len() as u32);
+
+            for table_id in level {
+                buffer.put_u64(*table_id);
+            }
+        }
+
+        let checksum = crc32fast::hash(&buffer);
+        buffer.put_u32(checksum);
+        buffer
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let (mut data, mut checksum) = data.split_at(data.len().checked_sub(4)?);
+
+        if crc32fast::hash(data) != checksum.get_u32() || data.remaining() < 20 {
+            return None;
+        }
+
+        if data.get_u64() != MANIFEST_MAGIC {
+            return None;
+        }
+
+        let next_table_id = data.get_u64();
+        let level_count = data.get_u32();
+        let mut levels = Vec::new();
+
+        for _ in 0..level_count {
+            if data.remaining() < 4 {
+                return None;
+            }
+
+            let table_count = data.get_u32() as usize;
+
+            if data.remaining() < table_count * 8 {
+                return None;
+            }
+
+            levels.push((0..table_count).map(|_| data.get_u64()).collect());
+        }
+
+        Some(Self {
+            next_table_id,
+            levels,
+        })
+    }
+}
+
+#[cfg(unix)]
+fn sync_directory(directory: &Path) -> std::io::Result<()> {
+    File::open(directory)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_directory(_directory: &Path) -> std::io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,647 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::ErrorKind,
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use self::{
+    iterator::{EntryIterator, MergeIterator},
+    manifest::Manifest,
+    sstable::{SsTable, SsTableWriter},
+};
+
+use super::{
+    snapshot::{SnapshotChunk, StorageBackend},
+    Delete, Flush, Get, Scan, ScanIterator, Snapshot, StorageError, StorageResult, Upsert,
+};
+
+mod bloom;
+mod iterator;
+mod manifest;
+mod sstable;
+
+pub(crate) type Entry = (Bytes, Option<Bytes>);
+
+const TABLE_EXTENSION: &str = "sst";
+
+// rough per-entry bookkeeping cost of the memtable
+const MEMTABLE_ENTRY_OVERHEAD: usize = 32;
+
+pub struct LsmOptions {
+    pub memtable_size: usize,
+    pub block_size: usize,
+    pub table_size: u64,
+    pub level0_table_limit: usize,
+    pub level1_size: u64,
+    pub level_size_multiplier: u64,
+    pub bloom_bits_per_key: usize,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        Self {
+            memtable_size: 4 * 1024 * 1024,
+            block_size: 4 * 1024,
+            table_size: 2 * 1024 * 1024,
+            level0_table_limit: 4,
+            level1_size: 10 * 1024 * 1024,
+            level_size_multiplier: 10,
+            bloom_bits_per_key: 10,
+        }
+    }
+}
+
+/// A log-structured merge tree with leveled compaction.
+///
+/// There's no write-ahead log - the replicated op log is the source of
+/// durability. Writes that haven't been flushed yet are lost on restart, and
+/// a flush triggered by the memtable's size may persist an operation only in
+/// part, so the storage isn't a recovery point of its own: it's rebuilt from
+/// the latest checkpoint on startup, and the op log is replayed past it.
+pub struct LsmStorage {
+    directory: PathBuf,
+    options: LsmOptions,
+    state: RwLock<LsmState>,
+}
+
+struct LsmState {
+    memtable: BTreeMap<Bytes, Option<Bytes>>,
+    memtable_size: usize,
+    // level 0 tables may overlap each other and are kept newest first, tables
+    // on deeper levels are sorted by key and never overlap
+    levels: Vec<Vec<Arc<SsTable>>>,
+    compaction_cursors: Vec<Option<Bytes>>,
+    next_table_id: u64,
+}
+
+impl LsmStorage {
+    pub fn open<P: AsRef<Path>>(directory: P, options: LsmOptions) -> StorageResult<Self> {
+        let directory = directory.as_ref().to_path_buf();
+
+        fs::create_dir_all(&directory).map_err(StorageError::Io)?;
+
+        let manifest = Manifest::load(&directory)?.unwrap_or_default();
+        let mut levels = vec![Vec::new()];
+
+        for (level, table_ids) in manifest.levels.iter().enumerate() {
+            if levels.len() <= level {
+                levels.push(Vec::new());
+            }
+
+            for table_id in table_ids {
+                let table = SsTable::open(&table_path(&directory, *table_id), *table_id)?;
+
+                levels[level].push(Arc::new(table));
+            }
+        }
+
+        remove_orphaned_tables(&directory, &manifest)?;
+
+        Ok(Self {
+            directory,
+            options,
+            state: RwLock::new(LsmState {
+                memtable: BTreeMap::new(),
+                memtable_size: 0,
+                compaction_cursors: vec![None; levels.len()],
+                levels,
+                next_table_id: manifest.next_table_id,
+            }),
+        })
+    }
+
+    fn read(&self) -> StorageResult<RwLockReadGuard<'_, LsmState>> {
+        self.state
+            .read()
+            .map_err(|_| StorageError::Unknown("LSM storage lock is poisoned!".into()))
+    }
+
+    fn write(&self) -> StorageResult<RwLockWriteGuard<'_, LsmState>> {
+        self.state
+            .write()
+            .map_err(|_| StorageError::Unknown("LSM storage lock is poisoned!".into()))
+    }
+
+    fn put(&self, key: &[u8], value: Option<&[u8]>) -> StorageResult<()> {
+        let mut state = self.write()?;
+
+        state.memtable_size += key.len() + value.map_or(0, <[u8]>::len) + MEMTABLE_ENTRY_OVERHEAD;
+        state.memtable.insert(
+            Bytes::copy_from_slice(key),
+            value.map(Bytes::copy_from_slice),
+        );
+
+        if state.memtable_size >= self.options.memtable_size {
+            self.flush_memtable(&mut state)?;
+        }
+
+        Ok(())
+    }
+
+    fn scan(&self, start: Bound<Bytes>, end: Bound<Bytes>) -> ScanIterator<'_, Bytes> {
+        let state = match self.read() {
+            Ok(state) => state,
+            Err(error) => return Box::new(std::iter::once(Err(error))),
+        };
+
+        if is_empty_range(&start, &end) {
+            return Box::new(std::iter::empty());
+        }
+
+        // the memtable is copied so that the lock isn't held by the iterator
+        let memtable = state
+            .memtable
+            .range((start.clone(), end.clone()))
+            .map(|(key, value)| Ok((key.clone(), value.clone())))
+            .collect::<Vec<_>>();
+
+        let mut sources: Vec<EntryIterator> = vec![Box::new(memtable.into_iter())];
+
+        for table in &state.levels[0] {
+            sources.push(Box::new(table.iter_from(start.clone())));
+        }
+
+        for level in &state.levels[1..] {
+            let tables = level.clone();
+            let start = start.clone();
+
+            sources.push(Box::new(
+                tables
+                    .into_iter()
+                    .flat_map(move |table| table.iter_from(start.clone())),
+            ));
+        }
+
+        Box::new(
+            MergeIterator::new(sources)
+                .take_while(move |entry| match entry {
+                    Ok((key, _)) => is_before_end(key, &end),
+                    Err(_) => true,
+                })
+                .filter_map(|entry| match entry {
+                    Ok((key, Some(value))) => Some(Ok((key, value))),
+                    Ok((_, None)) => None,
+                    Err(error) => Some(Err(error)),
+                }),
+        )
+    }
+
+    fn flush_memtable(&self, state: &mut LsmState) -> StorageResult<()> {
+        if state.memtable.is_empty() {
+            return Ok(());
+        }
+
+        let (id, mut writer) = self.new_table(state)?;
+
+        for (key, value) in &state.memtable {
+            writer.add(key, value)?;
+        }
+
+        let table = writer.finish(id, self.options.bloom_bits_per_key)?;
+
+        state.levels[0].insert(0, Arc::new(table));
+        state.memtable.clear();
+        state.memtable_size = 0;
+
+        let obsolete = self.compact(state)?;
+
+        self.save_manifest(state)?;
+        remove_tables(&obsolete)
+    }
+
+    fn compact(&self, state: &mut LsmState) -> StorageResult<Vec<Arc<SsTable>>> {
+        let mut obsolete = Vec::new();
+
+        loop {
+            let level = if state.levels[0].len() > self.options.level0_table_limit {
+                0
+            } else if let Some(level) = (1..state.levels.len())
+                .find(|level| level_size(&state.levels[*level]) > self.max_level_size(*level))
+            {
+                level
+            } else {
+                break;
+            };
+
+            obsolete.extend(self.compact_level(state, level)?);
+        }
+
+        Ok(obsolete)
+    }
+
+    // merges tables from `level` into the overlapping tables of the next one
+    fn compact_level(
+        &self,
+        state: &mut LsmState,
+        level: usize,
+    ) -> StorageResult<Vec<Arc<SsTable>>> {
+        if state.levels.len() <= level + 1 {
+            state.levels.push(Vec::new());
+            state.compaction_cursors.push(None);
+        }
+
+        let upper = match level {
+            0 => std::mem::take(&mut state.levels[0]),
+            _ => {
+                // deeper levels compact a single table at a time, round-robin
+                let tables = &state.levels[level];
+                let index = match &state.compaction_cursors[level] {
+                    Some(cursor) => tables
+                        .iter()
+                        .position(|table| table.first_key > *cursor)
+                        .unwrap_or(0),
+                    None => 0,
+                };
+
+                state.compaction_cursors[level] = Some(tables[index].last_key.clone());
+                vec![state.levels[level].remove(index)]
+            }
+        };
+
+        let first_key = upper.iter().map(|table| &table.first_key).min().cloned();
+        let last_key = upper.iter().map(|table| &table.last_key).max().cloned();
+        let (Some(first_key), Some(last_key)) = (first_key, last_key) else {
+            return Ok(Vec::new());
+        };
+
+        let (lower, untouched): (Vec<_>, Vec<_>) = std::mem::take(&mut state.levels[level + 1])
+            .into_iter()
+            .partition(|table| table.overlaps(&first_key, &last_key));
+
+        // nothing older can be shadowed by tombstones once they reach the bottom
+        let drop_tombstones = state.levels[level + 2..].iter().all(Vec::is_empty);
+
+        let mut sources: Vec<EntryIterator> = upper
+            .iter()
+            .map(|table| Box::new(table.iter_from(Bound::Unbounded)) as EntryIterator)
+            .collect();
+
+        sources.push(Box::new(
+            lower
+                .iter()
+                .flat_map(|table| table.iter_from(Bound::Unbounded)),
+        ));
+
+        let mut output = untouched;
+        let mut writer = None;
+
+        for entry in MergeIterator::new(sources) {
+            let (key, value) = entry?;
+
+            if drop_tombstones && value.is_none() {
+                continue;
+            }
+
+            let (_, table_writer) = match &mut writer {
+                Some(writer) => writer,
+                None => writer.insert(self.new_table(state)?),
+            };
+
+            table_writer.add(&key, &value)?;
+
+            if table_writer.estimated_size() >= self.options.table_size {
+                let (id, table_writer) = writer.take().unwrap();
+
+                output.push(Arc::new(
+                    table_writer.finish(id, self.options.bloom_bits_per_key)?,
+                ));
+            }
+        }
+
+        if let Some((id, table_writer)) = writer {
+            if !table_writer.is_empty() {
+                output.push(Arc::new(
+                    table_writer.finish(id, self.options.bloom_bits_per_key)?,
+                ));
+            }
+        }
+
+        output.sort_by(|left, right| left.first_key.cmp(&right.first_key));
+        state.levels[level + 1] = output;
+
+        Ok(upper.into_iter().chain(lower).collect())
+    }
+
+    fn new_table(&self, state: &mut LsmState) -> StorageResult<(u64, SsTableWriter)> {
+        let id = state.next_table_id;
+        state.next_table_id += 1;
+
+        SsTableWriter::create(&table_path(&self.directory, id), self.options.block_size)
+            .map(|writer| (id, writer))
+    }
+
+    fn max_level_size(&self, level: usize) -> u64 {
+        (1..level).fold(self.options.level1_size, |size, _| {
+            size.saturating_mul(self.options.level_size_multiplier)
+        })
+    }
+
+    fn save_manifest(&self, state: &LsmState) -> StorageResult<()> {
+        Manifest {
+            next_table_id: state.next_table_id,
+            levels: state
+                .levels
+                .iter()
+                .map(|level| level.iter().map(|table| table.id).collect())
+                .collect(),
+        }
+        .save(&self.directory)
+    }
+}
+
+impl Get for LsmStorage {
+    type ReturnValue = Bytes;
+
+    fn get<K>(&self, key: K) -> StorageResult<Option<Self::ReturnValue>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let key = key.as_ref();
+        let state = self.read()?;
+
+        if let Some(value) = state.memtable.get(key) {
+            return Ok(value.clone());
+        }
+
+        for table in &state.levels[0] {
+            if let Some(value) = table.get(key)? {
+                return Ok(value);
+            }
+        }
+
+        for level in &state.levels[1..] {
+            let index = level.partition_point(|table| table.last_key.as_ref() < key);
+
+            if let Some(Some(value)) = level.get(index).map(|table| table.get(key)).transpose()? {
+                return Ok(value);
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl Upsert for LsmStorage {
+    fn upsert<K, V>(&self, key: K, value: V) -> StorageResult<()>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.put(key.as_ref(), Some(value.as_ref()))
+    }
+}
+
+impl Delete for LsmStorage {
+    fn delete<K>(&self, key: K) -> StorageResult<()>
+    where
+        K: AsRef<[u8]>,
+    {
+        self.put(key.as_ref(), None)
+    }
+}
+
+impl Scan for LsmStorage {
+    fn scan_prefix<P>(&self, prefix: P) -> ScanIterator<'_, Self::ReturnValue>
+    where
+        P: AsRef<[u8]>,
+    {
+        let prefix = prefix.as_ref();
+
+        self.scan(
+            Bound::Included(Bytes::copy_from_slice(prefix)),
+            prefix_end(prefix),
+        )
+    }
+
+    fn scan_range<R>(&self, range: R) -> ScanIterator<'_, Self::ReturnValue>
+    where
+        R: RangeBounds<Bytes>,
+    {
+        self.scan(range.start_bound().cloned(), range.end_bound().cloned())
+    }
+}
+
+#[async_trait]
+impl Flush for LsmStorage {
+    async fn flush(&self) -> StorageResult<()> {
+        let mut state = self.write()?;
+
+        self.flush_memtable(&mut state)
+    }
+}
+
+impl Snapshot for LsmStorage {
+    fn backend(&self) -> StorageBackend {
+        StorageBackend::Lsm
+    }
+
+    fn clear(&mut self) -> StorageResult<()> {
+        let mut state = self.write()?;
+        let obsolete = state
+            .levels
+            .iter_mut()
+            .flat_map(std::mem::take)
+            .collect::<Vec<_>>();
+
+        state.memtable.clear();
+        state.memtable_size = 0;
+
+        self.save_manifest(&state)?;
+        remove_tables(&obsolete)
+    }
+
+    fn apply_chunk(&mut self, chunk: SnapshotChunk) -> StorageResult<()> {
+        for (key, value) in chunk.entries {
+            self.put(&key, Some(&value))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn table_path(directory: &Path, id: u64) -> PathBuf {
+    directory.join(format!("{id:016}.{TABLE_EXTENSION}"))
+}
+
+fn level_size(tables: &[Arc<SsTable>]) -> u64 {
+    tables.iter().map(|table| table.size).sum()
+}
+
+fn remove_tables(tables: &[Arc<SsTable>]) -> StorageResult<()> {
+    for table in tables {
+        match fs::remove_file(table.path()) {
+            Err(error) if error.kind() != ErrorKind::NotFound => {
+                return Err(StorageError::Io(error))
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+// tables written by a flush or compaction that didn't make it into the
+// manifest before a crash
+fn remove_orphaned_tables(directory: &Path, manifest: &Manifest) -> StorageResult<()> {
+    for entry in fs::read_dir(directory).map_err(StorageError::Io)? {
+        let path = entry.map_err(StorageError::Io)?.path();
+
+        if path.extension().and_then(|extension| extension.to_str()) != Some(TABLE_EXTENSION) {
+            continue;
+        }
+
+        let id = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<u64>().ok());
+
+        let is_live = id.is_some_and(|id| manifest.levels.iter().any(|level| level.contains(&id)));
+
+        if !is_live {
+            fs::remove_file(&path).map_err(StorageError::Io)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn prefix_end(prefix: &[u8]) -> Bound<Bytes> {
+    let mut end = prefix.to_vec();
+
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Bound::Excluded(end.into());
+        }
+    }
+
+    Bound::Unbounded
+}
+
+fn is_before_end(key: &Bytes, end: &Bound<Bytes>) -> bool {
+    match end {
+        Bound::Included(end) => key <= end,
+        Bound::Excluded(end) => key < end,
+        Bound::Unbounded => true,
+    }
+}
+
+fn is_empty_range(start: &Bound<Bytes>, end: &Bound<Bytes>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use bytes::Bytes;
+    use futures::executor::block_on;
+
+    use crate::storage::{Delete, Flush, Get, Scan, Upsert};
+
+    use super::{LsmOptions, LsmStorage};
+
+    fn temporary_directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("togo-lsm-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+
+        directory
+    }
+
+    fn small_options() -> LsmOptions {
+        LsmOptions {
+            memtable_size: 2 * 1024,
+            block_size: 256,
+            table_size: 4 * 1024,
+            level0_table_limit: 2,
+            level1_size: 8 * 1024,
+            level_size_multiplier: 4,
+            bloom_bits_per_key: 10,
+        }
+    }
+
+    fn key(index: u32) -> Bytes {
+        format!("key-{index:06}").into()
+    }
+
+    #[test]
+    pub fn reads_survive_flushes_compactions_and_reopening() {
+        let directory = temporary_directory("reopen");
+        let storage = LsmStorage::open(&directory, small_options()).unwrap();
+
+        for round in 0..4u32 {
+            for index in 0..500 {
+                storage.upsert(key(index), format!("{round}")).unwrap();
+            }
+        }
+
+        for index in (0..500).step_by(3) {
+            storage.delete(key(index)).unwrap();
+        }
+
+        block_on(storage.flush()).unwrap();
+        drop(storage);
+
+        let storage = LsmStorage::open(&directory, small_options()).unwrap();
+
+        assert_eq!(storage.get(key(0)).unwrap(), None);
+        assert_eq!(storage.get(key(1)).unwrap().unwrap(), Bytes::from("3"));
+        assert_eq!(storage.get(key(1000)).unwrap(), None);
+
+        let keys = storage
+            .scan_prefix("key-0001")
+            .map(|entry| entry.unwrap().0)
+            .collect::<Vec<_>>();
+
+        let expected = (100..200)
+            .filter(|index| index % 3 != 0)
+            .map(key)
+            .collect::<Vec<_>>();
+
+        assert_eq!(keys, expected);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    pub fn newer_writes_shadow_older_tables_in_scans() {
+        let directory = temporary_directory("shadow");
+        let storage = LsmStorage::open(&directory, small_options()).unwrap();
+
+        storage.upsert("a", "old").unwrap();
+        storage.upsert("b", "old").unwrap();
+        storage.upsert("c", "old").unwrap();
+        block_on(storage.flush()).unwrap();
+
+        storage.upsert("b", "new").unwrap();
+        storage.delete("c").unwrap();
+
+        let entries = storage
+            .scan_range(Bytes::from("a")..)
+            .map(|entry| entry.unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            entries,
+            vec![
+                (Bytes::from("a"), Bytes::from("old")),
+                (Bytes::from("b"), Bytes::from("new")),
+            ]
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
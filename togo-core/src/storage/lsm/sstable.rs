@@ -0,0 +1,428 @@
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::storage::{StorageError, StorageResult};
+
+use super::{
+    bloom::{hash_key, BloomFilter},
+    Entry,
+};
+
+const FOOTER_SIZE: usize = 48;
+const TABLE_MAGIC: u64 = 0x746f_676f_5353_5431; // "togoSST1"
+
+const VALUE_KIND: u8 = 0;
+const TOMBSTONE_KIND: u8 = 1;
+
+struct BlockHandle {
+    first_key: Bytes,
+    last_key: Bytes,
+    offset: u64,
+    size: u32,
+}
+
+/// An immutable, sorted table laid out as
+/// `[data block]* [index block] [bloom block] [footer]`, every block being
+/// followed by its CRC32.
+pub(crate) struct SsTable {
+    pub id: u64,
+    pub first_key: Bytes,
+    pub last_key: Bytes,
+    pub size: u64,
+    path: PathBuf,
+    file: Mutex<File>,
+    index: Vec<BlockHandle>,
+    bloom: BloomFilter,
+}
+
+impl SsTable {
+    pub fn open(path: &Path, id: u64) -> StorageResult<Self> {
+        let mut file = File::open(path).map_err(StorageError::Io)?;
+        let size = file.metadata().map_err(StorageError::Io)?.len();
+
+        if size < FOOTER_SIZE as u64 {
+            return Err(corrupted(path, "file is shorter than its footer"));
+        }
+
+        let mut footer = [0; FOOTER_SIZE];
+        file.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))
+            .and_then(|_| file.read_exact(&mut footer))
+            .map_err(StorageError::Io)?;
+
+        let mut footer = &footer[..];
+        let index_offset = footer.get_u64();
+        let index_size = footer.get_u64();
+        let bloom_offset = footer.get_u64();
+        let bloom_size = footer.get_u64();
+        let _entry_count = footer.get_u64();
+
+        if footer.get_u64() != TABLE_MAGIC {
+            return Err(corrupted(path, "invalid magic number"));
+        }
+
+        let index = read_checked(&mut file, path, index_offset, index_size)?;
+        let bloom = read_checked(&mut file, path, bloom_offset, bloom_size)?;
+
+        let index = decode_index(&index).ok_or_else(|| corrupted(path, "malformed index"))?;
+        let bloom = BloomFilter::decode(&bloom)?;
+
+        let (first_key, last_key) = match (index.first(), index.last()) {
+            (Some(first), Some(last)) => (first.first_key.clone(), last.last_key.clone()),
+            _ => return Err(corrupted(path, "table has no blocks")),
+        };
+
+        Ok(Self {
+            id,
+            first_key,
+            last_key,
+            size,
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+            index,
+            bloom,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn overlaps(&self, first_key: &[u8], last_key: &[u8]) -> bool {
+        self.first_key.as_ref() <= last_key && first_key <= self.last_key.as_ref()
+    }
+
+    /// `Some(None)` means the key was deleted by this table's tombstone.
+    pub fn get(&self, key: &[u8]) -> StorageResult<Option<Option<Bytes>>> {
+        if key < self.first_key.as_ref() || key > self.last_key.as_ref() {
+            return Ok(None);
+        }
+
+        if !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+
+        let block_index = self
+            .index
+            .partition_point(|handle| handle.last_key.as_ref() < key);
+
+        if block_index == self.index.len() {
+            return Ok(None);
+        }
+
+        Ok(self
+            .read_block(block_index)?
+            .into_iter()
+            .find(|(entry_key, _)| entry_key.as_ref() == key)
+            .map(|(_, value)| value))
+    }
+
+    pub fn iter_from(self: &Arc<Self>, start: Bound<Bytes>) -> SsTableIterator {
+        let block_index = match &start {
+            Bound::Included(key) | Bound::Excluded(key) => {
+                self.index.partition_point(|handle| handle.last_key < *key)
+            }
+            Bound::Unbounded => 0,
+        };
+
+        SsTableIterator {
+            table: self.clone(),
+            next_block: block_index,
+            entries: VecDeque::new(),
+            start,
+        }
+    }
+
+    fn read_block(&self, block_index: usize) -> StorageResult<Vec<Entry>> {
+        let handle = &self.index[block_index];
+        let data = {
+            let mut file = self
+                .file
+                .lock()
+                .map_err(|_| StorageError::Unknown("SSTable file lock is poisoned!".into()))?;
+
+            read_checked(&mut file, &self.path, handle.offset, handle.size as u64)?
+        };
+
+        decode_block(data).ok_or_else(|| corrupted(&self.path, "malformed data block"))
+    }
+}
+
+pub(crate) struct SsTableIterator {
+    table: Arc<SsTable>,
+    next_block: usize,
+    entries: VecDeque<Entry>,
+    start: Bound<Bytes>,
+}
+
+impl Iterator for SsTableIterator {
+    type Item = StorageResult<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.entries.is_empty() {
+            if self.next_block >= self.table.index.len() {
+                return None;
+            }
+
+            match self.table.read_block(self.next_block) {
+                Ok(entries) => self.entries = entries.into(),
+                Err(error) => {
+                    self.next_block = self.table.index.len();
+                    return Some(Err(error));
+                }
+            }
+
+            self.next_block += 1;
+
+            let start = std::mem::replace(&mut self.start, Bound::Unbounded);
+            self.entries.retain(|(key, _)| match &start {
+                Bound::Included(start) => key >= start,
+                Bound::Excluded(start) => key > start,
+                Bound::Unbounded => true,
+            });
+        }
+
+        self.entries.pop_front().map(Ok)
+    }
+}
+
+pub(crate) struct SsTableWriter {
+    path: PathBuf,
+    file: BufWriter<File>,
+    block_size: usize,
+    block: BytesMut,
+    block_first_key: Option<Bytes>,
+    last_key: Option<Bytes>,
+    index: Vec<BlockHandle>,
+    hashes: Vec<u64>,
+    offset: u64,
+}
+
+impl SsTableWriter {
+    pub fn create(path: &Path, block_size: usize) -> StorageResult<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(StorageError::Io)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: BufWriter::new(file),
+            block_size,
+            block: BytesMut::with_capacity(block_size),
+            block_first_key: None,
+            last_key: None,
+            index: Vec::new(),
+            hashes: Vec::new(),
+            offset: 0,
+        })
+    }
+
+    /// Entries have to be added in strictly ascending key order.
+    pub fn add(&mut self, key: &Bytes, value: &Option<Bytes>) -> StorageResult<()> {
+        debug_assert!(self.last_key.as_ref().is_none_or(|last| last < key));
+
+        self.block.put_u32(key.len() as u32);
+        self.block.put_slice(key);
+
+        match value {
+            Some(value) => {
+                self.block.put_u8(VALUE_KIND);
+                self.block.put_u32(value.len() as u32);
+                self.block.put_slice(value);
+            }
+            None => {
+                self.block.put_u8(TOMBSTONE_KIND);
+                self.block.put_u32(0);
+            }
+        }
+
+        self.hashes.push(hash_key(key));
+        self.block_first_key.get_or_insert_with(|| key.clone());
+        self.last_key = Some(key.clone());
+
+        if self.block.len() >= self.block_size {
+            self.finish_block()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    pub fn estimated_size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    pub fn finish(mut self, id: u64, bloom_bits_per_key: usize) -> StorageResult<SsTable> {
+        self.finish_block()?;
+
+        let mut index = BytesMut::new();
+
+        for handle in &self.index {
+            index.put_u32(handle.first_key.len() as u32);
+            index.put_slice(&handle.first_key);
+            index.put_u32(handle.last_key.len() as u32);
+            index.put_slice(&handle.last_key);
+            index.put_u64(handle.offset);
+            index.put_u32(handle.size);
+        }
+
+        let mut bloom = BytesMut::new();
+        BloomFilter::from_hashes(&self.hashes, bloom_bits_per_key).encode(&mut bloom);
+
+        let index_offset = self.offset;
+        let index_size = self.write_checked(&index)?;
+        let bloom_offset = self.offset;
+        let bloom_size = self.write_checked(&bloom)?;
+
+        let mut footer = BytesMut::with_capacity(FOOTER_SIZE);
+        footer.put_u64(index_offset);
+        footer.put_u64(index_size);
+        footer.put_u64(bloom_offset);
+        footer.put_u64(bloom_size);
+        footer.put_u64(self.hashes.len() as u64);
+        footer.put_u64(TABLE_MAGIC);
+
+        self.file.write_all(&footer).map_err(StorageError::Io)?;
+        self.file
+            .into_inner()
+            .map_err(|error| StorageError::Io(error.into_error()))?
+            .sync_all()
+            .map_err(StorageError::Io)?;
+
+        SsTable::open(&self.path, id)
+    }
+
+    fn finish_block(&mut self) -> StorageResult<()> {
+        let (Some(first_key), Some(last_key)) =
+            (self.block_first_key.take(), self.last_key.clone())
+        else {
+            return Ok(());
+        };
+
+        let block = std::mem::replace(&mut self.block, BytesMut::with_capacity(self.block_size));
+        let offset = self.offset;
+        let size = self.write_checked(&block)?;
+
+        self.index.push(BlockHandle {
+            first_key,
+            last_key,
+            offset,
+            size: size as u32,
+        });
+
+        Ok(())
+    }
+
+    fn write_checked(&mut self, data: &[u8]) -> StorageResult<u64> {
+        self.file.write_all(data).map_err(StorageError::Io)?;
+        self.file
+            .write_all(&crc32fast::hash(data).to_be_bytes())
+            .map_err(StorageError::Io)?;
+
+        let size = data.len() as u64 + 4;
+        self.offset += size;
+
+        Ok(size)
+    }
+}
+
+fn read_checked(file: &mut File, path: &Path, offset: u64, size: u64) -> StorageResult<Bytes> {
+    if size < 4 {
+        return Err(corrupted(path, "block is shorter than its checksum"));
+    }
+
+    let mut data = vec![0; size as usize];
+
+    file.seek(SeekFrom::Start(offset))
+        .and_then(|_| file.read_exact(&mut data))
+        .map_err(StorageError::Io)?;
+
+    let checksum = data.split_off(data.len() - 4);
+
+    if crc32fast::hash(&data).to_be_bytes()[..] != checksum[..] {
+        return Err(corrupted(path, "block checksum mismatch"));
+    }
+
+    Ok(data.into())
+}
+
+fn decode_block(mut data: Bytes) -> Option<Vec<Entry>> {
+    let mut entries = Vec::new();
+
+    while data.has_remaining() {
+        let key = take_prefixed(&mut data)?;
+
+        if data.remaining() < 5 {
+            return None;
+        }
+
+        let kind = data.get_u8();
+        let length = data.get_u32() as usize;
+
+        if data.remaining() < length {
+            return None;
+        }
+
+        let value = data.split_to(length);
+
+        match kind {
+            VALUE_KIND => entries.push((key, Some(value))),
+            TOMBSTONE_KIND => entries.push((key, None)),
+            _ => return None,
+        }
+    }
+
+    Some(entries)
+}
+
+fn decode_index(data: &Bytes) -> Option<Vec<BlockHandle>> {
+    let mut data = data.clone();
+    let mut index = Vec::new();
+
+    while data.has_remaining() {
+        let first_key = take_prefixed(&mut data)?;
+        let last_key = take_prefixed(&mut data)?;
+
+        if data.remaining() < 12 {
+            return None;
+        }
+
+        index.push(BlockHandle {
+            first_key,
+            last_key,
+            offset: data.get_u64(),
+            size: data.get_u32(),
+        });
+    }
+
+    Some(index)
+}
+
+fn take_prefixed(data: &mut Bytes) -> Option<Bytes> {
+    if data.remaining() < 4 {
+        return None;
+    }
+
+    let length = data.get_u32() as usize;
+
+    (data.remaining() >= length).then(|| data.split_to(length))
+}
+
+fn corrupted(path: &Path, reason: &str) -> StorageError {
+    StorageError::CorruptionDetected(format!(
+        "SSTable {} is corrupted: {reason}!",
+        path.display()
+    ))
+}
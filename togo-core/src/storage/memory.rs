@@ -0,0 +1,195 @@
+use std::{
+    collections::BTreeMap,
+    ops::{Bound, RangeBounds},
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use super::{
+    snapshot::{SnapshotChunk, StorageBackend},
+    Delete, Flush, Get, Scan, ScanIterator, Snapshot, StorageError, StorageResult, Upsert,
+};
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    data: RwLock<BTreeMap<Bytes, Bytes>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn read(&self) -> StorageResult<RwLockReadGuard<'_, BTreeMap<Bytes, Bytes>>> {
+        self.data
+            .read()
+            .map_err(|_| StorageError::Unknown("Memory storage lock is poisoned!".into()))
+    }
+
+    fn write(&self) -> StorageResult<RwLockWriteGuard<'_, BTreeMap<Bytes, Bytes>>> {
+        self.data
+            .write()
+            .map_err(|_| StorageError::Unknown("Memory storage lock is poisoned!".into()))
+    }
+}
+
+impl Get for MemoryStorage {
+    type ReturnValue = Bytes;
+
+    fn get<K>(&self, key: K) -> StorageResult<Option<Self::ReturnValue>>
+    where
+        K: AsRef<[u8]>,
+    {
+        Ok(self.read()?.get(key.as_ref()).cloned())
+    }
+}
+
+impl Upsert for MemoryStorage {
+    fn upsert<K, V>(&self, key: K, value: V) -> StorageResult<()>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.write()?.insert(
+            Bytes::copy_from_slice(key.as_ref()),
+            Bytes::copy_from_slice(value.as_ref()),
+        );
+
+        Ok(())
+    }
+}
+
+impl Delete for MemoryStorage {
+    fn delete<K>(&self, key: K) -> StorageResult<()>
+    where
+        K: AsRef<[u8]>,
+    {
+        self.write()?.remove(key.as_ref());
+
+        Ok(())
+    }
+}
+
+// the lock can't be held across the returned iterator, so matching entries are
+// cloned upfront - cheap, since `Bytes` clones share the underlying buffer
+impl Scan for MemoryStorage {
+    fn scan_prefix<P>(&self, prefix: P) -> ScanIterator<'_, Self::ReturnValue>
+    where
+        P: AsRef<[u8]>,
+    {
+        let prefix = prefix.as_ref();
+        let entries = self.read().map(|data| {
+            data.range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+                .take_while(|(key, _)| key.starts_with(prefix))
+                .map(|(key, value)| Ok((key.clone(), value.clone())))
+                .collect::<Vec<_>>()
+        });
+
+        match entries {
+            Ok(entries) => Box::new(entries.into_iter()),
+            Err(error) => Box::new(std::iter::once(Err(error))),
+        }
+    }
+
+    fn scan_range<R>(&self, range: R) -> ScanIterator<'_, Self::ReturnValue>
+    where
+        R: RangeBounds<Bytes>,
+    {
+        let entries = self.read().map(|data| {
+            data.range(range)
+                .map(|(key, value)| Ok((key.clone(), value.clone())))
+                .collect::<Vec<_>>()
+        });
+
+        match entries {
+            Ok(entries) => Box::new(entries.into_iter()),
+            Err(error) => Box::new(std::iter::once(Err(error))),
+        }
+    }
+}
+
+#[async_trait]
+impl Flush for MemoryStorage {
+    async fn flush(&self) -> StorageResult<()> {
+        Ok(())
+    }
+}
+
+impl Snapshot for MemoryStorage {
+    fn backend(&self) -> StorageBackend {
+        StorageBackend::Memory
+    }
+
+    fn clear(&mut self) -> StorageResult<()> {
+        self.write()?.clear();
+
+        Ok(())
+    }
+
+    fn apply_chunk(&mut self, chunk: SnapshotChunk) -> StorageResult<()> {
+        self.write()?.extend(
+            chunk
+                .entries
+                .into_iter()
+                .map(|(key, value)| (key.into(), value.into())),
+        );
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::storage::{
+        sled::SledStorage, snapshot::StorageBackend, Get, Scan, Snapshot, Upsert,
+    };
+
+    use super::MemoryStorage;
+
+    #[test]
+    pub fn scan_prefix_stops_at_prefix_boundary() {
+        let storage = MemoryStorage::new();
+
+        storage.upsert("a", "0").unwrap();
+        storage.upsert("ab", "1").unwrap();
+        storage.upsert("abc", "2").unwrap();
+        storage.upsert("b", "3").unwrap();
+
+        let keys = storage
+            .scan_prefix("ab")
+            .map(|entry| entry.unwrap().0)
+            .collect::<Vec<_>>();
+
+        assert_eq!(keys, vec![Bytes::from("ab"), Bytes::from("abc")]);
+    }
+
+    #[test]
+    pub fn snapshot_can_be_applied_to_sled_storage() {
+        let storage = MemoryStorage::new();
+        let mut snapshot = Vec::new();
+
+        storage.upsert("key", "value").unwrap();
+        storage.upsert("other", "value2").unwrap();
+        let header = storage.snapshot_header(2, 1, 7);
+        storage.save_snapshot(header, &mut snapshot).unwrap();
+
+        let mut sled_storage: SledStorage =
+            sled::Config::new().temporary(true).open().unwrap().into();
+        sled_storage.upsert("stale", "value").unwrap();
+        let metadata = sled_storage.apply_snapshot(&snapshot[..]).unwrap();
+
+        let mut restored = MemoryStorage::new();
+        restored.apply_snapshot(&snapshot[..]).unwrap();
+
+        assert_eq!(metadata.header.op_number, 2);
+        assert_eq!(metadata.header.backend, StorageBackend::Memory);
+        assert_eq!(metadata.manifest.key_count, 2);
+        assert_eq!(sled_storage.get("other").unwrap().unwrap(), b"value2");
+        assert_eq!(sled_storage.get("stale").unwrap(), None);
+        assert_eq!(restored.get("key").unwrap().unwrap(), Bytes::from("value"));
+    }
+}
@@ -1,12 +1,27 @@
-use std::path::Path;
+use std::{
+    io::{Read, Write},
+    ops::RangeBounds,
+};
 
 use async_trait::async_trait;
+use bytes::Bytes;
 use savefile::SavefileError;
 use thiserror::Error;
+use togo_vr::codec::CodecError;
 
+pub mod checkpoint;
+pub mod lsm;
+pub mod memory;
 pub mod sled;
+pub mod snapshot;
+
+use snapshot::{
+    archive, SnapshotChunk, SnapshotChunks, SnapshotCompression, SnapshotHeader, SnapshotMetadata,
+    SnapshotReader, StorageBackend, DEFAULT_CHUNK_SIZE,
+};
 
 pub type StorageResult<T> = Result<T, StorageError>;
+pub type ScanIterator<'a, V> = Box<dyn Iterator<Item = StorageResult<(V, V)>> + 'a>;
 
 pub trait Get {
     type ReturnValue: AsRef<[u8]>;
@@ -23,15 +38,80 @@ pub trait Upsert {
         V: AsRef<[u8]>;
 }
 
+pub trait Delete {
+    fn delete<K>(&self, key: K) -> StorageResult<()>
+    where
+        K: AsRef<[u8]>;
+}
+
+pub trait Scan: Get {
+    fn scan_prefix<P>(&self, prefix: P) -> ScanIterator<'_, Self::ReturnValue>
+    where
+        P: AsRef<[u8]>;
+
+    fn scan_range<R>(&self, range: R) -> ScanIterator<'_, Self::ReturnValue>
+    where
+        R: RangeBounds<Bytes>;
+}
+
 #[async_trait]
 pub trait Flush {
     async fn flush(&self) -> StorageResult<()>;
 }
 
 #[async_trait]
-pub trait Snapshot {
-    fn save_snapshot(&self, path: &Path) -> StorageResult<()>;
-    fn apply_snapshot(&mut self, path: &Path) -> StorageResult<()>;
+pub trait Snapshot: Scan {
+    fn backend(&self) -> StorageBackend;
+    /// Drops all of the contents in preparation for restoring a snapshot.
+    fn clear(&mut self) -> StorageResult<()>;
+    fn apply_chunk(&mut self, chunk: SnapshotChunk) -> StorageResult<()>;
+
+    fn snapshot_chunks(&self, chunk_size: usize) -> SnapshotChunks<'_, Self::ReturnValue> {
+        SnapshotChunks::new(self.scan_range::<std::ops::RangeFull>(..), chunk_size)
+    }
+
+    fn snapshot_header(&self, op_number: u64, view: u64, cluster_id: u64) -> SnapshotHeader {
+        SnapshotHeader {
+            op_number,
+            view,
+            cluster_id,
+            backend: self.backend(),
+            compression: SnapshotCompression::None,
+        }
+    }
+
+    fn save_snapshot<W: Write>(
+        &self,
+        header: SnapshotHeader,
+        writer: W,
+    ) -> StorageResult<SnapshotMetadata> {
+        snapshot::write_snapshot(writer, header, self.snapshot_chunks(DEFAULT_CHUNK_SIZE))
+    }
+
+    /// Saves an image that can be read in place - see
+    /// [`archive::MappedSnapshot`].
+    fn save_image<W: Write>(&self, header: &SnapshotHeader, writer: W) -> StorageResult<()> {
+        archive::write_image(writer, header, self.scan_range::<std::ops::RangeFull>(..))
+    }
+
+    /// The manifest can only be checked once every chunk has been applied, so
+    /// a snapshot that fails verification leaves the storage cleared.
+    fn apply_snapshot<R: Read>(&mut self, reader: R) -> StorageResult<SnapshotMetadata> {
+        let mut reader = SnapshotReader::new(reader)?;
+
+        self.clear()?;
+
+        for chunk in reader.by_ref() {
+            if let Err(error) = chunk.and_then(|chunk| self.apply_chunk(chunk)) {
+                self.clear()?;
+                return Err(error);
+            }
+        }
+
+        reader.metadata().ok_or_else(|| {
+            StorageError::CorruptionDetected("Snapshot ended before its manifest!".into())
+        })
+    }
 }
 
 #[derive(Debug, Error)]
@@ -44,6 +124,14 @@ pub enum StorageError {
     Unknown(String),
     #[error("Storage/snapshot corrupted! {}", .0)]
     CorruptionDetected(String),
+    #[error("Version history isn't retained by this storage!")]
+    HistoryNotRetained,
+}
+
+impl From<CodecError> for StorageError {
+    fn from(value: CodecError) -> Self {
+        StorageError::CorruptionDetected(value.to_string())
+    }
 }
 
 impl From<SavefileError> for StorageError {
@@ -1,15 +1,26 @@
-use std::path::Path;
+use std::{ops::RangeBounds, path::Path};
 
 use async_trait::async_trait;
-use savefile::prelude::Savefile;
-use sled::{Db, IVec};
+use bytes::Bytes;
+use sled::{Batch, Db, IVec};
 
-use super::{Flush, Get, Snapshot, StorageError, StorageResult, Upsert};
+use super::{
+    snapshot::{SnapshotChunk, StorageBackend},
+    Delete, Flush, Get, Scan, ScanIterator, Snapshot, StorageError, StorageResult, Upsert,
+};
 
 pub struct SledStorage {
     db: Db,
 }
 
+impl SledStorage {
+    pub fn open<P: AsRef<Path>>(directory: P) -> StorageResult<Self> {
+        sled::open(directory)
+            .map(SledStorage::from)
+            .map_err(|error| error.into())
+    }
+}
+
 impl Get for SledStorage {
     type ReturnValue = IVec;
 
@@ -34,75 +45,70 @@ impl Upsert for SledStorage {
     }
 }
 
-#[async_trait]
-impl Flush for SledStorage {
-    async fn flush(&self) -> StorageResult<()> {
+impl Delete for SledStorage {
+    fn delete<K>(&self, key: K) -> StorageResult<()>
+    where
+        K: AsRef<[u8]>,
+    {
         self.db
-            .flush_async()
-            .await
+            .remove(key)
             .and(Ok(()))
             .map_err(|error| error.into())
     }
 }
 
-const SNAPSHOT_VERSION: u32 = 1;
-
-impl Snapshot for SledStorage {
-    fn save_snapshot(&self, path: &Path) -> StorageResult<()> {
-        let snapshot: SledSnapshot = self
-            .db
-            .export()
-            .into_iter()
-            .map(|part| part.into())
-            .collect::<Vec<_>>()
-            .into();
-
-        savefile::save_file(path, SNAPSHOT_VERSION, &snapshot).map_err(|error| error.into())
+impl Scan for SledStorage {
+    fn scan_prefix<P>(&self, prefix: P) -> ScanIterator<'_, Self::ReturnValue>
+    where
+        P: AsRef<[u8]>,
+    {
+        Box::new(
+            self.db
+                .scan_prefix(prefix)
+                .map(|entry| entry.map_err(|error| error.into())),
+        )
     }
 
-    fn apply_snapshot(&mut self, path: &Path) -> StorageResult<()> {
-        let snapshot: SledSnapshot = savefile::load_file(path, SNAPSHOT_VERSION)
-            .map_err(std::convert::Into::<StorageError>::into)?;
-
-        self.db.import(snapshot.into());
-
-        Ok(())
+    fn scan_range<R>(&self, range: R) -> ScanIterator<'_, Self::ReturnValue>
+    where
+        R: RangeBounds<Bytes>,
+    {
+        Box::new(
+            self.db
+                .range(range)
+                .map(|entry| entry.map_err(|error| error.into())),
+        )
     }
 }
 
-#[derive(Savefile)]
-pub(crate) struct SledSnapshot(Vec<SledSnapshotPart>);
-
-impl From<Vec<SledSnapshotPart>> for SledSnapshot {
-    fn from(value: Vec<SledSnapshotPart>) -> Self {
-        Self(value)
+#[async_trait]
+impl Flush for SledStorage {
+    async fn flush(&self) -> StorageResult<()> {
+        self.db
+            .flush_async()
+            .await
+            .and(Ok(()))
+            .map_err(|error| error.into())
     }
 }
 
-impl From<SledSnapshot> for Vec<(Vec<u8>, Vec<u8>, std::vec::IntoIter<Vec<Vec<u8>>>)> {
-    fn from(value: SledSnapshot) -> Self {
-        value
-            .0
-            .into_iter()
-            .map(|part| (part.vec_one, part.vec_two, part.data.into_iter()))
-            .collect()
+impl Snapshot for SledStorage {
+    fn backend(&self) -> StorageBackend {
+        StorageBackend::Sled
     }
-}
 
-#[derive(Savefile)]
-pub(crate) struct SledSnapshotPart {
-    pub vec_one: Vec<u8>,
-    pub vec_two: Vec<u8>,
-    pub data: Vec<Vec<Vec<u8>>>,
-}
+    fn clear(&mut self) -> StorageResult<()> {
+        self.db.clear().map_err(|error| error.into())
+    }
 
-impl<I: Iterator<Item = Vec<Vec<u8>>>> From<(Vec<u8>, Vec<u8>, I)> for SledSnapshotPart {
-    fn from(value: (Vec<u8>, Vec<u8>, I)) -> Self {
-        Self {
-            vec_one: value.0,
-            vec_two: value.1,
-            data: value.2.collect(),
+    fn apply_chunk(&mut self, chunk: SnapshotChunk) -> StorageResult<()> {
+        let mut batch = Batch::default();
+
+        for (key, value) in chunk.entries {
+            batch.insert(key, value);
         }
+
+        self.db.apply_batch(batch).map_err(|error| error.into())
     }
 }
 
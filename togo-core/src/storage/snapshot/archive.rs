@@ -0,0 +1,249 @@
+use std::{
+    fs::File,
+    io::Write,
+    ops::{Bound, RangeBounds},
+    path::Path,
+};
+
+use bytes::Bytes;
+use memmap2::Mmap;
+use rkyv::{Archive, Serialize};
+
+use crate::storage::{ScanIterator, Snapshot, StorageError, StorageResult};
+
+use super::{
+    ArchivedStorageBackend, SnapshotChunks, SnapshotCompression, SnapshotHeader, StorageBackend,
+    DEFAULT_CHUNK_SIZE,
+};
+
+const IMAGE_MAGIC: &[u8; 8] = b"togoRKV1";
+// magic, the archive's checksum and padding that keeps the archive aligned
+const IMAGE_PREFIX_SIZE: usize = 16;
+
+/// The whole contents of a storage laid out so that it can be used in place,
+/// with entries sorted by key.
+#[derive(Archive, Serialize)]
+#[archive(check_bytes)]
+pub struct SnapshotImage {
+    pub op_number: u64,
+    pub view: u64,
+    pub cluster_id: u64,
+    pub backend: StorageBackend,
+    pub entries: Vec<ImageEntry>,
+}
+
+#[derive(Archive, Serialize)]
+#[archive(check_bytes)]
+pub struct ImageEntry {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+/// Unlike chunked snapshots, the image has to be built in memory as a whole
+/// before it's written out.
+pub fn write_image<W, V>(
+    mut writer: W,
+    header: &SnapshotHeader,
+    entries: ScanIterator<'_, V>,
+) -> StorageResult<()>
+where
+    W: Write,
+    V: AsRef<[u8]>,
+{
+    let image = SnapshotImage {
+        op_number: header.op_number,
+        view: header.view,
+        cluster_id: header.cluster_id,
+        backend: header.backend,
+        entries: entries
+            .map(|entry| {
+                entry.map(|(key, value)| ImageEntry {
+                    key: key.as_ref().to_vec(),
+                    value: value.as_ref().to_vec(),
+                })
+            })
+            .collect::<StorageResult<_>>()?,
+    };
+
+    let archive = rkyv::to_bytes::<_, 4096>(&image).map_err(|error| {
+        StorageError::Unknown(format!("Snapshot image can't be serialized! {error}"))
+    })?;
+
+    writer
+        .write_all(IMAGE_MAGIC)
+        .and_then(|_| writer.write_all(&crc32fast::hash(&archive).to_be_bytes()))
+        .and_then(|_| writer.write_all(&[0; IMAGE_PREFIX_SIZE - 12]))
+        .and_then(|_| writer.write_all(&archive))
+        .and_then(|_| writer.flush())
+        .map_err(StorageError::Io)
+}
+
+/// A snapshot image mapped into memory - it's validated once when opened,
+/// after which entries are read straight out of the mapping.
+pub struct MappedSnapshot {
+    mmap: Mmap,
+}
+
+impl MappedSnapshot {
+    pub fn open<P: AsRef<Path>>(path: P) -> StorageResult<Self> {
+        let file = File::open(path).map_err(StorageError::Io)?;
+
+        // SAFETY: images are written once and never modified in place
+        let mmap = unsafe { Mmap::map(&file) }.map_err(StorageError::Io)?;
+
+        if mmap.len() < IMAGE_PREFIX_SIZE || &mmap[..8] != IMAGE_MAGIC {
+            return Err(StorageError::CorruptionDetected(
+                "File isn't a snapshot image!".into(),
+            ));
+        }
+
+        let checksum = u32::from_be_bytes([mmap[8], mmap[9], mmap[10], mmap[11]]);
+        let archive = &mmap[IMAGE_PREFIX_SIZE..];
+
+        if crc32fast::hash(archive) != checksum {
+            return Err(StorageError::CorruptionDetected(
+                "Snapshot image checksum mismatch!".into(),
+            ));
+        }
+
+        rkyv::check_archived_root::<SnapshotImage>(archive).map_err(|error| {
+            StorageError::CorruptionDetected(format!("Snapshot image is malformed! {error}"))
+        })?;
+
+        Ok(Self { mmap })
+    }
+
+    pub fn image(&self) -> &ArchivedSnapshotImage {
+        // SAFETY: the archive was validated when the image was opened and the
+        // mapping is read-only
+        unsafe { rkyv::archived_root::<SnapshotImage>(&self.mmap[IMAGE_PREFIX_SIZE..]) }
+    }
+
+    pub fn header(&self) -> SnapshotHeader {
+        let image = self.image();
+
+        SnapshotHeader {
+            op_number: image.op_number,
+            view: image.view,
+            cluster_id: image.cluster_id,
+            backend: match image.backend {
+                ArchivedStorageBackend::Memory => StorageBackend::Memory,
+                ArchivedStorageBackend::Sled => StorageBackend::Sled,
+                ArchivedStorageBackend::Lsm => StorageBackend::Lsm,
+            },
+            compression: SnapshotCompression::None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.image().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.image().entries.is_empty()
+    }
+
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Option<&[u8]> {
+        let entries = &self.image().entries;
+
+        entries
+            .binary_search_by(|entry| entry.key.as_slice().cmp(key.as_ref()))
+            .ok()
+            .map(|index| entries[index].value.as_slice())
+    }
+
+    pub fn range<R>(&self, range: R) -> impl Iterator<Item = (&[u8], &[u8])>
+    where
+        R: RangeBounds<Bytes>,
+    {
+        let entries = &self.image().entries;
+        let start = match range.start_bound() {
+            Bound::Included(start) => {
+                entries.partition_point(|entry| entry.key.as_slice() < start.as_ref())
+            }
+            Bound::Excluded(start) => {
+                entries.partition_point(|entry| entry.key.as_slice() <= start.as_ref())
+            }
+            Bound::Unbounded => 0,
+        };
+        let end = range.end_bound().cloned();
+
+        entries[start..]
+            .iter()
+            .map(|entry| (entry.key.as_slice(), entry.value.as_slice()))
+            .take_while(move |(key, _)| match &end {
+                Bound::Included(end) => *key <= end.as_ref(),
+                Bound::Excluded(end) => *key < end.as_ref(),
+                Bound::Unbounded => true,
+            })
+    }
+
+    /// Copies the entries within `range` into the storage, on top of what it
+    /// already holds.
+    pub fn restore_range<S, R>(&self, storage: &mut S, range: R) -> StorageResult<u64>
+    where
+        S: Snapshot,
+        R: RangeBounds<Bytes>,
+    {
+        let entries: ScanIterator<'_, &[u8]> = Box::new(self.range(range).map(Ok));
+        let mut restored = 0;
+
+        for chunk in SnapshotChunks::new(entries, DEFAULT_CHUNK_SIZE) {
+            let chunk = chunk?;
+
+            restored += chunk.entries.len() as u64;
+            storage.apply_chunk(chunk)?;
+        }
+
+        Ok(restored)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use bytes::Bytes;
+
+    use crate::storage::{memory::MemoryStorage, Get, Snapshot, Upsert};
+
+    use super::MappedSnapshot;
+
+    #[test]
+    pub fn images_are_read_in_place_and_partially_restored() {
+        let path = std::env::temp_dir().join(format!("togo-image-{}.snapshot", std::process::id()));
+        let storage = MemoryStorage::new();
+
+        for key in ["a", "b", "c", "d"] {
+            storage.upsert(key, format!("value-{key}")).unwrap();
+        }
+
+        let header = storage.snapshot_header(7, 1, 1);
+        storage
+            .save_image(&header, File::create(&path).unwrap())
+            .unwrap();
+
+        let image = MappedSnapshot::open(&path).unwrap();
+        let mut restored = MemoryStorage::new();
+        let count = image
+            .restore_range(&mut restored, Bytes::from("b")..Bytes::from("d"))
+            .unwrap();
+
+        assert_eq!(image.header(), header);
+        assert_eq!(image.len(), 4);
+        assert_eq!(image.get("c"), Some(&b"value-c"[..]));
+        assert_eq!(image.get("e"), None);
+        assert_eq!(count, 2);
+        assert_eq!(restored.get("a").unwrap(), None);
+        assert_eq!(restored.get("c").unwrap().unwrap(), Bytes::from("value-c"));
+
+        let mut data = std::fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        std::fs::write(&path, data).unwrap();
+
+        assert!(MappedSnapshot::open(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,157 @@
+use std::{collections::VecDeque, io::Read};
+
+use savefile::prelude::Savefile;
+
+use crate::storage::{ScanIterator, StorageResult};
+
+use super::{
+    SnapshotChunk, SnapshotChunks, SnapshotCompression, SnapshotHeader, StorageBackend,
+    DEFAULT_CHUNK_SIZE,
+};
+
+pub(super) const LEGACY_SNAPSHOT_VERSION: u32 = 1;
+pub(super) const UNCOMPRESSED_SNAPSHOT_VERSION: u32 = 2;
+
+const TREE_COLLECTION: &[u8] = b"tree";
+const DEFAULT_TREE: &[u8] = b"__sled__default";
+
+/// The layout of `sled::Db::export`, which is how sled storages used to save
+/// their snapshots.
+#[derive(Savefile)]
+pub(super) struct SledSnapshot(pub Vec<SledSnapshotPart>);
+
+#[derive(Savefile)]
+pub(super) struct SledSnapshotPart {
+    pub vec_one: Vec<u8>,
+    pub vec_two: Vec<u8>,
+    pub data: Vec<Vec<Vec<u8>>>,
+}
+
+/// Headers from before chunks could be compressed.
+#[derive(Savefile)]
+struct UncompressedSnapshotHeader {
+    op_number: u64,
+    view: u64,
+    cluster_id: u64,
+    backend: StorageBackend,
+}
+
+pub(super) fn load_uncompressed_header<R: Read>(reader: &mut R) -> StorageResult<SnapshotHeader> {
+    let header: UncompressedSnapshotHeader = savefile::load(reader, UNCOMPRESSED_SNAPSHOT_VERSION)?;
+
+    Ok(SnapshotHeader {
+        op_number: header.op_number,
+        view: header.view,
+        cluster_id: header.cluster_id,
+        backend: header.backend,
+        compression: SnapshotCompression::None,
+    })
+}
+
+pub(super) fn legacy_header() -> SnapshotHeader {
+    SnapshotHeader {
+        op_number: 0,
+        view: 0,
+        cluster_id: 0,
+        backend: StorageBackend::Sled,
+        compression: SnapshotCompression::None,
+    }
+}
+
+/// Only the default tree is restored, since it's the only one storages have
+/// ever written to.
+pub(super) fn load_chunks<R: Read>(reader: &mut R) -> StorageResult<VecDeque<SnapshotChunk>> {
+    let snapshot: SledSnapshot = savefile::load(reader, LEGACY_SNAPSHOT_VERSION)?;
+
+    let entries: ScanIterator<'static, Vec<u8>> = Box::new(
+        snapshot
+            .0
+            .into_iter()
+            .filter(|part| part.vec_one == TREE_COLLECTION && part.vec_two == DEFAULT_TREE)
+            .flat_map(|part| part.data)
+            .filter_map(|mut row| {
+                let value = row.pop()?;
+                let key = row.pop()?;
+
+                Some(Ok((key, value)))
+            }),
+    );
+
+    SnapshotChunks::new(entries, DEFAULT_CHUNK_SIZE).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::storage::{
+        memory::MemoryStorage,
+        snapshot::{upgrade, verify, SnapshotCompression, SnapshotReader, StorageBackend},
+        Get, Snapshot, StorageResult,
+    };
+
+    use super::{SledSnapshot, SledSnapshotPart, DEFAULT_TREE, LEGACY_SNAPSHOT_VERSION};
+
+    #[test]
+    pub fn legacy_sled_snapshots_are_read_and_upgraded() {
+        let snapshot = SledSnapshot(vec![SledSnapshotPart {
+            vec_one: b"tree".to_vec(),
+            vec_two: DEFAULT_TREE.to_vec(),
+            data: vec![
+                vec![b"key".to_vec(), b"value".to_vec()],
+                vec![b"other".to_vec(), b"value2".to_vec()],
+            ],
+        }]);
+
+        let mut legacy = Vec::new();
+        savefile::save(&mut legacy, LEGACY_SNAPSHOT_VERSION, &snapshot).unwrap();
+
+        let chunks = SnapshotReader::new(&legacy[..])
+            .unwrap()
+            .collect::<StorageResult<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(
+            chunks[0].entries,
+            vec![
+                (b"key".to_vec(), b"value".to_vec()),
+                (b"other".to_vec(), b"value2".to_vec())
+            ]
+        );
+
+        let mut upgraded = Vec::new();
+        upgrade(&legacy[..], &mut upgraded, SnapshotCompression::Lz4).unwrap();
+
+        let metadata = verify(&upgraded[..]).unwrap();
+
+        assert_eq!(metadata.header.compression, SnapshotCompression::Lz4);
+        assert_eq!(metadata.manifest.key_count, 2);
+    }
+
+    // written by the code that introduced headers and manifests, before
+    // chunks could be compressed - 20 keys in chunks of about 100 bytes
+    #[test]
+    pub fn uncompressed_snapshots_are_restored() {
+        let fixture = include_bytes!("fixtures/v2.snapshot");
+        let mut storage = MemoryStorage::new();
+
+        let metadata = storage.apply_snapshot(&fixture[..]).unwrap();
+
+        assert_eq!(metadata, verify(&fixture[..]).unwrap());
+        assert_eq!(metadata.header.op_number, 42);
+        assert_eq!(metadata.header.view, 3);
+        assert_eq!(metadata.header.cluster_id, 7);
+        assert_eq!(metadata.header.backend, StorageBackend::Memory);
+        assert_eq!(metadata.header.compression, SnapshotCompression::None);
+        assert_eq!(metadata.manifest.key_count, 20);
+        assert!(metadata.manifest.chunk_checksums.len() > 1);
+
+        for index in 0..20 {
+            assert_eq!(
+                storage.get(format!("key-{index:02}")).unwrap(),
+                Some(Bytes::from(format!("value-{index}")))
+            );
+        }
+    }
+}
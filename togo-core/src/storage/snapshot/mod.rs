@@ -0,0 +1,555 @@
+use std::{
+    collections::VecDeque,
+    io::{Chain, Cursor, Read, Write},
+    iter::Peekable,
+};
+
+use crc32fast::Hasher;
+use savefile::prelude::Savefile;
+
+use super::{ScanIterator, StorageError, StorageResult};
+
+pub mod archive;
+mod legacy;
+
+// 1 - a whole sled export saved as a single savefile
+// 2 - header, chunks and manifest
+// 3 - optionally compressed chunks
+pub(crate) const SNAPSHOT_VERSION: u32 = 3;
+
+// every savefile starts with a magic string, its own format version and the
+// version of the data that follows
+const SAVEFILE_HEADER_SIZE: usize = 15;
+
+pub const DEFAULT_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Savefile, rkyv::Archive, rkyv::Serialize)]
+#[archive(check_bytes)]
+pub enum StorageBackend {
+    Memory,
+    Sled,
+    Lsm,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Savefile)]
+pub enum SnapshotCompression {
+    #[default]
+    None,
+    Lz4,
+}
+
+/// Written ahead of the chunks - identifies the op a snapshot was taken at,
+/// so that only the log suffix past `op_number` has to be replayed on top.
+#[derive(Debug, Clone, PartialEq, Eq, Savefile)]
+pub struct SnapshotHeader {
+    pub op_number: u64,
+    pub view: u64,
+    pub cluster_id: u64,
+    pub backend: StorageBackend,
+    pub compression: SnapshotCompression,
+}
+
+/// Written after the last chunk, since the totals are only known once the
+/// whole storage has been streamed. The checksum covers the header and all
+/// of the chunks.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Savefile)]
+pub struct SnapshotManifest {
+    pub key_count: u64,
+    pub chunk_checksums: Vec<u32>,
+    pub checksum: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotMetadata {
+    pub header: SnapshotHeader,
+    pub manifest: SnapshotManifest,
+}
+
+/// A bounded slice of a storage's contents, in key order.
+///
+/// A snapshot is a stream of chunks where only the final one is marked as
+/// `last` - it's the unit written to files and shipped between replicas.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Savefile)]
+pub struct SnapshotChunk {
+    pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+    pub last: bool,
+}
+
+impl SnapshotChunk {
+    pub fn write_to<W: Write>(
+        &self,
+        writer: &mut W,
+        compression: SnapshotCompression,
+    ) -> StorageResult<()> {
+        match compression {
+            SnapshotCompression::None => savefile::save(writer, SNAPSHOT_VERSION, self)?,
+            SnapshotCompression::Lz4 => {
+                let mut encoded = Vec::new();
+                savefile::save_noschema(&mut encoded, SNAPSHOT_VERSION, self)?;
+
+                let compressed = lz4_flex::compress_prepend_size(&encoded);
+                savefile::save(writer, SNAPSHOT_VERSION, &compressed)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(
+        reader: &mut R,
+        compression: SnapshotCompression,
+    ) -> StorageResult<Self> {
+        match compression {
+            SnapshotCompression::None => Ok(savefile::load(reader, SNAPSHOT_VERSION)?),
+            SnapshotCompression::Lz4 => {
+                let compressed: Vec<u8> = savefile::load(reader, SNAPSHOT_VERSION)?;
+                let encoded =
+                    lz4_flex::decompress_size_prepended(&compressed).map_err(|error| {
+                        StorageError::CorruptionDetected(format!(
+                            "Snapshot chunk can't be decompressed! {error}"
+                        ))
+                    })?;
+
+                Ok(savefile::load_noschema(
+                    &mut &encoded[..],
+                    SNAPSHOT_VERSION,
+                )?)
+            }
+        }
+    }
+}
+
+/// Lazily groups scanned entries into chunks of roughly `chunk_size` bytes,
+/// so that only a single chunk is held in memory at a time.
+pub struct SnapshotChunks<'a, V> {
+    entries: Peekable<ScanIterator<'a, V>>,
+    chunk_size: usize,
+    finished: bool,
+}
+
+impl<'a, V> SnapshotChunks<'a, V> {
+    pub fn new(entries: ScanIterator<'a, V>, chunk_size: usize) -> Self {
+        Self {
+            entries: entries.peekable(),
+            chunk_size,
+            finished: false,
+        }
+    }
+}
+
+impl<'a, V> Iterator for SnapshotChunks<'a, V>
+where
+    V: AsRef<[u8]>,
+{
+    type Item = StorageResult<SnapshotChunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        let mut chunk = SnapshotChunk::default();
+        let mut size = 0;
+
+        while size < self.chunk_size {
+            match self.entries.next() {
+                Some(Ok((key, value))) => {
+                    size += key.as_ref().len() + value.as_ref().len();
+                    chunk
+                        .entries
+                        .push((key.as_ref().to_vec(), value.as_ref().to_vec()));
+                }
+                Some(Err(error)) => {
+                    self.finished = true;
+                    return Some(Err(error));
+                }
+                None => break,
+            }
+        }
+
+        if self.entries.peek().is_none() {
+            self.finished = true;
+            chunk.last = true;
+        }
+
+        Some(Ok(chunk))
+    }
+}
+
+/// Reads a snapshot stream chunk by chunk, checking every chunk against the
+/// manifest once it has been reached.
+///
+/// Snapshots of every earlier version can be read too - legacy whole-file
+/// snapshots carry no metadata, so they're reported as taken at op 0 and
+/// can't be verified.
+pub struct SnapshotReader<R> {
+    reader: ChecksumReader<Chain<Cursor<[u8; SAVEFILE_HEADER_SIZE]>, R>>,
+    header: SnapshotHeader,
+    checksums: Vec<u32>,
+    key_count: u64,
+    manifest: Option<SnapshotManifest>,
+    legacy_chunks: Option<VecDeque<SnapshotChunk>>,
+    finished: bool,
+}
+
+impl<R: Read> SnapshotReader<R> {
+    pub fn new(mut reader: R) -> StorageResult<Self> {
+        // the version has to be known before anything can be loaded, the
+        // peeked bytes are then read again as part of the first savefile
+        let mut prefix = [0; SAVEFILE_HEADER_SIZE];
+        reader.read_exact(&mut prefix).map_err(StorageError::Io)?;
+
+        let version = u32::from_le_bytes([prefix[11], prefix[12], prefix[13], prefix[14]]);
+        let mut reader = ChecksumReader::new(Cursor::new(prefix).chain(reader));
+
+        if version == legacy::LEGACY_SNAPSHOT_VERSION {
+            let legacy_chunks = legacy::load_chunks(&mut reader)?;
+
+            return Ok(Self {
+                reader,
+                header: legacy::legacy_header(),
+                checksums: Vec::new(),
+                key_count: 0,
+                manifest: None,
+                legacy_chunks: Some(legacy_chunks),
+                finished: false,
+            });
+        }
+
+        let header = match version {
+            legacy::UNCOMPRESSED_SNAPSHOT_VERSION => legacy::load_uncompressed_header(&mut reader)?,
+            _ => savefile::load(&mut reader, SNAPSHOT_VERSION)?,
+        };
+
+        reader.take_chunk_checksum();
+
+        Ok(Self {
+            reader,
+            header,
+            checksums: Vec::new(),
+            key_count: 0,
+            manifest: None,
+            legacy_chunks: None,
+            finished: false,
+        })
+    }
+
+    pub fn header(&self) -> &SnapshotHeader {
+        &self.header
+    }
+
+    /// Only available once every chunk has been read and verified.
+    pub fn metadata(&self) -> Option<SnapshotMetadata> {
+        self.manifest.clone().map(|manifest| SnapshotMetadata {
+            header: self.header.clone(),
+            manifest,
+        })
+    }
+
+    fn read_manifest(&mut self) -> StorageResult<()> {
+        let checksum = self.reader.checksum();
+        let manifest: SnapshotManifest = savefile::load(&mut self.reader, SNAPSHOT_VERSION)?;
+
+        if manifest.chunk_checksums != self.checksums {
+            return Err(StorageError::CorruptionDetected(
+                "Snapshot chunks don't match the manifest!".into(),
+            ));
+        }
+
+        if manifest.key_count != self.key_count {
+            return Err(StorageError::CorruptionDetected(format!(
+                "Snapshot holds {} keys, but its manifest lists {}!",
+                self.key_count, manifest.key_count
+            )));
+        }
+
+        if manifest.checksum != checksum {
+            return Err(StorageError::CorruptionDetected(
+                "Snapshot checksum mismatch!".into(),
+            ));
+        }
+
+        self.manifest = Some(manifest);
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for SnapshotReader<R> {
+    type Item = StorageResult<SnapshotChunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        if let Some(legacy_chunks) = &mut self.legacy_chunks {
+            let chunk = legacy_chunks.pop_front()?;
+
+            self.key_count += chunk.entries.len() as u64;
+            self.finished = chunk.last;
+
+            if chunk.last {
+                self.manifest = Some(SnapshotManifest {
+                    key_count: self.key_count,
+                    chunk_checksums: Vec::new(),
+                    checksum: self.reader.checksum(),
+                });
+            }
+
+            return Some(Ok(chunk));
+        }
+
+        let compression = self.header.compression;
+        let chunk = SnapshotChunk::read_from(&mut self.reader, compression).and_then(|chunk| {
+            self.checksums.push(self.reader.take_chunk_checksum());
+            self.key_count += chunk.entries.len() as u64;
+
+            if chunk.last {
+                self.read_manifest()?;
+            }
+
+            Ok(chunk)
+        });
+
+        self.finished = chunk.as_ref().map_or(true, |chunk| chunk.last);
+
+        Some(chunk)
+    }
+}
+
+/// Reads through a whole snapshot without applying it anywhere.
+pub fn verify<R: Read>(reader: R) -> StorageResult<SnapshotMetadata> {
+    let mut reader = SnapshotReader::new(reader)?;
+
+    for chunk in reader.by_ref() {
+        chunk?;
+    }
+
+    reader.metadata().ok_or_else(|| {
+        StorageError::CorruptionDetected("Snapshot ended before its manifest!".into())
+    })
+}
+
+/// Rewrites a snapshot of any supported version in the current format.
+pub fn upgrade<R, W>(
+    reader: R,
+    writer: W,
+    compression: SnapshotCompression,
+) -> StorageResult<SnapshotMetadata>
+where
+    R: Read,
+    W: Write,
+{
+    let mut reader = SnapshotReader::new(reader)?;
+    let header = SnapshotHeader {
+        compression,
+        ..reader.header().clone()
+    };
+
+    write_snapshot(writer, header, reader.by_ref())
+}
+
+pub(crate) fn write_snapshot<W, I>(
+    writer: W,
+    header: SnapshotHeader,
+    chunks: I,
+) -> StorageResult<SnapshotMetadata>
+where
+    W: Write,
+    I: Iterator<Item = StorageResult<SnapshotChunk>>,
+{
+    let mut writer = ChecksumWriter::new(writer);
+    let mut manifest = SnapshotManifest::default();
+
+    savefile::save(&mut writer, SNAPSHOT_VERSION, &header)?;
+    writer.take_chunk_checksum();
+
+    for chunk in chunks {
+        let chunk = chunk?;
+
+        chunk.write_to(&mut writer, header.compression)?;
+        manifest.key_count += chunk.entries.len() as u64;
+        manifest.chunk_checksums.push(writer.take_chunk_checksum());
+    }
+
+    manifest.checksum = writer.checksum();
+    savefile::save(&mut writer, SNAPSHOT_VERSION, &manifest)?;
+    writer.flush().map_err(StorageError::Io)?;
+
+    Ok(SnapshotMetadata { header, manifest })
+}
+
+/// Keeps a running CRC32 of everything written, along with one of the bytes
+/// written since the current chunk started.
+struct ChecksumWriter<W> {
+    writer: W,
+    file: Hasher,
+    chunk: Hasher,
+}
+
+impl<W> ChecksumWriter<W> {
+    fn new(writer: W) -> Self {
+        Self {
+            writer,
+            file: Hasher::new(),
+            chunk: Hasher::new(),
+        }
+    }
+
+    fn checksum(&self) -> u32 {
+        self.file.clone().finalize()
+    }
+
+    fn take_chunk_checksum(&mut self) -> u32 {
+        std::mem::take(&mut self.chunk).finalize()
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+        let written = self.writer.write(buffer)?;
+
+        self.file.update(&buffer[..written]);
+        self.chunk.update(&buffer[..written]);
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+struct ChecksumReader<R> {
+    reader: R,
+    file: Hasher,
+    chunk: Hasher,
+}
+
+impl<R> ChecksumReader<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            file: Hasher::new(),
+            chunk: Hasher::new(),
+        }
+    }
+
+    fn checksum(&self) -> u32 {
+        self.file.clone().finalize()
+    }
+
+    fn take_chunk_checksum(&mut self) -> u32 {
+        std::mem::take(&mut self.chunk).finalize()
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.reader.read(buffer)?;
+
+        self.file.update(&buffer[..read]);
+        self.chunk.update(&buffer[..read]);
+
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::storage::{ScanIterator, StorageResult};
+
+    use super::{
+        verify, write_snapshot, SnapshotChunks, SnapshotCompression, SnapshotHeader,
+        SnapshotReader, StorageBackend,
+    };
+
+    fn entries() -> ScanIterator<'static, Bytes> {
+        Box::new(
+            (0..100u32)
+                .map(|index| Ok((index.to_be_bytes().to_vec().into(), Bytes::from("value")))),
+        )
+    }
+
+    fn header() -> SnapshotHeader {
+        SnapshotHeader {
+            op_number: 42,
+            view: 3,
+            cluster_id: 1,
+            backend: StorageBackend::Memory,
+            compression: SnapshotCompression::None,
+        }
+    }
+
+    #[test]
+    pub fn chunks_are_bounded_and_round_trip_through_a_stream() {
+        let chunks = SnapshotChunks::new(entries(), 90)
+            .collect::<StorageResult<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(chunks.len(), 10);
+        assert!(chunks.iter().all(|chunk| chunk.entries.len() <= 10));
+        assert!(chunks.last().unwrap().last);
+
+        let mut stream = Vec::new();
+        let written =
+            write_snapshot(&mut stream, header(), chunks.clone().into_iter().map(Ok)).unwrap();
+
+        let mut reader = SnapshotReader::new(&stream[..]).unwrap();
+        let read = reader.by_ref().collect::<StorageResult<Vec<_>>>().unwrap();
+
+        assert_eq!(read, chunks);
+        assert_eq!(reader.metadata().unwrap(), written);
+        assert_eq!(written.header, header());
+        assert_eq!(written.manifest.key_count, 100);
+        assert_eq!(written.manifest.chunk_checksums.len(), 10);
+    }
+
+    #[test]
+    pub fn corrupted_snapshots_fail_verification() {
+        let mut stream = Vec::new();
+        write_snapshot(&mut stream, header(), SnapshotChunks::new(entries(), 90)).unwrap();
+
+        assert!(verify(&stream[..]).is_ok());
+
+        let position = stream.len() / 2;
+        stream[position] ^= 0xff;
+
+        assert!(verify(&stream[..]).is_err());
+    }
+
+    #[test]
+    pub fn compressed_chunks_are_smaller_and_read_back_unchanged() {
+        let mut plain = Vec::new();
+        let mut compressed = Vec::new();
+        let compressed_header = SnapshotHeader {
+            compression: SnapshotCompression::Lz4,
+            ..header()
+        };
+
+        write_snapshot(&mut plain, header(), SnapshotChunks::new(entries(), 900)).unwrap();
+        write_snapshot(
+            &mut compressed,
+            compressed_header,
+            SnapshotChunks::new(entries(), 900),
+        )
+        .unwrap();
+
+        let read = |stream: &[u8]| {
+            SnapshotReader::new(stream)
+                .unwrap()
+                .flat_map(|chunk| chunk.unwrap().entries)
+                .collect::<Vec<_>>()
+        };
+
+        assert!(compressed.len() < plain.len());
+        assert_eq!(read(&compressed), read(&plain));
+        assert_eq!(
+            verify(&compressed[..]).unwrap().header.compression,
+            SnapshotCompression::Lz4
+        );
+    }
+}
@@ -0,0 +1,546 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+};
+
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::future::try_join_all;
+use thiserror::Error;
+use togo_vr::codec::{get_u64, Codec, CodecResult};
+
+use crate::{
+    kv::VersionedValue,
+    operation::{Operation, OperationResult},
+    router::{GroupClient, Router, RouterError, RoutingSource},
+    shard::{Partitioner, ShardIdentifier},
+    storage::StorageError,
+};
+
+pub type TransactionResult<T> = Result<T, TransactionError>;
+
+/// Transaction records are routed by a key under this prefix - they're kept
+/// apart from the keys clients write to, in whichever shard owns it.
+pub const TRANSACTION_RECORD_PREFIX: &[u8] = b"\xfftransaction/";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TransactionId(pub u64);
+
+impl Codec for TransactionId {
+    fn encode(&self, buffer: &mut BytesMut) {
+        buffer.put_u64(self.0);
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        get_u64(data).map(TransactionId)
+    }
+}
+
+impl Display for TransactionId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Commit,
+    Abort,
+}
+
+/// The coordinator's state of a transaction - its participants, and the
+/// decision once there is one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransactionRecord {
+    pub participants: Vec<ShardIdentifier>,
+    pub decision: Option<Decision>,
+}
+
+impl TransactionRecord {
+    pub fn key(transaction: TransactionId) -> Bytes {
+        let mut buffer = BytesMut::with_capacity(TRANSACTION_RECORD_PREFIX.len() + 8);
+
+        buffer.put_slice(TRANSACTION_RECORD_PREFIX);
+        buffer.put_u64(transaction.0);
+        buffer.freeze()
+    }
+
+    pub fn encode(&self) -> Bytes {
+        let mut buffer = BytesMut::with_capacity(5 + 4 * self.participants.len());
+
+        buffer.put_u8(match self.decision {
+            None => 0,
+            Some(Decision::Commit) => 1,
+            Some(Decision::Abort) => 2,
+        });
+        buffer.put_u32(self.participants.len() as u32);
+
+        for participant in &self.participants {
+            buffer.put_u32(participant.0);
+        }
+
+        buffer.freeze()
+    }
+
+    pub fn decode(mut data: &[u8]) -> TransactionResult<Self> {
+        if data.remaining() < 5 {
+            return Err(TransactionError::MalformedRecord);
+        }
+
+        let decision = match data.get_u8() {
+            0 => None,
+            1 => Some(Decision::Commit),
+            2 => Some(Decision::Abort),
+            _ => return Err(TransactionError::MalformedRecord),
+        };
+        let count = data.get_u32() as usize;
+
+        if data.remaining() != 4 * count {
+            return Err(TransactionError::MalformedRecord);
+        }
+
+        Ok(Self {
+            participants: (0..count)
+                .map(|_| ShardIdentifier(data.get_u32()))
+                .collect(),
+            decision,
+        })
+    }
+}
+
+/// Submits operations to shards, through their replicated logs.
+#[async_trait]
+pub trait ShardClient {
+    fn shard_for(&self, key: &[u8]) -> TransactionResult<ShardIdentifier>;
+
+    /// Submits a write to the shard owning its key.
+    async fn write(&self, operation: Operation) -> TransactionResult<OperationResult>;
+
+    /// Submits an operation to the given shard.
+    async fn submit(
+        &self,
+        shard: ShardIdentifier,
+        operation: Operation,
+    ) -> TransactionResult<OperationResult>;
+
+    /// Reads the coordinator's record of the transaction.
+    async fn record(&self, transaction: TransactionId)
+        -> TransactionResult<Option<VersionedValue>>;
+}
+
+#[async_trait]
+impl<P, C, R> ShardClient for Router<P, C, R>
+where
+    P: Partitioner + Send + Sync,
+    C: GroupClient + Send + Sync,
+    R: RoutingSource<P> + Send + Sync,
+{
+    fn shard_for(&self, key: &[u8]) -> TransactionResult<ShardIdentifier> {
+        Ok(Router::shard_for(self, key)?)
+    }
+
+    async fn write(&self, operation: Operation) -> TransactionResult<OperationResult> {
+        Ok(Router::write(self, operation).await?)
+    }
+
+    async fn submit(
+        &self,
+        shard: ShardIdentifier,
+        operation: Operation,
+    ) -> TransactionResult<OperationResult> {
+        Ok(Router::submit(self, shard, operation).await?)
+    }
+
+    async fn record(
+        &self,
+        transaction: TransactionId,
+    ) -> TransactionResult<Option<VersionedValue>> {
+        Ok(Router::transaction_record(self, transaction).await?)
+    }
+}
+
+/// Drives two-phase commit across the shards a transaction writes to.
+///
+/// Every step is committed through a replicated log - participants prepare,
+/// commit and abort through their own shard's log, and the decision is made
+/// by a compare-and-upsert on the transaction record, itself stored in a
+/// shard out of the reach of client writes. A coordinator failing halfway through therefore never blocks the
+/// participants: anyone can pick the transaction up again with `recover`.
+pub struct Coordinator<C> {
+    client: C,
+}
+
+impl<C: ShardClient + Send + Sync> Coordinator<C> {
+    pub fn new(client: C) -> Self {
+        Self { client }
+    }
+
+    pub fn client(&self) -> &C {
+        &self.client
+    }
+
+    /// Atomically applies the writes, which may span any number of shards -
+    /// the transaction is aborted if any of them conflicts with a prepared
+    /// transaction or fails its version check.
+    pub async fn execute(
+        &self,
+        transaction: TransactionId,
+        writes: Vec<Operation>,
+    ) -> TransactionResult<Decision> {
+        let mut participants: BTreeMap<ShardIdentifier, Vec<Operation>> = BTreeMap::new();
+
+        for write in writes {
+            let key = write.key().ok_or(TransactionError::NotAWrite)?;
+
+            participants
+                .entry(self.client.shard_for(key)?)
+                .or_default()
+                .push(write);
+        }
+
+        let record = TransactionRecord {
+            participants: participants.keys().copied().collect(),
+            decision: None,
+        };
+        let version = match self
+            .client
+            .write(Operation::Record(transaction, record.encode(), None))
+            .await?
+        {
+            OperationResult::Written(version) => version,
+            OperationResult::VersionMismatch(_) => {
+                return Err(TransactionError::AlreadyExists(transaction))
+            }
+            result => return Err(TransactionError::Unexpected(result)),
+        };
+
+        let results = try_join_all(participants.into_iter().map(|(shard, writes)| {
+            self.client
+                .submit(shard, Operation::Prepare(transaction, writes))
+        }))
+        .await?;
+        let decision = match results
+            .iter()
+            .all(|result| *result == OperationResult::Prepared)
+        {
+            true => Decision::Commit,
+            false => Decision::Abort,
+        };
+
+        self.decide(transaction, record, version, decision).await
+    }
+
+    /// Finishes a transaction its coordinator has left behind - one that
+    /// hasn't been decided yet is aborted, as some participants might never
+    /// have prepared.
+    pub async fn recover(&self, transaction: TransactionId) -> TransactionResult<Decision> {
+        let value = self
+            .client
+            .record(transaction)
+            .await?
+            .ok_or(TransactionError::UnknownTransaction(transaction))?;
+        let record = TransactionRecord::decode(&value.value)?;
+
+        match record.decision {
+            Some(decision) => {
+                self.finish(transaction, &record.participants, decision)
+                    .await?;
+
+                Ok(decision)
+            }
+            None => {
+                self.decide(transaction, record, value.version, Decision::Abort)
+                    .await
+            }
+        }
+    }
+
+    // the first decision recorded wins, whoever else tried to decide the
+    // transaction in the meantime has to go with it
+    async fn decide(
+        &self,
+        transaction: TransactionId,
+        mut record: TransactionRecord,
+        version: u64,
+        decision: Decision,
+    ) -> TransactionResult<Decision> {
+        record.decision = Some(decision);
+
+        let decision = match self
+            .client
+            .write(Operation::Record(
+                transaction,
+                record.encode(),
+                Some(version),
+            ))
+            .await?
+        {
+            OperationResult::Written(_) => decision,
+            OperationResult::VersionMismatch(_) => {
+                let value = self
+                    .client
+                    .record(transaction)
+                    .await?
+                    .ok_or(TransactionError::UnknownTransaction(transaction))?;
+
+                TransactionRecord::decode(&value.value)?
+                    .decision
+                    .ok_or(TransactionError::UnknownTransaction(transaction))?
+            }
+            result => return Err(TransactionError::Unexpected(result)),
+        };
+
+        self.finish(transaction, &record.participants, decision)
+            .await?;
+
+        Ok(decision)
+    }
+
+    async fn finish(
+        &self,
+        transaction: TransactionId,
+        participants: &[ShardIdentifier],
+        decision: Decision,
+    ) -> TransactionResult<()> {
+        let operation = match decision {
+            Decision::Commit => Operation::Commit(transaction),
+            Decision::Abort => Operation::Abort(transaction),
+        };
+        let results = try_join_all(
+            participants
+                .iter()
+                .map(|shard| self.client.submit(*shard, operation.clone())),
+        )
+        .await?;
+
+        // a commit that finds nothing to commit has already been applied
+        if let Some(result) = results.into_iter().find(|result| {
+            !matches!(
+                result,
+                OperationResult::Committed(_) | OperationResult::Aborted | OperationResult::NoOp
+            )
+        }) {
+            return Err(TransactionError::Unexpected(result));
+        }
+
+        match self.client.write(Operation::Forget(transaction)).await? {
+            OperationResult::Deleted => Ok(()),
+            result => Err(TransactionError::Unexpected(result)),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum TransactionError {
+    #[error("Transaction {} already exists!", .0)]
+    AlreadyExists(TransactionId),
+    #[error("Transaction {} is unknown!", .0)]
+    UnknownTransaction(TransactionId),
+    #[error("Only upserts, compare-and-upserts and deletes can be part of a transaction!")]
+    NotAWrite,
+    #[error("Transaction record is malformed!")]
+    MalformedRecord,
+    #[error("Shard answered with an unexpected result! {:?}", .0)]
+    Unexpected(OperationResult),
+    #[error("Router error: {}", .0)]
+    Router(RouterError),
+    #[error("Storage error: {}", .0)]
+    Storage(StorageError),
+}
+
+impl From<RouterError> for TransactionError {
+    fn from(value: RouterError) -> Self {
+        Self::Router(value)
+    }
+}
+
+impl From<StorageError> for TransactionError {
+    fn from(value: StorageError) -> Self {
+        Self::Storage(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use futures::executor::block_on;
+    use togo_vr::state::OperationContext;
+
+    use crate::{
+        kv::{KvStateMachine, VersionedValue},
+        operation::{Operation, OperationResult},
+        shard::{range::RangeMap, ShardIdentifier},
+        storage::{memory::MemoryStorage, Get},
+    };
+
+    use super::{
+        Coordinator, Decision, ShardClient, TransactionError, TransactionId, TransactionRecord,
+        TransactionResult,
+    };
+
+    // two shards split at "m", applying operations as soon as they're
+    // submitted
+    struct Shards {
+        map: RangeMap,
+        shards: Vec<KvStateMachine<MemoryStorage>>,
+        op_number: AtomicU64,
+    }
+
+    #[async_trait]
+    impl ShardClient for Shards {
+        fn shard_for(&self, key: &[u8]) -> TransactionResult<ShardIdentifier> {
+            Ok(self.map.shard_for(key))
+        }
+
+        async fn write(&self, operation: Operation) -> TransactionResult<OperationResult> {
+            let shard = self.shard_for(&operation.routing_key().unwrap())?;
+
+            self.submit(shard, operation).await
+        }
+
+        async fn submit(
+            &self,
+            shard: ShardIdentifier,
+            operation: Operation,
+        ) -> TransactionResult<OperationResult> {
+            let context = OperationContext {
+                op_number: self.op_number.fetch_add(1, Ordering::AcqRel) + 1,
+                timestamp: 0,
+                seed: 0,
+            };
+
+            Ok(self.shards[shard.0 as usize].apply(&context, &operation)?)
+        }
+
+        async fn record(
+            &self,
+            transaction: TransactionId,
+        ) -> TransactionResult<Option<VersionedValue>> {
+            let shard = self.shard_for(&TransactionRecord::key(transaction))?;
+
+            Ok(self.shards[shard.0 as usize].transaction_record(transaction)?)
+        }
+    }
+
+    impl Shards {
+        fn get(&self, key: &'static str) -> Option<VersionedValue> {
+            let shard = self.map.shard_for(key.as_bytes());
+
+            self.shards[shard.0 as usize].get(key).unwrap()
+        }
+    }
+
+    fn upsert(key: &'static str, value: &'static str) -> Operation {
+        Operation::Upsert(Bytes::from(key), Bytes::from(value), None)
+    }
+
+    #[test]
+    pub fn transactions_commit_atomically_and_recover_after_a_coordinator_failure() {
+        let mut map = RangeMap::new(ShardIdentifier(0));
+
+        map.split(ShardIdentifier(0), "m".into(), ShardIdentifier(1))
+            .unwrap();
+
+        let shards = map
+            .ranges()
+            .map(|(_, range)| KvStateMachine::new(MemoryStorage::new()).with_range(range))
+            .collect();
+        let coordinator = Coordinator::new(Shards {
+            map,
+            shards,
+            op_number: AtomicU64::new(0),
+        });
+        let client = coordinator.client();
+
+        block_on(async {
+            let decision = coordinator
+                .execute(
+                    TransactionId(1),
+                    vec![upsert("apple", "red"), upsert("plum", "purple")],
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(decision, Decision::Commit);
+
+            let apple = client.get("apple").unwrap();
+            let plum = client.get("plum").unwrap();
+
+            assert_eq!(apple.value, Bytes::from("red"));
+            assert_eq!(plum.value, Bytes::from("purple"));
+            assert!(client.record(TransactionId(1)).await.unwrap().is_none());
+
+            // a failed version check aborts the whole transaction
+            let decision = coordinator
+                .execute(
+                    TransactionId(2),
+                    vec![
+                        upsert("kiwi", "green"),
+                        Operation::CompareAndUpsert(
+                            Bytes::from("plum"),
+                            Bytes::from("blue"),
+                            Some(plum.version + 1),
+                        ),
+                    ],
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(decision, Decision::Abort);
+            assert!(client.get("kiwi").is_none());
+
+            // the coordinator fails right after preparing
+            let record = TransactionRecord {
+                participants: vec![ShardIdentifier(0)],
+                decision: None,
+            };
+
+            client
+                .write(Operation::Record(TransactionId(3), record.encode(), None))
+                .await
+                .unwrap();
+            client
+                .submit(
+                    ShardIdentifier(0),
+                    Operation::Prepare(TransactionId(3), vec![upsert("apple", "green")]),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(
+                client.write(upsert("apple", "yellow")).await.unwrap(),
+                OperationResult::Locked(TransactionId(3))
+            );
+            assert!(matches!(
+                coordinator
+                    .execute(TransactionId(3), vec![upsert("kiwi", "green")])
+                    .await,
+                Err(TransactionError::AlreadyExists(_))
+            ));
+            // clients can't forge a decision, nor erase the record
+            let forged = TransactionRecord {
+                decision: Some(Decision::Commit),
+                ..record
+            };
+            let key = TransactionRecord::key(TransactionId(3));
+
+            client
+                .write(Operation::Upsert(key.clone(), forged.encode(), None))
+                .await
+                .unwrap();
+            client.write(Operation::Delete(key)).await.unwrap();
+
+            assert_eq!(
+                coordinator.recover(TransactionId(3)).await.unwrap(),
+                Decision::Abort
+            );
+            assert!(matches!(
+                client.write(upsert("apple", "yellow")).await.unwrap(),
+                OperationResult::Written(_)
+            ));
+        });
+    }
+}
@@ -0,0 +1,574 @@
+use std::rc::Rc;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use thiserror::Error;
+
+use crate::{
+    log::LogEntry,
+    message::{
+        Batch, CheckpointChunkMessage, ClientMessage, ClusterMessage, ClusterMessageEnvelope,
+        CommitMessage, EntryMessage, GetCheckpointMessage, GetStateMessage, NewStateMessage,
+        PrepareMessage, PrepareOkMessage,
+    },
+    replica::{client::ClientIdentity, client::ClientRequest, GroupIdentifier, ReplicaIdentity},
+};
+
+pub type CodecResult<T> = Result<T, CodecError>;
+
+/// Binary encoding of whatever is sent to other nodes or persisted in a
+/// durable log - integers are big-endian, and byte strings and sequences are
+/// prefixed with their length as a `u32`.
+pub trait Codec: Sized {
+    fn encode(&self, buffer: &mut BytesMut);
+    fn decode(data: &mut &[u8]) -> CodecResult<Self>;
+
+    fn to_bytes(&self) -> Bytes {
+        let mut buffer = BytesMut::new();
+
+        self.encode(&mut buffer);
+        buffer.freeze()
+    }
+
+    /// Decodes a value that has to span all of the data.
+    fn from_bytes(mut data: &[u8]) -> CodecResult<Self> {
+        let value = Self::decode(&mut data)?;
+
+        match data.has_remaining() {
+            true => Err(CodecError::TrailingBytes),
+            false => Ok(value),
+        }
+    }
+}
+
+pub fn get_u8(data: &mut &[u8]) -> CodecResult<u8> {
+    match data.remaining() {
+        1.. => Ok(data.get_u8()),
+        _ => Err(CodecError::UnexpectedEnd),
+    }
+}
+
+pub fn get_u32(data: &mut &[u8]) -> CodecResult<u32> {
+    match data.remaining() {
+        4.. => Ok(data.get_u32()),
+        _ => Err(CodecError::UnexpectedEnd),
+    }
+}
+
+pub fn get_u64(data: &mut &[u8]) -> CodecResult<u64> {
+    match data.remaining() {
+        8.. => Ok(data.get_u64()),
+        _ => Err(CodecError::UnexpectedEnd),
+    }
+}
+
+impl Codec for u64 {
+    fn encode(&self, buffer: &mut BytesMut) {
+        buffer.put_u64(*self);
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        get_u64(data)
+    }
+}
+
+impl Codec for bool {
+    fn encode(&self, buffer: &mut BytesMut) {
+        buffer.put_u8(*self as u8);
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        match get_u8(data)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => Err(CodecError::UnknownTag("bool", tag)),
+        }
+    }
+}
+
+impl Codec for Bytes {
+    fn encode(&self, buffer: &mut BytesMut) {
+        buffer.put_u32(self.len() as u32);
+        buffer.put_slice(self);
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        let length = get_u32(data)? as usize;
+
+        if data.remaining() < length {
+            return Err(CodecError::UnexpectedEnd);
+        }
+
+        let bytes = Bytes::copy_from_slice(&data[..length]);
+
+        data.advance(length);
+
+        Ok(bytes)
+    }
+}
+
+impl Codec for String {
+    fn encode(&self, buffer: &mut BytesMut) {
+        buffer.put_u32(self.len() as u32);
+        buffer.put_slice(self.as_bytes());
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        String::from_utf8(Bytes::decode(data)?.to_vec())
+            .map_err(|_| CodecError::Malformed("string".into()))
+    }
+}
+
+impl<T: Codec> Codec for Option<T> {
+    fn encode(&self, buffer: &mut BytesMut) {
+        match self {
+            Some(value) => {
+                buffer.put_u8(1);
+                value.encode(buffer);
+            }
+            None => buffer.put_u8(0),
+        }
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        match get_u8(data)? {
+            0 => Ok(None),
+            1 => T::decode(data).map(Some),
+            tag => Err(CodecError::UnknownTag("option", tag)),
+        }
+    }
+}
+
+impl<T: Codec> Codec for Vec<T> {
+    fn encode(&self, buffer: &mut BytesMut) {
+        buffer.put_u32(self.len() as u32);
+
+        for value in self {
+            value.encode(buffer);
+        }
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        (0..get_u32(data)?).map(|_| T::decode(data)).collect()
+    }
+}
+
+impl Codec for ReplicaIdentity {
+    fn encode(&self, buffer: &mut BytesMut) {
+        buffer.put_u32(self.0);
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        get_u32(data).map(ReplicaIdentity)
+    }
+}
+
+impl Codec for GroupIdentifier {
+    fn encode(&self, buffer: &mut BytesMut) {
+        buffer.put_u32(self.0);
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        get_u32(data).map(GroupIdentifier)
+    }
+}
+
+impl Codec for ClientIdentity {
+    fn encode(&self, buffer: &mut BytesMut) {
+        buffer.put_u64(self.0);
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        get_u64(data).map(ClientIdentity)
+    }
+}
+
+impl<O: Codec> Codec for ClientRequest<O> {
+    fn encode(&self, buffer: &mut BytesMut) {
+        match self {
+            ClientRequest::Register => buffer.put_u8(0),
+            ClientRequest::Operation(operation) => {
+                buffer.put_u8(1);
+                operation.encode(buffer);
+            }
+        }
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        match get_u8(data)? {
+            0 => Ok(ClientRequest::Register),
+            1 => O::decode(data).map(ClientRequest::Operation),
+            tag => Err(CodecError::UnknownTag("client request", tag)),
+        }
+    }
+}
+
+impl<O: Codec> Codec for ClientMessage<O> {
+    fn encode(&self, buffer: &mut BytesMut) {
+        buffer.put_u64(self.session);
+        buffer.put_u64(self.request_number);
+        self.request.encode(buffer);
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        Ok(Self {
+            session: get_u64(data)?,
+            request_number: get_u64(data)?,
+            request: ClientRequest::decode(data)?,
+        })
+    }
+}
+
+impl<O: Codec> Codec for LogEntry<O> {
+    fn encode(&self, buffer: &mut BytesMut) {
+        self.client.encode(buffer);
+        buffer.put_u64(self.request_number);
+        buffer.put_u64(self.timestamp);
+        buffer.put_u64(self.seed);
+        self.operation.encode(buffer);
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        Ok(Self {
+            client: ClientIdentity::decode(data)?,
+            request_number: get_u64(data)?,
+            timestamp: get_u64(data)?,
+            seed: get_u64(data)?,
+            operation: Rc::new(ClientRequest::decode(data)?),
+        })
+    }
+}
+
+impl<T: Codec> Codec for Batch<T> {
+    fn encode(&self, buffer: &mut BytesMut) {
+        buffer.put_u32(self.messages().len() as u32);
+
+        for message in self.messages() {
+            message.encode(buffer);
+        }
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        Vec::decode(data).map(Batch::from)
+    }
+}
+
+impl<O: Codec> Codec for ClusterMessageEnvelope<O> {
+    fn encode(&self, buffer: &mut BytesMut) {
+        self.sender.encode(buffer);
+        self.group.encode(buffer);
+        self.content.encode(buffer);
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        Ok(Self {
+            sender: ReplicaIdentity::decode(data)?,
+            group: GroupIdentifier::decode(data)?,
+            content: ClusterMessage::decode(data)?,
+        })
+    }
+}
+
+impl<O: Codec> Codec for ClusterMessage<O> {
+    fn encode(&self, buffer: &mut BytesMut) {
+        match self {
+            ClusterMessage::Prepare(message) => {
+                buffer.put_u8(0);
+                message.requesting_replica.encode(buffer);
+                buffer.put_u64(message.view_number);
+                buffer.put_u64(message.op_number);
+                buffer.put_u64(message.commit_number);
+                buffer.put_u64(message.timestamp);
+                buffer.put_u64(message.seed);
+                message.client.encode(buffer);
+                message.request.encode(buffer);
+                buffer.put_u64(message.request_number);
+            }
+            ClusterMessage::PrepareOk(message) => {
+                buffer.put_u8(1);
+                message.replica.encode(buffer);
+                buffer.put_u64(message.view_number);
+                buffer.put_u64(message.op_number);
+            }
+            ClusterMessage::Commit(message) => {
+                buffer.put_u8(2);
+                message.encode(buffer);
+            }
+            ClusterMessage::GetState(message) => {
+                buffer.put_u8(3);
+                message.replica.encode(buffer);
+                buffer.put_u64(message.view_number);
+                buffer.put_u64(message.op_number);
+            }
+            ClusterMessage::NewState(message) => {
+                buffer.put_u8(4);
+                message.replica.encode(buffer);
+                buffer.put_u64(message.view_number);
+                buffer.put_u64(message.commit_number);
+                message.checkpoint_op_number.encode(buffer);
+                buffer.put_u64(message.first_op_number);
+                message.entries.encode(buffer);
+            }
+            ClusterMessage::GetCheckpoint(message) => {
+                buffer.put_u8(5);
+                message.replica.encode(buffer);
+                buffer.put_u64(message.view_number);
+                buffer.put_u64(message.op_number);
+                buffer.put_u64(message.offset);
+            }
+            ClusterMessage::CheckpointChunk(message) => {
+                buffer.put_u8(6);
+                message.replica.encode(buffer);
+                buffer.put_u64(message.view_number);
+                buffer.put_u64(message.op_number);
+                buffer.put_u64(message.offset);
+                message.data.encode(buffer);
+                message.last.encode(buffer);
+            }
+            ClusterMessage::StartViewChange => buffer.put_u8(7),
+            ClusterMessage::DoViewChange => buffer.put_u8(8),
+            ClusterMessage::StartView => buffer.put_u8(9),
+        }
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        match get_u8(data)? {
+            0 => Ok(ClusterMessage::Prepare(PrepareMessage {
+                requesting_replica: ReplicaIdentity::decode(data)?,
+                view_number: get_u64(data)?,
+                op_number: get_u64(data)?,
+                commit_number: get_u64(data)?,
+                timestamp: get_u64(data)?,
+                seed: get_u64(data)?,
+                client: ClientIdentity::decode(data)?,
+                request: ClientRequest::decode(data)?,
+                request_number: get_u64(data)?,
+            })),
+            1 => Ok(ClusterMessage::PrepareOk(PrepareOkMessage {
+                replica: ReplicaIdentity::decode(data)?,
+                view_number: get_u64(data)?,
+                op_number: get_u64(data)?,
+            })),
+            2 => CommitMessage::decode(data).map(ClusterMessage::Commit),
+            3 => Ok(ClusterMessage::GetState(GetStateMessage {
+                replica: ReplicaIdentity::decode(data)?,
+                view_number: get_u64(data)?,
+                op_number: get_u64(data)?,
+            })),
+            4 => Ok(ClusterMessage::NewState(NewStateMessage {
+                replica: ReplicaIdentity::decode(data)?,
+                view_number: get_u64(data)?,
+                commit_number: get_u64(data)?,
+                checkpoint_op_number: Option::decode(data)?,
+                first_op_number: get_u64(data)?,
+                entries: Vec::decode(data)?,
+            })),
+            5 => Ok(ClusterMessage::GetCheckpoint(GetCheckpointMessage {
+                replica: ReplicaIdentity::decode(data)?,
+                view_number: get_u64(data)?,
+                op_number: get_u64(data)?,
+                offset: get_u64(data)?,
+            })),
+            6 => Ok(ClusterMessage::CheckpointChunk(CheckpointChunkMessage {
+                replica: ReplicaIdentity::decode(data)?,
+                view_number: get_u64(data)?,
+                op_number: get_u64(data)?,
+                offset: get_u64(data)?,
+                data: Bytes::decode(data)?,
+                last: bool::decode(data)?,
+            })),
+            7 => Ok(ClusterMessage::StartViewChange),
+            8 => Ok(ClusterMessage::DoViewChange),
+            9 => Ok(ClusterMessage::StartView),
+            tag => Err(CodecError::UnknownTag("cluster message", tag)),
+        }
+    }
+}
+
+impl Codec for CommitMessage {
+    fn encode(&self, buffer: &mut BytesMut) {
+        buffer.put_u64(self.view_number);
+        buffer.put_u64(self.commit_number);
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        Ok(Self {
+            view_number: get_u64(data)?,
+            commit_number: get_u64(data)?,
+        })
+    }
+}
+
+impl<O: Codec> Codec for EntryMessage<O> {
+    fn encode(&self, buffer: &mut BytesMut) {
+        self.client.encode(buffer);
+        buffer.put_u64(self.request_number);
+        buffer.put_u64(self.timestamp);
+        buffer.put_u64(self.seed);
+        self.request.encode(buffer);
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        Ok(Self {
+            client: ClientIdentity::decode(data)?,
+            request_number: get_u64(data)?,
+            timestamp: get_u64(data)?,
+            seed: get_u64(data)?,
+            request: ClientRequest::decode(data)?,
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum CodecError {
+    #[error("Data ended unexpectedly!")]
+    UnexpectedEnd,
+    #[error("Data continues past the end of the value!")]
+    TrailingBytes,
+    #[error("Unknown {} tag {}!", .0, .1)]
+    UnknownTag(&'static str, u8),
+    #[error("Malformed {}!", .0)]
+    Malformed(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::{
+        message::{
+            BatchedClusterMessage, CheckpointChunkMessage, ClusterMessage, ClusterMessageEnvelope,
+            EntryMessage, NewStateMessage, PrepareMessage,
+        },
+        replica::{
+            client::{ClientIdentity, ClientRequest},
+            GroupIdentifier, ReplicaIdentity,
+        },
+    };
+
+    use super::{Codec, CodecError};
+
+    fn envelope(content: ClusterMessage<u64>) -> ClusterMessageEnvelope<u64> {
+        ClusterMessageEnvelope {
+            sender: ReplicaIdentity(2),
+            group: GroupIdentifier(5),
+            content,
+        }
+    }
+
+    fn batch() -> BatchedClusterMessage<u64> {
+        BatchedClusterMessage::from(vec![
+            envelope(ClusterMessage::Prepare(PrepareMessage {
+                requesting_replica: ReplicaIdentity(1),
+                view_number: 3,
+                op_number: 10,
+                commit_number: 9,
+                timestamp: 1234,
+                seed: 99,
+                client: ClientIdentity(7),
+                request: ClientRequest::Operation(42),
+                request_number: 4,
+            })),
+            envelope(ClusterMessage::NewState(NewStateMessage {
+                replica: ReplicaIdentity(1),
+                view_number: 3,
+                commit_number: 9,
+                checkpoint_op_number: Some(8),
+                first_op_number: 9,
+                entries: vec![EntryMessage {
+                    client: ClientIdentity(7),
+                    request_number: 1,
+                    timestamp: 1000,
+                    seed: 11,
+                    request: ClientRequest::Register,
+                }],
+            })),
+            envelope(ClusterMessage::CheckpointChunk(CheckpointChunkMessage {
+                replica: ReplicaIdentity(1),
+                view_number: 3,
+                op_number: 8,
+                offset: 16,
+                data: Bytes::from_static(b"checkpoint"),
+                last: true,
+            })),
+        ])
+    }
+
+    #[test]
+    pub fn messages_survive_a_round_trip() {
+        let encoded = batch().to_bytes();
+        let decoded = BatchedClusterMessage::<u64>::from_bytes(&encoded).unwrap();
+        let messages = decoded.messages();
+
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].sender, ReplicaIdentity(2));
+        assert_eq!(messages[0].group, GroupIdentifier(5));
+
+        match &messages[0].content {
+            ClusterMessage::Prepare(message) => {
+                assert_eq!(message.op_number, 10);
+                assert_eq!(message.seed, 99);
+                assert!(matches!(message.request, ClientRequest::Operation(42)));
+            }
+            _ => panic!("Expected a prepare!"),
+        }
+
+        match &messages[1].content {
+            ClusterMessage::NewState(message) => {
+                assert_eq!(message.checkpoint_op_number, Some(8));
+                assert_eq!(message.entries.len(), 1);
+                assert!(matches!(
+                    message.entries[0].request,
+                    ClientRequest::Register
+                ));
+            }
+            _ => panic!("Expected a new state!"),
+        }
+
+        match &messages[2].content {
+            ClusterMessage::CheckpointChunk(message) => {
+                assert_eq!(message.data, Bytes::from_static(b"checkpoint"));
+                assert!(message.last);
+            }
+            _ => panic!("Expected a checkpoint chunk!"),
+        }
+
+        assert_eq!(decoded.to_bytes(), encoded);
+    }
+
+    #[test]
+    pub fn truncated_and_malformed_data_is_rejected() {
+        let encoded = batch().to_bytes();
+
+        for length in 0..encoded.len() {
+            assert!(matches!(
+                BatchedClusterMessage::<u64>::from_bytes(&encoded[..length]),
+                Err(CodecError::UnexpectedEnd)
+            ));
+        }
+
+        let mut trailing = encoded.to_vec();
+
+        trailing.push(0);
+        assert!(matches!(
+            BatchedClusterMessage::<u64>::from_bytes(&trailing),
+            Err(CodecError::TrailingBytes)
+        ));
+
+        // the first message's tag follows the batch length, sender and group
+        let mut unknown = encoded.to_vec();
+
+        unknown[12] = 10;
+        assert!(matches!(
+            BatchedClusterMessage::<u64>::from_bytes(&unknown),
+            Err(CodecError::UnknownTag("cluster message", 10))
+        ));
+
+        let mut flag = encoded.to_vec();
+
+        *flag.last_mut().unwrap() = 2;
+        assert!(matches!(
+            BatchedClusterMessage::<u64>::from_bytes(&flag),
+            Err(CodecError::UnknownTag("bool", 2))
+        ));
+    }
+}
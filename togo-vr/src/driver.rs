@@ -0,0 +1,642 @@
+use std::{cmp::Ordering, future::Future, mem};
+
+use futures::{
+    channel::{mpsc, oneshot},
+    pin_mut, select, FutureExt, Stream, StreamExt,
+};
+use thiserror::Error;
+
+use crate::{
+    log::{Footprint, Log, LogEntry},
+    message::{BatchedClusterMessage, ClientMessage, ClusterMessage, ClusterMessageEnvelope},
+    replica::{
+        client::{ClientIdentity, ClientReply, ClientRequest, RequestNumber, SessionId},
+        Replica, ReplicaError, ReplicaIdentity, ReplicaResult,
+    },
+    state::{Checkpoint, StateMachine},
+    transport::{TransportChannel, TransportError},
+};
+
+pub type DriverResult<T> = Result<T, DriverError>;
+
+type Job<R> = Box<dyn FnOnce(&mut R) + Send>;
+type ReplicaCommand<O, OR, T, L, S> = Command<O, OR, Replica<O, OR, T, L, S>>;
+
+/// Ticks a submitted request may wait to be committed before its client is
+/// told that it timed out.
+pub const REQUEST_TIMEOUT: u64 = 100;
+
+/// Runs a replica - receives its messages, feeds it client requests and
+/// ticks, and sends whatever it has to say in between. Clients reach it
+/// through `DriverHandle`s, which can be used from any task.
+pub struct Driver<O, OR, T, L, S>
+where
+    T: TransportChannel<ReplicaIdentity, BatchedClusterMessage<O>>,
+    L: Log<LogEntry<O>>,
+    S: StateMachine<O, OR>,
+{
+    replica: Replica<O, OR, T, L, S>,
+    sender: mpsc::UnboundedSender<ReplicaCommand<O, OR, T, L, S>>,
+    commands: mpsc::UnboundedReceiver<ReplicaCommand<O, OR, T, L, S>>,
+    // requests submitted to the log, answered once committed
+    waiters: Vec<Waiter<OR>>,
+    request_timeout: u64,
+    reporter: Option<Box<dyn FnMut(ReplicaError)>>,
+}
+
+pub struct DriverHandle<O, OR, R> {
+    sender: mpsc::UnboundedSender<Command<O, OR, R>>,
+}
+
+enum Command<O, OR, R> {
+    Submit {
+        client: ClientIdentity,
+        message: ClientMessage<O>,
+        reply: oneshot::Sender<DriverResult<ClientReply<OR>>>,
+    },
+    Run(Job<R>),
+}
+
+struct Waiter<OR> {
+    client: ClientIdentity,
+    awaited: Awaited,
+    // ticks since the request was submitted
+    ticks: u64,
+    reply: oneshot::Sender<DriverResult<ClientReply<OR>>>,
+}
+
+enum Awaited {
+    Session {
+        previous: Option<SessionId>,
+    },
+    Request {
+        session: SessionId,
+        request_number: RequestNumber,
+    },
+}
+
+impl<O, OR, T, L, S> Driver<O, OR, T, L, S>
+where
+    O: Clone + Footprint,
+    OR: Clone,
+    T: TransportChannel<ReplicaIdentity, BatchedClusterMessage<O>>,
+    L: Log<LogEntry<O>>,
+    S: StateMachine<O, OR> + Checkpoint<OR>,
+{
+    pub fn new(replica: Replica<O, OR, T, L, S>) -> Self {
+        let (sender, commands) = mpsc::unbounded();
+
+        Self {
+            replica,
+            sender,
+            commands,
+            waiters: Vec::new(),
+            request_timeout: REQUEST_TIMEOUT,
+            reporter: None,
+        }
+    }
+
+    /// Errors of individual messages and ticks don't stop the driver, they're
+    /// handed to the reporter instead.
+    pub fn with_reporter<F: FnMut(ReplicaError) + 'static>(mut self, reporter: F) -> Self {
+        self.reporter = Some(Box::new(reporter));
+        self
+    }
+
+    /// Requests that haven't been committed within `ticks` ticks are answered
+    /// with `DriverError::TimedOut` - they may still be committed later, e.g.
+    /// once the replica is back in touch with a quorum.
+    pub fn with_request_timeout(mut self, ticks: u64) -> Self {
+        self.request_timeout = ticks;
+        self
+    }
+
+    pub fn handle(&self) -> DriverHandle<O, OR, Replica<O, OR, T, L, S>> {
+        DriverHandle {
+            sender: self.sender.clone(),
+        }
+    }
+
+    pub fn replica(&self) -> &Replica<O, OR, T, L, S> {
+        &self.replica
+    }
+
+    /// Drives the replica until `shutdown` resolves, handing it back then.
+    ///
+    /// The transport's `receive` is expected to return `None` once nothing is
+    /// pending, `arrivals` yields whenever messages may have arrived - e.g.
+    /// `Multiplexer::arrivals` - and ends when the transport is closed. The
+    /// replica's timer advances on every item of `ticks`.
+    pub async fn run<A, K, F>(
+        mut self,
+        arrivals: A,
+        ticks: K,
+        shutdown: F,
+    ) -> DriverResult<Replica<O, OR, T, L, S>>
+    where
+        A: Stream<Item = Result<(), TransportError>>,
+        K: Stream,
+        F: Future,
+    {
+        let arrivals = arrivals.fuse();
+        let ticks = ticks.fuse();
+        let shutdown = shutdown.fuse();
+
+        pin_mut!(arrivals, ticks, shutdown);
+
+        loop {
+            select! {
+                arrival = arrivals.next() => match arrival {
+                    Some(Ok(())) => self.receive().await?,
+                    Some(Err(error)) => return Err(DriverError::Transport(error)),
+                    None => return Err(DriverError::Transport(TransportError::Closed)),
+                },
+                command = self.commands.next() => {
+                    if let Some(command) = command {
+                        self.execute(command);
+                    }
+                }
+                tick = ticks.next() => {
+                    if tick.is_some() {
+                        let result = self.replica.advance_time();
+
+                        self.report(result);
+
+                        for waiter in &mut self.waiters {
+                            waiter.ticks += 1;
+                        }
+                    }
+                }
+                _ = shutdown => break,
+            }
+
+            self.flush().await;
+            self.answer_waiters();
+        }
+
+        self.flush().await;
+
+        // whoever is still waiting learns that the driver has stopped once
+        // their reply is dropped
+        Ok(self.replica)
+    }
+
+    async fn receive(&mut self) -> DriverResult<()> {
+        while let Some(envelope) = self
+            .replica
+            .cluster_mut()
+            .receive()
+            .await
+            .map_err(DriverError::Transport)?
+        {
+            let result = self.dispatch(envelope);
+
+            self.report(result);
+        }
+
+        Ok(())
+    }
+
+    fn dispatch(&mut self, envelope: ClusterMessageEnvelope<O>) -> ReplicaResult<()> {
+        let replica = &mut self.replica;
+
+        match envelope.content {
+            ClusterMessage::Prepare(message) => replica.apply_prepare(message),
+            ClusterMessage::PrepareOk(message) => replica.apply_prepare_ok(message),
+            ClusterMessage::Commit(message) => replica.apply_commit(message),
+            ClusterMessage::GetState(message) => replica.apply_get_state(message),
+            ClusterMessage::NewState(message) => replica.apply_new_state(message),
+            ClusterMessage::GetCheckpoint(message) => replica.apply_get_checkpoint(message),
+            ClusterMessage::CheckpointChunk(message) => replica.apply_checkpoint_chunk(message),
+            // view changes aren't implemented by the replica yet
+            ClusterMessage::StartViewChange
+            | ClusterMessage::DoViewChange
+            | ClusterMessage::StartView => Ok(()),
+        }
+    }
+
+    fn execute(&mut self, command: Command<O, OR, Replica<O, OR, T, L, S>>) {
+        let (client, message, reply) = match command {
+            Command::Submit {
+                client,
+                message,
+                reply,
+            } => (client, message, reply),
+            Command::Run(job) => return job(&mut self.replica),
+        };
+        let awaited = match message.request {
+            ClientRequest::Register => Awaited::Session {
+                previous: self.replica.client_reply(client).map(|reply| reply.session),
+            },
+            ClientRequest::Operation(_) => Awaited::Request {
+                session: message.session,
+                request_number: message.request_number,
+            },
+        };
+
+        match self.replica.apply_request(client, message) {
+            Ok(()) => self.waiters.push(Waiter {
+                client,
+                awaited,
+                ticks: 0,
+                reply,
+            }),
+            Err(error) => {
+                let _ = reply.send(Err(DriverError::Replica(error)));
+            }
+        }
+    }
+
+    async fn flush(&mut self) {
+        let result = self
+            .replica
+            .cluster_mut()
+            .send_bufferred_messages()
+            .await
+            .map_err(ReplicaError::TransportIssue);
+
+        self.report(result);
+    }
+
+    fn answer_waiters(&mut self) {
+        for waiter in mem::take(&mut self.waiters) {
+            match waiter.answer(self.replica.client_reply(waiter.client)) {
+                Some(answer) => {
+                    let _ = waiter.reply.send(answer);
+                }
+                None if waiter.ticks >= self.request_timeout => {
+                    let _ = waiter.reply.send(Err(DriverError::TimedOut));
+                }
+                None => self.waiters.push(waiter),
+            }
+        }
+    }
+
+    fn report(&mut self, result: ReplicaResult<()>) {
+        if let (Err(error), Some(reporter)) = (result, self.reporter.as_mut()) {
+            reporter(error);
+        }
+    }
+}
+
+impl<OR: Clone> Waiter<OR> {
+    // the answer once the awaited request has been committed
+    fn answer(&self, reply: Option<&ClientReply<OR>>) -> Option<DriverResult<ClientReply<OR>>> {
+        match (&self.awaited, reply) {
+            (Awaited::Session { previous }, reply) => reply
+                .filter(|reply| Some(reply.session) != *previous)
+                .map(|reply| Ok(reply.clone())),
+            (
+                Awaited::Request {
+                    session,
+                    request_number,
+                },
+                Some(reply),
+            ) if reply.session == *session => match reply.request_number.cmp(request_number) {
+                Ordering::Less => None,
+                Ordering::Equal => Some(Ok(reply.clone())),
+                Ordering::Greater => Some(Err(DriverError::Superseded)),
+            },
+            // the session has been evicted while the request was in flight
+            (Awaited::Request { session, .. }, _) => {
+                Some(Err(DriverError::Replica(ReplicaError::SessionExpired {
+                    session: *session,
+                })))
+            }
+        }
+    }
+}
+
+impl<O, OR, R> DriverHandle<O, OR, R> {
+    /// Submits the client's request to the log, resolving with the client's
+    /// reply once the request has been committed.
+    pub async fn submit(
+        &self,
+        client: ClientIdentity,
+        message: ClientMessage<O>,
+    ) -> DriverResult<ClientReply<OR>> {
+        let (reply, answer) = oneshot::channel();
+
+        self.sender
+            .unbounded_send(Command::Submit {
+                client,
+                message,
+                reply,
+            })
+            .map_err(|_| DriverError::Stopped)?;
+
+        answer.await.map_err(|_| DriverError::Stopped)?
+    }
+
+    /// Runs the closure on the replica in between its events, e.g. to serve
+    /// a read from its state machine or to report its state - anything it
+    /// sends goes out right after.
+    pub async fn run<F, V>(&self, job: F) -> DriverResult<V>
+    where
+        F: FnOnce(&mut R) -> V + Send + 'static,
+        V: Send + 'static,
+    {
+        let (reply, answer) = oneshot::channel();
+        let job: Job<R> = Box::new(move |replica| {
+            let _ = reply.send(job(replica));
+        });
+
+        self.sender
+            .unbounded_send(Command::Run(job))
+            .map_err(|_| DriverError::Stopped)?;
+
+        answer.await.map_err(|_| DriverError::Stopped)
+    }
+}
+
+impl<O, OR, R> Clone for DriverHandle<O, OR, R> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DriverError {
+    #[error("Driver has stopped!")]
+    Stopped,
+    #[error("A newer request of the client has been committed in the meantime!")]
+    Superseded,
+    #[error("Request hasn't been committed in time!")]
+    TimedOut,
+    #[error("Replica error: {}", .0)]
+    Replica(ReplicaError),
+    #[error("Transport error: {}", .0)]
+    Transport(TransportError),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        collections::{BTreeMap, BTreeSet, VecDeque},
+        rc::Rc,
+        sync::{Arc, Mutex},
+    };
+
+    use async_trait::async_trait;
+    use futures::{
+        channel::mpsc, executor::LocalPool, future, stream, task::LocalSpawnExt, StreamExt,
+    };
+
+    use crate::{
+        log::{memory::MemoryLog, Footprint},
+        message::{BatchedClusterMessage, ClientMessage},
+        replica::{
+            client::{ClientIdentity, ClientRequest, ClientTable},
+            cluster::Cluster,
+            Replica, ReplicaError, ReplicaIdentity,
+        },
+        state::{
+            Checkpoint, CheckpointChunk, OperationContext, StateError, StateMachine, StateResult,
+        },
+        transport::{TransportChannel, TransportResult},
+    };
+
+    use super::{Driver, DriverError};
+
+    #[derive(Clone)]
+    struct Add(u64);
+
+    impl Footprint for Add {
+        fn footprint(&self) -> usize {
+            8
+        }
+    }
+
+    // answers every operation with the sum of everything added so far
+    struct Sum(Cell<u64>);
+
+    impl StateMachine<Add, u64> for Sum {
+        fn apply_operations(
+            &self,
+            operations: &[(OperationContext, &Add)],
+        ) -> StateResult<Vec<u64>> {
+            Ok(operations
+                .iter()
+                .map(|(_, Add(value))| {
+                    self.0.set(self.0.get() + value);
+                    self.0.get()
+                })
+                .collect())
+        }
+    }
+
+    impl Checkpoint<u64> for Sum {
+        fn checkpoint(&self, _: u64, _: u64, _: &ClientTable<u64>) -> StateResult<()> {
+            Ok(())
+        }
+
+        fn read_checkpoint(&self, _: u64, _: u64, _: usize) -> StateResult<CheckpointChunk> {
+            Err(StateError::CheckpointTransferFailed("Unsupported!".into()))
+        }
+
+        fn receive_checkpoint(&self, _: u64, _: u64, _: &[u8]) -> StateResult<()> {
+            Err(StateError::CheckpointTransferFailed("Unsupported!".into()))
+        }
+
+        fn install_checkpoint(&mut self, _: u64) -> StateResult<ClientTable<u64>> {
+            Err(StateError::CheckpointTransferFailed("Unsupported!".into()))
+        }
+
+        fn last_timestamp(&self) -> u64 {
+            0
+        }
+    }
+
+    // every replica's inbox, along with a way to let its driver know that
+    // something has arrived
+    type Inboxes = BTreeMap<
+        ReplicaIdentity,
+        (
+            VecDeque<BatchedClusterMessage<Add>>,
+            mpsc::UnboundedSender<()>,
+        ),
+    >;
+
+    struct Endpoint {
+        identity: ReplicaIdentity,
+        inboxes: Arc<Mutex<Inboxes>>,
+    }
+
+    #[async_trait]
+    impl TransportChannel<ReplicaIdentity, BatchedClusterMessage<Add>> for Endpoint {
+        async fn send(
+            &self,
+            recipient: ReplicaIdentity,
+            message: BatchedClusterMessage<Add>,
+        ) -> TransportResult<()> {
+            let mut inboxes = self.inboxes.lock().unwrap();
+            let (inbox, arrivals) = inboxes.get_mut(&recipient).unwrap();
+
+            inbox.push_back(message);
+            arrivals.unbounded_send(()).unwrap();
+            Ok(())
+        }
+
+        async fn receive(&self) -> TransportResult<Option<BatchedClusterMessage<Add>>> {
+            let mut inboxes = self.inboxes.lock().unwrap();
+
+            Ok(inboxes.get_mut(&self.identity).unwrap().0.pop_front())
+        }
+    }
+
+    fn message(session: u64, request_number: u64, value: u64) -> ClientMessage<Add> {
+        ClientMessage {
+            session,
+            request_number,
+            request: ClientRequest::Operation(Add(value)),
+        }
+    }
+
+    #[test]
+    pub fn requests_are_committed_and_answered_through_handles() {
+        let identities: BTreeSet<_> = (1..=3).map(ReplicaIdentity).collect();
+        let inboxes = Arc::new(Mutex::new(Inboxes::new()));
+        let mut pool = LocalPool::new();
+        let mut handles = Vec::new();
+
+        for identity in identities.iter().copied() {
+            let (poke, arrivals) = mpsc::unbounded();
+
+            inboxes
+                .lock()
+                .unwrap()
+                .insert(identity, (VecDeque::new(), poke));
+
+            let endpoint = Endpoint {
+                identity,
+                inboxes: inboxes.clone(),
+            };
+            let cluster = Cluster::bootstrap(endpoint, identities.clone()).unwrap();
+            let replica = Replica::new(identity, cluster, MemoryLog::new(), Sum(Cell::new(0)));
+            let driver = Driver::new(replica).with_reporter(|error| panic!("{error}"));
+
+            handles.push(driver.handle());
+            pool.spawner()
+                .spawn_local(async move {
+                    let arrivals = arrivals.map(Ok);
+
+                    driver
+                        .run(arrivals, stream::pending::<()>(), future::pending::<()>())
+                        .await
+                        .map(|_| ())
+                        .unwrap()
+                })
+                .unwrap();
+        }
+
+        let client = ClientIdentity(7);
+
+        pool.run_until(async {
+            let register = ClientMessage {
+                session: 0,
+                request_number: 0,
+                request: ClientRequest::Register,
+            };
+            let session = handles[0].submit(client, register).await.unwrap().session;
+
+            for value in 1..=3 {
+                let reply = handles[0]
+                    .submit(client, message(session, value, value))
+                    .await
+                    .unwrap();
+
+                assert_eq!(reply.request_number, value);
+                assert_eq!(reply.response, Some(value * (value + 1) / 2));
+            }
+
+            let rejected = handles[1].submit(client, message(session, 4, 4)).await;
+
+            assert!(matches!(
+                rejected,
+                Err(DriverError::Replica(ReplicaError::NotPrimary))
+            ));
+
+            let commit_number = handles[0]
+                .run(|replica| replica.state().commit_number())
+                .await
+                .unwrap();
+
+            assert_eq!(commit_number, 4);
+        });
+    }
+
+    #[test]
+    pub fn requests_without_a_quorum_time_out() {
+        let identities: BTreeSet<_> = (1..=3).map(ReplicaIdentity).collect();
+        let inboxes = Arc::new(Mutex::new(Inboxes::new()));
+        // only the primary runs, the backups' arrivals are never looked at
+        let mut arrivals: Vec<_> = identities
+            .iter()
+            .map(|identity| {
+                let (poke, arrivals) = mpsc::unbounded();
+
+                inboxes
+                    .lock()
+                    .unwrap()
+                    .insert(*identity, (VecDeque::new(), poke));
+                arrivals
+            })
+            .collect();
+        let endpoint = Endpoint {
+            identity: ReplicaIdentity(1),
+            inboxes: inboxes.clone(),
+        };
+        let cluster = Cluster::bootstrap(endpoint, identities.clone()).unwrap();
+        let replica = Replica::new(
+            ReplicaIdentity(1),
+            cluster,
+            MemoryLog::new(),
+            Sum(Cell::new(0)),
+        );
+        let driver = Driver::new(replica).with_request_timeout(3);
+        let handle = driver.handle();
+        let (tick, ticks) = mpsc::unbounded::<()>();
+        let answer = Rc::new(RefCell::new(None));
+        let mut pool = LocalPool::new();
+        let spawner = pool.spawner();
+        let primary_arrivals = arrivals.remove(0).map(Ok);
+
+        spawner
+            .spawn_local(async move {
+                let _ = driver
+                    .run(primary_arrivals, ticks, future::pending::<()>())
+                    .await;
+            })
+            .unwrap();
+        spawner
+            .spawn_local({
+                let answer = answer.clone();
+
+                async move {
+                    let register = ClientMessage {
+                        session: 0,
+                        request_number: 0,
+                        request: ClientRequest::Register,
+                    };
+
+                    *answer.borrow_mut() = Some(handle.submit(ClientIdentity(7), register).await);
+                }
+            })
+            .unwrap();
+
+        for _ in 0..2 {
+            pool.run_until_stalled();
+            tick.unbounded_send(()).unwrap();
+        }
+
+        pool.run_until_stalled();
+        assert!(answer.borrow().is_none());
+
+        tick.unbounded_send(()).unwrap();
+        pool.run_until_stalled();
+        assert!(matches!(*answer.borrow(), Some(Err(DriverError::TimedOut))));
+    }
+}
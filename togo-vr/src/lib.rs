@@ -1,5 +1,8 @@
+pub mod codec;
+pub mod driver;
 pub mod log;
 pub mod replica;
 pub mod message;
+pub mod multiplex;
 pub mod state;
 pub mod transport;
\ No newline at end of file
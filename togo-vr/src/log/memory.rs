@@ -36,7 +36,7 @@ impl<T> Log<T> for MemoryLog<T> {
     }
 
     fn get(&self, index: u64) -> LogResult<&T> {
-        if index < self.offset || index > self.current_size_with_offset() {
+        if index < self.offset || index >= self.current_size_with_offset() {
             return Err(LogError::InvalidIndex);
         }
 
@@ -45,12 +45,14 @@ impl<T> Log<T> for MemoryLog<T> {
         Ok(self.data.get(get_index as usize).unwrap())
     }
 
-    fn push(&mut self, value: T) {
+    fn push(&mut self, value: T) -> LogResult<()> {
         self.data.push_back(value);
+
+        Ok(())
     }
 
     fn trim_front(&mut self, first: u64) -> LogResult<()> {
-        if first < self.offset || first >= self.current_size_with_offset() {
+        if first < self.offset || first > self.current_size_with_offset() {
             return Err(LogError::InvalidIndex);
         }
 
@@ -77,6 +79,13 @@ impl<T> Log<T> for MemoryLog<T> {
 
         Ok(())
     }
+
+    fn reset(&mut self, offset: u64) -> LogResult<()> {
+        self.data.clear();
+        self.offset = offset;
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -89,10 +98,10 @@ mod tests {
     pub fn get_retrieves_correct_element() {
         let mut log = MemoryLog::new();
 
-        log.push(347);
-        log.push(11);
-        log.push(45);
-        log.push(125);
+        log.push(347).unwrap();
+        log.push(11).unwrap();
+        log.push(45).unwrap();
+        log.push(125).unwrap();
 
         assert_eq!(log.get(0).unwrap(), &347);
         assert_eq!(log.get(2).unwrap(), &45);
@@ -102,8 +111,8 @@ mod tests {
 
         assert_eq!(log.get(log.current_size_with_offset() - 1).unwrap(), &125);
 
-        log.push(2233);
-        log.push(111);
+        log.push(2233).unwrap();
+        log.push(111).unwrap();
 
         assert_eq!(log.get(4).unwrap(), &2233);
         assert_eq!(log.get(log.current_size_with_offset() - 1).unwrap(), &111);
@@ -117,14 +126,14 @@ mod tests {
     pub fn size_is_correct_when_pushed_and_trimmed() {
         let mut log = MemoryLog::new();
 
-        log.push(12);
-        log.push(12);
+        log.push(12).unwrap();
+        log.push(12).unwrap();
 
         assert_eq!(log.current_size(), 2);
         assert_eq!(log.current_size_with_offset(), 2);
 
-        log.push(12);
-        log.push(12);
+        log.push(12).unwrap();
+        log.push(12).unwrap();
 
         assert_eq!(log.current_size(), 4);
         assert_eq!(log.current_size_with_offset(), 4);
@@ -134,7 +143,7 @@ mod tests {
         assert_eq!(log.current_size(), 2);
         assert_eq!(log.current_size_with_offset(), 4);
 
-        log.push(12);
+        log.push(12).unwrap();
 
         assert_eq!(log.current_size(), 3);
         assert_eq!(log.current_size_with_offset(), 5);
@@ -143,5 +152,17 @@ mod tests {
 
         assert_eq!(log.current_size(), 1);
         assert_eq!(log.current_size_with_offset(), 3);
+
+        log.trim_front(3).unwrap();
+
+        assert_eq!(log.current_size(), 0);
+        assert_eq!(log.current_size_with_offset(), 3);
+        assert!(log.trim_front(4).is_err());
+
+        log.reset(10).unwrap();
+        log.push(12).unwrap();
+
+        assert_eq!(log.current_size(), 1);
+        assert_eq!(log.current_size_with_offset(), 11);
     }
 }
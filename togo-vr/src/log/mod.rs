@@ -1,5 +1,9 @@
+use std::rc::Rc;
+
 use thiserror::Error;
 
+use crate::replica::client::{ClientIdentity, ClientRequest, RequestNumber};
+
 pub mod memory;
 
 pub type LogResult<T> = Result<T, LogError>;
@@ -9,13 +13,31 @@ pub trait Log<T> {
     fn current_offset(&self) -> u64;
     fn current_size_with_offset(&self) -> u64;
     fn get(&self, index: u64) -> LogResult<&T>;
-    fn push(&mut self, value: T);
+    fn push(&mut self, value: T) -> LogResult<()>;
     fn trim_front(&mut self, first: u64) -> LogResult<()>;
     fn trim_end(&mut self, last: u64) -> LogResult<()>;
+    /// Drops every entry and continues the log at `offset`, e.g. after a
+    /// checkpoint has been installed.
+    fn reset(&mut self, offset: u64) -> LogResult<()>;
+}
+
+/// Rough size of an operation in bytes, used to compact the log by volume.
+pub trait Footprint {
+    fn footprint(&self) -> usize;
+}
+
+pub struct LogEntry<O> {
+    pub client: ClientIdentity,
+    pub request_number: RequestNumber,
+    pub timestamp: u64,
+    pub seed: u64,
+    pub operation: Rc<ClientRequest<O>>,
 }
 
 #[derive(Debug, Error)]
 pub enum LogError {
     #[error("Invalid index was supplied!")]
-    InvalidIndex
+    InvalidIndex,
+    #[error("Failed to persist the log! {}", .0)]
+    Persistence(String)
 }
\ No newline at end of file
@@ -1,45 +1,129 @@
-use crate::replica::{client::ClientIdentity, ReplicaIdentity};
+use bytes::Bytes;
+
+use crate::replica::{
+    client::{ClientIdentity, ClientRequest, SessionId},
+    GroupIdentifier, ReplicaIdentity,
+};
 
 pub type BatchedClusterMessage<T> = Batch<ClusterMessageEnvelope<T>>;
 
 pub struct Batch<T>(Vec<T>);
 
+impl<T> Batch<T> {
+    pub fn messages(&self) -> &[T] {
+        &self.0
+    }
+
+    pub fn into_messages(self) -> Vec<T> {
+        self.0
+    }
+}
+
+impl<T> From<Vec<T>> for Batch<T> {
+    fn from(value: Vec<T>) -> Self {
+        Self(value)
+    }
+}
+
+#[derive(Clone)]
 pub struct ClusterMessageEnvelope<T> {
     pub sender: ReplicaIdentity,
-    pub content: ClusterMessage<T>
+    /// The group both replicas belong to, for transports shared by several.
+    pub group: GroupIdentifier,
+    pub content: ClusterMessage<T>,
 }
 
+#[derive(Clone)]
 pub enum ClusterMessage<T> {
     Prepare(PrepareMessage<T>),
     PrepareOk(PrepareOkMessage),
     Commit(CommitMessage),
+    GetState(GetStateMessage),
+    NewState(NewStateMessage<T>),
+    GetCheckpoint(GetCheckpointMessage),
+    CheckpointChunk(CheckpointChunkMessage),
     StartViewChange,
     DoViewChange,
     StartView,
 }
 
+#[derive(Clone)]
 pub struct PrepareMessage<T> {
     pub requesting_replica: ReplicaIdentity,
     pub view_number: u64,
     pub op_number: u64,
     pub commit_number: u64,
+    pub timestamp: u64,
+    pub seed: u64,
     pub client: ClientIdentity,
-    pub request: T,
+    pub request: ClientRequest<T>,
     pub request_number: u64,
 }
 
+#[derive(Clone)]
 pub struct PrepareOkMessage {
     pub replica: ReplicaIdentity,
     pub view_number: u64,
     pub op_number: u64,
 }
 
+#[derive(Clone)]
 pub struct CommitMessage {
     pub view_number: u64,
     pub commit_number: u64,
 }
 
+#[derive(Clone)]
+pub struct GetStateMessage {
+    pub replica: ReplicaIdentity,
+    pub view_number: u64,
+    pub op_number: u64,
+}
+
+#[derive(Clone)]
+pub struct NewStateMessage<T> {
+    pub replica: ReplicaIdentity,
+    pub view_number: u64,
+    pub commit_number: u64,
+    /// Set when the requested ops were compacted away - the entries then
+    /// follow the checkpoint with this op number, which has to be installed
+    /// before they can be applied.
+    pub checkpoint_op_number: Option<u64>,
+    pub first_op_number: u64,
+    pub entries: Vec<EntryMessage<T>>,
+}
+
+#[derive(Clone)]
+pub struct GetCheckpointMessage {
+    pub replica: ReplicaIdentity,
+    pub view_number: u64,
+    pub op_number: u64,
+    pub offset: u64,
+}
+
+#[derive(Clone)]
+pub struct CheckpointChunkMessage {
+    pub replica: ReplicaIdentity,
+    pub view_number: u64,
+    pub op_number: u64,
+    pub offset: u64,
+    pub data: Bytes,
+    pub last: bool,
+}
+
+#[derive(Clone)]
+pub struct EntryMessage<T> {
+    pub client: ClientIdentity,
+    pub request_number: u64,
+    pub timestamp: u64,
+    pub seed: u64,
+    pub request: ClientRequest<T>,
+}
+
+#[derive(Clone)]
 pub struct ClientMessage<T> {
+    /// Ignored for `Register` requests.
+    pub session: SessionId,
     pub request_number: u64,
-    pub request: T,
+    pub request: ClientRequest<T>,
 }
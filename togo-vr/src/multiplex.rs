@@ -0,0 +1,431 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use async_trait::async_trait;
+use bytes::{BufMut, BytesMut};
+use futures::{stream, Stream};
+
+use crate::{
+    codec::{get_u32, get_u8, Codec, CodecError, CodecResult},
+    message::{BatchedClusterMessage, ClusterMessage, ClusterMessageEnvelope, CommitMessage},
+    replica::{GroupIdentifier, ReplicaIdentity},
+    transport::{TransportChannel, TransportError, TransportResult},
+};
+
+/// What nodes exchange over the shared transport.
+pub enum NodeMessage<T> {
+    /// Messages of any number of groups, each addressed by its envelope.
+    Batch(BatchedClusterMessage<T>),
+    /// The latest commit message of every group the sender has sent one to
+    /// the recipient since the last tick - commits double as heartbeats, so
+    /// a node hosting many groups sends one of these per peer instead of one
+    /// per group.
+    Heartbeats {
+        sender: ReplicaIdentity,
+        commits: Vec<(GroupIdentifier, CommitMessage)>,
+    },
+}
+
+impl<O: Codec> Codec for NodeMessage<O> {
+    fn encode(&self, buffer: &mut BytesMut) {
+        match self {
+            NodeMessage::Batch(batch) => {
+                buffer.put_u8(0);
+                batch.encode(buffer);
+            }
+            NodeMessage::Heartbeats { sender, commits } => {
+                buffer.put_u8(1);
+                sender.encode(buffer);
+                buffer.put_u32(commits.len() as u32);
+
+                for (group, commit) in commits {
+                    group.encode(buffer);
+                    commit.encode(buffer);
+                }
+            }
+        }
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        match get_u8(data)? {
+            0 => BatchedClusterMessage::decode(data).map(NodeMessage::Batch),
+            1 => Ok(NodeMessage::Heartbeats {
+                sender: ReplicaIdentity::decode(data)?,
+                commits: (0..get_u32(data)?)
+                    .map(|_| Ok((GroupIdentifier::decode(data)?, CommitMessage::decode(data)?)))
+                    .collect::<CodecResult<_>>()?,
+            }),
+            tag => Err(CodecError::UnknownTag("node message", tag)),
+        }
+    }
+}
+
+/// Shares one transport between all the groups a node hosts - every group's
+/// `Cluster` gets its own `GroupChannel`, and the multiplexer routes the
+/// messages it receives to them by the group in their envelope.
+pub struct Multiplexer<O, T> {
+    identity: ReplicaIdentity,
+    channel: Arc<T>,
+    shared: Arc<Shared<O>>,
+}
+
+/// The transport of a single group, see `Multiplexer`.
+pub struct GroupChannel<O, T> {
+    group: GroupIdentifier,
+    channel: Arc<T>,
+    shared: Arc<Shared<O>>,
+}
+
+struct Shared<O> {
+    inboxes: Mutex<BTreeMap<GroupIdentifier, VecDeque<ClusterMessageEnvelope<O>>>>,
+    // per recipient, the commit messages waiting for the next tick
+    heartbeats: Mutex<BTreeMap<ReplicaIdentity, BTreeMap<GroupIdentifier, CommitMessage>>>,
+}
+
+impl<O> Shared<O> {
+    fn inboxes(
+        &self,
+    ) -> TransportResult<
+        MutexGuard<'_, BTreeMap<GroupIdentifier, VecDeque<ClusterMessageEnvelope<O>>>>,
+    > {
+        self.inboxes.lock().map_err(|_| TransportError::Poisoned)
+    }
+
+    fn heartbeats(
+        &self,
+    ) -> TransportResult<
+        MutexGuard<'_, BTreeMap<ReplicaIdentity, BTreeMap<GroupIdentifier, CommitMessage>>>,
+    > {
+        self.heartbeats.lock().map_err(|_| TransportError::Poisoned)
+    }
+}
+
+impl<O, T> Multiplexer<O, T>
+where
+    O: Send,
+    T: TransportChannel<ReplicaIdentity, NodeMessage<O>> + Send + Sync,
+{
+    pub fn new(identity: ReplicaIdentity, channel: T) -> Self {
+        Self {
+            identity,
+            channel: Arc::new(channel),
+            shared: Arc::new(Shared {
+                inboxes: Mutex::new(BTreeMap::new()),
+                heartbeats: Mutex::new(BTreeMap::new()),
+            }),
+        }
+    }
+
+    /// Starts routing the group's messages, returning its channel.
+    pub fn register(&self, group: GroupIdentifier) -> TransportResult<GroupChannel<O, T>> {
+        self.shared.inboxes()?.entry(group).or_default();
+
+        Ok(GroupChannel {
+            group,
+            channel: self.channel.clone(),
+            shared: self.shared.clone(),
+        })
+    }
+
+    /// Stops routing the group's messages, e.g. once its shard has moved
+    /// away - whatever is still addressed to it is dropped.
+    pub fn deregister(&self, group: GroupIdentifier) -> TransportResult<()> {
+        self.shared.inboxes()?.remove(&group);
+
+        for commits in self.shared.heartbeats()?.values_mut() {
+            commits.remove(&group);
+        }
+
+        Ok(())
+    }
+
+    /// Receives a message from the transport and routes it to the groups it's
+    /// addressed to - `false` when there was nothing to receive.
+    pub async fn receive(&self) -> TransportResult<bool> {
+        let Some(message) = self.channel.receive().await? else {
+            return Ok(false);
+        };
+        let envelopes = match message {
+            NodeMessage::Batch(batch) => batch.into_messages(),
+            NodeMessage::Heartbeats { sender, commits } => commits
+                .into_iter()
+                .map(|(group, commit)| ClusterMessageEnvelope {
+                    sender,
+                    group,
+                    content: ClusterMessage::Commit(commit),
+                })
+                .collect(),
+        };
+        let mut inboxes = self.shared.inboxes()?;
+
+        // groups that aren't hosted (anymore) have nobody to deliver to
+        for envelope in envelopes {
+            if let Some(inbox) = inboxes.get_mut(&envelope.group) {
+                inbox.push_back(envelope);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Receives and routes messages for as long as the transport is open,
+    /// yielding after each one - see `Driver::run`.
+    pub fn arrivals(&self) -> impl Stream<Item = TransportResult<()>> + '_ {
+        stream::unfold(self, |multiplexer| async move {
+            match multiplexer.receive().await {
+                Ok(true) => Some((Ok(()), multiplexer)),
+                Ok(false) => None,
+                Err(error) => Some((Err(error), multiplexer)),
+            }
+        })
+    }
+
+    /// Sends the commit messages gathered since the last tick, coalesced into
+    /// a single message per peer - every peer gets its heartbeat even if
+    /// sending to another one fails, the first error is returned at the end.
+    pub async fn flush_heartbeats(&self) -> TransportResult<()> {
+        let heartbeats = std::mem::take(&mut *self.shared.heartbeats()?);
+        let mut result = Ok(());
+
+        for (recipient, commits) in heartbeats {
+            let message = NodeMessage::Heartbeats {
+                sender: self.identity,
+                commits: commits.into_iter().collect(),
+            };
+            let sent = self.channel.send(recipient, message).await;
+
+            result = result.and(sent);
+        }
+
+        result
+    }
+}
+
+impl<O, T> GroupChannel<O, T> {
+    pub fn group(&self) -> GroupIdentifier {
+        self.group
+    }
+}
+
+#[async_trait]
+impl<O, T> TransportChannel<ReplicaIdentity, BatchedClusterMessage<O>> for GroupChannel<O, T>
+where
+    O: Send,
+    T: TransportChannel<ReplicaIdentity, NodeMessage<O>> + Send + Sync,
+{
+    /// Commit messages are held back until the next heartbeat flush, only the
+    /// latest one per group is sent - everything else goes out right away.
+    async fn send(
+        &self,
+        recipient: ReplicaIdentity,
+        message: BatchedClusterMessage<O>,
+    ) -> TransportResult<()> {
+        let mut envelopes = Vec::new();
+
+        {
+            let mut heartbeats = self.shared.heartbeats()?;
+
+            for envelope in message.into_messages() {
+                match envelope.content {
+                    ClusterMessage::Commit(commit) => {
+                        heartbeats
+                            .entry(recipient)
+                            .or_default()
+                            .insert(envelope.group, commit);
+                    }
+                    _ => envelopes.push(envelope),
+                }
+            }
+        }
+
+        if envelopes.is_empty() {
+            return Ok(());
+        }
+
+        self.channel
+            .send(recipient, NodeMessage::Batch(envelopes.into()))
+            .await
+    }
+
+    /// Everything routed to the group since the last call, as a single batch.
+    async fn receive(&self) -> TransportResult<Option<BatchedClusterMessage<O>>> {
+        let envelopes: Vec<_> = match self.shared.inboxes()?.get_mut(&self.group) {
+            Some(inbox) => inbox.drain(..).collect(),
+            None => return Ok(None),
+        };
+
+        Ok((!envelopes.is_empty()).then(|| envelopes.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+    };
+
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use futures::executor::block_on;
+
+    use crate::{
+        codec::Codec,
+        message::{ClusterMessage, ClusterMessageEnvelope, CommitMessage, GetStateMessage},
+        replica::{GroupIdentifier, ReplicaIdentity},
+        transport::{TransportChannel, TransportError, TransportResult},
+    };
+
+    use super::{Multiplexer, NodeMessage};
+
+    // both nodes share the same wire, what one sends the other receives
+    #[derive(Clone, Default)]
+    struct Wire(Arc<Mutex<VecDeque<Bytes>>>);
+
+    #[async_trait]
+    impl TransportChannel<ReplicaIdentity, NodeMessage<u64>> for Wire {
+        async fn send(&self, _: ReplicaIdentity, message: NodeMessage<u64>) -> TransportResult<()> {
+            self.0.lock().unwrap().push_back(message.to_bytes());
+            Ok(())
+        }
+
+        async fn receive(&self) -> TransportResult<Option<NodeMessage<u64>>> {
+            let message = self.0.lock().unwrap().pop_front();
+
+            Ok(message.map(|message| NodeMessage::from_bytes(&message).unwrap()))
+        }
+    }
+
+    // records who has been sent what, replica 2 can't be reached
+    #[derive(Clone, Default)]
+    struct Peers(Arc<Mutex<Vec<ReplicaIdentity>>>);
+
+    #[async_trait]
+    impl TransportChannel<ReplicaIdentity, NodeMessage<u64>> for Peers {
+        async fn send(
+            &self,
+            recipient: ReplicaIdentity,
+            _: NodeMessage<u64>,
+        ) -> TransportResult<()> {
+            if recipient == ReplicaIdentity(2) {
+                return Err(TransportError::Unreachable("Replica 2 is down!".into()));
+            }
+
+            self.0.lock().unwrap().push(recipient);
+            Ok(())
+        }
+
+        async fn receive(&self) -> TransportResult<Option<NodeMessage<u64>>> {
+            Ok(None)
+        }
+    }
+
+    fn commit(group: u32, commit_number: u64) -> ClusterMessageEnvelope<u64> {
+        ClusterMessageEnvelope {
+            sender: ReplicaIdentity(1),
+            group: GroupIdentifier(group),
+            content: ClusterMessage::Commit(CommitMessage {
+                view_number: 1,
+                commit_number,
+            }),
+        }
+    }
+
+    #[test]
+    pub fn messages_are_routed_by_group_and_heartbeats_coalesced() {
+        let wire = Wire::default();
+        let sender = Multiplexer::new(ReplicaIdentity(1), wire.clone());
+        let receiver = Multiplexer::new(ReplicaIdentity(2), wire.clone());
+        let groups = (0..3).map(GroupIdentifier);
+        let outgoing: Vec<_> = groups
+            .clone()
+            .map(|group| sender.register(group).unwrap())
+            .collect();
+        let incoming: Vec<_> = groups
+            .map(|group| receiver.register(group).unwrap())
+            .collect();
+        let peer = ReplicaIdentity(2);
+
+        block_on(async {
+            for channel in &outgoing {
+                let group = channel.group().0;
+
+                for commit_number in 1..=2 {
+                    channel
+                        .send(peer, vec![commit(group, commit_number)].into())
+                        .await
+                        .unwrap();
+                }
+            }
+
+            let get_state = ClusterMessageEnvelope {
+                sender: ReplicaIdentity(1),
+                group: GroupIdentifier(1),
+                content: ClusterMessage::GetState(GetStateMessage {
+                    replica: peer,
+                    view_number: 1,
+                    op_number: 3,
+                }),
+            };
+
+            outgoing[1]
+                .send(peer, vec![get_state].into())
+                .await
+                .unwrap();
+
+            // only the get state has gone out so far, all six commits then
+            // share a single message
+            assert_eq!(wire.0.lock().unwrap().len(), 1);
+            sender.flush_heartbeats().await.unwrap();
+            assert_eq!(wire.0.lock().unwrap().len(), 2);
+
+            while receiver.receive().await.unwrap() {}
+
+            for channel in &incoming {
+                let contents: Vec<_> = channel
+                    .receive()
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .into_messages()
+                    .into_iter()
+                    .map(|envelope| {
+                        assert_eq!(envelope.group, channel.group());
+                        envelope.content
+                    })
+                    .collect();
+
+                match (channel.group().0, contents.as_slice()) {
+                    (1, [ClusterMessage::GetState(_), ClusterMessage::Commit(commit)])
+                    | (0 | 2, [ClusterMessage::Commit(commit)]) => {
+                        assert_eq!(commit.commit_number, 2)
+                    }
+                    _ => panic!("Unexpected messages for group {}!", channel.group()),
+                }
+
+                assert!(channel.receive().await.unwrap().is_none());
+            }
+        });
+    }
+
+    #[test]
+    pub fn an_unreachable_peer_doesnt_hold_back_the_other_heartbeats() {
+        let peers = Peers::default();
+        let sender = Multiplexer::new(ReplicaIdentity(1), peers.clone());
+        let channel = sender.register(GroupIdentifier(0)).unwrap();
+
+        block_on(async {
+            for peer in [2, 3].map(ReplicaIdentity) {
+                channel.send(peer, vec![commit(0, 1)].into()).await.unwrap();
+            }
+
+            assert!(matches!(
+                sender.flush_heartbeats().await,
+                Err(TransportError::Unreachable(_))
+            ));
+            assert_eq!(*peers.0.lock().unwrap(), [ReplicaIdentity(3)]);
+        });
+    }
+}
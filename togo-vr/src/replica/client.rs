@@ -1,12 +1,73 @@
-use std::rc::Rc;
+use std::{collections::BTreeMap, rc::Rc};
 
-use crate::message::ClientMessage;
+use crate::{log::Footprint, message::ClientMessage};
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
-pub struct ClientIdentity {}
+pub struct ClientIdentity(pub u64);
 
-#[derive(Debug)]
-pub struct RequestNumber(u64);
+pub type RequestNumber = u64;
+
+/// The op number of the `Register` request that opened the session - unique
+/// and identical on every replica.
+pub type SessionId = u64;
+
+/// The committed session of every registered client along with its latest
+/// request and the reply to it - retried requests are answered from here
+/// instead of being executed again.
+pub type ClientTable<OR> = BTreeMap<ClientIdentity, ClientReply<OR>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientReply<OR> {
+    pub session: SessionId,
+    pub request_number: RequestNumber,
+    pub response: Option<OR>,
+    /// Timestamp of the latest committed request, assigned by the primary.
+    pub last_timestamp: u64,
+}
+
+/// Sessions idle for longer than `idle_timeout` milliseconds are evicted once
+/// a new client registers - with no timeout, sessions never expire.
+///
+/// Eviction only runs when a registration is committed, so an idle session
+/// stays usable for as long as nobody else registers.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SessionPolicy {
+    pub idle_timeout: Option<u64>,
+}
+
+impl SessionPolicy {
+    pub fn is_expired(&self, last_timestamp: u64, timestamp: u64) -> bool {
+        self.idle_timeout
+            .is_some_and(|timeout| timestamp.saturating_sub(last_timestamp) > timeout)
+    }
+}
+
+pub enum ClientRequest<O> {
+    /// Opens a new session, committed through the log like any operation.
+    Register,
+    Operation(O),
+}
+
+impl<O> Clone for ClientRequest<O>
+where
+    O: ToOwned<Owned = O>,
+{
+    fn clone(&self) -> Self {
+        match self {
+            ClientRequest::Register => ClientRequest::Register,
+            ClientRequest::Operation(operation) => ClientRequest::Operation(operation.to_owned()),
+        }
+    }
+}
+
+impl<O: Footprint> Footprint for ClientRequest<O> {
+    fn footprint(&self) -> usize {
+        match self {
+            ClientRequest::Register => 0,
+            ClientRequest::Operation(operation) => operation.footprint(),
+        }
+    }
+}
 
 pub struct ClientOperation<O, OR> {
     pub request_number: RequestNumber,
@@ -14,7 +75,7 @@ pub struct ClientOperation<O, OR> {
     pub response: Option<OR>,
 }
 
-impl<O, OR> From<ClientMessage<O>> for ClientOperation<O, OR> {
+impl<O, OR> From<ClientMessage<O>> for ClientOperation<ClientRequest<O>, OR> {
     fn from(value: ClientMessage<O>) -> Self {
         Self {
             request_number: value.request_number,
@@ -24,7 +85,7 @@ impl<O, OR> From<ClientMessage<O>> for ClientOperation<O, OR> {
     }
 }
 
-impl<O, OR> From<ClientMessage<O>> for ClientOperation<Rc<O>, OR> {
+impl<O, OR> From<ClientMessage<O>> for ClientOperation<Rc<ClientRequest<O>>, OR> {
     fn from(value: ClientMessage<O>) -> Self {
         Self {
             request_number: value.request_number,
@@ -33,3 +94,20 @@ impl<O, OR> From<ClientMessage<O>> for ClientOperation<Rc<O>, OR> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::SessionPolicy;
+
+    #[test]
+    pub fn sessions_expire_only_after_the_idle_timeout() {
+        let policy = SessionPolicy {
+            idle_timeout: Some(1000),
+        };
+
+        assert!(!policy.is_expired(500, 1500));
+        assert!(policy.is_expired(500, 1501));
+        assert!(!policy.is_expired(2000, 1000));
+        assert!(!SessionPolicy::default().is_expired(0, u64::MAX));
+    }
+}
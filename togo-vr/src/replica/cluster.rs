@@ -1,4 +1,4 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 
 use thiserror::Error;
 
@@ -7,7 +7,7 @@ use crate::{
     transport::{TransportChannel, TransportResult},
 };
 
-use super::ReplicaIdentity;
+use super::{GroupIdentifier, ReplicaIdentity};
 
 pub type ClusterResult<T> = Result<T, ClusterError>;
 
@@ -16,9 +16,13 @@ where
     T: TransportChannel<ReplicaIdentity, BatchedClusterMessage<O>>,
 {
     channel: T,
+    group: GroupIdentifier,
     replicas: BTreeSet<ReplicaIdentity>,
     current_primary: ReplicaIdentity,
-    message_buffer: Vec<ClusterMessageEnvelope<O>>,
+    // messages are only sent once the handler producing them has finished,
+    // batched per recipient
+    message_buffer: Vec<(ReplicaIdentity, ClusterMessageEnvelope<O>)>,
+    received_messages: VecDeque<ClusterMessageEnvelope<O>>,
 }
 
 impl<O, T> Cluster<O, T>
@@ -39,30 +43,87 @@ where
 
         Ok(Self {
             channel,
+            group: GroupIdentifier::default(),
             replicas,
             current_primary,
             message_buffer: Vec::new(),
+            received_messages: VecDeque::new(),
         })
     }
 
+    pub fn with_group(mut self, group: GroupIdentifier) -> Self {
+        self.group = group;
+        self
+    }
+
+    pub fn group(&self) -> GroupIdentifier {
+        self.group
+    }
+
+    pub fn replicas(&self) -> &BTreeSet<ReplicaIdentity> {
+        &self.replicas
+    }
+
+    /// Number of replicas that make up a majority, the primary included.
+    pub fn quorum(&self) -> usize {
+        self.replicas.len() / 2 + 1
+    }
+
     pub fn current_primary(&self) -> ReplicaIdentity {
         self.current_primary
     }
 
-    pub fn broadcast(&mut self, message: ClusterMessageEnvelope<O>) -> TransportResult<()> {
-        todo!()
+    /// Sends the message to every replica but its sender.
+    pub fn broadcast(&mut self, message: ClusterMessageEnvelope<O>) -> TransportResult<()>
+    where
+        O: Clone,
+    {
+        for recipient in &self.replicas {
+            if *recipient != message.sender {
+                self.message_buffer.push((*recipient, message.clone()));
+            }
+        }
+
+        Ok(())
     }
 
-    pub fn send(&mut self, recipient: ReplicaIdentity, message: ClusterMessageEnvelope<O>) -> TransportResult<()> {
-        todo!()
+    pub fn send(
+        &mut self,
+        recipient: ReplicaIdentity,
+        message: ClusterMessageEnvelope<O>,
+    ) -> TransportResult<()> {
+        self.message_buffer.push((recipient, message));
+
+        Ok(())
     }
 
-    pub async fn receive(&self) -> TransportResult<Option<ClusterMessageEnvelope<O>>> {
-        todo!()
+    pub async fn receive(&mut self) -> TransportResult<Option<ClusterMessageEnvelope<O>>> {
+        if self.received_messages.is_empty() {
+            if let Some(batch) = self.channel.receive().await? {
+                self.received_messages.extend(batch.into_messages());
+            }
+        }
+
+        Ok(self.received_messages.pop_front())
     }
 
+    /// A recipient that can't be reached doesn't hold up the others, the
+    /// first failure is reported once every batch has been sent.
     pub async fn send_bufferred_messages(&mut self) -> TransportResult<()> {
-        todo!()
+        let mut batches: BTreeMap<ReplicaIdentity, Vec<_>> = BTreeMap::new();
+        let mut result = Ok(());
+
+        for (recipient, message) in self.message_buffer.drain(..) {
+            batches.entry(recipient).or_default().push(message);
+        }
+
+        for (recipient, messages) in batches {
+            let sent = self.channel.send(recipient, messages.into()).await;
+
+            result = result.and(sent);
+        }
+
+        result
     }
 }
 
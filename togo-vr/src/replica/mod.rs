@@ -1,19 +1,32 @@
-use std::{collections::BTreeMap, ops::Deref, rc::Rc, marker::PhantomData};
+use std::{
+    cmp::Ordering,
+    collections::{hash_map::RandomState, BTreeMap},
+    fmt::{self, Display, Formatter},
+    hash::{BuildHasher, Hash, Hasher},
+    marker::PhantomData,
+    ops::Deref,
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use thiserror::Error;
 
 use crate::{
-    log::Log,
+    log::{Footprint, Log, LogEntry, LogError},
     message::{
-        BatchedClusterMessage, ClientMessage, ClusterMessage, ClusterMessageEnvelope,
-        PrepareMessage, PrepareOkMessage, CommitMessage,
+        BatchedClusterMessage, CheckpointChunkMessage, ClientMessage, ClusterMessage,
+        ClusterMessageEnvelope, CommitMessage, EntryMessage, GetCheckpointMessage, GetStateMessage,
+        NewStateMessage, PrepareMessage, PrepareOkMessage,
     },
-    state::StateMachine,
+    state::{Checkpoint, OperationContext, StateError, StateMachine},
     transport::{TransportChannel, TransportError},
 };
 
 use self::{
-    client::{ClientIdentity, ClientOperation, RequestNumber},
+    client::{
+        ClientIdentity, ClientOperation, ClientReply, ClientRequest, ClientTable, RequestNumber,
+        SessionId, SessionPolicy,
+    },
     cluster::Cluster,
 };
 
@@ -22,45 +35,225 @@ pub mod cluster;
 
 pub type ReplicaResult<T> = Result<T, ReplicaError>;
 
+const CHECKPOINT_CHUNK_SIZE: usize = 1024 * 1024;
+// rough limit on the footprint of the ops in a single NewState, a replica
+// that's further behind asks again for the rest
+const NEW_STATE_SIZE: usize = 1024 * 1024;
+// ticks a replica waits for the answer to its GetState before asking again
+const STATE_REQUEST_TIMEOUT: u64 = 10;
+
 pub struct Replica<O, OR, T, L, S>
 where
     T: TransportChannel<ReplicaIdentity, BatchedClusterMessage<O>>,
-    L: Log<ClientOperation<O, OR>>,
+    L: Log<LogEntry<O>>,
     S: StateMachine<O, OR>,
 {
     identity: ReplicaIdentity,
     op_log: L,
-    client_log: BTreeMap<ClientIdentity, RequestNumber>,
+    client_log: ClientTable<OR>,
+    // requests prepared by this replica as the primary but not committed yet
+    pending_requests: BTreeMap<ClientIdentity, RequestNumber>,
+    // the highest op number every backup has acknowledged to the primary
+    acknowledged: BTreeMap<ReplicaIdentity, u64>,
     state_machine: S,
     state: ReplicaState,
     cluster: Cluster<O, T>,
-    phantom_operation_result: PhantomData<OR>
+    checkpoint_policy: CheckpointPolicy,
+    session_policy: SessionPolicy,
+    phantom_operation_result: PhantomData<OR>,
 }
 
 pub struct ReplicaState {
     commit_number: u64,
     view_number: u64,
+    last_timestamp: u64,
     ticks_since_last_commit: u64,
+    checkpoint_op_number: u64,
+    bytes_since_checkpoint: u64,
+    checkpoint_transfer: Option<CheckpointTransfer>,
+    // ticks since the outstanding GetState has been sent
+    state_request: Option<u64>,
     status: ReplicaStatus,
 }
 
-#[derive(PartialEq)]
+// a checkpoint being fetched from another replica, `offset` bytes of it have
+// been received so far
+struct CheckpointTransfer {
+    source: ReplicaIdentity,
+    op_number: u64,
+    offset: u64,
+}
+
+/// A checkpoint is taken as soon as either limit is reached, after which the
+/// log is trimmed up to it - with neither set, the log is never compacted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CheckpointPolicy {
+    pub operations: Option<u64>,
+    pub bytes: Option<u64>,
+}
+
+impl ReplicaState {
+    pub fn commit_number(&self) -> u64 {
+        self.commit_number
+    }
+
+    pub fn view_number(&self) -> u64 {
+        self.view_number
+    }
+
+    pub fn checkpoint_op_number(&self) -> u64 {
+        self.checkpoint_op_number
+    }
+
+    pub fn status(&self) -> ReplicaStatus {
+        self.status
+    }
+}
+
+impl CheckpointPolicy {
+    pub fn is_due(&self, operations: u64, bytes: u64) -> bool {
+        self.operations.is_some_and(|limit| operations >= limit)
+            || self.bytes.is_some_and(|limit| bytes >= limit)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ReplicaStatus {
     Normal,
     Recovery,
     ViewChange,
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
-pub struct ReplicaIdentity {}
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
+pub struct ReplicaIdentity(pub u32);
+
+impl Display for ReplicaIdentity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A VR group - nodes hosting many shards run one group per shard, all
+/// sharing the same transport.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct GroupIdentifier(pub u32);
+
+impl Display for GroupIdentifier {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
 
 impl<O, OR, T, L, S> Replica<O, OR, T, L, S>
 where
-    O: ToOwned<Owned = O>,
-    OR: ToOwned<Owned = O>,
     T: TransportChannel<ReplicaIdentity, BatchedClusterMessage<O>>,
-    L: Log<Rc<O>>,
+    L: Log<LogEntry<O>>,
     S: StateMachine<O, OR>,
+{
+    /// A replica in view 0 that considers everything up to its log's offset
+    /// committed, e.g. a fresh one.
+    pub fn new(
+        identity: ReplicaIdentity,
+        cluster: Cluster<O, T>,
+        op_log: L,
+        state_machine: S,
+    ) -> Self {
+        let commit_number = op_log.current_offset();
+
+        Self {
+            identity,
+            op_log,
+            client_log: ClientTable::new(),
+            pending_requests: BTreeMap::new(),
+            acknowledged: BTreeMap::new(),
+            state_machine,
+            state: ReplicaState {
+                commit_number,
+                view_number: 0,
+                last_timestamp: 0,
+                ticks_since_last_commit: 0,
+                checkpoint_op_number: commit_number,
+                bytes_since_checkpoint: 0,
+                checkpoint_transfer: None,
+                state_request: None,
+                status: ReplicaStatus::Normal,
+            },
+            cluster,
+            checkpoint_policy: CheckpointPolicy::default(),
+            session_policy: SessionPolicy::default(),
+            phantom_operation_result: PhantomData,
+        }
+    }
+
+    /// Continues from the checkpoint the state machine has been restored from,
+    /// with the client table stored in it - logged ops up to the checkpoint
+    /// aren't applied again.
+    pub fn with_checkpoint(mut self, op_number: u64, client_table: ClientTable<OR>) -> Self {
+        self.client_log = client_table;
+        self.state.commit_number = self.state.commit_number.max(op_number);
+        self.state.checkpoint_op_number = self.state.commit_number;
+        self
+    }
+
+    pub fn with_checkpoint_policy(mut self, checkpoint_policy: CheckpointPolicy) -> Self {
+        self.checkpoint_policy = checkpoint_policy;
+        self
+    }
+
+    pub fn with_session_policy(mut self, session_policy: SessionPolicy) -> Self {
+        self.session_policy = session_policy;
+        self
+    }
+
+    pub fn identity(&self) -> ReplicaIdentity {
+        self.identity
+    }
+
+    pub fn is_primary(&self) -> bool {
+        self.cluster.current_primary() == self.identity
+    }
+
+    pub fn state(&self) -> &ReplicaState {
+        &self.state
+    }
+
+    /// Number of the latest op in the log, committed or not.
+    pub fn op_number(&self) -> u64 {
+        self.op_log.current_size_with_offset()
+    }
+
+    /// Number of the op the log starts after - everything up to it is only
+    /// kept in checkpoints.
+    pub fn log_offset(&self) -> u64 {
+        self.op_log.current_offset()
+    }
+
+    /// The client's session along with the reply to its latest committed
+    /// request.
+    pub fn client_reply(&self, client: ClientIdentity) -> Option<&ClientReply<OR>> {
+        self.client_log.get(&client)
+    }
+
+    pub fn state_machine(&self) -> &S {
+        &self.state_machine
+    }
+
+    pub fn cluster(&self) -> &Cluster<O, T> {
+        &self.cluster
+    }
+
+    pub fn cluster_mut(&mut self) -> &mut Cluster<O, T> {
+        &mut self.cluster
+    }
+}
+
+impl<O, OR, T, L, S> Replica<O, OR, T, L, S>
+where
+    O: Clone + Footprint,
+    OR: Clone,
+    T: TransportChannel<ReplicaIdentity, BatchedClusterMessage<O>>,
+    L: Log<LogEntry<O>>,
+    S: StateMachine<O, OR> + Checkpoint<OR>,
 {
     pub fn apply_request(
         &mut self,
@@ -75,34 +268,67 @@ where
             return Err(ReplicaError::InvalidState);
         }
 
-        if let Some(last_request) = self.client_log.get_mut(&client) {
-            if last_request.request_number > request.request_number {
+        if let ClientRequest::Operation(_) = request.request {
+            // sessions only come into existence once their registration has
+            // been committed, so an unknown one has been evicted since
+            let Some(last_request) = self
+                .client_log
+                .get(&client)
+                .filter(|last_request| last_request.session == request.session)
+            else {
+                return Err(ReplicaError::SessionExpired {
+                    session: request.session,
+                });
+            };
+
+            let last_request_number = self
+                .pending_requests
+                .get(&client)
+                .copied()
+                .unwrap_or_default()
+                .max(last_request.request_number);
+
+            if last_request_number > request.request_number {
                 return Err(ReplicaError::UnexpectedRequestNumber {
-                    request_number: last_request.request_number,
+                    request_number: last_request_number,
                     replica_number: request.request_number,
                 });
             }
 
-            if last_request.request_number == request.request_number {
+            if last_request_number == request.request_number {
                 // TODO: we shouldn't assume that it's the same request even if request_number matches
                 return Ok(());
             }
         }
 
-        let new_operation: ClientOperation<Rc<O>, OR> = request.into();
+        let new_operation: ClientOperation<Rc<ClientRequest<O>>, OR> = request.into();
+        let op_number = self.op_log.current_size_with_offset() + 1;
+        let timestamp = self.next_timestamp();
+        let seed = Self::new_seed(op_number, timestamp);
 
         let prepare_message = self.new_message(ClusterMessage::Prepare(PrepareMessage {
             requesting_replica: self.identity,
             view_number: self.state.view_number,
-            op_number: self.op_log.current_size_with_offset(),
+            op_number,
             commit_number: self.state.commit_number,
+            timestamp,
+            seed,
             client,
             request: new_operation.operation.deref().to_owned(),
             request_number: new_operation.request_number,
         }));
 
-        self.op_log.push(new_operation.operation.clone());
-        self.client_log.insert(client, new_operation);
+        self.op_log
+            .push(LogEntry {
+                client,
+                request_number: new_operation.request_number,
+                timestamp,
+                seed,
+                operation: new_operation.operation,
+            })
+            .map_err(ReplicaError::LogIssue)?;
+        self.pending_requests
+            .insert(client, new_operation.request_number);
 
         self.cluster
             .broadcast(prepare_message)
@@ -114,14 +340,8 @@ where
             return Err(ReplicaError::NotForPrimary);
         }
 
-        if message.view_number > self.state.view_number {
-            // TODO: go into recovery and initiate a state transfer
-            todo!()
-        }
-
-        if message.view_number < self.state.view_number {
-            // TODO: drop the message and notify the replica that it's lagging behind
-            todo!()
+        if !self.is_current_view(message.view_number)? {
+            return Ok(());
         }
 
         let op_number = self.op_log.current_size_with_offset();
@@ -135,44 +355,323 @@ where
             return Ok(());
         }
 
+        // we've missed some prepares, ask for them before going any further
         if message.op_number > op_number + 1 {
-            // TODO: go into recovery and initiate a state transfer
-            todo!()
+            if self.state.checkpoint_transfer.is_some() {
+                return Ok(());
+            }
+
+            return self.request_state(message.requesting_replica);
         }
 
         assert_eq!(message.op_number, op_number + 1);
 
-        let new_operation = ClientOperation {
-            request_number: message.request_number,
-            operation: Rc::new(message.request),
-            response: None,
-        };
-
-        self.op_log.push(new_operation.operation.clone());
-        self.client_log.insert(message.client, new_operation);
+        self.op_log
+            .push(LogEntry {
+                client: message.client,
+                request_number: message.request_number,
+                timestamp: message.timestamp,
+                seed: message.seed,
+                operation: Rc::new(message.request),
+            })
+            .map_err(ReplicaError::LogIssue)?;
+        self.state.last_timestamp = self.state.last_timestamp.max(message.timestamp);
 
         self.cluster
             .send(message.requesting_replica, self.new_prepare_ok_message())
             .map_err(ReplicaError::TransportIssue)?;
 
-        Ok(())
+        self.commit(message.commit_number)
+    }
+
+    pub fn apply_prepare_ok(&mut self, message: PrepareOkMessage) -> ReplicaResult<()> {
+        if self.cluster.current_primary() != self.identity {
+            return Err(ReplicaError::NotPrimary);
+        }
+
+        if self.state.status != ReplicaStatus::Normal
+            || message.view_number != self.state.view_number
+            || message.replica == self.identity
+        {
+            return Ok(());
+        }
+
+        let acknowledged = self.acknowledged.entry(message.replica).or_default();
+
+        *acknowledged = (*acknowledged).max(message.op_number);
+
+        // the primary has prepared everything in its log, so an op is
+        // committed as soon as enough backups to make up a quorum with it
+        // have acknowledged it
+        let mut op_numbers: Vec<_> = self.acknowledged.values().copied().collect();
+
+        op_numbers.sort_unstable_by(|left, right| right.cmp(left));
+
+        match op_numbers.get(self.cluster.quorum() - 2) {
+            Some(op_number) => self.commit(*op_number),
+            None => Ok(()),
+        }
     }
 
-    pub fn apply_commit(&self, message: CommitMessage) -> ReplicaResult<()>{
+    pub fn apply_commit(&mut self, message: CommitMessage) -> ReplicaResult<()> {
         if self.cluster.current_primary() == self.identity {
             return Err(ReplicaError::NotForPrimary);
         }
 
-        todo!()
+        if !self.is_current_view(message.view_number)? {
+            return Ok(());
+        }
+
+        self.commit(message.commit_number)?;
+
+        // commits double as heartbeats, a replica that has fallen behind
+        // (e.g. while it was down) learns about it from them
+        if message.commit_number > self.op_log.current_size_with_offset()
+            && self.state.checkpoint_transfer.is_none()
+        {
+            return self.request_state(self.cluster.current_primary());
+        }
+
+        Ok(())
     }
 
-    pub fn advance_time(&mut self) {
-        todo!()
+    pub fn apply_get_state(&mut self, message: GetStateMessage) -> ReplicaResult<()> {
+        if self.state.status != ReplicaStatus::Normal
+            || message.view_number != self.state.view_number
+        {
+            return Ok(());
+        }
+
+        // the requested ops might have been compacted away already, in which
+        // case the replica has to install the latest checkpoint first
+        let offset = self.op_log.current_offset();
+        let checkpoint_op_number = (message.op_number < offset).then_some(offset);
+        let first_op_number = message.op_number.max(offset) + 1;
+
+        let mut entries = Vec::new();
+        let mut size = 0;
+
+        for op_number in first_op_number..=self.op_log.current_size_with_offset() {
+            let entry = self
+                .op_log
+                .get(op_number - 1)
+                .map_err(ReplicaError::LogIssue)?;
+
+            size += entry.operation.footprint();
+
+            // an entry is sent however big it is, as long as it's the first
+            if size > NEW_STATE_SIZE && !entries.is_empty() {
+                break;
+            }
+
+            entries.push(EntryMessage {
+                client: entry.client,
+                request_number: entry.request_number,
+                timestamp: entry.timestamp,
+                seed: entry.seed,
+                request: entry.operation.deref().to_owned(),
+            });
+        }
+
+        let new_state = self.new_message(ClusterMessage::NewState(NewStateMessage {
+            replica: self.identity,
+            view_number: self.state.view_number,
+            commit_number: self.state.commit_number,
+            checkpoint_op_number,
+            first_op_number,
+            entries,
+        }));
+
+        self.cluster
+            .send(message.replica, new_state)
+            .map_err(ReplicaError::TransportIssue)
+    }
+
+    pub fn apply_new_state(&mut self, message: NewStateMessage<O>) -> ReplicaResult<()> {
+        if message.view_number != self.state.view_number {
+            return Ok(());
+        }
+
+        self.state.state_request = None;
+
+        let op_number = self.op_log.current_size_with_offset();
+
+        if let Some(checkpoint_op_number) = message.checkpoint_op_number {
+            if checkpoint_op_number > op_number {
+                return self.start_checkpoint_transfer(message.replica, checkpoint_op_number);
+            }
+        }
+
+        if message.first_op_number > op_number + 1 {
+            return Err(ReplicaError::LogIssue(LogError::InvalidIndex));
+        }
+
+        let skipped = (op_number + 1 - message.first_op_number) as usize;
+
+        for entry in message.entries.into_iter().skip(skipped) {
+            self.op_log
+                .push(LogEntry {
+                    client: entry.client,
+                    request_number: entry.request_number,
+                    timestamp: entry.timestamp,
+                    seed: entry.seed,
+                    operation: Rc::new(entry.request),
+                })
+                .map_err(ReplicaError::LogIssue)?;
+            self.state.last_timestamp = self.state.last_timestamp.max(entry.timestamp);
+        }
+
+        self.commit(message.commit_number)?;
+
+        // the answer has been cut short, the rest is asked for right away
+        if message.commit_number > self.op_log.current_size_with_offset() {
+            return self.request_state(message.replica);
+        }
+
+        Ok(())
+    }
+
+    pub fn apply_get_checkpoint(&mut self, message: GetCheckpointMessage) -> ReplicaResult<()> {
+        if message.view_number != self.state.view_number {
+            return Ok(());
+        }
+
+        let chunk = self
+            .state_machine
+            .read_checkpoint(message.op_number, message.offset, CHECKPOINT_CHUNK_SIZE)
+            .map_err(ReplicaError::StateMachineIssue)?;
+
+        let chunk = self.new_message(ClusterMessage::CheckpointChunk(CheckpointChunkMessage {
+            replica: self.identity,
+            view_number: self.state.view_number,
+            op_number: message.op_number,
+            offset: message.offset,
+            data: chunk.data,
+            last: chunk.last,
+        }));
+
+        self.cluster
+            .send(message.replica, chunk)
+            .map_err(ReplicaError::TransportIssue)
+    }
+
+    pub fn apply_checkpoint_chunk(&mut self, message: CheckpointChunkMessage) -> ReplicaResult<()> {
+        let Some(transfer) = self.state.checkpoint_transfer.as_mut() else {
+            return Ok(());
+        };
+
+        // stale or duplicated chunks are dropped, the next request resumes
+        // the transfer from where it is
+        if message.op_number != transfer.op_number || message.offset != transfer.offset {
+            return Ok(());
+        }
+
+        self.state_machine
+            .receive_checkpoint(message.op_number, message.offset, &message.data)
+            .map_err(ReplicaError::StateMachineIssue)?;
+
+        transfer.offset += message.data.len() as u64;
+
+        if !message.last {
+            return self.request_checkpoint_chunk();
+        }
+
+        let op_number = message.op_number;
+
+        self.client_log = self
+            .state_machine
+            .install_checkpoint(op_number)
+            .map_err(ReplicaError::StateMachineIssue)?;
+
+        // everything up to the checkpoint is committed by definition, the
+        // remaining suffix is fetched from the same replica
+        self.op_log
+            .reset(op_number)
+            .map_err(ReplicaError::LogIssue)?;
+        self.state.commit_number = op_number;
+        self.state.checkpoint_op_number = op_number;
+        self.state.bytes_since_checkpoint = 0;
+        self.state.checkpoint_transfer = None;
+        // registrations never reach the state machine, their timestamps are
+        // only kept in the client table
+        self.state.last_timestamp = self
+            .client_log
+            .values()
+            .map(|reply| reply.last_timestamp)
+            .fold(self.state_machine.last_timestamp(), u64::max)
+            .max(self.state.last_timestamp);
+
+        self.request_state(message.replica)
+    }
+
+    /// Called on every tick - the primary lets the backups know its commit
+    /// number, which doubles as its heartbeat.
+    pub fn advance_time(&mut self) -> ReplicaResult<()> {
+        self.state.ticks_since_last_commit += 1;
+        // an unanswered GetState is given up on, whatever finds the replica
+        // behind next asks again
+        self.state.state_request = self
+            .state
+            .state_request
+            .map(|ticks| ticks + 1)
+            .filter(|ticks| *ticks < STATE_REQUEST_TIMEOUT);
+
+        if self.cluster.current_primary() != self.identity
+            || self.state.status != ReplicaStatus::Normal
+        {
+            // TODO: start a view change once the primary has been silent for too long
+            return Ok(());
+        }
+
+        let commit = self.new_message(ClusterMessage::Commit(CommitMessage {
+            view_number: self.state.view_number,
+            commit_number: self.state.commit_number,
+        }));
+
+        self.cluster
+            .broadcast(commit)
+            .map_err(ReplicaError::TransportIssue)
+    }
+
+    // milliseconds since UNIX epoch, stamped by the primary into each prepared
+    // operation so that every replica applies it with the same notion of time -
+    // it never goes backwards, even if the wall clock does or the view changes
+    fn next_timestamp(&mut self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or_default();
+
+        self.state.last_timestamp = now.max(self.state.last_timestamp + 1);
+        self.state.last_timestamp
+    }
+
+    // view changes aren't implemented yet, so a replica can't follow its
+    // group into a newer view and reports the message instead - messages from
+    // an older view are stale and dropped
+    fn is_current_view(&self, view_number: u64) -> ReplicaResult<bool> {
+        match view_number.cmp(&self.state.view_number) {
+            Ordering::Less => Ok(false),
+            Ordering::Equal => Ok(true),
+            Ordering::Greater => Err(ReplicaError::UnexpectedView {
+                view_number,
+                replica_view_number: self.state.view_number,
+            }),
+        }
+    }
+
+    fn new_seed(op_number: u64, timestamp: u64) -> u64 {
+        let mut hasher = RandomState::new().build_hasher();
+
+        op_number.hash(&mut hasher);
+        timestamp.hash(&mut hasher);
+        hasher.finish()
     }
 
     fn new_message(&self, content: ClusterMessage<O>) -> ClusterMessageEnvelope<O> {
         ClusterMessageEnvelope {
             sender: self.identity,
+            group: self.cluster.group(),
             content,
         }
     }
@@ -185,8 +684,170 @@ where
         }))
     }
 
-    fn commit(&mut self, up_to_operation: u64) {
+    // only a single GetState is outstanding at a time
+    fn request_state(&mut self, recipient: ReplicaIdentity) -> ReplicaResult<()> {
+        if self.state.state_request.is_some() {
+            return Ok(());
+        }
+
+        self.state.state_request = Some(0);
+
+        let get_state = self.new_message(ClusterMessage::GetState(GetStateMessage {
+            replica: self.identity,
+            view_number: self.state.view_number,
+            op_number: self.op_log.current_size_with_offset(),
+        }));
+
+        self.cluster
+            .send(recipient, get_state)
+            .map_err(ReplicaError::TransportIssue)
+    }
+
+    fn start_checkpoint_transfer(
+        &mut self,
+        source: ReplicaIdentity,
+        op_number: u64,
+    ) -> ReplicaResult<()> {
+        if let Some(transfer) = &self.state.checkpoint_transfer {
+            if transfer.op_number >= op_number {
+                return Ok(());
+            }
+        }
+
+        self.state.checkpoint_transfer = Some(CheckpointTransfer {
+            source,
+            op_number,
+            offset: 0,
+        });
+
+        self.request_checkpoint_chunk()
+    }
+
+    fn request_checkpoint_chunk(&mut self) -> ReplicaResult<()> {
+        let Some(transfer) = &self.state.checkpoint_transfer else {
+            return Ok(());
+        };
 
+        let recipient = transfer.source;
+        let get_checkpoint =
+            self.new_message(ClusterMessage::GetCheckpoint(GetCheckpointMessage {
+                replica: self.identity,
+                view_number: self.state.view_number,
+                op_number: transfer.op_number,
+                offset: transfer.offset,
+            }));
+
+        self.cluster
+            .send(recipient, get_checkpoint)
+            .map_err(ReplicaError::TransportIssue)
+    }
+
+    fn commit(&mut self, up_to_operation: u64) -> ReplicaResult<()> {
+        let up_to_operation = up_to_operation.min(self.op_log.current_size_with_offset());
+
+        while self.state.commit_number < up_to_operation {
+            let op_number = self.state.commit_number + 1;
+            let entry = self
+                .op_log
+                .get(op_number - 1)
+                .map_err(ReplicaError::LogIssue)?;
+
+            match entry.operation.deref() {
+                ClientRequest::Register => {
+                    // the table only grows here, so this is where idle
+                    // sessions make room - judged by the primary's clock
+                    // alone, every replica evicts the same ones
+                    let session_policy = self.session_policy;
+
+                    self.client_log.retain(|_, reply| {
+                        !session_policy.is_expired(reply.last_timestamp, entry.timestamp)
+                    });
+                    self.client_log.insert(
+                        entry.client,
+                        ClientReply {
+                            session: op_number,
+                            request_number: entry.request_number,
+                            response: None,
+                            last_timestamp: entry.timestamp,
+                        },
+                    );
+                }
+                ClientRequest::Operation(operation) => {
+                    let context = OperationContext {
+                        op_number,
+                        timestamp: entry.timestamp,
+                        seed: entry.seed,
+                    };
+
+                    let response = self
+                        .state_machine
+                        .apply_operations(&[(context, operation)])
+                        .map_err(ReplicaError::StateMachineIssue)?
+                        .pop();
+
+                    // the session may have been evicted while the request was
+                    // in flight, its reply is dropped then
+                    if let Some(reply) = self.client_log.get_mut(&entry.client) {
+                        if reply.request_number < entry.request_number {
+                            reply.request_number = entry.request_number;
+                            reply.response = response;
+                            reply.last_timestamp = entry.timestamp;
+                        }
+                    }
+                }
+            }
+
+            if self.pending_requests.get(&entry.client) == Some(&entry.request_number) {
+                self.pending_requests.remove(&entry.client);
+            }
+
+            self.state.commit_number = op_number;
+            self.state.ticks_since_last_commit = 0;
+            self.state.bytes_since_checkpoint += entry.operation.footprint() as u64;
+        }
+
+        self.compact_log()
+    }
+
+    fn compact_log(&mut self) -> ReplicaResult<()> {
+        let operations = self.state.commit_number - self.state.checkpoint_op_number;
+
+        if operations == 0
+            || !self
+                .checkpoint_policy
+                .is_due(operations, self.state.bytes_since_checkpoint)
+        {
+            return Ok(());
+        }
+
+        self.checkpoint().map(|_| ())
+    }
+
+    /// Takes a checkpoint at the commit number right away regardless of the
+    /// policy, and trims the log up to it - returns the op number of the
+    /// latest checkpoint, which stays the same if nothing has been committed
+    /// since.
+    pub fn checkpoint(&mut self) -> ReplicaResult<u64> {
+        let commit_number = self.state.commit_number;
+
+        if commit_number == self.state.checkpoint_op_number {
+            return Ok(commit_number);
+        }
+
+        self.state_machine
+            .checkpoint(commit_number, self.state.view_number, &self.client_log)
+            .map_err(ReplicaError::StateMachineIssue)?;
+
+        // only committed ops are covered by the checkpoint, the uncommitted
+        // suffix stays in the log
+        self.op_log
+            .trim_front(commit_number)
+            .map_err(ReplicaError::LogIssue)?;
+
+        self.state.checkpoint_op_number = commit_number;
+        self.state.bytes_since_checkpoint = 0;
+
+        Ok(commit_number)
     }
 }
 
@@ -203,6 +864,762 @@ pub enum ReplicaError {
         request_number: u64,
         replica_number: u64,
     },
+    #[error("Message from view {} reached a replica in view {}!", .view_number, .replica_view_number)]
+    UnexpectedView {
+        view_number: u64,
+        replica_view_number: u64,
+    },
+    #[error("Client session {} has expired, the client has to register again!", .session)]
+    SessionExpired { session: SessionId },
     #[error("An error occurred when attempting to send a message! {}", .0)]
     TransportIssue(TransportError),
+    #[error("An error occurred when accessing the op log! {}", .0)]
+    LogIssue(LogError),
+    #[error("State machine failed to apply a committed operation! {}", .0)]
+    StateMachineIssue(StateError),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        collections::{BTreeMap, BTreeSet, VecDeque},
+        sync::{Arc, Mutex},
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use async_trait::async_trait;
+    use futures::executor::block_on;
+
+    use crate::{
+        log::{memory::MemoryLog, Footprint, Log, LogEntry},
+        message::{
+            BatchedClusterMessage, ClientMessage, ClusterMessage, ClusterMessageEnvelope,
+            CommitMessage, GetStateMessage, PrepareMessage,
+        },
+        state::{
+            Checkpoint, CheckpointChunk, OperationContext, StateError, StateMachine, StateResult,
+        },
+        transport::{TransportChannel, TransportResult},
+    };
+
+    use super::{
+        client::{
+            ClientIdentity, ClientReply, ClientRequest, ClientTable, SessionId, SessionPolicy,
+        },
+        cluster::Cluster,
+        CheckpointPolicy, Replica, ReplicaError, ReplicaIdentity, ReplicaResult,
+        STATE_REQUEST_TIMEOUT,
+    };
+
+    #[derive(Clone)]
+    struct Add(u64);
+
+    // as big as the value it adds, so that a few ops can fill a NewState
+    impl Footprint for Add {
+        fn footprint(&self) -> usize {
+            self.0 as usize
+        }
+    }
+
+    // answers every operation with the sum of everything added so far, and
+    // remembers the context of the latest one - checkpoints are kept in
+    // memory, encoded along with the client table, and handed out in small
+    // chunks
+    #[derive(Default)]
+    struct Sum {
+        total: Cell<u64>,
+        timestamp: Cell<u64>,
+        context: Cell<Option<OperationContext>>,
+        checkpoints: RefCell<BTreeMap<u64, Vec<u8>>>,
+        received: RefCell<Vec<u8>>,
+    }
+
+    impl Sum {
+        fn encode(&self, clients: &ClientTable<u64>) -> Vec<u8> {
+            let mut values = vec![self.total.get(), self.timestamp.get()];
+
+            for (client, reply) in clients {
+                values.extend([
+                    client.0,
+                    reply.session,
+                    reply.request_number,
+                    u64::from(reply.response.is_some()),
+                    reply.response.unwrap_or_default(),
+                    reply.last_timestamp,
+                ]);
+            }
+
+            values
+                .iter()
+                .flat_map(|value| value.to_be_bytes())
+                .collect()
+        }
+
+        fn decode(&self, data: &[u8]) -> ClientTable<u64> {
+            let values: Vec<_> = data
+                .chunks_exact(8)
+                .map(|value| u64::from_be_bytes(value.try_into().unwrap()))
+                .collect();
+
+            self.total.set(values[0]);
+            self.timestamp.set(values[1]);
+
+            values[2..]
+                .chunks_exact(6)
+                .map(|reply| {
+                    (
+                        ClientIdentity(reply[0]),
+                        ClientReply {
+                            session: reply[1],
+                            request_number: reply[2],
+                            response: (reply[3] == 1).then_some(reply[4]),
+                            last_timestamp: reply[5],
+                        },
+                    )
+                })
+                .collect()
+        }
+    }
+
+    impl StateMachine<Add, u64> for Sum {
+        fn apply_operations(
+            &self,
+            operations: &[(OperationContext, &Add)],
+        ) -> StateResult<Vec<u64>> {
+            Ok(operations
+                .iter()
+                .map(|(context, Add(value))| {
+                    self.total.set(self.total.get() + value);
+                    self.timestamp.set(context.timestamp);
+                    self.context.set(Some(*context));
+                    self.total.get()
+                })
+                .collect())
+        }
+    }
+
+    impl Checkpoint<u64> for Sum {
+        fn checkpoint(
+            &self,
+            op_number: u64,
+            _: u64,
+            clients: &ClientTable<u64>,
+        ) -> StateResult<()> {
+            let checkpoint = self.encode(clients);
+
+            self.checkpoints.borrow_mut().insert(op_number, checkpoint);
+            Ok(())
+        }
+
+        fn read_checkpoint(
+            &self,
+            op_number: u64,
+            offset: u64,
+            length: usize,
+        ) -> StateResult<CheckpointChunk> {
+            let checkpoints = self.checkpoints.borrow();
+            let checkpoint = checkpoints.get(&op_number).ok_or_else(|| {
+                StateError::CheckpointTransferFailed(format!("No checkpoint at {op_number}!"))
+            })?;
+            let start = (offset as usize).min(checkpoint.len());
+            let end = (start + length.min(16)).min(checkpoint.len());
+
+            Ok(CheckpointChunk {
+                data: checkpoint[start..end].to_vec().into(),
+                last: end == checkpoint.len(),
+            })
+        }
+
+        fn receive_checkpoint(&self, _: u64, offset: u64, data: &[u8]) -> StateResult<()> {
+            let mut received = self.received.borrow_mut();
+
+            received.truncate(offset as usize);
+            received.extend_from_slice(data);
+            Ok(())
+        }
+
+        fn install_checkpoint(&mut self, op_number: u64) -> StateResult<ClientTable<u64>> {
+            let checkpoint = self.received.take();
+            let clients = self.decode(&checkpoint);
+
+            self.checkpoints.borrow_mut().insert(op_number, checkpoint);
+            Ok(clients)
+        }
+
+        fn last_timestamp(&self) -> u64 {
+            self.timestamp.get()
+        }
+    }
+
+    type Inboxes = Arc<Mutex<BTreeMap<ReplicaIdentity, VecDeque<BatchedClusterMessage<Add>>>>>;
+
+    struct Endpoint {
+        identity: ReplicaIdentity,
+        inboxes: Inboxes,
+    }
+
+    #[async_trait]
+    impl TransportChannel<ReplicaIdentity, BatchedClusterMessage<Add>> for Endpoint {
+        async fn send(
+            &self,
+            recipient: ReplicaIdentity,
+            message: BatchedClusterMessage<Add>,
+        ) -> TransportResult<()> {
+            let mut inboxes = self.inboxes.lock().unwrap();
+
+            inboxes.entry(recipient).or_default().push_back(message);
+            Ok(())
+        }
+
+        async fn receive(&self) -> TransportResult<Option<BatchedClusterMessage<Add>>> {
+            let mut inboxes = self.inboxes.lock().unwrap();
+
+            Ok(inboxes.entry(self.identity).or_default().pop_front())
+        }
+    }
+
+    type TestReplica = Replica<Add, u64, Endpoint, MemoryLog<LogEntry<Add>>, Sum>;
+
+    // three replicas exchanging messages in memory, the first one is the
+    // primary
+    struct Group {
+        replicas: Vec<TestReplica>,
+        inboxes: Inboxes,
+    }
+
+    impl Group {
+        fn new() -> Self {
+            let identities: BTreeSet<_> = (1..=3).map(ReplicaIdentity).collect();
+            let inboxes = Inboxes::default();
+            let replicas = identities
+                .iter()
+                .map(|identity| {
+                    let endpoint = Endpoint {
+                        identity: *identity,
+                        inboxes: inboxes.clone(),
+                    };
+                    let cluster = Cluster::bootstrap(endpoint, identities.clone()).unwrap();
+
+                    Replica::new(*identity, cluster, MemoryLog::new(), Sum::default())
+                })
+                .collect();
+
+            Self { replicas, inboxes }
+        }
+
+        fn with_checkpoint_policy(mut self, checkpoint_policy: CheckpointPolicy) -> Self {
+            self.replicas = self
+                .replicas
+                .into_iter()
+                .map(|replica| replica.with_checkpoint_policy(checkpoint_policy))
+                .collect();
+            self
+        }
+
+        fn with_session_policy(mut self, session_policy: SessionPolicy) -> Self {
+            self.replicas = self
+                .replicas
+                .into_iter()
+                .map(|replica| replica.with_session_policy(session_policy))
+                .collect();
+            self
+        }
+
+        // sends and handles messages until there are none left - replicas
+        // that are down neither receive nor send any
+        fn deliver(&mut self, down: &[usize]) {
+            loop {
+                let mut delivered = false;
+
+                for replica in &mut self.replicas {
+                    block_on(replica.cluster_mut().send_bufferred_messages()).unwrap();
+                }
+
+                for (index, replica) in self.replicas.iter_mut().enumerate() {
+                    while let Some(envelope) = block_on(replica.cluster_mut().receive()).unwrap() {
+                        let sender = envelope.sender.0 as usize - 1;
+
+                        delivered = true;
+
+                        if !down.contains(&index) && !down.contains(&sender) {
+                            dispatch(replica, envelope).unwrap();
+                        }
+                    }
+                }
+
+                if !delivered {
+                    return;
+                }
+            }
+        }
+
+        // starts the replica over from the latest checkpoint it has taken or
+        // installed - or from scratch, as if it had lost its data
+        fn restart(&mut self, index: usize, restore: bool) {
+            let identity = self.replicas[index].identity();
+            let identities = self.replicas[index].cluster().replicas().clone();
+            let endpoint = Endpoint {
+                identity,
+                inboxes: self.inboxes.clone(),
+            };
+            let cluster = Cluster::bootstrap(endpoint, identities).unwrap();
+            let state_machine = Sum::default();
+            let mut op_log = MemoryLog::new();
+            let mut replica = match restore {
+                true => {
+                    let checkpoints = self.replicas[index].state_machine().checkpoints.take();
+                    let (op_number, checkpoint) = checkpoints.last_key_value().unwrap();
+                    let op_number = *op_number;
+                    let clients = state_machine.decode(checkpoint);
+
+                    op_log.reset(op_number).unwrap();
+                    state_machine.checkpoints.replace(checkpoints);
+
+                    Replica::new(identity, cluster, op_log, state_machine)
+                        .with_checkpoint(op_number, clients)
+                }
+                false => Replica::new(identity, cluster, op_log, state_machine),
+            };
+
+            replica.checkpoint_policy = self.replicas[index].checkpoint_policy;
+            self.replicas[index] = replica;
+        }
+
+        // sends whatever the replicas have to say, and takes the messages
+        // waiting for the replica without handling them
+        fn take_inbox(&mut self, index: usize) -> Vec<ClusterMessage<Add>> {
+            for replica in &mut self.replicas {
+                block_on(replica.cluster_mut().send_bufferred_messages()).unwrap();
+            }
+
+            let identity = self.replicas[index].identity();
+            let mut inboxes = self.inboxes.lock().unwrap();
+
+            inboxes
+                .remove(&identity)
+                .unwrap_or_default()
+                .into_iter()
+                .flat_map(|batch| batch.into_messages())
+                .map(|envelope| envelope.content)
+                .collect()
+        }
+
+        fn register(&mut self, client: ClientIdentity, down: &[usize]) -> SessionId {
+            let register = ClientMessage {
+                session: 0,
+                request_number: 0,
+                request: ClientRequest::Register,
+            };
+
+            self.replicas[0].apply_request(client, register).unwrap();
+            self.deliver(down);
+            self.replicas[0].client_reply(client).unwrap().session
+        }
+
+        fn add(
+            &mut self,
+            client: ClientIdentity,
+            session: SessionId,
+            request_number: u64,
+            value: u64,
+            down: &[usize],
+        ) -> Option<u64> {
+            let message = ClientMessage {
+                session,
+                request_number,
+                request: ClientRequest::Operation(Add(value)),
+            };
+
+            self.replicas[0].apply_request(client, message).unwrap();
+            self.deliver(down);
+            self.replicas[0].client_reply(client).unwrap().response
+        }
+    }
+
+    fn dispatch(
+        replica: &mut TestReplica,
+        envelope: ClusterMessageEnvelope<Add>,
+    ) -> ReplicaResult<()> {
+        match envelope.content {
+            ClusterMessage::Prepare(message) => replica.apply_prepare(message),
+            ClusterMessage::PrepareOk(message) => replica.apply_prepare_ok(message),
+            ClusterMessage::Commit(message) => replica.apply_commit(message),
+            ClusterMessage::GetState(message) => replica.apply_get_state(message),
+            ClusterMessage::NewState(message) => replica.apply_new_state(message),
+            ClusterMessage::GetCheckpoint(message) => replica.apply_get_checkpoint(message),
+            ClusterMessage::CheckpointChunk(message) => replica.apply_checkpoint_chunk(message),
+            ClusterMessage::StartViewChange
+            | ClusterMessage::DoViewChange
+            | ClusterMessage::StartView => Ok(()),
+        }
+    }
+
+    fn prepare(view_number: u64, op_number: u64) -> PrepareMessage<Add> {
+        PrepareMessage {
+            requesting_replica: ReplicaIdentity(1),
+            view_number,
+            op_number,
+            commit_number: op_number,
+            timestamp: 0,
+            seed: 0,
+            client: ClientIdentity(7),
+            request: ClientRequest::Operation(Add(1)),
+            request_number: op_number,
+        }
+    }
+
+    #[test]
+    pub fn messages_from_other_views_are_reported_or_dropped() {
+        let mut group = Group::new();
+        let client = ClientIdentity(7);
+        let session = group.register(client, &[]);
+
+        group.add(client, session, 1, 5, &[]);
+        group.replicas[0].advance_time().unwrap();
+        group.deliver(&[]);
+
+        let backup = &mut group.replicas[1];
+
+        assert!(matches!(
+            backup.apply_prepare(prepare(1, 3)),
+            Err(ReplicaError::UnexpectedView {
+                view_number: 1,
+                replica_view_number: 0
+            })
+        ));
+        assert!(matches!(
+            backup.apply_commit(CommitMessage {
+                view_number: 1,
+                commit_number: 3
+            }),
+            Err(ReplicaError::UnexpectedView { .. })
+        ));
+
+        // once the replica has moved on, the old view's messages are stale
+        backup.state.view_number = 2;
+
+        backup.apply_prepare(prepare(1, 3)).unwrap();
+        backup
+            .apply_commit(CommitMessage {
+                view_number: 1,
+                commit_number: 3,
+            })
+            .unwrap();
+
+        assert_eq!(backup.op_number(), 2);
+        assert_eq!(backup.state().commit_number(), 2);
+        assert_eq!(backup.state_machine().total.get(), 5);
+    }
+
+    #[test]
+    pub fn timestamps_never_go_backwards_even_if_the_clock_does() {
+        let mut group = Group::new();
+        let client = ClientIdentity(7);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        // as if the clock had been an hour ahead when the last op was stamped
+        let ahead = now + 3_600_000;
+
+        group.replicas[0].state.last_timestamp = ahead;
+
+        let session = group.register(client, &[]);
+
+        group.add(client, session, 1, 5, &[]);
+
+        let timestamps: Vec<_> = (0..2)
+            .map(|index| group.replicas[0].op_log.get(index).unwrap().timestamp)
+            .collect();
+
+        assert_eq!(timestamps, vec![ahead + 1, ahead + 2]);
+    }
+
+    #[test]
+    pub fn backups_apply_ops_with_the_primarys_timestamp_and_seed() {
+        let mut group = Group::new();
+        let client = ClientIdentity(7);
+        let session = group.register(client, &[]);
+
+        group.add(client, session, 1, 5, &[]);
+        group.add(client, session, 2, 6, &[]);
+        // the backups learn about the latest commit from the next heartbeat
+        group.replicas[0].advance_time().unwrap();
+        group.deliver(&[]);
+
+        let entries = |replica: &TestReplica| {
+            (0..3)
+                .map(|index| {
+                    let entry = replica.op_log.get(index).unwrap();
+
+                    (entry.timestamp, entry.seed)
+                })
+                .collect::<Vec<_>>()
+        };
+        let primary = entries(&group.replicas[0]);
+
+        assert!(primary.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_ne!(primary[1].1, primary[2].1);
+
+        for replica in &group.replicas {
+            let context = replica.state_machine().context.get().unwrap();
+
+            assert_eq!(entries(replica), primary);
+            assert_eq!(context.op_number, 3);
+            assert_eq!((context.timestamp, context.seed), primary[2]);
+        }
+    }
+
+    #[test]
+    pub fn log_is_trimmed_behind_checkpoints_which_serve_lagging_replicas() {
+        let mut group = Group::new().with_checkpoint_policy(CheckpointPolicy {
+            operations: Some(2),
+            bytes: None,
+        });
+        let client = ClientIdentity(7);
+        // the third replica is down while the first five ops are committed
+        let session = group.register(client, &[2]);
+
+        for value in 1..=4 {
+            group.add(client, session, value, value, &[2]);
+        }
+
+        let primary = &group.replicas[0];
+
+        assert_eq!(primary.state().commit_number(), 5);
+        assert_eq!(primary.state().checkpoint_op_number(), 4);
+        assert_eq!(primary.log_offset(), 4);
+        assert!(primary.op_log.get(3).is_err());
+        assert!(primary
+            .state_machine()
+            .checkpoints
+            .borrow()
+            .contains_key(&4));
+
+        // the ops it has missed are only left in the checkpoint, which it
+        // installs before fetching the rest of the log
+        group.replicas[0].advance_time().unwrap();
+        group.deliver(&[]);
+
+        let lagging = &group.replicas[2];
+
+        assert_eq!(lagging.state().commit_number(), 5);
+        assert_eq!(lagging.state().checkpoint_op_number(), 4);
+        assert_eq!(lagging.log_offset(), 4);
+        assert_eq!(lagging.op_number(), 5);
+        assert_eq!(lagging.state_machine().total.get(), 10);
+        assert_eq!(
+            lagging.client_reply(client),
+            group.replicas[0].client_reply(client)
+        );
+    }
+
+    #[test]
+    pub fn lagging_replicas_catch_up_through_checkpoints_and_bounded_new_states() {
+        let mut group = Group::new().with_checkpoint_policy(CheckpointPolicy {
+            operations: Some(4),
+            bytes: None,
+        });
+        let client = ClientIdentity(7);
+        let session = group.register(client, &[2]);
+
+        for value in 1..=3 {
+            group.add(client, session, value, value, &[2]);
+        }
+
+        // everything the third replica has missed is covered by the
+        // checkpoint, which takes several chunks to transfer
+        group.replicas[0].advance_time().unwrap();
+        group.deliver(&[]);
+
+        let primary = &group.replicas[0];
+        let lagging = &group.replicas[2];
+
+        assert_eq!(primary.log_offset(), 4);
+        assert_eq!(lagging.state().commit_number(), 4);
+        assert_eq!(lagging.state_machine().total.get(), 6);
+        assert_eq!(lagging.state.last_timestamp, primary.state.last_timestamp);
+        assert_eq!(lagging.state.state_request, None);
+
+        for request_number in 4..=6 {
+            group.add(client, session, request_number, 400_000, &[2]);
+        }
+
+        // the ops past the checkpoint don't fit into a single NewState
+        group.replicas[0]
+            .apply_get_state(GetStateMessage {
+                replica: ReplicaIdentity(3),
+                view_number: 0,
+                op_number: 4,
+            })
+            .unwrap();
+
+        let new_states: Vec<_> = group
+            .take_inbox(2)
+            .into_iter()
+            .filter_map(|message| match message {
+                ClusterMessage::NewState(message) => Some((
+                    message.first_op_number,
+                    message.entries.len(),
+                    message.commit_number,
+                )),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(new_states, vec![(5, 2, 7)]);
+
+        // while its GetState is unanswered, the replica doesn't ask again
+        let lagging = &mut group.replicas[2];
+
+        lagging
+            .apply_commit(CommitMessage {
+                view_number: 0,
+                commit_number: 7,
+            })
+            .unwrap();
+        lagging.apply_prepare(prepare(0, 8)).unwrap();
+
+        let get_states = group
+            .take_inbox(0)
+            .into_iter()
+            .filter(|message| matches!(message, ClusterMessage::GetState(_)))
+            .count();
+
+        assert_eq!(get_states, 1);
+
+        // the lost request times out, and the next prepare asks again
+        let lagging = &mut group.replicas[2];
+
+        for _ in 1..STATE_REQUEST_TIMEOUT {
+            lagging.advance_time().unwrap();
+        }
+
+        lagging.apply_prepare(prepare(0, 8)).unwrap();
+        assert_eq!(lagging.state.state_request, Some(STATE_REQUEST_TIMEOUT - 1));
+
+        lagging.advance_time().unwrap();
+        lagging.apply_prepare(prepare(0, 8)).unwrap();
+        assert_eq!(lagging.state.state_request, Some(0));
+
+        group.deliver(&[]);
+
+        let primary = &group.replicas[0];
+        let lagging = &group.replicas[2];
+
+        assert_eq!(lagging.state().commit_number(), 7);
+        assert_eq!(lagging.op_number(), 7);
+        assert_eq!(lagging.state_machine().total.get(), 1_200_006);
+        assert_eq!(lagging.client_reply(client), primary.client_reply(client));
+    }
+
+    #[test]
+    pub fn duplicate_requests_are_answered_from_restored_and_installed_client_tables() {
+        let mut group = Group::new().with_checkpoint_policy(CheckpointPolicy {
+            operations: Some(2),
+            bytes: None,
+        });
+        let client = ClientIdentity(7);
+        let session = group.register(client, &[]);
+        let duplicate = ClientMessage {
+            session,
+            request_number: 1,
+            request: ClientRequest::Operation(Add(5)),
+        };
+
+        assert_eq!(group.add(client, session, 1, 5, &[]), Some(5));
+
+        group.replicas[0].advance_time().unwrap();
+        group.deliver(&[]);
+
+        // the primary restarts from its own checkpoint, the retried request
+        // isn't applied again
+        group.restart(0, true);
+
+        let primary = &mut group.replicas[0];
+
+        assert_eq!(primary.state().commit_number(), 2);
+
+        primary.apply_request(client, duplicate.clone()).unwrap();
+        group.deliver(&[]);
+
+        let primary = &group.replicas[0];
+
+        assert_eq!(primary.op_number(), 2);
+        assert_eq!(primary.state_machine().total.get(), 5);
+        assert_eq!(primary.client_reply(client).unwrap().response, Some(5));
+
+        // this time it has lost its data and installs a backup's checkpoint
+        group.restart(0, false);
+        group.replicas[0].request_state(ReplicaIdentity(2)).unwrap();
+        group.deliver(&[]);
+
+        let primary = &mut group.replicas[0];
+
+        assert_eq!(primary.state().checkpoint_op_number(), 2);
+
+        primary.apply_request(client, duplicate).unwrap();
+        group.deliver(&[]);
+
+        let primary = &group.replicas[0];
+
+        assert_eq!(primary.op_number(), 2);
+        assert_eq!(primary.state_machine().total.get(), 5);
+        assert_eq!(primary.client_reply(client).unwrap().response, Some(5));
+
+        // the session carries on where it left off
+        assert_eq!(group.add(client, session, 2, 1, &[]), Some(6));
+    }
+
+    #[test]
+    pub fn idle_sessions_are_evicted_everywhere_once_another_client_registers() {
+        let mut group = Group::new().with_session_policy(SessionPolicy {
+            idle_timeout: Some(1000),
+        });
+        let (idle, other) = (ClientIdentity(7), ClientIdentity(8));
+        // the primary's clock is pinned by stamping ops from far ahead of it
+        let ahead = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+            + 3_600_000;
+
+        group.replicas[0].state.last_timestamp = ahead;
+
+        // a session is named after the op its registration was committed as
+        let session = group.register(idle, &[]);
+
+        assert_eq!(session, 1);
+        assert_eq!(group.add(idle, session, 1, 5, &[]), Some(5));
+
+        // long idle, but nobody has registered since
+        group.replicas[0].state.last_timestamp = ahead + 10_000;
+
+        assert_eq!(group.add(idle, session, 2, 1, &[]), Some(6));
+
+        group.replicas[0].state.last_timestamp = ahead + 20_000;
+
+        assert_eq!(group.register(other, &[]), 4);
+
+        group.replicas[0].advance_time().unwrap();
+        group.deliver(&[]);
+
+        for replica in &group.replicas {
+            assert_eq!(replica.state().commit_number(), 4);
+            assert!(replica.client_reply(idle).is_none());
+            assert_eq!(replica.client_reply(other).unwrap().session, 4);
+        }
+
+        let message = ClientMessage {
+            session,
+            request_number: 3,
+            request: ClientRequest::Operation(Add(1)),
+        };
+
+        assert!(matches!(
+            group.replicas[0].apply_request(idle, message),
+            Err(ReplicaError::SessionExpired { session: 1 })
+        ));
+    }
 }
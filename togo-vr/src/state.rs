@@ -1,10 +1,63 @@
+use bytes::Bytes;
 use thiserror::Error;
 
+use crate::replica::client::ClientTable;
+
 pub type StateResult<T> = Result<T, StateError>;
 
+/// Deterministic inputs assigned to an operation by the primary that prepared
+/// it - state machines must rely on these instead of the local clock or RNG.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OperationContext {
+    pub op_number: u64,
+    pub timestamp: u64,
+    pub seed: u64,
+}
+
 pub trait StateMachine<O, OR> {
-    fn apply_operations(&self, operations: &[O]) -> StateResult<Vec<OR>>;
+    fn apply_operations(&self, operations: &[(OperationContext, &O)]) -> StateResult<Vec<OR>>;
+}
+
+/// Persists the state machine as of `op_number`, so that the log prefix up to
+/// it can be trimmed.
+///
+/// Checkpoints are shipped to lagging replicas as opaque bytes - the sender
+/// reads them in parts, the receiver stages them and installs the checkpoint
+/// once the last part has arrived. The client table is committed state as
+/// well, so it's stored in and restored from every checkpoint.
+pub trait Checkpoint<OR> {
+    fn checkpoint(
+        &self,
+        op_number: u64,
+        view_number: u64,
+        clients: &ClientTable<OR>,
+    ) -> StateResult<()>;
+    fn read_checkpoint(
+        &self,
+        op_number: u64,
+        offset: u64,
+        length: usize,
+    ) -> StateResult<CheckpointChunk>;
+    fn receive_checkpoint(&self, op_number: u64, offset: u64, data: &[u8]) -> StateResult<()>;
+    /// Replaces the whole state with the staged checkpoint.
+    fn install_checkpoint(&mut self, op_number: u64) -> StateResult<ClientTable<OR>>;
+    /// Timestamp of the latest operation the state reflects, e.g. once a
+    /// checkpoint has been installed.
+    fn last_timestamp(&self) -> u64;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckpointChunk {
+    pub data: Bytes,
+    pub last: bool,
 }
 
 #[derive(Debug, Error)]
-pub enum StateError {}
+pub enum StateError {
+    #[error("Failed to apply an operation! {}", .0)]
+    ApplyFailed(String),
+    #[error("Failed to take a checkpoint! {}", .0)]
+    CheckpointFailed(String),
+    #[error("Failed to transfer a checkpoint! {}", .0)]
+    CheckpointTransferFailed(String),
+}
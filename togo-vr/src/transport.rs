@@ -11,5 +11,10 @@ pub trait TransportChannel<I, T> {
 
 #[derive(Debug, Error)]
 pub enum TransportError {
-
-}
\ No newline at end of file
+    #[error("Transport state lock is poisoned!")]
+    Poisoned,
+    #[error("Failed to reach the peer! {}", .0)]
+    Unreachable(String),
+    #[error("Transport has been closed!")]
+    Closed,
+}
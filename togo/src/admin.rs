@@ -0,0 +1,269 @@
+use clap::Subcommand;
+use futures::future::join_all;
+use togo_vr::replica::{ReplicaIdentity, ReplicaStatus};
+use tokio::net::TcpStream;
+
+use crate::{
+    client::{ClientError, ClientResult},
+    config::Config,
+    protocol::{exchange, AdminRequest, ClientFrame, ReplicaReport, ServerFrame},
+};
+
+// view changes, leadership transfers and reconfiguration are out of scope
+// until the replica implements them
+#[derive(Subcommand)]
+pub enum AdminCommand {
+    /// Prints where every replica stands and how far it lags behind the
+    /// primary.
+    Status,
+    /// Takes a checkpoint and trims the log up to it.
+    Checkpoint {
+        /// Only checkpoints this replica instead of all of them.
+        #[arg(long)]
+        replica: Option<u32>,
+    },
+}
+
+/// Runs the command against the replicas the node's configuration lists,
+/// printing every replica's answer.
+pub async fn run(config: &Config, command: AdminCommand) -> ClientResult<()> {
+    match command {
+        AdminCommand::Status => status(config).await,
+        AdminCommand::Checkpoint { replica } => {
+            let replicas = match replica {
+                Some(replica) => vec![ReplicaIdentity(replica)],
+                None => identities(config),
+            };
+
+            broadcast(config, &replicas, AdminRequest::Checkpoint).await
+        }
+    }
+}
+
+async fn status(config: &Config) -> ClientResult<()> {
+    let reports = reports(config).await;
+    let committed = committed(&reports);
+
+    println!(
+        "{:<8} {:<8} {:<11} {:>6} {:>10} {:>10} {:>10} {:>10} {:>8}",
+        "replica", "role", "status", "view", "op", "commit", "offset", "checkpoint", "lag"
+    );
+
+    for (identity, report) in &reports {
+        println!("{}", row(*identity, report, committed));
+    }
+
+    match reports.iter().filter(|(_, report)| report.is_err()).count() {
+        0 => Ok(()),
+        failed => Err(ClientError::Failed(format!(
+            "{failed} of {} replicas couldn't be reached!",
+            reports.len()
+        ))),
+    }
+}
+
+// the primary's commit number every replica's lag is measured against,
+// judged by the furthest replica if the primary can't be reached
+fn committed(reports: &[(ReplicaIdentity, ClientResult<ReplicaReport>)]) -> Option<u64> {
+    reports
+        .iter()
+        .filter_map(|(_, report)| report.as_ref().ok())
+        .map(|report| (report.identity == report.primary, report.commit_number))
+        .max()
+        .map(|(_, commit_number)| commit_number)
+}
+
+fn row(
+    identity: ReplicaIdentity,
+    report: &ClientResult<ReplicaReport>,
+    committed: Option<u64>,
+) -> String {
+    match report {
+        Ok(report) => format!(
+            "{:<8} {:<8} {:<11} {:>6} {:>10} {:>10} {:>10} {:>10} {:>8}",
+            identity.0,
+            if report.identity == report.primary {
+                "primary"
+            } else {
+                "backup"
+            },
+            match report.status {
+                ReplicaStatus::Normal => "normal",
+                ReplicaStatus::Recovery => "recovery",
+                ReplicaStatus::ViewChange => "view-change",
+            },
+            report.view_number,
+            report.op_number,
+            report.commit_number,
+            report.log_offset,
+            report.checkpoint_op_number,
+            committed
+                .unwrap_or_default()
+                .saturating_sub(report.commit_number),
+        ),
+        Err(error) => format!("{:<8} {error}", identity.0),
+    }
+}
+
+async fn reports(config: &Config) -> Vec<(ReplicaIdentity, ClientResult<ReplicaReport>)> {
+    let replicas = identities(config);
+    let reports = join_all(replicas.iter().map(|replica| async move {
+        match request(config, *replica, &AdminRequest::Status).await? {
+            ServerFrame::Status(report) => Ok(report),
+            ServerFrame::Failed(message) => Err(ClientError::Failed(message)),
+            _ => Err(unexpected()),
+        }
+    }))
+    .await;
+
+    replicas.into_iter().zip(reports).collect()
+}
+
+async fn broadcast(
+    config: &Config,
+    replicas: &[ReplicaIdentity],
+    request: AdminRequest,
+) -> ClientResult<()> {
+    let answers = join_all(
+        replicas
+            .iter()
+            .map(|replica| self::request(config, *replica, &request)),
+    )
+    .await;
+    let mut failed = 0;
+
+    for (replica, answer) in replicas.iter().zip(answers) {
+        match answer {
+            Ok(ServerFrame::Checkpointed(op_number)) => {
+                println!("{replica}: checkpoint at op {op_number}")
+            }
+            Ok(ServerFrame::Failed(message)) => {
+                failed += 1;
+                println!("{replica}: {message}");
+            }
+            Ok(_) => {
+                failed += 1;
+                println!("{replica}: {}", unexpected());
+            }
+            Err(error) => {
+                failed += 1;
+                println!("{replica}: {error}");
+            }
+        }
+    }
+
+    match failed {
+        0 => Ok(()),
+        failed => Err(ClientError::Failed(format!(
+            "{failed} of {} replicas failed!",
+            replicas.len()
+        ))),
+    }
+}
+
+async fn request(
+    config: &Config,
+    replica: ReplicaIdentity,
+    request: &AdminRequest,
+) -> ClientResult<ServerFrame> {
+    let address = config
+        .replicas
+        .iter()
+        .find(|config| config.identity == replica.0)
+        .map(|config| config.client_address.as_str())
+        .ok_or_else(|| ClientError::Failed(format!("Replica {replica} isn't configured!")))?;
+    let mut stream = TcpStream::connect(address).await?;
+
+    let frame = ClientFrame::Admin(request.clone());
+
+    Ok(exchange(&mut stream, &frame, config.client_timeout()).await?)
+}
+
+fn identities(config: &Config) -> Vec<ReplicaIdentity> {
+    let mut identities: Vec<_> = config
+        .replicas
+        .iter()
+        .map(|replica| ReplicaIdentity(replica.identity))
+        .collect();
+
+    identities.sort();
+    identities
+}
+
+fn unexpected() -> ClientError {
+    ClientError::Failed("Replica answered with an unexpected frame!".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use togo_vr::replica::{ReplicaIdentity, ReplicaStatus};
+
+    use crate::{
+        client::{ClientError, ClientResult},
+        config::Config,
+        protocol::{AdminRequest, ReplicaReport},
+    };
+
+    use super::{committed, identities, request, row};
+
+    fn report(identity: u32, commit_number: u64) -> ClientResult<ReplicaReport> {
+        Ok(ReplicaReport {
+            identity: ReplicaIdentity(identity),
+            primary: ReplicaIdentity(1),
+            status: ReplicaStatus::Normal,
+            view_number: 0,
+            op_number: commit_number + 1,
+            commit_number,
+            log_offset: 0,
+            checkpoint_op_number: 0,
+        })
+    }
+
+    fn unreachable() -> ClientResult<ReplicaReport> {
+        Err(ClientError::Failed("Connection refused!".into()))
+    }
+
+    #[test]
+    pub fn lag_is_measured_against_the_primary_or_the_furthest_replica() {
+        let mut reports = vec![
+            (ReplicaIdentity(1), report(1, 10)),
+            (ReplicaIdentity(2), report(2, 12)),
+            (ReplicaIdentity(3), report(3, 7)),
+        ];
+
+        assert_eq!(committed(&reports), Some(10));
+
+        let backup = row(ReplicaIdentity(3), &reports[2].1, committed(&reports));
+
+        assert!(backup.starts_with("3        backup   normal"));
+        assert!(backup.ends_with(" 3"));
+        assert!(row(ReplicaIdentity(1), &reports[0].1, Some(10)).contains("primary"));
+
+        reports[0].1 = unreachable();
+
+        assert_eq!(committed(&reports), Some(12));
+        assert_eq!(
+            row(ReplicaIdentity(1), &reports[0].1, Some(12)),
+            "1        Connection refused!"
+        );
+
+        reports[1].1 = unreachable();
+        reports[2].1 = unreachable();
+
+        assert_eq!(committed(&reports), None);
+    }
+
+    #[tokio::test]
+    pub async fn only_configured_replicas_are_asked() {
+        let config = Config::parse(include_str!("../togo.example.toml")).unwrap();
+
+        assert_eq!(
+            identities(&config),
+            vec![ReplicaIdentity(1), ReplicaIdentity(2), ReplicaIdentity(3)]
+        );
+        assert!(matches!(
+            request(&config, ReplicaIdentity(9), &AdminRequest::Status).await,
+            Err(ClientError::Failed(message)) if message == "Replica 9 isn't configured!"
+        ));
+    }
+}
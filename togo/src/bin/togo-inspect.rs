@@ -0,0 +1,495 @@
+use std::{
+    cmp::Ordering,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use bytes::Bytes;
+use clap::{Parser, Subcommand};
+use thiserror::Error;
+use togo_core::{
+    kv::{KvStateMachine, VersionedValue},
+    log::blocklog::{BlockFile, BlockLog},
+    operation::Operation,
+    shard::range::KeyRange,
+    storage::{
+        checkpoint::{CheckpointFile, CheckpointStore},
+        memory::MemoryStorage,
+        snapshot::{self, SnapshotMetadata},
+        StorageError,
+    },
+};
+use togo_vr::{log::LogEntry, replica::client::ClientRequest};
+
+type InspectResult<T> = Result<T, InspectError>;
+
+/// Reads a replica's data without a running server and without ever writing
+/// to it.
+#[derive(Parser)]
+#[command(version, about = "Inspects the logs and checkpoints of togo replicas")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Verifies the checksums of a block log and prints its entries.
+    Log {
+        /// The log directory, `log` within the replica's data directory.
+        directory: PathBuf,
+        /// The first op to print.
+        #[arg(long, default_value_t = 1)]
+        from: u64,
+        /// The last op to print.
+        #[arg(long)]
+        to: Option<u64>,
+        /// Only verifies the log without printing its entries.
+        #[arg(short, long)]
+        quiet: bool,
+    },
+    /// Verifies a snapshot and prints the live keys within the range.
+    Snapshot {
+        path: PathBuf,
+        #[arg(long, default_value = "")]
+        start: String,
+        /// The first key past the range, it extends over every key if unset.
+        #[arg(long)]
+        end: Option<String>,
+    },
+    /// Prints every key whose value differs between two snapshots.
+    Diff { left: PathBuf, right: PathBuf },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Difference {
+    Removed(Bytes, VersionedValue),
+    Added(Bytes, VersionedValue),
+    Changed(Bytes, VersionedValue, VersionedValue),
+}
+
+fn main() -> ExitCode {
+    let result = match Cli::parse().command {
+        Command::Log {
+            directory,
+            from,
+            to,
+            quiet,
+        } => inspect_log(&directory, from, to.unwrap_or(u64::MAX), quiet),
+        Command::Snapshot { path, start, end } => {
+            inspect_snapshot(&path, KeyRange::new(start, end))
+        }
+        Command::Diff { left, right } => diff_snapshots(&left, &right),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("{error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn inspect_log(directory: &Path, from: u64, to: u64, quiet: bool) -> InspectResult<()> {
+    let paths = BlockLog::<LogEntry<Operation>>::list(directory)?;
+    let mut problems = 0;
+    let mut next_op = None;
+
+    for (index, path) in paths.iter().enumerate() {
+        let block: BlockFile<LogEntry<Operation>> = match BlockLog::read_block(path) {
+            Ok(block) => block,
+            Err(error) => {
+                problems += 1;
+                println!("{}: {error}", path.display());
+                continue;
+            }
+        };
+        // op numbers are 1-based, the block starts after op `start_id`
+        let first = block.start_id + 1;
+        let last = block.start_id + block.entries.len() as u64;
+
+        println!(
+            "{}: ops {first}-{last}, {} of {} bytes valid",
+            block.path.display(),
+            block.valid_length,
+            block.length
+        );
+
+        if next_op.is_some_and(|next_op| next_op != first) {
+            problems += 1;
+            println!("  doesn't continue where the previous block ends!");
+        }
+
+        // a torn tail of the last block is what a crash leaves behind, it's
+        // cut off when the log is opened next - a bad record followed by more
+        // data is corruption wherever it is
+        if block.valid_length < block.length {
+            if index + 1 == paths.len() && !block.corrupted {
+                println!("  ends with a torn record");
+            } else {
+                problems += 1;
+                println!("  is corrupted past op {last}!");
+            }
+        }
+
+        if !quiet {
+            for (op_number, entry) in (first..).zip(&block.entries) {
+                if (from..=to).contains(&op_number) {
+                    print_entry(op_number, entry);
+                }
+            }
+        }
+
+        next_op = Some(last + 1);
+    }
+
+    match problems {
+        0 => Ok(()),
+        problems => Err(InspectError::Corrupted(format!(
+            "Found {problems} problems with the log!"
+        ))),
+    }
+}
+
+fn print_entry(op_number: u64, entry: &LogEntry<Operation>) {
+    let prefix = format!(
+        "op {op_number} client {} request {} at {}",
+        entry.client.0, entry.request_number, entry.timestamp
+    );
+
+    match entry.operation.as_ref() {
+        ClientRequest::Register => println!("{prefix}: register"),
+        ClientRequest::Operation(operation) => println!("{prefix}: {operation:?}"),
+    }
+}
+
+fn inspect_snapshot(path: &Path, range: KeyRange) -> InspectResult<()> {
+    let (metadata, state_machine) = load_snapshot(path)?;
+
+    print_metadata(path, &metadata);
+
+    for (key, value) in state_machine.scan(&range, usize::MAX)? {
+        println!("{key:?} version {} {:?}", value.version, value.value);
+    }
+
+    Ok(())
+}
+
+fn diff_snapshots(left_path: &Path, right_path: &Path) -> InspectResult<()> {
+    let (left_metadata, left) = load_snapshot(left_path)?;
+    let (right_metadata, right) = load_snapshot(right_path)?;
+    let everything = KeyRange::default();
+
+    print_metadata(left_path, &left_metadata);
+    print_metadata(right_path, &right_metadata);
+
+    let differences = diff(
+        left.scan(&everything, usize::MAX)?,
+        right.scan(&everything, usize::MAX)?,
+    );
+
+    for difference in &differences {
+        match difference {
+            Difference::Removed(key, value) => {
+                println!("- {key:?} version {} {:?}", value.version, value.value)
+            }
+            Difference::Added(key, value) => {
+                println!("+ {key:?} version {} {:?}", value.version, value.value)
+            }
+            Difference::Changed(key, left, right) => println!(
+                "~ {key:?} version {} {:?} -> version {} {:?}",
+                left.version, left.value, right.version, right.value
+            ),
+        }
+    }
+
+    match differences.len() {
+        0 => Ok(()),
+        count => Err(InspectError::Diverged(count)),
+    }
+}
+
+// both sides are in key order
+fn diff(
+    left: Vec<(Bytes, VersionedValue)>,
+    right: Vec<(Bytes, VersionedValue)>,
+) -> Vec<Difference> {
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    let mut differences = Vec::new();
+
+    loop {
+        let order = match (left.peek(), right.peek()) {
+            (Some((left_key, _)), Some((right_key, _))) => left_key.cmp(right_key),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => return differences,
+        };
+
+        match order {
+            Ordering::Less => {
+                let (key, value) = left.next().expect("peeked above");
+
+                differences.push(Difference::Removed(key, value));
+            }
+            Ordering::Greater => {
+                let (key, value) = right.next().expect("peeked above");
+
+                differences.push(Difference::Added(key, value));
+            }
+            Ordering::Equal => {
+                let (key, left_value) = left.next().expect("peeked above");
+                let (_, right_value) = right.next().expect("peeked above");
+
+                if left_value != right_value {
+                    differences.push(Difference::Changed(key, left_value, right_value));
+                }
+            }
+        }
+    }
+}
+
+// the snapshot is verified in full before it's restored into memory
+fn load_snapshot(path: &Path) -> InspectResult<(SnapshotMetadata, KvStateMachine<MemoryStorage>)> {
+    let file = File::open(path).map_err(StorageError::Io)?;
+    let metadata = snapshot::verify(BufReader::new(file))?;
+    let directory = path.parent().unwrap_or(Path::new("."));
+    let checkpoints = CheckpointStore::open(directory, metadata.header.cluster_id)?;
+    let mut state_machine = KvStateMachine::new(MemoryStorage::new()).with_checkpoints(checkpoints);
+
+    state_machine.restore(&CheckpointFile {
+        op_number: metadata.header.op_number,
+        path: path.to_path_buf(),
+    })?;
+
+    Ok((metadata, state_machine))
+}
+
+fn print_metadata(path: &Path, metadata: &SnapshotMetadata) {
+    let header = &metadata.header;
+    let manifest = &metadata.manifest;
+
+    println!(
+        "{}: op {} in view {} of cluster {}, {:?} backend, {} storage keys in {} chunks, checksum {:08x}",
+        path.display(),
+        header.op_number,
+        header.view,
+        header.cluster_id,
+        header.backend,
+        manifest.key_count,
+        manifest.chunk_checksums.len(),
+        manifest.checksum
+    );
+}
+
+#[derive(Debug, Error)]
+enum InspectError {
+    #[error("{}", .0)]
+    Corrupted(String),
+    #[error("Snapshots differ in {} keys!", .0)]
+    Diverged(usize),
+    #[error("Storage error: {}", .0)]
+    Storage(StorageError),
+}
+
+impl From<StorageError> for InspectError {
+    fn from(value: StorageError) -> Self {
+        Self::Storage(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, OpenOptions},
+        io::Write,
+        path::{Path, PathBuf},
+        rc::Rc,
+    };
+
+    use togo_core::{
+        kv::{KvStateMachine, VersionedValue},
+        log::blocklog::BlockLog,
+        operation::Operation,
+        shard::range::KeyRange,
+        storage::{checkpoint::CheckpointStore, memory::MemoryStorage},
+    };
+    use togo_vr::{
+        log::{Log, LogEntry},
+        replica::client::{ClientIdentity, ClientRequest, ClientTable},
+        state::{Checkpoint, OperationContext},
+    };
+
+    use super::{diff, inspect_log, load_snapshot, Difference, InspectError};
+
+    fn directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("togo-inspect-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+
+        directory
+    }
+
+    // a log of `count` upserts, returning its blocks
+    fn write_log(directory: &Path, block_size: u64, count: u64) -> Vec<PathBuf> {
+        let mut log = BlockLog::<LogEntry<Operation>>::open(directory)
+            .unwrap()
+            .with_block_size(block_size)
+            .with_sync(false);
+
+        for request_number in 1..=count {
+            log.push(LogEntry {
+                client: ClientIdentity(7),
+                request_number,
+                timestamp: 1000 + request_number,
+                seed: 0,
+                operation: Rc::new(ClientRequest::Operation(Operation::Upsert(
+                    format!("key-{request_number}").into(),
+                    "value".into(),
+                    None,
+                ))),
+            })
+            .unwrap();
+        }
+
+        BlockLog::<LogEntry<Operation>>::list(directory).unwrap()
+    }
+
+    // flips a byte of the record's payload, every record has an 8 byte header
+    fn corrupt(path: &Path, record: usize) {
+        let mut contents = fs::read(path).unwrap();
+        let mut position = 0;
+
+        for _ in 0..record {
+            let length = u32::from_be_bytes(contents[position..position + 4].try_into().unwrap());
+
+            position += 12 + length as usize;
+        }
+
+        contents[position + 12] ^= 0xff;
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    pub fn only_a_torn_tail_of_the_last_block_is_harmless() {
+        let directory = directory("torn");
+        let blocks = write_log(&directory, 1 << 20, 3);
+
+        assert_eq!(blocks.len(), 1);
+        assert!(inspect_log(&directory, 1, u64::MAX, true).is_ok());
+
+        OpenOptions::new()
+            .append(true)
+            .open(&blocks[0])
+            .and_then(|mut file| file.write_all(&[0, 0, 0, 60, 1, 2]))
+            .unwrap();
+
+        assert!(inspect_log(&directory, 1, u64::MAX, true).is_ok());
+
+        // a bad record in the middle of the last block isn't a torn one
+        corrupt(&blocks[0], 1);
+
+        assert!(matches!(
+            inspect_log(&directory, 1, u64::MAX, true),
+            Err(InspectError::Corrupted(_))
+        ));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    pub fn corrupted_blocks_before_the_last_are_problems() {
+        let directory = directory("corrupted");
+        let blocks = write_log(&directory, 64, 6);
+
+        assert!(blocks.len() > 2);
+        assert!(inspect_log(&directory, 1, u64::MAX, true).is_ok());
+
+        corrupt(&blocks[1], 0);
+
+        assert!(matches!(
+            inspect_log(&directory, 1, u64::MAX, true),
+            Err(InspectError::Corrupted(_))
+        ));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    pub fn snapshots_are_verified_and_loaded() {
+        let directory = directory("snapshot");
+        let checkpoints = CheckpointStore::open(&directory, 3).unwrap();
+        let kv = KvStateMachine::new(MemoryStorage::new()).with_checkpoints(checkpoints);
+
+        for op_number in 1..=5 {
+            let context = OperationContext {
+                op_number,
+                timestamp: 1000,
+                seed: 0,
+            };
+
+            kv.apply(
+                &context,
+                &Operation::Upsert(format!("key-{op_number}").into(), "value".into(), None),
+            )
+            .unwrap();
+        }
+
+        kv.checkpoint(5, 2, &ClientTable::new()).unwrap();
+
+        let latest = kv.checkpoints().unwrap().latest().unwrap().unwrap();
+        let (metadata, loaded) = load_snapshot(&latest.path).unwrap();
+
+        assert_eq!(metadata.header.op_number, 5);
+        assert_eq!(metadata.header.view, 2);
+        assert_eq!(metadata.header.cluster_id, 3);
+        assert_eq!(
+            loaded.scan(&KeyRange::default(), usize::MAX).unwrap(),
+            kv.scan(&KeyRange::default(), usize::MAX).unwrap()
+        );
+        assert_eq!(
+            loaded.scan(&KeyRange::default(), usize::MAX).unwrap().len(),
+            5
+        );
+
+        let mut contents = fs::read(&latest.path).unwrap();
+        let middle = contents.len() / 2;
+
+        contents[middle] ^= 0xff;
+        fs::write(&latest.path, contents).unwrap();
+
+        assert!(load_snapshot(&latest.path).is_err());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    pub fn differences_are_found_in_key_order() {
+        let value = |version, value: &'static str| VersionedValue {
+            version,
+            value: value.into(),
+        };
+        let left = vec![
+            ("apple".into(), value(1, "red")),
+            ("kiwi".into(), value(2, "green")),
+            ("plum".into(), value(3, "blue")),
+        ];
+        let right = vec![
+            ("kiwi".into(), value(2, "green")),
+            ("melon".into(), value(4, "yellow")),
+            ("plum".into(), value(5, "purple")),
+        ];
+
+        assert_eq!(
+            diff(left, right),
+            vec![
+                Difference::Removed("apple".into(), value(1, "red")),
+                Difference::Added("melon".into(), value(4, "yellow")),
+                Difference::Changed("plum".into(), value(3, "blue"), value(5, "purple")),
+            ]
+        );
+    }
+}
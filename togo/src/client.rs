@@ -0,0 +1,394 @@
+use std::{
+    io::{self, Read, Write},
+    process,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use clap::{Subcommand, ValueEnum};
+use thiserror::Error;
+use togo_core::{
+    operation::{Operation, OperationResult},
+    router::{GroupClient, Reply, Request, Router, RouterError, RouterResult, Routing},
+    shard::{
+        placement::{GroupIdentifier, Placement},
+        range::{KeyRange, RangeMap},
+        ShardIdentifier,
+    },
+};
+use togo_vr::{
+    message::ClientMessage,
+    replica::client::{ClientIdentity, ClientRequest, RequestNumber, SessionId},
+};
+use tokio::{net::TcpStream, sync::Mutex};
+
+use crate::{
+    config::Config,
+    protocol::{exchange, ClientFrame, ServerFrame},
+    server::ServerError,
+};
+
+pub type ClientResult<T> = Result<T, ClientError>;
+
+#[derive(Subcommand)]
+pub enum KvCommand {
+    /// Prints the key's value.
+    Get {
+        key: String,
+    },
+    /// Sets the key's value, read from stdin if not given.
+    Put {
+        key: String,
+        value: Option<String>,
+        /// Expires the key after this many seconds.
+        #[arg(long)]
+        ttl: Option<u64>,
+    },
+    Delete {
+        key: String,
+    },
+    /// Sets the key's value only if its current version matches.
+    Cas {
+        key: String,
+        value: Option<String>,
+        /// The expected version, leave out to expect the key not to exist.
+        #[arg(long)]
+        version: Option<u64>,
+    },
+    /// Prints the keys starting with the prefix along with their values.
+    Scan {
+        #[arg(long, default_value = "")]
+        prefix: String,
+        #[arg(long, default_value_t = 1000)]
+        limit: usize,
+    },
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Raw,
+    #[default]
+    Utf8,
+    Hex,
+}
+
+/// Talks to the group's replicas at their client addresses - replicas are
+/// indexed like the server indexes them, by their position in identity
+/// order.
+pub struct TcpGroupClient {
+    group: GroupIdentifier,
+    addresses: Vec<String>,
+    client: ClientIdentity,
+    timeout: Duration,
+    state: Mutex<ClientState>,
+}
+
+// a single session is opened with whichever replica is the primary and used
+// for every later write
+struct ClientState {
+    connections: Vec<Option<TcpStream>>,
+    session: Option<SessionId>,
+    request_number: RequestNumber,
+}
+
+impl TcpGroupClient {
+    pub fn new(config: &Config) -> Self {
+        let mut replicas: Vec<_> = config.replicas.iter().collect();
+
+        replicas.sort_by_key(|replica| replica.identity);
+
+        // unique enough for a command-line client, whose sessions are short
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let client = ClientIdentity(now.as_nanos() as u64 ^ (u64::from(process::id()) << 32));
+
+        Self {
+            group: config.group(),
+            addresses: replicas
+                .iter()
+                .map(|replica| replica.client_address.clone())
+                .collect(),
+            client,
+            timeout: config.client_timeout(),
+            state: Mutex::new(ClientState {
+                connections: replicas.iter().map(|_| None).collect(),
+                session: None,
+                request_number: 0,
+            }),
+        }
+    }
+
+    /// A router over the group, which hosts a single shard owning every key.
+    pub fn into_router(self) -> Router<RangeMap, Self, Routing<RangeMap>> {
+        let shard = ShardIdentifier(0);
+        let mut placement = Placement::new();
+
+        placement.assign(shard, self.group);
+
+        let routing = Routing {
+            map: RangeMap::new(shard),
+            placement,
+        };
+
+        Router::new(self, routing.clone(), routing)
+    }
+
+    async fn exchange(
+        &self,
+        state: &mut ClientState,
+        replica: usize,
+        message: ClientMessage<Request>,
+    ) -> io::Result<ServerFrame> {
+        let address = self.addresses.get(replica).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("No replica {replica}!"))
+        })?;
+        let connection = &mut state.connections[replica];
+
+        if connection.is_none() {
+            *connection = Some(TcpStream::connect(address).await?);
+        }
+
+        let stream = connection.as_mut().expect("connected above");
+        let frame = ClientFrame::Request {
+            client: self.client,
+            message,
+        };
+        let result = exchange(stream, &frame, self.timeout).await;
+
+        // the next request reconnects
+        if result.is_err() {
+            *connection = None;
+        }
+
+        result
+    }
+}
+
+#[async_trait]
+impl GroupClient for TcpGroupClient {
+    fn replica_count(&self, _: GroupIdentifier) -> usize {
+        self.addresses.len()
+    }
+
+    async fn send(
+        &self,
+        _: GroupIdentifier,
+        replica: usize,
+        _: ShardIdentifier,
+        request: Request,
+    ) -> RouterResult<Reply> {
+        let mut state = self.state.lock().await;
+        let unreachable = |error: io::Error| RouterError::Unreachable(error.to_string());
+
+        let session = match state.session {
+            Some(session) => session,
+            None => {
+                let register = ClientMessage {
+                    session: 0,
+                    request_number: 0,
+                    request: ClientRequest::Register,
+                };
+
+                match self
+                    .exchange(&mut state, replica, register)
+                    .await
+                    .map_err(unreachable)?
+                {
+                    ServerFrame::Registered(session) => {
+                        state.session = Some(session);
+                        session
+                    }
+                    ServerFrame::Reply(reply) => return Ok(reply),
+                    ServerFrame::Failed(message) => return Err(RouterError::Unreachable(message)),
+                    _ => return Err(RouterError::UnexpectedResponse),
+                }
+            }
+        };
+
+        state.request_number += 1;
+
+        let message = ClientMessage {
+            session,
+            request_number: state.request_number,
+            request: ClientRequest::Operation(request),
+        };
+
+        match self
+            .exchange(&mut state, replica, message)
+            .await
+            .map_err(unreachable)?
+        {
+            ServerFrame::Reply(reply) => Ok(reply),
+            ServerFrame::Failed(message) => Err(RouterError::Unreachable(message)),
+            _ => Err(RouterError::UnexpectedResponse),
+        }
+    }
+}
+
+/// Runs the command against the cluster the node's configuration describes,
+/// printing its outcome to stdout.
+pub async fn run(config: &Config, command: KvCommand, format: Format) -> ClientResult<()> {
+    let router = TcpGroupClient::new(config).into_router();
+    let mut stdout = io::stdout().lock();
+
+    match command {
+        KvCommand::Get { key } => {
+            let value = router.get(key).await?.ok_or(ClientError::NotFound)?;
+
+            print_value(&mut stdout, &value.value, format)?;
+        }
+        KvCommand::Put { key, value, ttl } => {
+            let operation =
+                Operation::Upsert(key.into(), input(value)?, ttl.map(Duration::from_secs));
+
+            written(&mut stdout, router.write(operation).await?)?;
+        }
+        KvCommand::Delete { key } => {
+            written(
+                &mut stdout,
+                router.write(Operation::Delete(key.into())).await?,
+            )?;
+        }
+        KvCommand::Cas {
+            key,
+            value,
+            version,
+        } => {
+            let operation = Operation::CompareAndUpsert(key.into(), input(value)?, version);
+
+            written(&mut stdout, router.write(operation).await?)?;
+        }
+        KvCommand::Scan { prefix, limit } => {
+            let range = prefix_range(prefix.into());
+
+            for (key, value) in router.scan(range, limit).await? {
+                write_bytes(&mut stdout, &key, format)?;
+                write!(stdout, "\t")?;
+                print_value(&mut stdout, &value.value, format)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn written<W: Write>(output: &mut W, result: OperationResult) -> ClientResult<()> {
+    match result {
+        OperationResult::Written(version) => writeln!(output, "version {version}")?,
+        OperationResult::Deleted => writeln!(output, "deleted")?,
+        OperationResult::VersionMismatch(version) => {
+            return Err(ClientError::VersionMismatch(version))
+        }
+        result => return Err(ClientError::Unexpected(result)),
+    }
+
+    Ok(())
+}
+
+fn print_value<W: Write>(output: &mut W, value: &[u8], format: Format) -> io::Result<()> {
+    write_bytes(output, value, format)?;
+    writeln!(output)
+}
+
+fn write_bytes<W: Write>(output: &mut W, value: &[u8], format: Format) -> io::Result<()> {
+    match format {
+        Format::Raw => output.write_all(value),
+        Format::Utf8 => output.write_all(String::from_utf8_lossy(value).as_bytes()),
+        Format::Hex => value
+            .iter()
+            .try_for_each(|byte| write!(output, "{byte:02x}")),
+    }
+}
+
+fn input(value: Option<String>) -> io::Result<Bytes> {
+    match value {
+        Some(value) => Ok(value.into()),
+        None => {
+            let mut value = Vec::new();
+
+            io::stdin().lock().read_to_end(&mut value)?;
+
+            Ok(value.into())
+        }
+    }
+}
+
+/// The range of every key starting with the prefix.
+fn prefix_range(prefix: Bytes) -> KeyRange {
+    let mut end = prefix.to_vec();
+
+    // trailing 0xff bytes can't be incremented, with nothing but them there's
+    // no key past the prefix
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+
+            return KeyRange::new(prefix, Some(end.into()));
+        }
+    }
+
+    KeyRange::new(prefix, None)
+}
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("Key not found!")]
+    NotFound,
+    #[error("Version mismatch, the key's current version is {}!", describe_version(.0))]
+    VersionMismatch(Option<u64>),
+    #[error("{}", .0)]
+    Failed(String),
+    #[error("Unexpected result: {:?}", .0)]
+    Unexpected(OperationResult),
+    #[error("IO error: {}", .0)]
+    Io(io::Error),
+    #[error("Router error: {}", .0)]
+    Router(RouterError),
+    #[error("{}", .0)]
+    Server(ServerError),
+}
+
+fn describe_version(version: &Option<u64>) -> String {
+    version.map_or_else(|| "none".into(), |version| version.to_string())
+}
+
+impl From<io::Error> for ClientError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<RouterError> for ClientError {
+    fn from(value: RouterError) -> Self {
+        Self::Router(value)
+    }
+}
+
+impl From<ServerError> for ClientError {
+    fn from(value: ServerError) -> Self {
+        Self::Server(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use togo_core::shard::range::KeyRange;
+
+    use super::prefix_range;
+
+    #[test]
+    pub fn prefix_ranges_end_past_the_last_prefixed_key() {
+        assert_eq!(prefix_range("ab".into()), KeyRange::new("ab", Some("ac")));
+        assert_eq!(
+            prefix_range(vec![b'a', 0xff].into()),
+            KeyRange::new(vec![b'a', 0xff], Some(vec![b'b']))
+        );
+        assert_eq!(
+            prefix_range(vec![0xff].into()),
+            KeyRange::new(vec![0xff], None)
+        );
+        assert_eq!(prefix_range("".into()), KeyRange::default());
+    }
+}
@@ -0,0 +1,202 @@
+use std::{fs, path::Path, path::PathBuf, time::Duration};
+
+use serde::Deserialize;
+use togo_vr::replica::{client::SessionPolicy, CheckpointPolicy, GroupIdentifier, ReplicaIdentity};
+
+use crate::server::{ServerError, ServerResult};
+
+// clients wait a little longer than the server takes to give up on a request,
+// so that they get to hear that it failed
+const CLIENT_TIMEOUT_SLACK: u64 = 2000;
+
+/// Everything a node needs to know to run its replica, read from a TOML file
+/// like `togo.example.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub identity: u32,
+    #[serde(default)]
+    pub group: u32,
+    /// Ties checkpoints to the cluster that took them.
+    #[serde(default)]
+    pub cluster_id: u64,
+    pub data_dir: PathBuf,
+    #[serde(default)]
+    pub storage: StorageKind,
+    /// Every replica of the group, this node included.
+    pub replicas: Vec<ReplicaConfig>,
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    #[serde(default)]
+    pub checkpoints: CheckpointConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub expiry: ExpiryConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplicaConfig {
+    pub identity: u32,
+    /// Where the other replicas reach this one.
+    pub address: String,
+    /// Where clients reach this one.
+    pub client_address: String,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    Memory,
+    #[default]
+    Sled,
+    Lsm,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    /// Interval of the primary's heartbeats, in milliseconds.
+    pub tick: u64,
+    /// Idle sessions are evicted after this many milliseconds, they're kept
+    /// forever if unset.
+    pub session_idle: Option<u64>,
+    /// Clients are told that their request failed if it hasn't been
+    /// committed within this many milliseconds.
+    pub request: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CheckpointConfig {
+    pub operations: Option<u64>,
+    pub bytes: Option<u64>,
+    pub retained: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    pub block_size: u64,
+    /// Whether every appended entry is synced to disk before it's
+    /// acknowledged.
+    pub sync: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ExpiryConfig {
+    /// How often the primary sweeps expired keys, in milliseconds.
+    pub interval: u64,
+    /// The most keys a single sweep reclaims.
+    pub limit: u32,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            tick: 100,
+            session_idle: None,
+            request: 10000,
+        }
+    }
+}
+
+impl Default for CheckpointConfig {
+    fn default() -> Self {
+        Self {
+            operations: Some(100_000),
+            bytes: None,
+            retained: 2,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            block_size: 64 * 1024 * 1024,
+            sync: true,
+        }
+    }
+}
+
+impl Default for ExpiryConfig {
+    fn default() -> Self {
+        Self {
+            interval: 1000,
+            limit: 1000,
+        }
+    }
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> ServerResult<Self> {
+        let contents = fs::read_to_string(path.as_ref()).map_err(ServerError::Io)?;
+
+        Self::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> ServerResult<Self> {
+        let config: Config = toml::from_str(contents)
+            .map_err(|error| ServerError::Configuration(error.to_string()))?;
+
+        config.own()?;
+
+        Ok(config)
+    }
+
+    pub fn identity(&self) -> ReplicaIdentity {
+        ReplicaIdentity(self.identity)
+    }
+
+    pub fn group(&self) -> GroupIdentifier {
+        GroupIdentifier(self.group)
+    }
+
+    /// This node's entry among the replicas.
+    pub fn own(&self) -> ServerResult<&ReplicaConfig> {
+        self.replicas
+            .iter()
+            .find(|replica| replica.identity == self.identity)
+            .ok_or_else(|| {
+                ServerError::Configuration(format!(
+                    "Replica {} isn't listed among the replicas!",
+                    self.identity
+                ))
+            })
+    }
+
+    pub fn checkpoint_policy(&self) -> CheckpointPolicy {
+        CheckpointPolicy {
+            operations: self.checkpoints.operations,
+            bytes: self.checkpoints.bytes,
+        }
+    }
+
+    pub fn session_policy(&self) -> SessionPolicy {
+        SessionPolicy {
+            idle_timeout: self.timeouts.session_idle,
+        }
+    }
+
+    /// How long clients wait for a replica's answer.
+    pub fn client_timeout(&self) -> Duration {
+        Duration::from_millis(self.timeouts.request.saturating_add(CLIENT_TIMEOUT_SLACK))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, StorageKind};
+
+    #[test]
+    pub fn example_config_is_valid() {
+        let config = Config::parse(include_str!("../togo.example.toml")).unwrap();
+
+        assert_eq!(config.own().unwrap().address, "127.0.0.1:7001");
+        assert_eq!(config.storage, StorageKind::Sled);
+        assert_eq!(config.replicas.len(), 3);
+        assert!(config.client_timeout().as_millis() > u128::from(config.timeouts.request));
+        assert!(Config::parse("identity = 4\ndata_dir = \"data\"\nreplicas = []").is_err());
+    }
+}
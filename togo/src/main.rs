@@ -1,3 +1,89 @@
-fn main() {
-    println!("Hello, world!");
+use std::{fmt::Display, future::Future, path::PathBuf, process::ExitCode};
+
+use clap::{Parser, Subcommand};
+
+use crate::{
+    admin::AdminCommand,
+    client::{Format, KvCommand},
+    config::Config,
+};
+
+mod admin;
+mod client;
+mod config;
+mod protocol;
+mod server;
+mod transport;
+
+#[derive(Parser)]
+#[command(version, about = "A replicated, sharded key-value store")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Runs this node's replica until SIGTERM.
+    Serve {
+        /// Path to the node's configuration, see `togo.example.toml`.
+        #[arg(short, long)]
+        config: PathBuf,
+    },
+    /// Reads or writes keys of the cluster a node's configuration describes.
+    Client {
+        /// Path to the configuration of any of the cluster's nodes.
+        #[arg(short, long)]
+        config: PathBuf,
+        /// How keys and values are printed.
+        #[arg(short, long, value_enum, default_value_t)]
+        output: Format,
+        #[command(subcommand)]
+        command: KvCommand,
+    },
+    /// Inspects and operates the replicas a node's configuration lists.
+    Admin {
+        /// Path to the configuration of any of the cluster's nodes.
+        #[arg(short, long)]
+        config: PathBuf,
+        #[command(subcommand)]
+        command: AdminCommand,
+    },
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Command::Serve { config } => block_on(async { server::serve(Config::load(config)?).await }),
+        Command::Client {
+            config,
+            output,
+            command,
+        } => block_on(async { client::run(&Config::load(config)?, command, output).await }),
+        Command::Admin { config, command } => {
+            block_on(async { admin::run(&Config::load(config)?, command).await })
+        }
+    }
+}
+
+// replicas aren't `Send`, so everything runs on a single thread
+fn block_on<F, E>(future: F) -> ExitCode
+where
+    F: Future<Output = Result<(), E>>,
+    E: Display,
+{
+    let result = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|error| error.to_string())
+        .and_then(|runtime| runtime.block_on(future).map_err(|error| error.to_string()));
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("{error}");
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -0,0 +1,235 @@
+use std::{
+    io::{self, ErrorKind},
+    time::Duration,
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use togo_core::router::{Reply, Request};
+use togo_vr::{
+    codec::{get_u64, get_u8, Codec, CodecError, CodecResult},
+    message::ClientMessage,
+    replica::{
+        client::{ClientIdentity, SessionId},
+        ReplicaIdentity, ReplicaStatus,
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+// checkpoints are shipped in 1 MiB chunks, state transfers can be larger
+const MAX_FRAME_SIZE: usize = 256 * 1024 * 1024;
+
+pub enum ClientFrame {
+    /// A request from a client - registrations and writes go through the
+    /// log, reads are answered by the primary right away.
+    Request {
+        client: ClientIdentity,
+        message: ClientMessage<Request>,
+    },
+    /// A request from an operator, answered by the replica it's sent to.
+    Admin(AdminRequest),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminRequest {
+    Status,
+    Checkpoint,
+}
+
+pub enum ServerFrame {
+    Registered(SessionId),
+    Reply(Reply),
+    Failed(String),
+    Status(ReplicaReport),
+    /// Op number of the replica's latest checkpoint.
+    Checkpointed(u64),
+}
+
+/// Where a replica stands, as it sees it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplicaReport {
+    pub identity: ReplicaIdentity,
+    pub primary: ReplicaIdentity,
+    pub status: ReplicaStatus,
+    pub view_number: u64,
+    pub op_number: u64,
+    pub commit_number: u64,
+    pub log_offset: u64,
+    pub checkpoint_op_number: u64,
+}
+
+impl Codec for ClientFrame {
+    fn encode(&self, buffer: &mut BytesMut) {
+        match self {
+            ClientFrame::Request { client, message } => {
+                buffer.put_u8(0);
+                client.encode(buffer);
+                message.encode(buffer);
+            }
+            ClientFrame::Admin(request) => {
+                buffer.put_u8(1);
+                request.encode(buffer);
+            }
+        }
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        match get_u8(data)? {
+            0 => Ok(ClientFrame::Request {
+                client: ClientIdentity::decode(data)?,
+                message: ClientMessage::decode(data)?,
+            }),
+            1 => AdminRequest::decode(data).map(ClientFrame::Admin),
+            tag => Err(CodecError::UnknownTag("client frame", tag)),
+        }
+    }
+}
+
+impl Codec for AdminRequest {
+    fn encode(&self, buffer: &mut BytesMut) {
+        match self {
+            AdminRequest::Status => buffer.put_u8(0),
+            AdminRequest::Checkpoint => buffer.put_u8(1),
+        }
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        match get_u8(data)? {
+            0 => Ok(AdminRequest::Status),
+            1 => Ok(AdminRequest::Checkpoint),
+            tag => Err(CodecError::UnknownTag("admin request", tag)),
+        }
+    }
+}
+
+impl Codec for ReplicaReport {
+    fn encode(&self, buffer: &mut BytesMut) {
+        self.identity.encode(buffer);
+        self.primary.encode(buffer);
+        buffer.put_u8(match self.status {
+            ReplicaStatus::Normal => 0,
+            ReplicaStatus::Recovery => 1,
+            ReplicaStatus::ViewChange => 2,
+        });
+        buffer.put_u64(self.view_number);
+        buffer.put_u64(self.op_number);
+        buffer.put_u64(self.commit_number);
+        buffer.put_u64(self.log_offset);
+        buffer.put_u64(self.checkpoint_op_number);
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        Ok(Self {
+            identity: ReplicaIdentity::decode(data)?,
+            primary: ReplicaIdentity::decode(data)?,
+            status: match get_u8(data)? {
+                0 => ReplicaStatus::Normal,
+                1 => ReplicaStatus::Recovery,
+                2 => ReplicaStatus::ViewChange,
+                tag => return Err(CodecError::UnknownTag("replica status", tag)),
+            },
+            view_number: get_u64(data)?,
+            op_number: get_u64(data)?,
+            commit_number: get_u64(data)?,
+            log_offset: get_u64(data)?,
+            checkpoint_op_number: get_u64(data)?,
+        })
+    }
+}
+
+impl Codec for ServerFrame {
+    fn encode(&self, buffer: &mut BytesMut) {
+        match self {
+            ServerFrame::Registered(session) => {
+                buffer.put_u8(0);
+                buffer.put_u64(*session);
+            }
+            ServerFrame::Reply(reply) => {
+                buffer.put_u8(1);
+                reply.encode(buffer);
+            }
+            ServerFrame::Failed(message) => {
+                buffer.put_u8(2);
+                message.encode(buffer);
+            }
+            ServerFrame::Status(report) => {
+                buffer.put_u8(3);
+                report.encode(buffer);
+            }
+            ServerFrame::Checkpointed(op_number) => {
+                buffer.put_u8(4);
+                buffer.put_u64(*op_number);
+            }
+        }
+    }
+
+    fn decode(data: &mut &[u8]) -> CodecResult<Self> {
+        match get_u8(data)? {
+            0 => get_u64(data).map(ServerFrame::Registered),
+            1 => Reply::decode(data).map(ServerFrame::Reply),
+            2 => String::decode(data).map(ServerFrame::Failed),
+            3 => ReplicaReport::decode(data).map(ServerFrame::Status),
+            4 => get_u64(data).map(ServerFrame::Checkpointed),
+            tag => Err(CodecError::UnknownTag("server frame", tag)),
+        }
+    }
+}
+
+/// Reads a frame prefixed with its length - `None` once the peer has closed
+/// the connection between frames.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Bytes>> {
+    let length = match reader.read_u32().await {
+        Ok(length) => length as usize,
+        Err(error) if error.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error),
+    };
+
+    if length > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Frame of {length} bytes is too large!"),
+        ));
+    }
+
+    let mut frame = vec![0; length];
+
+    reader.read_exact(&mut frame).await?;
+
+    Ok(Some(frame.into()))
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &[u8]) -> io::Result<()> {
+    writer.write_u32(frame.len() as u32).await?;
+    writer.write_all(frame).await?;
+    writer.flush().await
+}
+
+/// Sends the frame and reads the answer to it, giving up if there's none
+/// within the limit - a group without a quorum never answers registrations
+/// and writes. The connection is out of step after an error.
+pub async fn exchange(
+    stream: &mut TcpStream,
+    frame: &ClientFrame,
+    limit: Duration,
+) -> io::Result<ServerFrame> {
+    let exchange = async {
+        write_frame(stream, &frame.to_bytes()).await?;
+
+        match read_frame(stream).await? {
+            Some(frame) => ServerFrame::from_bytes(&frame).map_err(invalid_data),
+            None => Err(ErrorKind::UnexpectedEof.into()),
+        }
+    };
+
+    match timeout(limit, exchange).await {
+        Ok(answer) => answer,
+        Err(_) => Err(ErrorKind::TimedOut.into()),
+    }
+}
+
+pub fn invalid_data(error: CodecError) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, error.to_string())
+}
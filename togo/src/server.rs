@@ -0,0 +1,453 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{stream, StreamExt};
+use thiserror::Error;
+use togo_core::{
+    kv::KvStateMachine,
+    log::blocklog::BlockLog,
+    operation::{Operation, OperationResult},
+    router::{Reply, Request},
+    storage::{
+        checkpoint::CheckpointStore,
+        lsm::{LsmOptions, LsmStorage},
+        memory::MemoryStorage,
+        sled::SledStorage,
+        Delete, Flush, Snapshot, StorageError, Upsert,
+    },
+};
+use togo_vr::{
+    codec::Codec,
+    driver::{Driver, DriverError, DriverHandle},
+    log::{Log, LogEntry},
+    message::ClientMessage,
+    multiplex::{GroupChannel, Multiplexer, NodeMessage},
+    replica::{
+        client::{ClientIdentity, ClientRequest},
+        cluster::{Cluster, ClusterError},
+        Replica, ReplicaError, ReplicaIdentity,
+    },
+    transport::TransportError,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    signal::unix::{signal, SignalKind},
+    time::{interval, MissedTickBehavior},
+};
+
+use crate::{
+    config::{Config, ExpiryConfig, StorageKind},
+    protocol::{
+        invalid_data, read_frame, write_frame, AdminRequest, ClientFrame, ReplicaReport,
+        ServerFrame,
+    },
+    transport::TcpTransport,
+};
+
+pub type ServerResult<T> = Result<T, ServerError>;
+
+type Transport = TcpTransport<NodeMessage<Operation>>;
+type KvReplica<S> = Replica<
+    Operation,
+    OperationResult,
+    GroupChannel<Operation, Transport>,
+    BlockLog<LogEntry<Operation>>,
+    KvStateMachine<S>,
+>;
+type KvHandle<S> = DriverHandle<Operation, OperationResult, KvReplica<S>>;
+
+/// Runs the node's replica until SIGTERM or Ctrl-C, flushing the storage
+/// before returning.
+pub async fn serve(config: Config) -> ServerResult<()> {
+    let directory = &config.data_dir;
+
+    match config.storage {
+        StorageKind::Memory => run(&config, MemoryStorage::new()).await,
+        StorageKind::Sled => run(&config, SledStorage::open(directory.join("sled"))?).await,
+        StorageKind::Lsm => {
+            let storage = LsmStorage::open(directory.join("lsm"), LsmOptions::default())?;
+
+            run(&config, storage).await
+        }
+    }
+}
+
+async fn run<S>(config: &Config, storage: S) -> ServerResult<()>
+where
+    S: Snapshot + Upsert + Delete + Flush + 'static,
+{
+    let own = config.own()?;
+    let peers: BTreeMap<_, _> = config
+        .replicas
+        .iter()
+        .map(|replica| (ReplicaIdentity(replica.identity), replica.address.clone()))
+        .collect();
+    let identities: BTreeSet<_> = peers.keys().copied().collect();
+
+    let transport = Transport::bind(&own.address, peers)
+        .await
+        .map_err(ServerError::Io)?;
+    let multiplexer = Multiplexer::new(config.identity(), transport);
+    let cluster = Cluster::bootstrap(multiplexer.register(config.group())?, identities)?
+        .with_group(config.group());
+    let driver = Driver::new(open_replica(config, cluster, storage)?)
+        .with_reporter(|error| eprintln!("{error}"))
+        .with_request_timeout(config.timeouts.request / config.timeouts.tick.max(1));
+
+    let listener = TcpListener::bind(&own.client_address)
+        .await
+        .map_err(ServerError::Io)?;
+
+    tokio::spawn(accept_clients(listener, driver.handle()));
+    tokio::spawn(sweep_expired(
+        driver.handle(),
+        config.identity(),
+        config.expiry.clone(),
+    ));
+
+    // the commits gathered during a tick go out as a single heartbeat per
+    // peer at the start of the next one
+    let mut interval = interval(Duration::from_millis(config.timeouts.tick.max(1)));
+
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let ticks = stream::unfold(interval, |mut interval| async {
+        interval.tick().await;
+        Some(((), interval))
+    })
+    .then(|()| async {
+        if let Err(error) = multiplexer.flush_heartbeats().await {
+            eprintln!("{error}");
+        }
+    });
+
+    let mut terminate = signal(SignalKind::terminate()).map_err(ServerError::Io)?;
+    let shutdown = async {
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    };
+
+    let replica = driver.run(multiplexer.arrivals(), ticks, shutdown).await?;
+
+    replica.state_machine().storage().flush().await?;
+
+    Ok(())
+}
+
+// storage past the latest checkpoint is rebuilt from the log, so it's rolled
+// back to the checkpoint first
+fn open_replica<S>(
+    config: &Config,
+    cluster: Cluster<Operation, GroupChannel<Operation, Transport>>,
+    mut storage: S,
+) -> ServerResult<KvReplica<S>>
+where
+    S: Snapshot + Upsert + Delete,
+{
+    let directory = &config.data_dir;
+    let checkpoints = CheckpointStore::open(directory.join("checkpoints"), config.cluster_id)?
+        .with_retained(config.checkpoints.retained);
+    let op_log = BlockLog::open(directory.join("log"))?
+        .with_block_size(config.log.block_size)
+        .with_sync(config.log.sync);
+    let latest = checkpoints.latest()?;
+
+    if latest.is_none() {
+        storage.clear()?;
+    }
+
+    let mut state_machine = KvStateMachine::new(storage).with_checkpoints(checkpoints);
+    let (op_number, clients) = match latest {
+        Some(checkpoint) => (checkpoint.op_number, state_machine.restore(&checkpoint)?),
+        None => (0, Default::default()),
+    };
+    let offset = op_log.current_offset();
+
+    if offset > op_number {
+        return Err(ServerError::MissingCheckpoint(offset));
+    }
+
+    Ok(
+        Replica::new(config.identity(), cluster, op_log, state_machine)
+            .with_checkpoint(op_number, clients)
+            .with_checkpoint_policy(config.checkpoint_policy())
+            .with_session_policy(config.session_policy()),
+    )
+}
+
+async fn accept_clients<S>(listener: TcpListener, handle: KvHandle<S>)
+where
+    S: Snapshot + Upsert + Delete + 'static,
+{
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(serve_client(stream, handle.clone()));
+            }
+            Err(error) => eprintln!("Failed to accept a client connection! {error}"),
+        }
+    }
+}
+
+// requests of a single connection are answered one at a time
+async fn serve_client<S>(stream: TcpStream, handle: KvHandle<S>) -> io::Result<()>
+where
+    S: Snapshot + Upsert + Delete + 'static,
+{
+    let (mut reader, mut writer) = stream.into_split();
+
+    while let Some(frame) = read_frame(&mut reader).await? {
+        let frame = ClientFrame::from_bytes(&frame).map_err(invalid_data)?;
+        let response = answer(&handle, frame).await;
+
+        write_frame(&mut writer, &response.to_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+async fn answer<S>(handle: &KvHandle<S>, frame: ClientFrame) -> ServerFrame
+where
+    S: Snapshot + Upsert + Delete + 'static,
+{
+    match frame {
+        ClientFrame::Request { client, message } => request(handle, client, message).await,
+        ClientFrame::Admin(request) => admin(handle, request).await,
+    }
+}
+
+// expired keys are hidden from reads right away but only reclaimed through
+// the log, so that every replica drops the same ones - the primary submits a
+// sweep under a session of its own whenever a key has expired by its clock
+async fn sweep_expired<S>(handle: KvHandle<S>, identity: ReplicaIdentity, config: ExpiryConfig)
+where
+    S: Snapshot + Upsert + Delete + 'static,
+{
+    // out of the way of the identities clients pick for themselves
+    let client = ClientIdentity(u64::MAX - u64::from(identity.0));
+    let mut session = None;
+    let mut request_number = 0;
+    let mut interval = interval(Duration::from_millis(config.interval.max(1)));
+
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or_default();
+        let due = handle
+            .run(move |replica| match replica.is_primary() {
+                true => replica.state_machine().expires_by(now),
+                false => Ok(false),
+            })
+            .await;
+
+        match due {
+            Ok(Ok(true)) => {}
+            Ok(Ok(false)) => continue,
+            Ok(Err(error)) => {
+                eprintln!("Failed to look for expired keys! {error}");
+                continue;
+            }
+            Err(_) => return,
+        }
+
+        let current = match session {
+            Some(current) => current,
+            None => {
+                let register = ClientMessage {
+                    session: 0,
+                    request_number: 0,
+                    request: ClientRequest::Register,
+                };
+
+                match handle.submit(client, register).await {
+                    Ok(reply) => {
+                        request_number = 0;
+                        *session.insert(reply.session)
+                    }
+                    Err(error) => {
+                        eprintln!("Failed to register for sweeping expired keys! {error}");
+                        continue;
+                    }
+                }
+            }
+        };
+
+        request_number += 1;
+
+        let sweep = ClientMessage {
+            session: current,
+            request_number,
+            request: ClientRequest::Operation(Operation::SweepExpired(config.limit)),
+        };
+
+        // the next sweep registers again, e.g. once the session has expired
+        if let Err(error) = handle.submit(client, sweep).await {
+            eprintln!("Failed to sweep expired keys! {error}");
+            session = None;
+        }
+    }
+}
+
+// registrations and writes are committed through the log, reads are served
+// by the primary from its state machine
+async fn request<S>(
+    handle: &KvHandle<S>,
+    client: ClientIdentity,
+    message: ClientMessage<Request>,
+) -> ServerFrame
+where
+    S: Snapshot + Upsert + Delete + 'static,
+{
+    let request = match message.request {
+        ClientRequest::Operation(Request::Write(operation)) => ClientRequest::Operation(operation),
+        ClientRequest::Operation(request) => {
+            let read = handle.run(move |replica| read(replica, &request)).await;
+
+            return read.unwrap_or_else(|error| ServerFrame::Failed(error.to_string()));
+        }
+        ClientRequest::Register => ClientRequest::Register,
+    };
+    let registering = matches!(request, ClientRequest::Register);
+    let message = ClientMessage {
+        session: message.session,
+        request_number: message.request_number,
+        request,
+    };
+
+    match handle.submit(client, message).await {
+        Ok(reply) if registering => ServerFrame::Registered(reply.session),
+        Ok(reply) => match reply.response {
+            Some(result) => ServerFrame::Reply(Reply::written(result)),
+            None => ServerFrame::Failed("Request was committed without a result!".into()),
+        },
+        Err(DriverError::Replica(ReplicaError::NotPrimary)) => handle
+            .run(|replica| ServerFrame::Reply(not_primary(replica)))
+            .await
+            .unwrap_or_else(|error| ServerFrame::Failed(error.to_string())),
+        Err(error) => ServerFrame::Failed(error.to_string()),
+    }
+}
+
+async fn admin<S>(handle: &KvHandle<S>, request: AdminRequest) -> ServerFrame
+where
+    S: Snapshot + Upsert + Delete + 'static,
+{
+    let answer = match request {
+        AdminRequest::Status => {
+            handle
+                .run(|replica| ServerFrame::Status(report(replica)))
+                .await
+        }
+        AdminRequest::Checkpoint => {
+            handle
+                .run(|replica| match replica.checkpoint() {
+                    Ok(op_number) => ServerFrame::Checkpointed(op_number),
+                    Err(error) => ServerFrame::Failed(error.to_string()),
+                })
+                .await
+        }
+    };
+
+    answer.unwrap_or_else(|error| ServerFrame::Failed(error.to_string()))
+}
+
+fn report<S>(replica: &KvReplica<S>) -> ReplicaReport
+where
+    S: Snapshot + Upsert + Delete,
+{
+    let state = replica.state();
+
+    ReplicaReport {
+        identity: replica.identity(),
+        primary: replica.cluster().current_primary(),
+        status: state.status(),
+        view_number: state.view_number(),
+        op_number: replica.op_number(),
+        commit_number: state.commit_number(),
+        log_offset: replica.log_offset(),
+        checkpoint_op_number: state.checkpoint_op_number(),
+    }
+}
+
+// reads don't go through the log and the primary holds no lease - that's
+// only safe while a group's primary is fixed, once view changes are in place
+// a primary deposed without knowing it would keep serving stale values until
+// it learns of the new view
+fn read<S>(replica: &KvReplica<S>, request: &Request) -> ServerFrame
+where
+    S: Snapshot + Upsert + Delete,
+{
+    if !replica.is_primary() {
+        return ServerFrame::Reply(not_primary(replica));
+    }
+
+    match Reply::read(replica.state_machine(), request) {
+        Ok(reply) => ServerFrame::Reply(reply),
+        Err(error) => ServerFrame::Failed(error.to_string()),
+    }
+}
+
+fn not_primary<S>(replica: &KvReplica<S>) -> Reply
+where
+    S: Snapshot + Upsert + Delete,
+{
+    let cluster = replica.cluster();
+    let primary = cluster
+        .replicas()
+        .iter()
+        .position(|identity| *identity == cluster.current_primary());
+
+    Reply::NotPrimary { primary }
+}
+
+#[derive(Debug, Error)]
+pub enum ServerError {
+    #[error("Invalid configuration! {}", .0)]
+    Configuration(String),
+    #[error("The log starts at op {} but there's no checkpoint covering the ops before it!", .0)]
+    MissingCheckpoint(u64),
+    #[error("IO error: {}", .0)]
+    Io(io::Error),
+    #[error("Storage error: {}", .0)]
+    Storage(StorageError),
+    #[error("Transport error: {}", .0)]
+    Transport(TransportError),
+    #[error("Cluster error: {}", .0)]
+    Cluster(ClusterError),
+    #[error("Driver error: {}", .0)]
+    Driver(DriverError),
+}
+
+impl From<StorageError> for ServerError {
+    fn from(value: StorageError) -> Self {
+        Self::Storage(value)
+    }
+}
+
+impl From<TransportError> for ServerError {
+    fn from(value: TransportError) -> Self {
+        Self::Transport(value)
+    }
+}
+
+impl From<ClusterError> for ServerError {
+    fn from(value: ClusterError) -> Self {
+        Self::Cluster(value)
+    }
+}
+
+impl From<DriverError> for ServerError {
+    fn from(value: DriverError) -> Self {
+        Self::Driver(value)
+    }
+}
@@ -0,0 +1,199 @@
+use std::{collections::BTreeMap, io, sync::Mutex};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use togo_vr::{
+    codec::Codec,
+    replica::ReplicaIdentity,
+    transport::{TransportChannel, TransportError, TransportResult},
+};
+use tokio::{
+    io::BufWriter,
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, Mutex as AsyncMutex},
+};
+
+use crate::protocol::{invalid_data, read_frame, write_frame};
+
+/// Messages between nodes over TCP, as frames of their encoding.
+///
+/// Sending never waits for the peer - every peer gets a connection of its own
+/// that is (re)established in the background, and whatever can't be
+/// delivered is dropped, the replicas retry on their own.
+pub struct TcpTransport<M> {
+    peers: BTreeMap<ReplicaIdentity, String>,
+    connections: Mutex<BTreeMap<ReplicaIdentity, mpsc::UnboundedSender<Bytes>>>,
+    inbox: AsyncMutex<mpsc::UnboundedReceiver<M>>,
+}
+
+impl<M> TcpTransport<M>
+where
+    M: Codec + Send + 'static,
+{
+    /// Starts accepting connections from the peers on `address`.
+    pub async fn bind(address: &str, peers: BTreeMap<ReplicaIdentity, String>) -> io::Result<Self> {
+        let listener = TcpListener::bind(address).await?;
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        tokio::spawn(Self::accept(listener, sender));
+
+        Ok(Self {
+            peers,
+            connections: Mutex::new(BTreeMap::new()),
+            inbox: AsyncMutex::new(receiver),
+        })
+    }
+
+    async fn accept(listener: TcpListener, inbox: mpsc::UnboundedSender<M>) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(Self::read(stream, inbox.clone()));
+                }
+                Err(error) => eprintln!("Failed to accept a peer connection! {error}"),
+            }
+        }
+    }
+
+    async fn read(mut stream: TcpStream, inbox: mpsc::UnboundedSender<M>) -> io::Result<()> {
+        while let Some(frame) = read_frame(&mut stream).await? {
+            let message = M::from_bytes(&frame).map_err(invalid_data)?;
+
+            if inbox.send(message).is_err() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    // delivers the frames queued for a peer, reconnecting whenever the
+    // connection breaks
+    async fn write(address: String, mut frames: mpsc::UnboundedReceiver<Bytes>) {
+        let mut connection: Option<BufWriter<TcpStream>> = None;
+
+        while let Some(frame) = frames.recv().await {
+            if connection.is_none() {
+                connection = TcpStream::connect(&address).await.ok().map(BufWriter::new);
+            }
+
+            let Some(stream) = connection.as_mut() else {
+                continue;
+            };
+
+            if write_frame(stream, &frame).await.is_err() {
+                connection = None;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<M> TransportChannel<ReplicaIdentity, M> for TcpTransport<M>
+where
+    M: Codec + Send + 'static,
+{
+    async fn send(&self, recipient: ReplicaIdentity, message: M) -> TransportResult<()> {
+        let address = self
+            .peers
+            .get(&recipient)
+            .ok_or_else(|| TransportError::Unreachable(format!("Unknown replica {recipient}!")))?;
+        let mut connections = self
+            .connections
+            .lock()
+            .map_err(|_| TransportError::Poisoned)?;
+        let connection = connections.entry(recipient).or_insert_with(|| {
+            let (sender, receiver) = mpsc::unbounded_channel();
+
+            tokio::spawn(Self::write(address.clone(), receiver));
+            sender
+        });
+
+        connection
+            .send(message.to_bytes())
+            .map_err(|_| TransportError::Closed)
+    }
+
+    async fn receive(&self) -> TransportResult<Option<M>> {
+        Ok(self.inbox.lock().await.recv().await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, net::TcpListener as StdTcpListener, time::Duration};
+
+    use togo_vr::{replica::ReplicaIdentity, transport::TransportChannel};
+    use tokio::{io::AsyncReadExt, io::AsyncWriteExt, net::TcpStream, time::timeout};
+
+    use crate::protocol::write_frame;
+
+    use super::TcpTransport;
+
+    fn free_address() -> String {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+
+        listener.local_addr().unwrap().to_string()
+    }
+
+    async fn pair() -> (TcpTransport<u64>, TcpTransport<u64>, String) {
+        let (first, second) = (free_address(), free_address());
+        let sender = TcpTransport::bind(
+            &first,
+            BTreeMap::from([(ReplicaIdentity(2), second.clone())]),
+        )
+        .await
+        .unwrap();
+        let receiver = TcpTransport::bind(&second, BTreeMap::from([(ReplicaIdentity(1), first)]))
+            .await
+            .unwrap();
+
+        (sender, receiver, second)
+    }
+
+    async fn receive(transport: &TcpTransport<u64>) -> Option<u64> {
+        timeout(Duration::from_secs(5), transport.receive())
+            .await
+            .expect("Nothing has arrived!")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    pub async fn messages_are_delivered_as_frames() {
+        let (sender, receiver, _) = pair().await;
+
+        for value in 1..=3 {
+            sender.send(ReplicaIdentity(2), value).await.unwrap();
+        }
+
+        for value in 1..=3 {
+            assert_eq!(receive(&receiver).await, Some(value));
+        }
+
+        assert!(sender.send(ReplicaIdentity(3), 4).await.is_err());
+    }
+
+    #[tokio::test]
+    pub async fn malformed_frames_only_drop_their_connection() {
+        let (sender, receiver, address) = pair().await;
+
+        // a frame too short for the message
+        let mut malformed = TcpStream::connect(&address).await.unwrap();
+
+        write_frame(&mut malformed, &[1, 2, 3]).await.unwrap();
+
+        let closed = timeout(Duration::from_secs(5), malformed.read(&mut [0; 1])).await;
+
+        assert!(matches!(closed, Ok(Ok(0)) | Ok(Err(_))));
+
+        // a frame cut off by the connection closing
+        let mut truncated = TcpStream::connect(&address).await.unwrap();
+
+        truncated.write_u32(100).await.unwrap();
+        truncated.write_all(&[0; 4]).await.unwrap();
+        drop(truncated);
+
+        sender.send(ReplicaIdentity(2), 7).await.unwrap();
+        assert_eq!(receive(&receiver).await, Some(7));
+    }
+}